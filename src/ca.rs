@@ -7,6 +7,7 @@ use std::path::PathBuf;
 use log;
 
 use crate::enums;
+use crate::mad::CapabilityMask;
 
 pub const SYS_INFINIBAND: &str = "/sys/class/infiniband";
 
@@ -101,7 +102,56 @@ pub struct IbCa {
     pub dev_paths: Option<IbCaDevPaths>,
 }
 
+/// Splits a sysfs rate such as "100 Gb/sec (4X EDR)" into Gb/s, width and speed.
+/// Older kernels omit the speed for SDR ("10 Gb/sec (4X)"); it is derived from the
+/// per-lane rate in that case.
+fn parse_rate(rate: &str) -> Option<(f64, enums::LinkWidth, enums::LinkSpeed)> {
+    let gbps: f64 = rate.split_whitespace().next()?.parse().ok()?;
+
+    let start = rate.find('(')?;
+    let end = rate[start..].find(')')? + start;
+    let mut parts = rate[start + 1..end].split_whitespace();
+
+    let lanes: u8 = parts.next()?.trim_end_matches(['X', 'x']).parse().ok()?;
+    let width = enums::LinkWidth::from_lanes(lanes)?;
+
+    let speed = match parts.next() {
+        Some(s) => s.parse().ok()?,
+        None => {
+            let lane = gbps / lanes as f64;
+            enums::LinkSpeed::ALL
+                .into_iter()
+                .find(|s| (s.lane_gbps() - lane).abs() < 0.5)?
+        }
+    };
+
+    Some((gbps, width, speed))
+}
+
 impl IbCaPort {
+    pub fn capabilities(&self) -> CapabilityMask {
+        CapabilityMask(self.cap_mask)
+    }
+
+    /// The leading rate in the sysfs `rate` file, in Gb/s.
+    pub fn rate_gbps(&self) -> Option<f64> {
+        parse_rate(self.rate.as_deref()?).map(|(gbps, _, _)| gbps)
+    }
+
+    pub fn link_width(&self) -> Option<enums::LinkWidth> {
+        parse_rate(self.rate.as_deref()?).map(|(_, width, _)| width)
+    }
+
+    pub fn link_speed(&self) -> Option<enums::LinkSpeed> {
+        parse_rate(self.rate.as_deref()?).map(|(_, _, speed)| speed)
+    }
+
+    /// Data rate after line encoding, comparable with `port_info::data_rate_gbps`.
+    pub fn data_rate_gbps(&self) -> Option<f64> {
+        let (_, width, speed) = parse_rate(self.rate.as_deref()?)?;
+        Some(enums::link_data_rate_gbps(width, speed))
+    }
+
     pub fn get_counters(&self) -> Result<HashMap<String, u64>, io::Error> {
         let mut counters = HashMap::new();
 
@@ -0,0 +1,159 @@
+use std::io;
+
+use super::lib::{Fabric, SmpTarget};
+use crate::{
+    enums,
+    mad::{self, port_info},
+};
+
+impl Fabric {
+    /// Fetches PortInfo for `port_num` on a DR path or LID.
+    pub fn get_port_info(
+        &mut self,
+        target: SmpTarget,
+        port_num: u8,
+    ) -> Result<port_info, io::Error> {
+        let attr = self.smp_request(
+            target,
+            enums::Methods::Get,
+            enums::SmiAttrID::PortInfo,
+            port_num as u32,
+            [0; 64],
+        )?;
+        port_info::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "could not parse portinfo data.")
+        })
+    }
+
+    /// Writes `pi` verbatim with a SubnSet and returns the PortInfo from the GetResp.
+    ///
+    /// Prefer `modify_port_info`, which preserves the fields you don't touch.
+    pub fn set_port_info(
+        &mut self,
+        target: SmpTarget,
+        port_num: u8,
+        pi: &port_info,
+    ) -> Result<port_info, io::Error> {
+        log::debug!("Setting PortInfo for port {} on {}", port_num, target);
+
+        let attr = self.smp_request(
+            target,
+            enums::Methods::Set,
+            enums::SmiAttrID::PortInfo,
+            port_num as u32,
+            pi.data,
+        )?;
+        port_info::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "could not parse portinfo data.")
+        })
+    }
+
+    /// Read-modify-write of a port's PortInfo.
+    ///
+    /// The current PortInfo is read first, and PortState and PortPhysicalState are
+    /// zeroed ("no state change") so that writing back what was read doesn't request
+    /// a transition. `f` then applies the change and the result is Set.
+    pub fn modify_port_info<F>(
+        &mut self,
+        target: SmpTarget,
+        port_num: u8,
+        f: F,
+    ) -> Result<port_info, io::Error>
+    where
+        F: FnOnce(&mut port_info),
+    {
+        let mut pi = self.get_port_info(target, port_num)?;
+        pi.set_port_state(0);
+        pi.set_port_physical_state(0);
+        f(&mut pi);
+        self.set_port_info(target, port_num, &pi)
+    }
+
+    /// Disables the port's physical layer; the link goes down and stays down.
+    pub fn disable_port(
+        &mut self,
+        target: SmpTarget,
+        port_num: u8,
+    ) -> Result<port_info, io::Error> {
+        log::info!("Disabling port {} on {}", port_num, target);
+        self.modify_port_info(target, port_num, |pi| {
+            pi.set_port_physical_state(enums::IbPortPhyState::Disabled as u8)
+        })
+    }
+
+    /// Re-enables a disabled port by moving it to Polling so the link can train.
+    pub fn enable_port(&mut self, target: SmpTarget, port_num: u8) -> Result<port_info, io::Error> {
+        log::info!("Enabling port {} on {}", port_num, target);
+        self.modify_port_info(target, port_num, |pi| {
+            pi.set_port_physical_state(enums::IbPortPhyState::Polling as u8)
+        })
+    }
+
+    /// Bounces the link: disable followed by enable, like `ibportstate reset`.
+    ///
+    /// Don't reset the port the SMPs leave through, or a port on the only route to
+    /// `target`, since the enable will never arrive.
+    pub fn reset_port(&mut self, target: SmpTarget, port_num: u8) -> Result<port_info, io::Error> {
+        self.disable_port(target, port_num)?;
+        self.enable_port(target, port_num)
+    }
+
+    /// Sets LinkWidthEnabled (PortInfo bit mask: 1 = 1x, 2 = 4x, 4 = 8x, 8 = 12x,
+    /// 16 = 2x). The new width takes effect when the link next trains.
+    pub fn set_port_link_width_enabled(
+        &mut self,
+        target: SmpTarget,
+        port_num: u8,
+        width: u8,
+    ) -> Result<port_info, io::Error> {
+        log::info!(
+            "Setting LinkWidthEnabled {:#x} on port {} of {}",
+            width,
+            port_num,
+            target
+        );
+        self.modify_port_info(target, port_num, |pi| pi.set_link_width_enabled(width))
+    }
+
+    /// Sets LinkSpeedEnabled (PortInfo bit mask: 1 = SDR, 2 = DDR, 4 = QDR).
+    /// The new speed takes effect when the link next trains.
+    pub fn set_port_link_speed_enabled(
+        &mut self,
+        target: SmpTarget,
+        port_num: u8,
+        speed: u8,
+    ) -> Result<port_info, io::Error> {
+        log::info!(
+            "Setting LinkSpeedEnabled {:#x} on port {} of {}",
+            speed,
+            port_num,
+            target
+        );
+        self.modify_port_info(target, port_num, |pi| pi.set_link_speed_enabled(speed))
+    }
+
+    /// Writes a node's NodeDescription and returns the description from the GetResp.
+    /// Descriptions are truncated to the 64 bytes the attribute holds.
+    pub fn set_node_description(
+        &mut self,
+        target: SmpTarget,
+        description: &str,
+    ) -> Result<String, io::Error> {
+        log::info!("Setting NodeDescription of {} to '{}'", target, description);
+
+        let mut attr = [0; 64];
+        let bytes = description.as_bytes();
+        let len = bytes.len().min(mad::node::NODE_DESC_LENGTH);
+        attr[..len].copy_from_slice(&bytes[..len]);
+
+        let attr = self.smp_request(
+            target,
+            enums::Methods::Set,
+            enums::SmiAttrID::NodeDesc,
+            0,
+            attr,
+        )?;
+        let end = attr.iter().position(|&b| b == 0).unwrap_or(attr.len());
+        Ok(String::from_utf8_lossy(&attr[..end]).to_string())
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt, io,
+    str::FromStr,
+};
+
+use super::diff::{LinkRate, NodeChange, PortId, rate_str};
+use super::ibnetdiscover::{guid_from_name, parse_link};
+use super::lib::{Fabric, lock_err};
+use crate::enums::{LinkSpeed, LinkWidth};
+
+/// How a cable map names a node: by GUID, or by its NodeDescription.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NodeRef {
+    /// Node GUID in host order.
+    Guid(u64),
+    Name(String),
+}
+
+impl fmt::Display for NodeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeRef::Guid(guid) => write!(f, "0x{:016x}", guid),
+            NodeRef::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+impl FromStr for NodeRef {
+    type Err = ();
+
+    /// `0x<guid>` and ibnetdiscover `S-<guid>` names are GUIDs, anything else is a
+    /// node description.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_matches('"');
+        if s.is_empty() {
+            return Err(());
+        }
+        if let Some(hex) = s.strip_prefix("0x")
+            && let Ok(guid) = u64::from_str_radix(hex, 16)
+        {
+            return Ok(NodeRef::Guid(guid));
+        }
+        Ok(guid_from_name(s).map_or_else(|| NodeRef::Name(s.to_string()), NodeRef::Guid))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CableEnd {
+    pub node: NodeRef,
+    pub port: u8,
+}
+
+impl fmt::Display for CableEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]", self.node, self.port)
+    }
+}
+
+/// One expected cable. A `None` width or speed is not checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cable {
+    pub a: CableEnd,
+    pub b: CableEnd,
+    pub width: Option<LinkWidth>,
+    pub speed: Option<LinkSpeed>,
+}
+
+impl fmt::Display for Cable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <-> {}", self.a, self.b)
+    }
+}
+
+/// The expected cabling of a fabric, see `Fabric::validate_cabling`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CableMap {
+    pub cables: Vec<Cable>,
+}
+
+fn csv_err(line: usize, msg: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("cable map line {}: {}", line, msg),
+    )
+}
+
+// "4xHDR", "4x" or "HDR".
+fn parse_rate(word: &str) -> Option<(Option<LinkWidth>, Option<LinkSpeed>)> {
+    match word.split_once('x') {
+        Some((_, "")) => {
+            let lanes = word.trim_end_matches('x').parse().ok()?;
+            Some((Some(LinkWidth::from_lanes(lanes)?), None))
+        }
+        Some(_) => match parse_link(word) {
+            (Some(width), Some(speed)) => Some((Some(width), Some(speed))),
+            _ => None,
+        },
+        None => Some((None, Some(LinkSpeed::from_str(word).ok()?))),
+    }
+}
+
+impl CableMap {
+    /// Parses a CSV cable map, one cable per line:
+    ///
+    /// ```text
+    /// # node_a, port_a, node_b, port_b[, rate]
+    /// leaf01, 1, 0x0002c903000e0b72, 1, 4xHDR
+    /// ```
+    ///
+    /// Nodes are GUIDs or node descriptions; the optional rate is `4xHDR`, `4x` or
+    /// `HDR`. Blank lines, `#` comments and a `node_a,...` header row are skipped.
+    pub fn from_csv(text: &str) -> Result<CableMap, io::Error> {
+        let mut cables = Vec::new();
+
+        for (idx, line) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if cables.is_empty() && fields[0].eq_ignore_ascii_case("node_a") {
+                continue;
+            }
+            if fields.len() < 4 || fields.len() > 5 {
+                return Err(csv_err(line_no, "expected 4 or 5 fields"));
+            }
+
+            let end = |node: &str, port: &str| -> Result<CableEnd, io::Error> {
+                Ok(CableEnd {
+                    node: node
+                        .parse()
+                        .map_err(|_| csv_err(line_no, "empty node name"))?,
+                    port: port
+                        .parse()
+                        .map_err(|_| csv_err(line_no, format!("invalid port '{}'", port)))?,
+                })
+            };
+            let (width, speed) = match fields.get(4) {
+                Some(rate) if !rate.is_empty() => parse_rate(rate)
+                    .ok_or_else(|| csv_err(line_no, format!("invalid rate '{}'", rate)))?,
+                _ => (None, None),
+            };
+
+            cables.push(Cable {
+                a: end(fields[0], fields[1])?,
+                b: end(fields[2], fields[3])?,
+                width,
+                speed,
+            });
+        }
+
+        Ok(CableMap { cables })
+    }
+
+    /// Every link of `fabric`, by GUID, with its active rate as the expected rate.
+    /// A snapshot of a known good fabric makes a cable map for its replacements.
+    pub fn from_fabric(fabric: &Fabric) -> Result<CableMap, io::Error> {
+        let mut cables = Vec::new();
+        for node_arc in &fabric.nodes {
+            let node = node_arc.read().map_err(lock_err)?;
+            for port_arc in &node.ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                let Some(remote_arc) = port.remote() else {
+                    continue;
+                };
+                let remote = remote_arc.read().map_err(lock_err)?;
+                let Some(remote_node) = remote.parent.upgrade() else {
+                    continue;
+                };
+                let a = CableEnd {
+                    node: NodeRef::Guid(node.node_guid.to_be()),
+                    port: port.number,
+                };
+                let b = CableEnd {
+                    node: NodeRef::Guid(remote_node.read().map_err(lock_err)?.node_guid.to_be()),
+                    port: remote.number,
+                };
+                if a < b {
+                    cables.push(Cable {
+                        a,
+                        b,
+                        width: port.link_width_active,
+                        speed: port.link_speed_active,
+                    });
+                }
+            }
+        }
+        cables.sort_by(|x, y| (&x.a, &x.b).cmp(&(&y.a, &y.b)));
+
+        Ok(CableMap { cables })
+    }
+
+    /// Reads an ibnetdiscover topology file as a cable map, see `from_fabric`.
+    pub fn from_ibnetdiscover(text: &str) -> Result<CableMap, io::Error> {
+        CableMap::from_fabric(&Fabric::from_ibnetdiscover(text)?)
+    }
+}
+
+/// A cable end whose port is connected somewhere other than the cable map says.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Miswire {
+    pub end: CableEnd,
+    pub expected: CableEnd,
+    pub actual: PortId,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateMismatch {
+    pub cable: Cable,
+    pub actual: LinkRate,
+}
+
+/// Result of `Fabric::validate_cabling`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CablingReport {
+    /// Cables with neither end connected elsewhere, including cables to nodes that
+    /// were not found.
+    pub missing_links: Vec<Cable>,
+    pub miswired: Vec<Miswire>,
+    /// Nodes in the cable map that are not in the fabric.
+    pub missing_nodes: Vec<NodeRef>,
+    /// Nodes in the fabric that no cable mentions.
+    pub unexpected_nodes: Vec<NodeChange>,
+    pub wrong_rates: Vec<RateMismatch>,
+}
+
+impl CablingReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing_links.is_empty()
+            && self.miswired.is_empty()
+            && self.missing_nodes.is_empty()
+            && self.unexpected_nodes.is_empty()
+            && self.wrong_rates.is_empty()
+    }
+}
+
+/// One problem per line.
+impl fmt::Display for CablingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in &self.missing_nodes {
+            writeln!(f, "missing node: {}", node)?;
+        }
+        for n in &self.unexpected_nodes {
+            let desc = n.description.as_deref().unwrap_or("N/A");
+            writeln!(
+                f,
+                "unexpected node: 0x{:016x} '{}'",
+                n.node_guid.to_be(),
+                desc
+            )?;
+        }
+        for cable in &self.missing_links {
+            writeln!(f, "missing link: {}", cable)?;
+        }
+        for m in &self.miswired {
+            writeln!(
+                f,
+                "miswired: {} should go to {} but goes to {}",
+                m.end, m.expected, m.actual
+            )?;
+        }
+        for r in &self.wrong_rates {
+            writeln!(
+                f,
+                "wrong rate: {} expected {} got {}",
+                r.cable,
+                rate_str(&(r.cable.width, r.cable.speed)),
+                rate_str(&r.actual)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Resolver {
+    by_desc: HashMap<String, Vec<u64>>,
+    guids: BTreeSet<u64>,
+}
+
+impl Resolver {
+    /// The `node_map` key of a node reference. Descriptions shared by several
+    /// nodes can't identify a cable end and are rejected.
+    fn resolve(&self, node: &NodeRef) -> Result<Option<u64>, io::Error> {
+        match node {
+            NodeRef::Guid(guid) => Ok(self.guids.get(&guid.to_be()).copied()),
+            NodeRef::Name(name) => match self.by_desc.get(name).map(Vec::as_slice) {
+                None | Some([]) => Ok(None),
+                Some([guid]) => Ok(Some(*guid)),
+                Some(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("node name '{}' matches several nodes", name),
+                )),
+            },
+        }
+    }
+}
+
+impl Fabric {
+    /// Checks the fabric against an expected cable map.
+    ///
+    /// A cable is fine when its two ports are linked to each other. If either port
+    /// is linked somewhere else that end is reported as miswired, otherwise the cable
+    /// is missing. Cables that are in place are checked against their expected width
+    /// and speed.
+    pub fn validate_cabling(&self, expected: &CableMap) -> Result<CablingReport, io::Error> {
+        let mut resolver = Resolver {
+            by_desc: HashMap::new(),
+            guids: BTreeSet::new(),
+        };
+        let mut peers: HashMap<PortId, (Option<PortId>, LinkRate)> = HashMap::new();
+        for (guid, node_arc) in &self.node_map {
+            let node = node_arc.read().map_err(lock_err)?;
+            resolver.guids.insert(*guid);
+            if let Some(desc) = &node.description {
+                resolver
+                    .by_desc
+                    .entry(desc.clone())
+                    .or_default()
+                    .push(*guid);
+            }
+            for port_arc in &node.ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                let mut remote_id = None;
+                if let Some(remote_arc) = port.remote() {
+                    let remote = remote_arc.read().map_err(lock_err)?;
+                    if let Some(parent) = remote.parent.upgrade() {
+                        remote_id = Some(PortId {
+                            node_guid: parent.read().map_err(lock_err)?.node_guid,
+                            port: remote.number,
+                        });
+                    }
+                }
+                peers.insert(
+                    PortId {
+                        node_guid: *guid,
+                        port: port.number,
+                    },
+                    (remote_id, (port.link_width_active, port.link_speed_active)),
+                );
+            }
+        }
+
+        let mut report = CablingReport::default();
+        let mut missing_nodes = BTreeSet::new();
+        let mut seen_nodes = BTreeSet::new();
+
+        for cable in &expected.cables {
+            let mut ids = [None, None];
+            for (id, end) in ids.iter_mut().zip([&cable.a, &cable.b]) {
+                match resolver.resolve(&end.node)? {
+                    Some(guid) => {
+                        seen_nodes.insert(guid);
+                        *id = Some(PortId {
+                            node_guid: guid,
+                            port: end.port,
+                        });
+                    }
+                    None => {
+                        missing_nodes.insert(end.node.clone());
+                    }
+                }
+            }
+            let peer = |id: Option<PortId>| id.and_then(|id| peers.get(&id).and_then(|p| p.0));
+            let (peer_a, peer_b) = (peer(ids[0]), peer(ids[1]));
+
+            if ids[1].is_some() && peer_a == ids[1] {
+                let (width, speed) = peers[&ids[0].unwrap()].1;
+                let width_ok = cable.width.is_none() || cable.width == width;
+                let speed_ok = cable.speed.is_none() || cable.speed == speed;
+                if !width_ok || !speed_ok {
+                    report.wrong_rates.push(RateMismatch {
+                        cable: cable.clone(),
+                        actual: (width, speed),
+                    });
+                }
+                continue;
+            }
+
+            if peer_a.is_none() && peer_b.is_none() {
+                report.missing_links.push(cable.clone());
+                continue;
+            }
+            for (end, expected, actual) in
+                [(&cable.a, &cable.b, peer_a), (&cable.b, &cable.a, peer_b)]
+            {
+                if let Some(actual) = actual {
+                    report.miswired.push(Miswire {
+                        end: end.clone(),
+                        expected: expected.clone(),
+                        actual,
+                    });
+                }
+            }
+        }
+
+        report.missing_nodes = missing_nodes.into_iter().collect();
+        for (guid, node_arc) in &self.node_map {
+            if !seen_nodes.contains(guid) {
+                report.unexpected_nodes.push(NodeChange {
+                    node_guid: *guid,
+                    description: node_arc.read().map_err(lock_err)?.description.clone(),
+                });
+            }
+        }
+        report.unexpected_nodes.sort_by_key(|n| n.node_guid.to_be());
+
+        Ok(report)
+    }
+}
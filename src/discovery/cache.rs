@@ -0,0 +1,214 @@
+use std::{collections::HashMap, io};
+
+use super::lib::{Fabric, Node, Port, START_PATH, lock_err};
+use crate::{
+    enums,
+    mad::{node_info, port_info},
+};
+
+/// libibnetdisc cache file magic and the only version it has written.
+pub const IBND_CACHE_MAGIC: u32 = 0x8FE7_832B;
+pub const IBND_CACHE_VERSION: u32 = 1;
+
+const SMP_DATA_SIZE: usize = 64;
+
+/// Reader over the little-endian records `ibnetdiscover --cache` writes.
+struct CacheReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> CacheReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("ibnetdiscover cache truncated at offset {}", self.offset),
+                )
+            })?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, io::Error> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, io::Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, io::Error> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+struct CachedPort {
+    guid: u64,
+    node_guid: u64,
+    port: Port,
+    remote: Option<(u64, u8)>,
+}
+
+fn read_node(r: &mut CacheReader) -> Result<(u64, Node), io::Error> {
+    let sma_lid = r.u16()?;
+    let _sma_lmc = r.u8()?;
+    let enhanced_port0 = r.u8()?;
+    r.bytes(SMP_DATA_SIZE)?; // SwitchInfo
+    let guid = r.u64()?;
+    let _node_type = r.u8()?;
+    let _nports = r.u8()?;
+    let ni = node_info::from_bytes(r.bytes(SMP_DATA_SIZE)?).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "could not parse cached nodeinfo.",
+        )
+    })?;
+    let desc = r.bytes(SMP_DATA_SIZE)?;
+
+    // The port keys repeat what the port records already say.
+    let ports_stored = r.u8()?;
+    r.bytes(ports_stored as usize * 9)?;
+
+    let mut node = Node::from_node_info(&ni, START_PATH)?;
+    let end = desc.iter().position(|&b| b == 0).unwrap_or(desc.len());
+    node.description = Some(String::from_utf8_lossy(&desc[..end]).to_string());
+    if node.node_type == enums::IbNodeType::Switch {
+        node.lid = sma_lid;
+        node.enhanced_port0 = enhanced_port0 != 0;
+    }
+
+    Ok((guid, node))
+}
+
+fn read_port(r: &mut CacheReader) -> Result<CachedPort, io::Error> {
+    let guid = r.u64()?;
+    let number = r.u8()?;
+    let _ext_number = r.u8()?;
+    let base_lid = r.u16()?;
+    let lmc = r.u8()?;
+    let pi = port_info::from_bytes(r.bytes(SMP_DATA_SIZE)?).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "could not parse cached portinfo.",
+        )
+    })?;
+    let node_guid = r.u64()?;
+    let has_remote = r.u8()? != 0;
+    let remote_guid = r.u64()?;
+    let remote_number = r.u8()?;
+
+    let mut port = Port::from_port_info(number, &pi)?;
+    port.lid = base_lid;
+    port.lmc = lmc;
+
+    Ok(CachedPort {
+        guid,
+        node_guid,
+        port,
+        remote: has_remote.then_some((remote_guid, remote_number)),
+    })
+}
+
+impl Fabric {
+    /// Builds an offline fabric from a libibnetdisc cache, as written by
+    /// `ibnetdiscover --cache`.
+    ///
+    /// The cache keeps the raw NodeInfo and PortInfo of every node and port, so the
+    /// result carries the same per-port detail as a live discovery.
+    pub fn from_ibnetdiscover_cache(data: &[u8]) -> Result<Fabric, io::Error> {
+        let mut r = CacheReader { data, offset: 0 };
+
+        let magic = r.u32()?;
+        if magic != IBND_CACHE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not an ibnetdiscover cache (magic 0x{:08X})", magic),
+            ));
+        }
+        let version = r.u32()?;
+        if version != IBND_CACHE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported ibnetdiscover cache version {}", version),
+            ));
+        }
+        let node_count = r.u32()?;
+        let port_count = r.u32()?;
+        let from_node_guid = r.u64()?;
+        let _max_hops = r.u32()?;
+
+        let mut nodes = Vec::with_capacity(node_count as usize);
+        for _ in 0..node_count {
+            nodes.push(read_node(&mut r)?);
+        }
+
+        // Cache GUIDs are in host order. Ports are keyed on (port GUID, port number)
+        // since all ports of a switch share one GUID.
+        let mut ports: HashMap<u64, Vec<(u64, Port)>> = HashMap::new();
+        let mut links = Vec::new();
+        for _ in 0..port_count {
+            let cached = read_port(&mut r)?;
+            if let Some(remote) = cached.remote {
+                links.push(((cached.guid, cached.port.number), remote));
+            }
+            ports
+                .entry(cached.node_guid)
+                .or_default()
+                .push((cached.guid, cached.port));
+        }
+
+        let mut fabric = Fabric::offline();
+        let mut raw_guids = HashMap::new();
+        let mut by_key = HashMap::new();
+        for (guid, node) in nodes {
+            raw_guids.insert(guid, node.node_guid);
+            let is_switch = node.node_type == enums::IbNodeType::Switch;
+            let mut node_ports = ports.remove(&guid).unwrap_or_default();
+            node_ports.sort_by_key(|(_, p)| p.number);
+
+            let mut keys = Vec::with_capacity(node_ports.len());
+            let node_ports = node_ports
+                .into_iter()
+                .map(|(port_guid, mut port)| {
+                    keys.push((port_guid, port.number));
+                    // Like `discover_guids`, only switch port 0 carries the switch GUID.
+                    if !is_switch || port.number == 0 {
+                        port.guids = vec![port_guid.to_be()];
+                    }
+                    port
+                })
+                .collect();
+
+            let node_arc = fabric.insert_node(node, node_ports)?;
+            let node = node_arc.read().map_err(lock_err)?;
+            for (key, port_arc) in keys.into_iter().zip(&node.ports) {
+                by_key.insert(key, port_arc.clone());
+            }
+        }
+
+        for (local, remote) in links {
+            match (by_key.get(&local), by_key.get(&remote)) {
+                (Some(a), Some(b)) => Fabric::link_ports(a, b)?,
+                _ => log::debug!(
+                    "ibnetdiscover cache: dropping link 0x{:x}[{}] -> 0x{:x}[{}]",
+                    local.0,
+                    local.1,
+                    remote.0,
+                    remote.1
+                ),
+            }
+        }
+
+        let start = raw_guids.get(&from_node_guid).copied();
+        fabric.finish_import(start)?;
+        Ok(fabric)
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+};
+
+use super::lib::{Fabric, lock_err};
+use crate::enums::{self, LinkSpeed, LinkWidth};
+
+/// A port, identified by its node's `node_map` key and port number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PortId {
+    pub node_guid: u64,
+    pub port: u8,
+}
+
+impl fmt::Display for PortId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:016x}[{}]", self.node_guid.to_be(), self.port)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeChange {
+    pub node_guid: u64,
+    pub description: Option<String>,
+}
+
+/// A port that is linked in both snapshots, but to a different remote port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkMove {
+    pub port: PortId,
+    pub old_remote: PortId,
+    pub new_remote: PortId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortChange<T> {
+    pub port: PortId,
+    pub old: T,
+    pub new: T,
+}
+
+/// Active width and speed of a port.
+pub type LinkRate = (Option<LinkWidth>, Option<LinkSpeed>);
+
+/// What changed between two `Fabric` snapshots, see `Fabric::diff`.
+///
+/// Links are stored with the lower `PortId` first. Every list is sorted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopologyDiff {
+    pub nodes_added: Vec<NodeChange>,
+    pub nodes_removed: Vec<NodeChange>,
+    pub links_added: Vec<(PortId, PortId)>,
+    pub links_removed: Vec<(PortId, PortId)>,
+    pub links_moved: Vec<LinkMove>,
+    pub link_state_changes: Vec<PortChange<enums::IbPortLinkLayerState>>,
+    pub link_rate_changes: Vec<PortChange<LinkRate>>,
+    /// LID changes on CA ports and switch port 0. External switch ports share the
+    /// port 0 LID and are not listed again.
+    pub lid_changes: Vec<PortChange<u16>>,
+}
+
+impl TopologyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.nodes_added.is_empty()
+            && self.nodes_removed.is_empty()
+            && self.links_added.is_empty()
+            && self.links_removed.is_empty()
+            && self.links_moved.is_empty()
+            && self.link_state_changes.is_empty()
+            && self.link_rate_changes.is_empty()
+            && self.lid_changes.is_empty()
+    }
+}
+
+pub(crate) fn rate_str((width, speed): &LinkRate) -> String {
+    match (width, speed) {
+        (Some(w), Some(s)) => format!("{}x{}", w.lanes(), s),
+        (Some(w), None) => format!("{}x", w.lanes()),
+        (None, Some(s)) => s.to_string(),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+/// One change per line, for logs and alerts.
+impl fmt::Display for TopologyDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for n in &self.nodes_added {
+            let desc = n.description.as_deref().unwrap_or("N/A");
+            writeln!(f, "node added: 0x{:016x} '{}'", n.node_guid.to_be(), desc)?;
+        }
+        for n in &self.nodes_removed {
+            let desc = n.description.as_deref().unwrap_or("N/A");
+            writeln!(f, "node removed: 0x{:016x} '{}'", n.node_guid.to_be(), desc)?;
+        }
+        for (a, b) in &self.links_added {
+            writeln!(f, "link added: {} <-> {}", a, b)?;
+        }
+        for (a, b) in &self.links_removed {
+            writeln!(f, "link removed: {} <-> {}", a, b)?;
+        }
+        for m in &self.links_moved {
+            writeln!(
+                f,
+                "link moved: {} was {} now {}",
+                m.port, m.old_remote, m.new_remote
+            )?;
+        }
+        for c in &self.link_state_changes {
+            writeln!(f, "link state: {} {:?} -> {:?}", c.port, c.old, c.new)?;
+        }
+        for c in &self.link_rate_changes {
+            writeln!(
+                f,
+                "link rate: {} {} -> {}",
+                c.port,
+                rate_str(&c.old),
+                rate_str(&c.new)
+            )?;
+        }
+        for c in &self.lid_changes {
+            writeln!(f, "lid: {} {} -> {}", c.port, c.old, c.new)?;
+        }
+        Ok(())
+    }
+}
+
+struct PortSnapshot {
+    state: enums::IbPortLinkLayerState,
+    rate: LinkRate,
+    lid: u16,
+    shares_switch_lid: bool,
+    remote: Option<PortId>,
+}
+
+struct Snapshot {
+    nodes: BTreeMap<u64, Option<String>>,
+    ports: BTreeMap<PortId, PortSnapshot>,
+}
+
+impl Snapshot {
+    fn take(fabric: &Fabric) -> Result<Snapshot, io::Error> {
+        let mut nodes = BTreeMap::new();
+        let mut ports = BTreeMap::new();
+
+        for (guid, node_arc) in &fabric.node_map {
+            let node = node_arc.read().map_err(lock_err)?;
+            nodes.insert(*guid, node.description.clone());
+            let is_switch = node.node_type == enums::IbNodeType::Switch;
+
+            for port_arc in &node.ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                let remote = match port.remote() {
+                    Some(remote_arc) => {
+                        let remote = remote_arc.read().map_err(lock_err)?;
+                        match remote.parent.upgrade() {
+                            Some(parent) => Some(PortId {
+                                node_guid: parent.read().map_err(lock_err)?.node_guid,
+                                port: remote.number,
+                            }),
+                            None => None,
+                        }
+                    }
+                    None => None,
+                };
+
+                ports.insert(
+                    PortId {
+                        node_guid: *guid,
+                        port: port.number,
+                    },
+                    PortSnapshot {
+                        state: port.link_state.clone(),
+                        rate: (port.link_width_active, port.link_speed_active),
+                        lid: port.lid,
+                        shares_switch_lid: is_switch && port.number != 0,
+                        remote,
+                    },
+                );
+            }
+        }
+
+        Ok(Snapshot { nodes, ports })
+    }
+
+    fn links(&self) -> BTreeSet<(PortId, PortId)> {
+        self.ports
+            .iter()
+            .filter_map(|(id, p)| p.remote.map(|r| ((*id).min(r), (*id).max(r))))
+            .collect()
+    }
+}
+
+impl Fabric {
+    /// Compares this fabric with a newer snapshot of the same subnet.
+    ///
+    /// Nodes are matched by their `node_map` key and ports by node and number, so
+    /// either side may be live or loaded from a file. A port that is linked in both
+    /// snapshots but to a different remote port is reported as moved rather than as
+    /// a link removed and added.
+    pub fn diff(&self, newer: &Fabric) -> Result<TopologyDiff, io::Error> {
+        let old = Snapshot::take(self)?;
+        let new = Snapshot::take(newer)?;
+        let mut diff = TopologyDiff::default();
+
+        let node_change = |(guid, description): (&u64, &Option<String>)| NodeChange {
+            node_guid: *guid,
+            description: description.clone(),
+        };
+        diff.nodes_added = new
+            .nodes
+            .iter()
+            .filter(|(guid, _)| !old.nodes.contains_key(guid))
+            .map(node_change)
+            .collect();
+        diff.nodes_removed = old
+            .nodes
+            .iter()
+            .filter(|(guid, _)| !new.nodes.contains_key(guid))
+            .map(node_change)
+            .collect();
+
+        let mut moved = BTreeSet::new();
+        for (id, new_port) in &new.ports {
+            let Some(old_port) = old.ports.get(id) else {
+                continue;
+            };
+
+            if let (Some(old_remote), Some(new_remote)) = (old_port.remote, new_port.remote)
+                && old_remote != new_remote
+            {
+                moved.insert(*id);
+                diff.links_moved.push(LinkMove {
+                    port: *id,
+                    old_remote,
+                    new_remote,
+                });
+            }
+            if old_port.state != new_port.state {
+                diff.link_state_changes.push(PortChange {
+                    port: *id,
+                    old: old_port.state.clone(),
+                    new: new_port.state.clone(),
+                });
+            }
+            if old_port.rate != new_port.rate {
+                diff.link_rate_changes.push(PortChange {
+                    port: *id,
+                    old: old_port.rate,
+                    new: new_port.rate,
+                });
+            }
+            if old_port.lid != new_port.lid && !new_port.shares_switch_lid {
+                diff.lid_changes.push(PortChange {
+                    port: *id,
+                    old: old_port.lid,
+                    new: new_port.lid,
+                });
+            }
+        }
+
+        // Links touching a moved port are already covered by `links_moved`.
+        let involves_move = |(a, b): &&(PortId, PortId)| moved.contains(a) || moved.contains(b);
+        let old_links = old.links();
+        let new_links = new.links();
+        diff.links_added = new_links
+            .difference(&old_links)
+            .filter(|l| !involves_move(l))
+            .copied()
+            .collect();
+        diff.links_removed = old_links
+            .difference(&new_links)
+            .filter(|l| !involves_move(l))
+            .copied()
+            .collect();
+
+        Ok(diff)
+    }
+}
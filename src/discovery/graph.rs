@@ -0,0 +1,332 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, Write},
+    sync::Arc,
+};
+
+use super::ibnetdiscover::link_str;
+use super::lib::{Fabric, lock_err};
+use super::link::link_issues;
+use super::nvlink::NVLINK_RING_PORTS;
+use crate::enums;
+
+/// Where a node sits in an NVLink spine/leaf fabric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Tier {
+    Spine,
+    Leaf,
+    Endpoint,
+}
+
+impl Tier {
+    fn name(self) -> &'static str {
+        match self {
+            Tier::Spine => "spine",
+            Tier::Leaf => "leaf",
+            Tier::Endpoint => "endpoint",
+        }
+    }
+}
+
+struct GraphNode {
+    id: String,
+    node_type: enums::IbNodeType,
+    description: String,
+    lid: u16,
+    hops: u8,
+    /// Set on every node when the fabric is an NVLink ring.
+    tier: Option<Tier>,
+}
+
+struct GraphEdge {
+    source: String,
+    source_port: u8,
+    target: String,
+    target_port: u8,
+    rate: String,
+    active: bool,
+    degraded: bool,
+    /// Links ring port 73 or 74 of one switch to a ring port of another.
+    ring: bool,
+}
+
+/// Nodes in `nodes` order and every link once, shared by the DOT and GraphML writers.
+///
+/// The fabric is taken as NVLink when every switch has both ring ports cabled to
+/// another switch, as `seq_discover_nvlink` finds them. Its switches are then leaves
+/// if a CA hangs off them and spines otherwise.
+fn collect(fabric: &Fabric) -> Result<(Vec<GraphNode>, Vec<GraphEdge>), io::Error> {
+    let mut nodes = Vec::with_capacity(fabric.nodes.len());
+    let mut edges = Vec::new();
+    let mut seen = HashSet::new();
+    // Per switch: ring ports cabled to a switch, and whether a CA is linked.
+    let mut switches: HashMap<String, (usize, bool)> = HashMap::new();
+
+    for node_arc in &fabric.nodes {
+        let node = node_arc.read().map_err(lock_err)?;
+        let id = format!("0x{:016x}", node.node_guid.to_be());
+        nodes.push(GraphNode {
+            id: id.clone(),
+            node_type: node.node_type.clone(),
+            description: node.description.clone().unwrap_or_default(),
+            lid: node.lid,
+            hops: Fabric::get_hop_count(&node.dr_path),
+            tier: None,
+        });
+        let is_switch = node.node_type == enums::IbNodeType::Switch;
+        if is_switch {
+            switches.insert(id.clone(), (0, false));
+        }
+
+        for port_arc in &node.ports {
+            let port = port_arc.read().map_err(lock_err)?;
+            let Some(remote_arc) = port.remote() else {
+                continue;
+            };
+            let remote = remote_arc.read().map_err(lock_err)?;
+            let Some(remote_node) = remote.parent.upgrade() else {
+                continue;
+            };
+            let (remote_guid, remote_is_switch) = {
+                let remote_node = remote_node.read().map_err(lock_err)?;
+                (
+                    remote_node.node_guid,
+                    remote_node.node_type == enums::IbNodeType::Switch,
+                )
+            };
+            let ring = is_switch
+                && remote_is_switch
+                && NVLINK_RING_PORTS.contains(&port.number)
+                && NVLINK_RING_PORTS.contains(&remote.number);
+            if let Some((ring_ports, has_ca)) = switches.get_mut(&id) {
+                *ring_ports += ring as usize;
+                *has_ca |= !remote_is_switch;
+            }
+
+            seen.insert(Arc::as_ptr(port_arc));
+            if seen.contains(&Arc::as_ptr(&remote_arc)) {
+                continue;
+            }
+
+            edges.push(GraphEdge {
+                source: id.clone(),
+                source_port: port.number,
+                target: format!("0x{:016x}", remote_guid.to_be()),
+                target_port: remote.number,
+                rate: link_str(&port),
+                active: port.link_state == enums::IbPortLinkLayerState::Active,
+                degraded: !link_issues(&port, &remote).is_empty(),
+                ring,
+            });
+        }
+    }
+
+    let nvlink = !switches.is_empty()
+        && switches
+            .values()
+            .all(|(ring_ports, _)| *ring_ports == NVLINK_RING_PORTS.len());
+    if nvlink {
+        for node in &mut nodes {
+            node.tier = Some(match switches.get(&node.id) {
+                Some((_, true)) => Tier::Leaf,
+                Some((_, false)) => Tier::Spine,
+                None => Tier::Endpoint,
+            });
+        }
+    }
+
+    Ok((nodes, edges))
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn type_name(node_type: &enums::IbNodeType) -> &'static str {
+    match node_type {
+        enums::IbNodeType::CA => "CA",
+        enums::IbNodeType::Switch => "Switch",
+        enums::IbNodeType::Router => "Router",
+        enums::IbNodeType::Rnic => "RNIC",
+    }
+}
+
+impl Fabric {
+    /// Writes the fabric as an undirected GraphViz graph.
+    ///
+    /// Switches are drawn as boxes and CAs as ellipses, ranked by their hop count from
+    /// the local port. Edges carry the port number at each end and the active rate;
+    /// links that are not Active are dashed and degraded links are red.
+    ///
+    /// An NVLink fabric, where every switch is on the ring through ports 73 and 74, is
+    /// ranked as spines, leaves and endpoints instead. Ring links are drawn bold and
+    /// orange and do not pull the ranks together.
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        let (nodes, edges) = collect(self)?;
+
+        writeln!(out, "graph fabric {{")?;
+        writeln!(out, "\trankdir=TB;")?;
+        writeln!(out, "\tnode [style=filled, fontsize=10];")?;
+        writeln!(out, "\tedge [fontsize=8];")?;
+
+        let mut ranks: BTreeMap<u8, Vec<&str>> = BTreeMap::new();
+        let mut tiers: BTreeMap<Tier, Vec<&str>> = BTreeMap::new();
+        for node in &nodes {
+            let (shape, color) = match node.node_type {
+                enums::IbNodeType::Switch => ("box", "lightsteelblue"),
+                enums::IbNodeType::Router => ("diamond", "palegreen"),
+                _ => ("ellipse", "lightgoldenrod"),
+            };
+            writeln!(
+                out,
+                "\t\"{}\" [label=\"{}\\n{}\\nlid {}\", shape={}, fillcolor={}];",
+                node.id,
+                dot_escape(&node.description),
+                node.id,
+                node.lid,
+                shape,
+                color
+            )?;
+            match node.tier {
+                Some(tier) => tiers.entry(tier).or_default().push(&node.id),
+                None => ranks.entry(node.hops).or_default().push(&node.id),
+            }
+        }
+
+        let quote = |ids: &Vec<&str>| -> String {
+            let ids: Vec<String> = ids.iter().map(|id| format!("\"{}\"", id)).collect();
+            ids.join("; ")
+        };
+        for (hops, ids) in &ranks {
+            writeln!(out, "\t{{ rank=same; {}; }} // hop {}", quote(ids), hops)?;
+        }
+        for (tier, ids) in &tiers {
+            writeln!(out, "\t{{ rank=same; {}; }} // {}", quote(ids), tier.name())?;
+        }
+
+        for edge in &edges {
+            let mut attrs = vec![
+                format!("taillabel=\"{}\"", edge.source_port),
+                format!("headlabel=\"{}\"", edge.target_port),
+            ];
+            if !edge.rate.is_empty() {
+                attrs.push(format!("label=\"{}\"", edge.rate));
+            }
+            if !edge.active {
+                attrs.push("style=dashed".to_string());
+            } else if edge.ring {
+                attrs.push("style=bold".to_string());
+            }
+            if edge.degraded {
+                attrs.push("color=red".to_string());
+            } else if edge.ring {
+                attrs.push("color=darkorange".to_string());
+            }
+            if edge.ring {
+                attrs.push("constraint=false".to_string());
+            }
+            writeln!(
+                out,
+                "\t\"{}\" -- \"{}\" [{}];",
+                edge.source,
+                edge.target,
+                attrs.join(", ")
+            )?;
+        }
+
+        writeln!(out, "}}")
+    }
+
+    /// `write_dot` into a string.
+    pub fn to_dot(&self) -> Result<String, io::Error> {
+        let mut out = Vec::new();
+        self.write_dot(&mut out)?;
+        String::from_utf8(out).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the fabric as GraphML for network analysis tools. Node ids are the
+    /// node GUIDs; node and edge attributes mirror what `write_dot` draws. On an
+    /// NVLink fabric nodes carry their tier and ring links are flagged.
+    pub fn write_graphml<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        let (nodes, edges) = collect(self)?;
+
+        writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(
+            out,
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">"
+        )?;
+        for (id, domain, name, kind) in [
+            ("d0", "node", "type", "string"),
+            ("d1", "node", "description", "string"),
+            ("d2", "node", "lid", "int"),
+            ("d3", "node", "hops", "int"),
+            ("d4", "edge", "source_port", "int"),
+            ("d5", "edge", "target_port", "int"),
+            ("d6", "edge", "rate", "string"),
+            ("d7", "edge", "active", "boolean"),
+            ("d8", "edge", "degraded", "boolean"),
+            ("d9", "node", "tier", "string"),
+            ("d10", "edge", "ring", "boolean"),
+        ] {
+            writeln!(
+                out,
+                "  <key id=\"{}\" for=\"{}\" attr.name=\"{}\" attr.type=\"{}\"/>",
+                id, domain, name, kind
+            )?;
+        }
+        writeln!(out, "  <graph id=\"fabric\" edgedefault=\"undirected\">")?;
+
+        for node in &nodes {
+            writeln!(out, "    <node id=\"{}\">", node.id)?;
+            writeln!(
+                out,
+                "      <data key=\"d0\">{}</data>",
+                type_name(&node.node_type)
+            )?;
+            writeln!(
+                out,
+                "      <data key=\"d1\">{}</data>",
+                xml_escape(&node.description)
+            )?;
+            writeln!(out, "      <data key=\"d2\">{}</data>", node.lid)?;
+            writeln!(out, "      <data key=\"d3\">{}</data>", node.hops)?;
+            if let Some(tier) = node.tier {
+                writeln!(out, "      <data key=\"d9\">{}</data>", tier.name())?;
+            }
+            writeln!(out, "    </node>")?;
+        }
+
+        for edge in &edges {
+            writeln!(
+                out,
+                "    <edge source=\"{}\" target=\"{}\">",
+                edge.source, edge.target
+            )?;
+            writeln!(out, "      <data key=\"d4\">{}</data>", edge.source_port)?;
+            writeln!(out, "      <data key=\"d5\">{}</data>", edge.target_port)?;
+            writeln!(out, "      <data key=\"d6\">{}</data>", edge.rate)?;
+            writeln!(out, "      <data key=\"d7\">{}</data>", edge.active)?;
+            writeln!(out, "      <data key=\"d8\">{}</data>", edge.degraded)?;
+            writeln!(out, "      <data key=\"d10\">{}</data>", edge.ring)?;
+            writeln!(out, "    </edge>")?;
+        }
+
+        writeln!(out, "  </graph>")?;
+        writeln!(out, "</graphml>")
+    }
+
+    /// `write_graphml` into a string.
+    pub fn to_graphml(&self) -> Result<String, io::Error> {
+        let mut out = Vec::new();
+        self.write_graphml(&mut out)?;
+        String::from_utf8(out).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::lib::{Fabric, Port, lock_err};
+use crate::{
+    enums,
+    mad::{guid::GUID_BLOCK_SIZE, guid_info},
+};
+
+impl Fabric {
+    /// Fetches one 8-entry GUIDInfo block. The port is implied by the DR path.
+    pub fn fetch_guid_info(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        block: u32,
+    ) -> Result<guid_info, io::Error> {
+        log::debug!(
+            "Fetching GUIDInfo block {} for path: [{}]",
+            block,
+            Fabric::format_path(&path)
+        );
+
+        let attr = self.fetch_attr(path, hop_cnt, enums::SmiAttrID::GUIDInfo, block)?;
+
+        guid_info::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "could not parse guidinfo data.")
+        })
+    }
+
+    /// Reads the first `guid_cap` GUIDs of a port, one block at a time, in the byte
+    /// order of `Node::node_guid`. Unassigned (zero) slots are dropped from the result.
+    pub fn query_port_guids(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        guid_cap: u8,
+    ) -> Result<Vec<u64>, io::Error> {
+        let num_blocks = (guid_cap as usize).div_ceil(GUID_BLOCK_SIZE);
+        let mut guids = Vec::new();
+
+        for block in 0..num_blocks {
+            let table = self.fetch_guid_info(path, hop_cnt, block as u32)?;
+            let remaining = guid_cap as usize - block * GUID_BLOCK_SIZE;
+            guids.extend(
+                table
+                    .entries()
+                    .into_iter()
+                    .take(remaining)
+                    .filter(|guid| *guid != 0)
+                    .map(u64::to_be),
+            );
+        }
+
+        Ok(guids)
+    }
+
+    /// Populates `Port.guids` for switch port 0 and the port each CA was reached on.
+    ///
+    /// GUIDInfo is answered for the port the SMP arrived on, so other ports of a
+    /// multi-port CA are left empty. The number of blocks read is bounded by the
+    /// port's PortInfo GUIDCap.
+    pub fn discover_guids(&mut self) -> Result<(), io::Error> {
+        let nodes = self.nodes.clone();
+
+        for node_arc in nodes {
+            let (path, port_num, description, port_arc) = {
+                let node = node_arc.read().map_err(lock_err)?;
+                let port_num = if node.node_type == enums::IbNodeType::Switch {
+                    0
+                } else {
+                    node.local_port
+                };
+                let port_arc = node
+                    .ports
+                    .iter()
+                    .find(|p| p.read().map(|p| p.number == port_num).unwrap_or(false))
+                    .cloned();
+                (node.dr_path, port_num, node.description.clone(), port_arc)
+            };
+            let Some(port_arc) = port_arc else {
+                continue;
+            };
+            let hop_cnt = Fabric::get_hop_count(&path);
+
+            let result = self
+                .query_port_info(path, hop_cnt, port_num)
+                .and_then(|pi| self.query_port_guids(path, hop_cnt, pi.guid_cap()));
+
+            match result {
+                Ok(guids) => port_arc.write().map_err(lock_err)?.guids = guids,
+                Err(e) => {
+                    log::warn!(
+                        "Failed to fetch GUIDInfo for port {} on '{}': {}",
+                        port_num,
+                        description.as_deref().unwrap_or("N/A"),
+                        e
+                    );
+                    self.mad_errors += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the physical port that has `guid` assigned, either as its port GUID
+    /// or as an alias GUID. `guid` is in the byte order of `Node::port_guid`.
+    pub fn port_by_guid(&self, guid: u64) -> Result<Option<Arc<RwLock<Port>>>, io::Error> {
+        for node_arc in &self.nodes {
+            let node = node_arc.read().map_err(lock_err)?;
+            for port_arc in &node.ports {
+                if port_arc.read().map_err(lock_err)?.guids.contains(&guid) {
+                    return Ok(Some(port_arc.clone()));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -23,6 +23,7 @@ impl Fabric {
         self.switches.clear();
         self.hcas.clear();
         self.dr_paths.clear();
+        self.target_guids.clear();
         self.ni_timings.clear();
         self.mad_errors = 0;
         self.mad_timeouts = 0;
@@ -167,7 +168,6 @@ impl Fabric {
         })?;
 
         let first_node = first_node_arc.read().map_err(lock_err)?;
-        let first_node_is_switch = first_node.node_type == enums::IbNodeType::Switch;
 
         for port_arc in first_node.ports.iter() {
             let port = port_arc.read().map_err(lock_err)?;
@@ -181,11 +181,8 @@ impl Fabric {
             }
 
             let mut path: [u8; 64] = [0; 64];
-            let (path_index, neighbor_hop_cnt) = if first_node_is_switch {
-                (hop_cnt as usize, hop_cnt + 1)
-            } else {
-                (hop_cnt as usize + 1, hop_cnt + 1)
-            };
+            // Index 0 of an initial path is never used; the first hop goes out path[1].
+            let (path_index, neighbor_hop_cnt) = (hop_cnt as usize + 1, hop_cnt + 1);
 
             if path_index >= path.len() {
                 continue;
@@ -0,0 +1,566 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use super::lib::{Fabric, Node, Port, START_PATH, lock_err};
+use crate::{
+    enums::{self, LinkSpeed, LinkWidth},
+    mad::node_info,
+};
+
+/// The `"S-<guid>"` / `"H-<guid>"` / `"R-<guid>"` name ibnetdiscover gives a node.
+pub fn node_name(node: &Node) -> String {
+    let prefix = match node.node_type {
+        enums::IbNodeType::Switch => 'S',
+        enums::IbNodeType::Router => 'R',
+        _ => 'H',
+    };
+    format!("\"{}-{:016x}\"", prefix, node.node_guid.to_be())
+}
+
+/// GUID of `port` as printed in `[port](guid)`. Switch ports share the port 0 GUID.
+pub fn port_guid(node: &Node, port: &Port) -> u64 {
+    if let Some(guid) = port.guids.first() {
+        guid.to_be()
+    } else if node.node_type == enums::IbNodeType::Switch || port.number == node.local_port {
+        node.port_guid.to_be()
+    } else {
+        node.node_guid.to_be()
+    }
+}
+
+// "4xQDR", or nothing if the port never reported an active width and speed.
+pub(crate) fn link_str(port: &Port) -> String {
+    match (port.link_width_active, port.link_speed_active) {
+        (Some(width), Some(speed)) => format!("{}x{}", width.lanes(), speed),
+        _ => String::new(),
+    }
+}
+
+fn sorted_ports(node: &Node) -> Result<Vec<Arc<RwLock<Port>>>, io::Error> {
+    let mut ports = Vec::with_capacity(node.ports.len());
+    for port_arc in &node.ports {
+        let number = port_arc.read().map_err(lock_err)?.number;
+        ports.push((number, port_arc.clone()));
+    }
+    ports.sort_by_key(|(number, _)| *number);
+    Ok(ports.into_iter().map(|(_, port)| port).collect())
+}
+
+fn parse_err(line: usize, msg: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("ibnetdiscover line {}: {}", line, msg),
+    )
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok()
+}
+
+// The GUID embedded in an "S-<guid>" style name. Names from a node name map
+// carry no GUID.
+pub(crate) fn guid_from_name(name: &str) -> Option<u64> {
+    let (prefix, guid) = name.split_once('-')?;
+    if prefix.len() != 1 || !"SHR".contains(prefix) {
+        return None;
+    }
+    parse_hex(guid)
+}
+
+// The value following `key` in a run of whitespace separated words.
+fn word_after<T: FromStr>(text: &str, key: &str) -> Option<T> {
+    let mut words = text.split_whitespace();
+    words.find(|w| *w == key)?;
+    words.next()?.parse().ok()
+}
+
+// "4xQDR" -> (4X, QDR). Speeds this crate doesn't know, like FDR10, give `None`.
+pub(crate) fn parse_link(word: &str) -> (Option<LinkWidth>, Option<LinkSpeed>) {
+    match word.split_once('x') {
+        Some((lanes, speed)) => (
+            lanes.parse().ok().and_then(LinkWidth::from_lanes),
+            LinkSpeed::from_str(speed).ok(),
+        ),
+        None => (None, None),
+    }
+}
+
+/// Cursor over one line of ibnetdiscover output.
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn skip_ws(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if let Some(rest) = self.rest.strip_prefix(c) {
+            self.rest = rest;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn word(&mut self) -> &'a str {
+        self.skip_ws();
+        let end = self
+            .rest
+            .find(char::is_whitespace)
+            .unwrap_or(self.rest.len());
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        word
+    }
+
+    fn delimited(&mut self, open: char, close: char) -> Option<&'a str> {
+        if !self.eat(open) {
+            return None;
+        }
+        let end = self.rest.find(close)?;
+        let inner = &self.rest[..end];
+        self.rest = &self.rest[end + 1..];
+        Some(inner)
+    }
+
+    fn quoted(&mut self) -> Option<&'a str> {
+        self.skip_ws();
+        self.delimited('"', '"')
+    }
+
+    fn port(&mut self) -> Option<u8> {
+        self.delimited('[', ']')?.parse().ok()
+    }
+
+    fn guid(&mut self) -> Option<u64> {
+        self.delimited('(', ')').and_then(parse_hex)
+    }
+
+    fn comment(&mut self) -> &'a str {
+        self.skip_ws();
+        if self.eat('#') { self.rest.trim() } else { "" }
+    }
+}
+
+/// The `vendid=`/`devid=`/`*guid=` lines preceding a node.
+#[derive(Default)]
+struct NodeHeader {
+    vendor_id: u32,
+    device_id: u16,
+    system_guid: Option<u64>,
+    guid: Option<u64>,
+    port_guid: Option<u64>,
+}
+
+/// A node whose link lines are still being read.
+struct ParsedNode {
+    node: Node,
+    ports: Vec<Port>,
+}
+
+/// A `[port] "remote"[port]` line, resolved once every node has been read.
+struct ParsedLink {
+    node_guid: u64,
+    port: u8,
+    remote: String,
+    remote_port: u8,
+    line: usize,
+}
+
+struct Parser {
+    fabric: Fabric,
+    header: NodeHeader,
+    current: Option<ParsedNode>,
+    names: HashMap<String, u64>,
+    links: Vec<ParsedLink>,
+    start_guid: Option<u64>,
+}
+
+impl Parser {
+    fn flush(&mut self) -> Result<(), io::Error> {
+        if let Some(ParsedNode { mut node, ports }) = self.current.take() {
+            // CA port GUIDs are only given on the port lines.
+            if node.node_type != enums::IbNodeType::Switch
+                && let Some(port) = ports.iter().min_by_key(|p| p.number)
+            {
+                node.local_port = port.number;
+                if let Some(guid) = port.guids.first() {
+                    node.port_guid = *guid;
+                }
+            }
+            self.fabric.insert_node(node, ports)?;
+        }
+        Ok(())
+    }
+
+    fn node_line(&mut self, n: usize, line: &str) -> Result<(), io::Error> {
+        self.flush()?;
+
+        let mut cur = Cursor { rest: line };
+        let node_type = match cur.word() {
+            "Switch" => enums::IbNodeType::Switch,
+            "Ca" => enums::IbNodeType::CA,
+            "Rt" => enums::IbNodeType::Router,
+            other => return Err(parse_err(n, format!("unknown node type '{}'", other))),
+        };
+        let nports: u8 = cur
+            .word()
+            .parse()
+            .map_err(|_| parse_err(n, "invalid port count"))?;
+        let name = cur
+            .quoted()
+            .ok_or_else(|| parse_err(n, "missing node name"))?;
+        let comment = cur.comment();
+
+        let header = std::mem::take(&mut self.header);
+        let guid = header
+            .guid
+            .or_else(|| guid_from_name(name))
+            .ok_or_else(|| parse_err(n, format!("no GUID for node '{}'", name)))?;
+        let port_guid = header.port_guid.unwrap_or(guid);
+
+        let vendor = header.vendor_id.to_be_bytes();
+        let ni = node_info {
+            base_version: 1,
+            class_version: 1,
+            node_type: node_type.clone() as u8,
+            nports,
+            system_guid: header.system_guid.unwrap_or(guid).to_be(),
+            node_guid: guid.to_be(),
+            port_guid: port_guid.to_be(),
+            partition_cap: 0,
+            device_id: header.device_id.to_be(),
+            revision: 0,
+            local_port: 0,
+            vendor_id: [vendor[1], vendor[2], vendor[3]],
+            reserved: [0; 24],
+        };
+        let mut node = Node::from_node_info(&ni, START_PATH)?;
+
+        let mut cur = Cursor { rest: comment };
+        node.description = Some(cur.quoted().unwrap_or("").to_string());
+
+        let mut ports = Vec::new();
+        if node_type == enums::IbNodeType::Switch {
+            node.lid = word_after(cur.rest, "lid").unwrap_or(0);
+            node.enhanced_port0 = cur.rest.contains("enhanced port 0");
+            ports.push(Port {
+                link_state: enums::IbPortLinkLayerState::Active,
+                phys_state: enums::IbPortPhyState::LinkUp,
+                lid: node.lid,
+                lmc: word_after(cur.rest, "lmc").unwrap_or(0),
+                guids: vec![port_guid.to_be()],
+                ..Port::new(0)
+            });
+        }
+
+        self.names.insert(name.to_string(), node.node_guid);
+        self.current = Some(ParsedNode { node, ports });
+        Ok(())
+    }
+
+    fn link_line(&mut self, n: usize, line: &str) -> Result<(), io::Error> {
+        let Some(current) = self.current.as_mut() else {
+            return Err(parse_err(n, "port line before any node"));
+        };
+
+        let mut cur = Cursor { rest: line };
+        let number = cur
+            .port()
+            .ok_or_else(|| parse_err(n, "invalid port number"))?;
+        cur.port(); // extended port number
+        let guid = cur.guid();
+        let remote = cur
+            .quoted()
+            .ok_or_else(|| parse_err(n, "missing remote name"))?;
+        let remote_port = cur
+            .port()
+            .ok_or_else(|| parse_err(n, "invalid remote port number"))?;
+        cur.port();
+        cur.guid();
+        let comment = cur.comment();
+
+        // Switch lines: "desc" lid N 4xQDR
+        // CA lines:     lid N lmc M "desc" lid N 4xQDR
+        let (local, remote_part) = match (comment.find('"'), comment.rfind('"')) {
+            (Some(first), Some(last)) => (&comment[..first], &comment[last + 1..]),
+            _ => ("", comment),
+        };
+        let (width, speed) = remote_part
+            .split_whitespace()
+            .last()
+            .map(parse_link)
+            .unwrap_or((None, None));
+
+        let node = &current.node;
+        let is_switch = node.node_type == enums::IbNodeType::Switch;
+        let port = Port {
+            link_state: enums::IbPortLinkLayerState::Active,
+            phys_state: enums::IbPortPhyState::LinkUp,
+            lid: if is_switch {
+                node.lid
+            } else {
+                word_after(local, "lid").unwrap_or(0)
+            },
+            lmc: word_after(local, "lmc").unwrap_or(0),
+            link_width_active: width,
+            link_speed_active: speed,
+            guids: guid.map(u64::to_be).into_iter().collect(),
+            ..Port::new(number)
+        };
+
+        self.links.push(ParsedLink {
+            node_guid: node.node_guid,
+            port: number,
+            remote: remote.to_string(),
+            remote_port,
+            line: n,
+        });
+        current.ports.push(port);
+        Ok(())
+    }
+
+    fn header_line(&mut self, n: usize, key: &str, value: &str) -> Result<(), io::Error> {
+        let value = value.split_whitespace().next().unwrap_or("");
+        let hex = || parse_hex(value).ok_or_else(|| parse_err(n, format!("invalid {}", key)));
+        match key {
+            "vendid" => self.header.vendor_id = hex()? as u32,
+            "devid" => self.header.device_id = hex()? as u16,
+            "sysimgguid" => self.header.system_guid = Some(hex()?),
+            "caguid" | "rtguid" => self.header.guid = Some(hex()?),
+            "switchguid" => {
+                // switchguid=0x<node guid>(<port guid>)
+                let (guid, port_guid) = match value.split_once('(') {
+                    Some((guid, port_guid)) => (guid, parse_hex(port_guid.trim_end_matches(')'))),
+                    None => (value, None),
+                };
+                self.header.guid =
+                    Some(parse_hex(guid).ok_or_else(|| parse_err(n, "invalid switchguid"))?);
+                self.header.port_guid = port_guid;
+            }
+            _ => log::trace!("Ignoring ibnetdiscover line {}: '{}'", n, key),
+        }
+        Ok(())
+    }
+
+    fn resolve_links(&mut self) -> Result<(), io::Error> {
+        for link in std::mem::take(&mut self.links) {
+            let remote_guid = match self.names.get(&link.remote) {
+                Some(guid) => *guid,
+                None => match guid_from_name(&link.remote) {
+                    Some(guid) => guid.to_be(),
+                    None => continue,
+                },
+            };
+            let local = self.fabric.find_port(link.node_guid, link.port)?;
+            let remote = self.fabric.find_port(remote_guid, link.remote_port)?;
+            match (local, remote) {
+                (Some(local), Some(remote)) => Fabric::link_ports(&local, &remote)?,
+                _ => log::debug!(
+                    "ibnetdiscover line {}: remote {}[{}] is not in the file",
+                    link.line,
+                    link.remote,
+                    link.remote_port
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Fabric {
+    /// Builds an offline fabric from `ibnetdiscover` text output.
+    ///
+    /// Ports only exist for link lines (and switch port 0), and carry the LID and
+    /// active width/speed annotated in the file. Links to nodes missing from the
+    /// file are dropped. DR paths are computed from the "Initiated from" node.
+    pub fn from_ibnetdiscover(text: &str) -> Result<Fabric, io::Error> {
+        let mut parser = Parser {
+            fabric: Fabric::offline(),
+            header: NodeHeader::default(),
+            current: None,
+            names: HashMap::new(),
+            links: Vec::new(),
+            start_guid: None,
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let n = i + 1;
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                let comment = comment.trim();
+                if let Some(rest) = comment.strip_prefix("Initiated from node") {
+                    parser.start_guid = rest.split_whitespace().next().and_then(parse_hex);
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                parser.link_line(n, line)?;
+            } else if let Some((key, value)) = line.split_once('=') {
+                parser.header_line(n, key.trim(), value)?;
+            } else {
+                parser.node_line(n, line)?;
+            }
+        }
+        parser.flush()?;
+        parser.resolve_links()?;
+
+        let mut fabric = parser.fabric;
+        fabric.finish_import(parser.start_guid.map(u64::to_be))?;
+        Ok(fabric)
+    }
+
+    /// Writes the topology in the text format produced by `ibnetdiscover`.
+    ///
+    /// Switches are written first, then CAs and routers, each in discovery order.
+    /// Only ports with a discovered `remote_port` get a link line.
+    pub fn write_ibnetdiscover<W: Write>(&self, out: &mut W) -> Result<(), io::Error> {
+        writeln!(out, "#")?;
+        writeln!(out, "# Topology file: generated by ibmad")?;
+        writeln!(out, "#")?;
+
+        let start = self.nodes.iter().find(|n| {
+            n.read()
+                .map(|n| Fabric::get_hop_count(&n.dr_path) == 0)
+                .unwrap_or(false)
+        });
+        if let Some(start) = start {
+            let node = start.read().map_err(lock_err)?;
+            writeln!(
+                out,
+                "# Initiated from node {:016x} port {:016x}",
+                node.node_guid.to_be(),
+                node.port_guid.to_be()
+            )?;
+        }
+
+        for node_arc in self.switches.iter().chain(&self.hcas) {
+            let Some(node_arc) = node_arc.upgrade() else {
+                continue;
+            };
+            let node = node_arc.read().map_err(lock_err)?;
+            Fabric::write_ibnetdiscover_node(out, &node)?;
+        }
+
+        Ok(())
+    }
+
+    /// `write_ibnetdiscover` into a `String`.
+    pub fn to_ibnetdiscover(&self) -> Result<String, io::Error> {
+        let mut out = Vec::new();
+        self.write_ibnetdiscover(&mut out)?;
+        String::from_utf8(out).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_ibnetdiscover_node<W: Write>(out: &mut W, node: &Node) -> Result<(), io::Error> {
+        let is_switch = node.node_type == enums::IbNodeType::Switch;
+        let description = node.description.as_deref().unwrap_or("");
+
+        writeln!(out)?;
+        writeln!(out, "vendid=0x{:x}", node.vendor_id)?;
+        writeln!(out, "devid=0x{:x}", node.device_id)?;
+        writeln!(out, "sysimgguid=0x{:x}", node.system_guid.to_be())?;
+
+        let ports = sorted_ports(node)?;
+        if is_switch {
+            let mut lid_lmc = (node.lid, 0);
+            for port_arc in &ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                if port.number == 0 {
+                    lid_lmc = (port.lid, port.lmc);
+                }
+            }
+            let (lid, lmc) = lid_lmc;
+            let port0 = if node.enhanced_port0 {
+                "enhanced"
+            } else {
+                "base"
+            };
+            writeln!(
+                out,
+                "switchguid=0x{:x}({:x})",
+                node.node_guid.to_be(),
+                node.port_guid.to_be()
+            )?;
+            writeln!(
+                out,
+                "Switch\t{} {}\t\t# \"{}\" {} port 0 lid {} lmc {}",
+                node.nports,
+                node_name(node),
+                description,
+                port0,
+                lid,
+                lmc
+            )?;
+        } else {
+            let (guid_key, kind) = if node.node_type == enums::IbNodeType::Router {
+                ("rtguid", "Rt")
+            } else {
+                ("caguid", "Ca")
+            };
+            writeln!(out, "{}=0x{:x}", guid_key, node.node_guid.to_be())?;
+            writeln!(
+                out,
+                "{}\t{} {}\t\t# \"{}\"",
+                kind,
+                node.nports,
+                node_name(node),
+                description
+            )?;
+        }
+
+        for port_arc in ports {
+            let port = port_arc.read().map_err(lock_err)?;
+            let Some(remote_arc) = port.remote() else {
+                continue;
+            };
+            let remote = remote_arc.read().map_err(lock_err)?;
+            let Some(remote_node_arc) = remote.parent.upgrade() else {
+                continue;
+            };
+            let remote_node = remote_node_arc.read().map_err(lock_err)?;
+            let remote_is_switch = remote_node.node_type == enums::IbNodeType::Switch;
+            let remote_desc = remote_node.description.as_deref().unwrap_or("");
+
+            write!(out, "[{}]", port.number)?;
+            if !is_switch {
+                write!(out, "({:x}) ", port_guid(node, &port))?;
+            }
+            write!(out, "\t{}[{}]", node_name(&remote_node), remote.number)?;
+            if !remote_is_switch {
+                write!(out, "({:x}) ", port_guid(&remote_node, &remote))?;
+            }
+            if is_switch {
+                writeln!(
+                    out,
+                    "\t\t# \"{}\" lid {} {}",
+                    remote_desc,
+                    remote.lid,
+                    link_str(&port)
+                )?;
+            } else {
+                writeln!(
+                    out,
+                    "\t\t# lid {} lmc {} \"{}\" lid {} {}",
+                    port.lid,
+                    port.lmc,
+                    remote_desc,
+                    remote.lid,
+                    link_str(&port)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    io::{self, Read, Write},
+    sync::Arc,
+    time,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::lib::{Fabric, Node, Port, lock_err};
+use crate::{
+    enums::{IbMtu, IbNodeType, IbPortLinkLayerState, IbPortPhyState, LinkSpeed, LinkWidth},
+    mad::{PKey, VlArbitration, node_info, sl2vl_table},
+};
+
+/// Schema version written by `Fabric::to_json`. Readers reject other versions;
+/// fields may be added within a version, never removed or changed.
+pub const FABRIC_JSON_VERSION: u32 = 1;
+
+/// A GUID in host order, written as a `"0x%016x"` string so that JSON consumers
+/// limited to 53 bit integers don't corrupt it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Guid(pub u64);
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:016x}", self.0)
+    }
+}
+
+impl Serialize for Guid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Guid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        u64::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map(Guid)
+            .map_err(|_| serde::de::Error::custom(format!("invalid GUID '{}'", s)))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsJson {
+    pub mads_sent: u64,
+    pub mad_errors: u64,
+    pub mad_timeouts: u64,
+    pub m_key_violations: u64,
+    /// NodeInfo round trip times in microseconds, in discovery order.
+    pub node_info_us: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortJson {
+    pub number: u8,
+    pub link_state: IbPortLinkLayerState,
+    pub phys_state: IbPortPhyState,
+    pub lid: u16,
+    pub lmc: u8,
+    pub link_width_active: Option<LinkWidth>,
+    pub link_width_enabled: Vec<LinkWidth>,
+    pub link_width_supported: Vec<LinkWidth>,
+    pub link_speed_active: Option<LinkSpeed>,
+    pub link_speed_enabled: Vec<LinkSpeed>,
+    pub link_speed_supported: Vec<LinkSpeed>,
+    /// MTUs in bytes.
+    pub mtu: Option<u16>,
+    pub mtu_cap: Option<u16>,
+    pub vl_cap: u8,
+    pub guids: Vec<Guid>,
+    /// Raw P_Key values, membership bit included.
+    pub pkeys: Vec<u16>,
+    /// VL for SL0..SL15, keyed by input port.
+    pub sl2vl: BTreeMap<u8, [u8; 16]>,
+    pub vl_arbitration: Option<VlArbitration>,
+    pub m_key_protect_bits: u8,
+    pub m_key_violations: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeJson {
+    pub guid: Guid,
+    pub system_guid: Guid,
+    pub port_guid: Guid,
+    pub node_type: IbNodeType,
+    pub description: Option<String>,
+    pub vendor_id: u32,
+    pub device_id: u16,
+    pub revision: u32,
+    pub lid: u16,
+    pub local_port: u8,
+    pub nports: u8,
+    pub partition_cap: u16,
+    #[serde(default)]
+    pub enhanced_port0: bool,
+    /// `Node::dr_path` without its trailing zeros.
+    pub dr_path: Vec<u8>,
+    pub ports: Vec<PortJson>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortRefJson {
+    pub node: Guid,
+    pub port: u8,
+}
+
+/// A link between two ports, listed once with the lower end first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkJson {
+    pub a: PortRefJson,
+    pub b: PortRefJson,
+}
+
+/// Top level document of the fabric JSON schema.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FabricJson {
+    pub version: u32,
+    pub stats: StatsJson,
+    pub nodes: Vec<NodeJson>,
+    pub links: Vec<LinkJson>,
+}
+
+fn invalid(msg: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn mtu_from_bytes(bytes: u16) -> Result<IbMtu, io::Error> {
+    if bytes < 256 || !bytes.is_power_of_two() {
+        return Err(invalid(format!("invalid MTU {}", bytes)));
+    }
+    IbMtu::try_from((bytes / 256).trailing_zeros() as u8 + 1)
+        .map_err(|_| invalid(format!("invalid MTU {}", bytes)))
+}
+
+fn port_to_json(port: &Port) -> PortJson {
+    PortJson {
+        number: port.number,
+        link_state: port.link_state.clone(),
+        phys_state: port.phys_state.clone(),
+        lid: port.lid,
+        lmc: port.lmc,
+        link_width_active: port.link_width_active,
+        link_width_enabled: port.link_width_enabled.clone(),
+        link_width_supported: port.link_width_supported.clone(),
+        link_speed_active: port.link_speed_active,
+        link_speed_enabled: port.link_speed_enabled.clone(),
+        link_speed_supported: port.link_speed_supported.clone(),
+        mtu: port.mtu.map(|m| m.bytes()),
+        mtu_cap: port.mtu_cap.map(|m| m.bytes()),
+        vl_cap: port.vl_cap,
+        guids: port.guids.iter().map(|g| Guid(g.to_be())).collect(),
+        pkeys: port.pkeys.iter().map(|pk| pk.0).collect(),
+        sl2vl: port
+            .sl2vl
+            .iter()
+            .map(|(in_port, table)| (*in_port, table.mapping()))
+            .collect(),
+        vl_arbitration: port.vl_arbitration.clone(),
+        m_key_protect_bits: port.m_key_protect_bits,
+        m_key_violations: port.m_key_violations,
+    }
+}
+
+fn port_from_json(json: &PortJson) -> Result<Port, io::Error> {
+    let mut sl2vl = std::collections::HashMap::new();
+    for (in_port, vls) in &json.sl2vl {
+        let mut table = sl2vl_table { data: [0; 8] };
+        for (sl, vl) in vls.iter().enumerate() {
+            table.set_vl(sl as u8, *vl);
+        }
+        sl2vl.insert(*in_port, table);
+    }
+
+    Ok(Port {
+        link_state: json.link_state.clone(),
+        phys_state: json.phys_state.clone(),
+        lid: json.lid,
+        lmc: json.lmc,
+        link_width_active: json.link_width_active,
+        link_width_enabled: json.link_width_enabled.clone(),
+        link_width_supported: json.link_width_supported.clone(),
+        link_speed_active: json.link_speed_active,
+        link_speed_enabled: json.link_speed_enabled.clone(),
+        link_speed_supported: json.link_speed_supported.clone(),
+        mtu: json.mtu.map(mtu_from_bytes).transpose()?,
+        mtu_cap: json.mtu_cap.map(mtu_from_bytes).transpose()?,
+        vl_cap: json.vl_cap,
+        guids: json.guids.iter().map(|g| g.0.to_be()).collect(),
+        pkeys: json.pkeys.iter().map(|pk| PKey(*pk)).collect(),
+        sl2vl,
+        vl_arbitration: json.vl_arbitration.clone(),
+        m_key_protect_bits: json.m_key_protect_bits,
+        m_key_violations: json.m_key_violations,
+        ..Port::new(json.number)
+    })
+}
+
+fn node_from_json(json: &NodeJson) -> Result<Node, io::Error> {
+    if json.dr_path.len() > 64 {
+        return Err(invalid(format!("DR path of {} is too long", json.guid)));
+    }
+    let mut path = [0; 64];
+    path[..json.dr_path.len()].copy_from_slice(&json.dr_path);
+
+    let vendor = json.vendor_id.to_be_bytes();
+    let ni = node_info {
+        base_version: 1,
+        class_version: 1,
+        node_type: json.node_type.clone() as u8,
+        nports: json.nports,
+        system_guid: json.system_guid.0.to_be(),
+        node_guid: json.guid.0.to_be(),
+        port_guid: json.port_guid.0.to_be(),
+        partition_cap: json.partition_cap.to_be(),
+        device_id: json.device_id.to_be(),
+        revision: json.revision.to_be(),
+        local_port: json.local_port,
+        vendor_id: [vendor[1], vendor[2], vendor[3]],
+        reserved: [0; 24],
+    };
+
+    let mut node = Node::from_node_info(&ni, path)?;
+    node.lid = json.lid;
+    node.description = json.description.clone();
+    node.enhanced_port0 = json.enhanced_port0;
+    Ok(node)
+}
+
+impl Fabric {
+    /// Converts the fabric into the versioned JSON schema.
+    pub fn to_json(&self) -> Result<FabricJson, io::Error> {
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut links = Vec::new();
+        let mut seen = HashSet::new();
+
+        for node_arc in &self.nodes {
+            let node = node_arc.read().map_err(lock_err)?;
+            let guid = Guid(node.node_guid.to_be());
+
+            let mut ports = Vec::with_capacity(node.ports.len());
+            for port_arc in &node.ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                ports.push(port_to_json(&port));
+
+                let Some(remote_arc) = port.remote() else {
+                    continue;
+                };
+                if !seen.insert(Arc::as_ptr(port_arc)) || seen.contains(&Arc::as_ptr(&remote_arc)) {
+                    continue;
+                }
+                let remote = remote_arc.read().map_err(lock_err)?;
+                let Some(remote_node) = remote.parent.upgrade() else {
+                    continue;
+                };
+                let remote_guid = Guid(remote_node.read().map_err(lock_err)?.node_guid.to_be());
+
+                let a = PortRefJson {
+                    node: guid,
+                    port: port.number,
+                };
+                let b = PortRefJson {
+                    node: remote_guid,
+                    port: remote.number,
+                };
+                let (a, b) = if (a.node, a.port) <= (b.node, b.port) {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                links.push(LinkJson { a, b });
+            }
+            ports.sort_by_key(|p| p.number);
+
+            let hops = node
+                .dr_path
+                .iter()
+                .rposition(|&p| p != 0)
+                .map_or(0, |i| i + 1);
+            nodes.push(NodeJson {
+                guid,
+                system_guid: Guid(node.system_guid.to_be()),
+                port_guid: Guid(node.port_guid.to_be()),
+                node_type: node.node_type.clone(),
+                description: node.description.clone(),
+                vendor_id: node.vendor_id,
+                device_id: node.device_id,
+                revision: node.revision,
+                lid: node.lid,
+                local_port: node.local_port,
+                nports: node.nports,
+                partition_cap: node.partition_cap,
+                enhanced_port0: node.enhanced_port0,
+                dr_path: node.dr_path[..hops].to_vec(),
+                ports,
+            });
+        }
+        links.sort_by_key(|l| (l.a.node, l.a.port, l.b.node, l.b.port));
+
+        Ok(FabricJson {
+            version: FABRIC_JSON_VERSION,
+            stats: StatsJson {
+                mads_sent: self.mads_sent,
+                mad_errors: self.mad_errors,
+                mad_timeouts: self.mad_timeouts,
+                m_key_violations: self.m_key_violations,
+                node_info_us: self
+                    .ni_timings
+                    .iter()
+                    .map(|t| t.as_micros() as u64)
+                    .collect(),
+            },
+            nodes,
+            links,
+        })
+    }
+
+    /// Rebuilds an offline fabric from the JSON schema. Nodes keep the order and
+    /// DR paths they were written with.
+    pub fn from_json(json: &FabricJson) -> Result<Fabric, io::Error> {
+        if json.version != FABRIC_JSON_VERSION {
+            return Err(invalid(format!(
+                "unsupported fabric JSON version {} (expected {})",
+                json.version, FABRIC_JSON_VERSION
+            )));
+        }
+
+        let mut fabric = Fabric::offline();
+        for node_json in &json.nodes {
+            let node = node_from_json(node_json)?;
+            if fabric.node_map.contains_key(&node.node_guid) {
+                return Err(invalid(format!("duplicate node {}", node_json.guid)));
+            }
+            let ports = node_json
+                .ports
+                .iter()
+                .map(port_from_json)
+                .collect::<Result<Vec<_>, _>>()?;
+            fabric.insert_node(node, ports)?;
+        }
+
+        for link in &json.links {
+            let find = |end: &PortRefJson| {
+                fabric
+                    .find_port(end.node.0.to_be(), end.port)?
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "link references unknown port {}[{}]",
+                            end.node, end.port
+                        ))
+                    })
+            };
+            Fabric::link_ports(&find(&link.a)?, &find(&link.b)?)?;
+        }
+        fabric.categorize_nodes()?;
+
+        fabric.mads_sent = json.stats.mads_sent;
+        fabric.mad_errors = json.stats.mad_errors;
+        fabric.mad_timeouts = json.stats.mad_timeouts;
+        fabric.m_key_violations = json.stats.m_key_violations;
+        fabric.ni_timings = json
+            .stats
+            .node_info_us
+            .iter()
+            .map(|us| time::Duration::from_micros(*us))
+            .collect();
+
+        Ok(fabric)
+    }
+
+    /// Writes `to_json` as pretty-printed JSON.
+    pub fn write_json<W: Write>(&self, out: W) -> Result<(), io::Error> {
+        serde_json::to_writer_pretty(out, &self.to_json()?).map_err(io::Error::from)
+    }
+
+    /// Reads a document written by `write_json`. The version is checked before the
+    /// rest of the document, so a newer schema gives a clear error.
+    pub fn read_json<R: Read>(input: R) -> Result<Fabric, io::Error> {
+        let value: serde_json::Value = serde_json::from_reader(input).map_err(io::Error::from)?;
+        let version = value.get("version").and_then(|v| v.as_u64());
+        if version != Some(FABRIC_JSON_VERSION as u64) {
+            return Err(invalid(format!(
+                "unsupported fabric JSON version {:?} (expected {})",
+                version, FABRIC_JSON_VERSION
+            )));
+        }
+        let json: FabricJson = serde_json::from_value(value).map_err(io::Error::from)?;
+        Fabric::from_json(&json)
+    }
+}
@@ -1,14 +1,18 @@
 use std::{
     collections::HashMap,
-    io,
+    fmt, io,
     sync::Weak,
     sync::{Arc, RwLock},
     time,
 };
 
+use super::mkey::MKeyProvider;
 use crate::{
     enums,
-    mad::{self, IbMadPort, ib_mad_addr, ib_user_mad, node_info, port_info},
+    mad::{
+        self, IbMadPort, LinkTraining, PKey, VlArbitration, ib_mad_addr, ib_user_mad, node_info,
+        port_info, sl2vl_table, switch_info,
+    },
 };
 
 pub(crate) const START_PATH: [u8; 64] = [0; 64];
@@ -19,25 +23,189 @@ pub struct Port {
     pub link_state: enums::IbPortLinkLayerState,
     pub phys_state: enums::IbPortPhyState,
     pub lid: u16,
+    pub lmc: u8,
+    pub link_width_active: Option<enums::LinkWidth>,
+    pub link_width_enabled: Vec<enums::LinkWidth>,
+    pub link_width_supported: Vec<enums::LinkWidth>,
+    pub link_speed_active: Option<enums::LinkSpeed>,
+    pub link_speed_enabled: Vec<enums::LinkSpeed>,
+    pub link_speed_supported: Vec<enums::LinkSpeed>,
+    /// Active MTU, from PortInfo NeighborMTU.
+    pub mtu: Option<enums::IbMtu>,
+    pub mtu_cap: Option<enums::IbMtu>,
+    /// PortInfo VLCap encoding, see `mad::qos::data_vls`.
+    pub vl_cap: u8,
+    /// Port GUIDs from GUIDInfo, populated by `Fabric::discover_guids`, in the same
+    /// byte order as `Node::node_guid`. Index 0 is the physical port GUID, any
+    /// further entries are alias GUIDs.
+    pub guids: Vec<u64>,
+    /// P_KeyTable contents, populated by `Fabric::discover_partitions`.
+    pub pkeys: Vec<PKey>,
+    /// SLtoVLMappingTable per input port, populated by `Fabric::discover_qos`.
+    /// CA ports have a single table, stored under their own port number.
+    pub sl2vl: HashMap<u8, sl2vl_table>,
+    pub vl_arbitration: Option<VlArbitration>,
+    pub m_key_protect_bits: u8,
+    /// PortInfo M_KeyViolations. Only meaningful on CA ports and switch port 0,
+    /// which are the ports that check the M_Key.
+    pub m_key_violations: u16,
     pub remote_port: Option<Weak<RwLock<Port>>>,
     pub parent: Weak<RwLock<Node>>,
 }
 
+impl Port {
+    /// A port with nothing known about it yet: Down/Disabled, no LID, no tables.
+    pub fn new(number: u8) -> Port {
+        Port {
+            number,
+            link_state: enums::IbPortLinkLayerState::Down,
+            phys_state: enums::IbPortPhyState::Disabled,
+            lid: 0,
+            lmc: 0,
+            link_width_active: None,
+            link_width_enabled: Vec::new(),
+            link_width_supported: Vec::new(),
+            link_speed_active: None,
+            link_speed_enabled: Vec::new(),
+            link_speed_supported: Vec::new(),
+            mtu: None,
+            mtu_cap: None,
+            vl_cap: 0,
+            guids: Vec::new(),
+            pkeys: Vec::new(),
+            sl2vl: HashMap::new(),
+            vl_arbitration: None,
+            m_key_protect_bits: 0,
+            m_key_violations: 0,
+            remote_port: None,
+            parent: Weak::new(),
+        }
+    }
+
+    /// Builds a port from a PortInfo attribute, as returned by a Get.
+    pub fn from_port_info(number: u8, pi: &port_info) -> Result<Port, io::Error> {
+        let link_state = enums::IbPortLinkLayerState::try_from(pi.port_state()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid port_state: {:?}", e),
+            )
+        })?;
+        let phy_state = enums::IbPortPhyState::try_from(pi.port_physical_state()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid port_physical_state: {:?}", e),
+            )
+        })?;
+
+        Ok(Port {
+            link_state,
+            phys_state: phy_state,
+            lid: pi.lid(),
+            lmc: pi.lmc(),
+            link_width_active: pi.active_link_width(),
+            link_width_enabled: pi.enabled_link_widths(),
+            link_width_supported: pi.supported_link_widths(),
+            link_speed_active: pi.active_link_speed(),
+            link_speed_enabled: pi.enabled_link_speeds(),
+            link_speed_supported: pi.supported_link_speeds(),
+            mtu: enums::IbMtu::try_from(pi.neighbor_mtu()).ok(),
+            mtu_cap: enums::IbMtu::try_from(pi.mtu_cap()).ok(),
+            vl_cap: pi.vl_cap(),
+            m_key_protect_bits: pi.m_key_protect_bits(),
+            m_key_violations: pi.m_key_violations(),
+            ..Port::new(number)
+        })
+    }
+
+    /// The port on the other end of the link, if it was discovered.
+    pub fn remote(&self) -> Option<Arc<RwLock<Port>>> {
+        self.remote_port.as_ref().and_then(|w| w.upgrade())
+    }
+
+    pub fn link_training(&self) -> LinkTraining {
+        LinkTraining::new(
+            self.link_width_active,
+            &self.link_width_enabled,
+            &self.link_width_supported,
+            self.link_speed_active,
+            &self.link_speed_enabled,
+            &self.link_speed_supported,
+        )
+    }
+
+    /// Data rate of the active link in Gb/s.
+    pub fn data_rate_gbps(&self) -> Option<f64> {
+        self.link_training().active_data_rate_gbps()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub lid: u16,
     pub dr_path: [u8; 64],
     pub node_type: enums::IbNodeType,
     pub node_guid: u64,
+    /// NodeInfo SystemImageGUID, in the same byte order as `node_guid`.
+    pub system_guid: u64,
+    /// NodeInfo PortGUID of `local_port`, in the same byte order as `node_guid`.
+    pub port_guid: u64,
+    pub vendor_id: u32,
+    pub device_id: u16,
+    pub revision: u32,
     pub description: Option<String>,
     pub local_port: u8, // Port found during discovery
     pub nports: u8,
+    pub partition_cap: u16,
+    /// SwitchInfo EnhancedPort0: switch port 0 supports the full port functions.
+    /// Always false for CAs and routers.
+    pub enhanced_port0: bool,
+    /// SwitchInfo as read during discovery. None for CAs and routers, for switches
+    /// that did not answer and for fabrics imported from a file.
+    pub switch_info: Option<switch_info>,
     pub ports: Vec<Arc<RwLock<Port>>>,
 }
 
+impl Node {
+    /// Builds a node from a NodeInfo attribute. GUIDs keep the NodeInfo byte order;
+    /// the description, LID and ports are filled in afterwards.
+    pub fn from_node_info(node_info: &node_info, path: [u8; 64]) -> Result<Node, io::Error> {
+        let node_type = enums::IbNodeType::try_from(node_info.node_type).map_err(|_e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid node_type: {}", node_info.node_type),
+            )
+        })?;
+
+        Ok(Node {
+            node_guid: node_info.node_guid,
+            system_guid: node_info.system_guid,
+            port_guid: node_info.port_guid,
+            vendor_id: u32::from_be_bytes([
+                0,
+                node_info.vendor_id[0],
+                node_info.vendor_id[1],
+                node_info.vendor_id[2],
+            ]),
+            device_id: u16::from_be(node_info.device_id),
+            revision: u32::from_be(node_info.revision),
+            dr_path: path,
+            node_type,
+            local_port: node_info.local_port,
+            nports: node_info.nports,
+            partition_cap: u16::from_be(node_info.partition_cap),
+            enhanced_port0: false,
+            switch_info: None,
+            description: None,
+            lid: 0,
+            ports: Vec::with_capacity(node_info.nports as usize),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Fabric {
-    pub port: IbMadPort,
+    /// `None` for a fabric loaded from a file, which can be analysed but not queried.
+    pub port: Option<IbMadPort>,
     /// UMAD agent id to use for DR SMP requests (returned by `mad::register_agent`).
     /// For sim/tests that don't use a real UMAD device, this can be 0.
     pub agent_id: u32,
@@ -46,6 +214,8 @@ pub struct Fabric {
     pub switches: Vec<Weak<RwLock<Node>>>,
     pub hcas: Vec<Weak<RwLock<Node>>>,
     pub dr_paths: HashMap<[u8; 64], Weak<RwLock<Port>>>,
+    /// GUIDs of discovered nodes by the DR paths and LIDs they were reached with.
+    pub target_guids: HashMap<SmpTarget, u64>,
     pub ni_timings: Vec<time::Duration>,
     pub retries: u32,
     pub timeout: u32,
@@ -53,6 +223,27 @@ pub struct Fabric {
     pub mad_timeouts: u64,
     pub mads_sent: u64,
     pub tid: u64,
+    /// M_Key sent in every SMP.
+    pub m_key: MKeyProvider,
+    /// Sets that were dropped by an M_Key protected port.
+    pub m_key_violations: u64,
+}
+
+/// Addressing for an SMP: a directed route from the local port, or a LID using the
+/// LID-routed SMP class. The DR hop count is derived from the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SmpTarget {
+    DirectRoute([u8; 64]),
+    Lid(u16),
+}
+
+impl fmt::Display for SmpTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpTarget::DirectRoute(path) => write!(f, "path [{}]", Fabric::format_path(path)),
+            SmpTarget::Lid(lid) => write!(f, "LID {}", lid),
+        }
+    }
 }
 
 pub(crate) fn lock_err<T: std::fmt::Debug>(e: T) -> io::Error {
@@ -77,6 +268,15 @@ impl Fabric {
         }
     }
 
+    fn mad_port(&mut self) -> Result<&mut IbMadPort, io::Error> {
+        self.port.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "fabric has no IbMadPort, it was loaded offline.",
+            )
+        })
+    }
+
     pub(crate) fn get_hop_count(path: &[u8; 64]) -> u8 {
         path.iter().skip(1).take_while(|&&p| p != 0).count() as u8
     }
@@ -136,9 +336,9 @@ impl Fabric {
         return mad;
     }
 
-    pub(crate) fn build_dr_smp(path: [u8; 64]) -> mad::dr_smp_mad {
+    pub(crate) fn build_dr_smp(path: [u8; 64], m_key: u64) -> mad::dr_smp_mad {
         let dr_smp = mad::dr_smp_mad {
-            m_key: 0x0,
+            m_key: m_key.to_be(),
             drslid: 0xffff,
             drdlid: 0xffff,
             reserved: [0; 28],
@@ -159,8 +359,9 @@ impl Fabric {
         agent_id: u32,
         timeout: u32,
         retries: u32,
+        m_key: u64,
     ) -> ib_user_mad {
-        let mut dr_smp = Fabric::build_dr_smp(path);
+        let mut dr_smp = Fabric::build_dr_smp(path, m_key);
         let mut mad = Fabric::build_mad(
             enums::MadClasses::DirecteRoute as u8,
             enums::Methods::Get as u8,
@@ -212,7 +413,7 @@ impl Fabric {
                 attempt + 1,
                 retries + 1
             );
-            if let Err(e) = mad::send(&mut self.port, &umad_to_send) {
+            if let Err(e) = mad::send(self.mad_port()?, &umad_to_send) {
                 log::debug!(
                     "Fatal error sending MAD with TID 0x{:X}: {:?}",
                     expected_tid,
@@ -243,9 +444,15 @@ impl Fabric {
                 let remaining_time = (deadline - now).as_millis() as u32;
                 let mut recv_umad = Fabric::build_umad(self.agent_id, self.timeout, self.retries);
 
-                match mad::recv(&mut self.port, &mut recv_umad, remaining_time) {
+                match mad::recv(self.mad_port()?, &mut recv_umad, remaining_time) {
                     Ok(_) => {
-                        if umad_to_send.is_tid_equal(&recv_umad) {
+                        if !recv_umad.is_response() {
+                            log::trace!(
+                                "Discarding unsolicited MAD with TID 0x{:X}",
+                                recv_umad.get_tid().unwrap_or(0)
+                            );
+                            continue;
+                        } else if umad_to_send.is_tid_equal(&recv_umad) {
                             log::trace!("<- Matched response for TID 0x{:X}", expected_tid);
                             return Ok(recv_umad);
                         } else {
@@ -283,7 +490,8 @@ impl Fabric {
 
     pub fn recv_smp(&mut self) -> Result<ib_user_mad, io::Error> {
         let mut umad = Fabric::build_umad(self.agent_id, self.timeout, self.retries);
-        let _s = mad::recv(&mut self.port, &mut umad, self.timeout)?;
+        let timeout = self.timeout;
+        let _s = mad::recv(self.mad_port()?, &mut umad, timeout)?;
 
         Ok(umad)
     }
@@ -296,6 +504,7 @@ impl Fabric {
         let node_info = self.fetch_node_info(path, hop_cnt)?;
 
         let node_guid = node_info.node_guid;
+        self.index_target(SmpTarget::DirectRoute(path), node_guid);
         if let Some(existing) = self.node_map.get(&node_guid) {
             log::trace!(
                 "Node 0x{:X} already discovered, reusing existing entry.",
@@ -304,27 +513,25 @@ impl Fabric {
             return Ok(existing.clone());
         }
 
-        let node_type = enums::IbNodeType::try_from(node_info.node_type).map_err(|_e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("invalid node_type: {}", node_info.node_type),
-            )
-        })?;
-
-        let mut node = Node {
-            node_guid: node_info.node_guid,
-            dr_path: path,
-            node_type,
-            local_port: node_info.local_port,
-            nports: node_info.nports,
-            description: None,
-            lid: 0,
-            ports: Vec::with_capacity(node_info.nports as usize),
-        };
+        let mut node = Node::from_node_info(&node_info, path)?;
 
         let node_desc = self.fetch_node_desc(path, hop_cnt)?;
         node.description = Some(node_desc);
 
+        if node.node_type == enums::IbNodeType::Switch {
+            match self.get_switch_info(SmpTarget::DirectRoute(path)) {
+                Ok(si) => {
+                    node.enhanced_port0 = si.enhanced_port0() != 0;
+                    node.switch_info = Some(si);
+                }
+                Err(e) => log::warn!(
+                    "Could not fetch SwitchInfo of '{}': {}",
+                    node.description.as_deref().unwrap_or("N/A"),
+                    e
+                ),
+            }
+        }
+
         log::debug!(
             "Discovered Node: '{}' (GUID: 0x{:X}, Type: {:?}, Ports: {})",
             node.description.as_deref().unwrap_or("N/A"),
@@ -337,6 +544,18 @@ impl Fabric {
         let node_rc = Arc::new(RwLock::new(node));
 
         self.populate_node_ports(&node_rc, nports, path, hop_cnt)?;
+        let lids: Vec<(u16, u8)> = node_rc
+            .read()
+            .map_err(lock_err)?
+            .ports
+            .iter()
+            .filter_map(|p| p.read().ok().map(|p| (p.lid, p.lmc)))
+            .collect();
+        for (lid, lmc) in lids.into_iter().filter(|(lid, _)| *lid != 0) {
+            for offset in 0..1u16 << lmc.min(7) {
+                self.index_target(SmpTarget::Lid(lid.wrapping_add(offset)), node_guid);
+            }
+        }
 
         self.nodes.push(node_rc.clone());
         self.node_map.insert(node_info.node_guid, node_rc.clone());
@@ -356,6 +575,7 @@ impl Fabric {
         );
 
         let tid = self.next_tid();
+        let m_key = self.m_key_for(&SmpTarget::DirectRoute(path));
         let umad_to_send = Fabric::build_dr_smp_umad(
             path,
             enums::SmiAttrID::NodeInfo,
@@ -365,6 +585,7 @@ impl Fabric {
             self.agent_id,
             self.timeout,
             self.retries,
+            m_key,
         );
 
         let recv_ni_umad = self.send_and_match_with_retries(umad_to_send)?;
@@ -390,6 +611,7 @@ impl Fabric {
         );
 
         let tid = self.next_tid();
+        let m_key = self.m_key_for(&SmpTarget::DirectRoute(path));
         let umad_to_send = Fabric::build_dr_smp_umad(
             path,
             enums::SmiAttrID::NodeDesc,
@@ -399,6 +621,7 @@ impl Fabric {
             self.agent_id,
             self.timeout,
             self.retries,
+            m_key,
         );
 
         let recv_nd_umad = self.send_and_match_with_retries(umad_to_send)?;
@@ -433,6 +656,7 @@ impl Fabric {
         );
 
         let tid = self.next_tid();
+        let m_key = self.m_key_for(&SmpTarget::DirectRoute(path));
         let umad_to_send = Fabric::build_dr_smp_umad(
             path,
             enums::SmiAttrID::PortInfo,
@@ -442,10 +666,24 @@ impl Fabric {
             self.agent_id,
             self.timeout,
             self.retries,
+            m_key,
         );
 
         let recv_pi_umad = self.send_and_match_with_retries(umad_to_send)?;
 
+        // Ports past the end of the node are rejected with a status and no PortInfo.
+        let status = u16::from_be_bytes([recv_pi_umad.data[4], recv_pi_umad.data[5]])
+            & !mad::dr_smp::DR_SMP_DIRECTION;
+        if status != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Device returned MAD status {:#x} for PortInfo of port {}",
+                    status, port_num
+                ),
+            ));
+        }
+
         let pi = port_info::from_bytes(&recv_pi_umad.data[64..]).ok_or_else(|| {
             io::Error::new(io::ErrorKind::InvalidData, "could not parse portinfo data.")
         })?;
@@ -458,27 +696,176 @@ impl Fabric {
             pi.port_physical_state()
         );
 
-        let link_state = enums::IbPortLinkLayerState::try_from(pi.port_state()).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("invalid port_state: {:?}", e),
-            )
-        })?;
-        let phy_state = enums::IbPortPhyState::try_from(pi.port_physical_state()).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("invalid port_physical_state: {:?}", e),
-            )
+        // ProtectBits 0/1 return the M_Key in a Get, so a different non-zero value
+        // means any Set we send this port will be dropped.
+        if pi.m_key_protect_bits() > 0 && pi.m_key() != 0 && pi.m_key() != m_key {
+            log::warn!(
+                "M_Key mismatch on port {} at path [{}]: Sets will be rejected (ProtectBits {})",
+                port_num,
+                Fabric::format_path(&path),
+                pi.m_key_protect_bits()
+            );
+        }
+        if pi.m_key_violations() > 0 {
+            log::warn!(
+                "Port {} at path [{}] has {} M_Key violations",
+                port_num,
+                Fabric::format_path(&path),
+                pi.m_key_violations()
+            );
+        }
+
+        Port::from_port_info(port_num, &pi)
+    }
+
+    /// Issues a DR SubnGet for `attr_id` and returns the raw attribute payload.
+    ///
+    /// Unlike the NodeInfo/PortInfo fetchers this checks the MAD status, since
+    /// optional attributes are commonly rejected by devices that don't implement them.
+    pub(crate) fn fetch_attr(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        attr_id: enums::SmiAttrID,
+        attr_mod: u32,
+    ) -> Result<[u8; 64], io::Error> {
+        let attr = attr_id.clone() as u16;
+        let tid = self.next_tid();
+        let m_key = self.m_key_for(&SmpTarget::DirectRoute(path));
+        let umad_to_send = Fabric::build_dr_smp_umad(
+            path,
+            attr_id,
+            attr_mod,
+            hop_cnt,
+            tid,
+            self.agent_id,
+            self.timeout,
+            self.retries,
+            m_key,
+        );
+
+        self.exchange_smp(umad_to_send, SmpTarget::DirectRoute(path), attr, attr_mod)
+    }
+
+    /// Sends an SMP with any method to a DR path or LID and returns the attribute
+    /// payload of the response. `attr` is carried in the request, which matters for Set.
+    pub(crate) fn smp_request(
+        &mut self,
+        target: SmpTarget,
+        method: enums::Methods,
+        attr_id: enums::SmiAttrID,
+        attr_mod: u32,
+        attr: [u8; 64],
+    ) -> Result<[u8; 64], io::Error> {
+        let attr_id_val = attr_id.clone() as u16;
+        let is_set = matches!(method, enums::Methods::Set);
+        let violations_before = if is_set {
+            self.m_key_violations_at(target)
+        } else {
+            None
+        };
+        let tid = self.next_tid();
+        let m_key = self.m_key_for(&target);
+        let mut umad = Fabric::build_umad(self.agent_id, self.timeout, self.retries);
+
+        let mad = match target {
+            SmpTarget::DirectRoute(path) => {
+                let mut dr_smp = Fabric::build_dr_smp(path, m_key);
+                dr_smp.attr_layout = attr;
+                let mut mad = Fabric::build_mad(
+                    enums::MadClasses::DirecteRoute as u8,
+                    method as u8,
+                    attr_id,
+                    attr_mod,
+                    Fabric::get_hop_count(&path),
+                    tid,
+                );
+                let dr_bytes = dr_smp.to_bytes();
+                mad.data[..dr_bytes.len()].copy_from_slice(&dr_bytes);
+                mad
+            }
+            SmpTarget::Lid(lid) => {
+                let smp = mad::smp_mad {
+                    m_key: m_key.to_be(),
+                    reserved: [0; 32],
+                    attr_layout: attr,
+                    reserved2: [0; 128],
+                };
+                let mut mad = Fabric::build_mad(
+                    enums::MadClasses::LidRouted as u8,
+                    method as u8,
+                    attr_id,
+                    attr_mod,
+                    0,
+                    tid,
+                );
+                let smp_bytes = smp.to_bytes();
+                mad.data[..smp_bytes.len()].copy_from_slice(&smp_bytes);
+                umad.addr.lid = lid.to_be();
+                mad
+            }
+        };
+
+        let mad_bytes = mad.to_bytes();
+        umad.data[..mad_bytes.len()].copy_from_slice(&mad_bytes);
+
+        match self.exchange_smp(umad, target, attr_id_val, attr_mod) {
+            Err(e) if is_set => Err(self.classify_set_timeout(target, m_key, violations_before, e)),
+            r => r,
+        }
+    }
+
+    fn exchange_smp(
+        &mut self,
+        umad_to_send: ib_user_mad,
+        target: SmpTarget,
+        attr_id: u16,
+        attr_mod: u32,
+    ) -> Result<[u8; 64], io::Error> {
+        let recv_umad = self.send_and_match_with_retries(umad_to_send)?;
+
+        let recv_mad = mad::ib_mad::from_bytes(&recv_umad.data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Failed to parse response MAD")
         })?;
-        let lid = pi.lid();
 
-        Ok(Port {
-            number: port_num,
-            link_state,
-            phys_state: phy_state,
-            lid,
-            parent: Weak::new(),
-            remote_port: None,
+        let mut status = u16::from_be(recv_mad.status);
+        if matches!(target, SmpTarget::DirectRoute(_)) {
+            // The D bit only says the response came back along the directed route.
+            status &= !mad::dr_smp::DR_SMP_DIRECTION;
+        }
+        if status != 0 {
+            return Err(io::Error::other(format!(
+                "Device returned MAD status {:#x} for AttrID 0x{:04X} (mod 0x{:08X}) on {}",
+                status, attr_id, attr_mod, target
+            )));
+        }
+
+        match target {
+            SmpTarget::DirectRoute(_) => {
+                let dr = mad::dr_smp_mad::from_bytes(&recv_mad.data).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Unable to parse DR SMP")
+                })?;
+                Ok(dr.attr_layout)
+            }
+            SmpTarget::Lid(_) => {
+                let smp = mad::smp_mad::from_bytes(&recv_mad.data).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Unable to parse SMP")
+                })?;
+                Ok(smp.attr_layout)
+            }
+        }
+    }
+
+    /// Returns the full PortInfo attribute for a port, rather than the summary `Port`.
+    pub fn query_port_info(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        port_num: u8,
+    ) -> Result<port_info, io::Error> {
+        let attr = self.fetch_attr(path, hop_cnt, enums::SmiAttrID::PortInfo, port_num as u32)?;
+        port_info::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "could not parse portinfo data.")
         })
     }
 
@@ -622,17 +1009,7 @@ impl Fabric {
                         Fabric::format_path(&path)
                     );
                     self.mad_timeouts += 1;
-                    (
-                        Port {
-                            number: p,
-                            link_state: enums::IbPortLinkLayerState::Down,
-                            phys_state: enums::IbPortPhyState::Disabled,
-                            lid: 0,
-                            parent: Weak::new(),
-                            remote_port: None,
-                        },
-                        true,
-                    )
+                    (Port::new(p), true)
                 }
                 Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
                     log::debug!(
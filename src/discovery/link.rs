@@ -0,0 +1,161 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::lib::{Fabric, Port, lock_err};
+use crate::enums::{self, LinkSpeed, LinkWidth};
+
+/// Why a link was reported by `Fabric::degraded_links`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkIssue {
+    /// The two ends report a different active speed.
+    SpeedMismatch { local: LinkSpeed, remote: LinkSpeed },
+    /// The two ends report a different active width.
+    WidthMismatch { local: LinkWidth, remote: LinkWidth },
+    /// The link trained narrower than both ends are enabled for, e.g. 1X on 4X ports.
+    NarrowWidth {
+        active: LinkWidth,
+        expected: LinkWidth,
+    },
+    /// The link trained slower than both ends are enabled for.
+    SlowSpeed {
+        active: LinkSpeed,
+        expected: LinkSpeed,
+    },
+}
+
+/// An active link with one or more `LinkIssue`s. Issues are described from the
+/// point of view of `port`.
+#[derive(Debug, Clone)]
+pub struct DegradedLink {
+    pub port: Arc<RwLock<Port>>,
+    pub remote: Arc<RwLock<Port>>,
+    pub issues: Vec<LinkIssue>,
+}
+
+// Best value both ends have enabled and supported. Ports that did not report a
+// mask are treated as allowing anything the other end does.
+fn best_common<T: Copy + PartialEq + Ord>(
+    a_enabled: &[T],
+    a_supported: &[T],
+    b_enabled: &[T],
+    b_supported: &[T],
+) -> Option<T> {
+    let allowed = |v: &T, list: &[T]| list.is_empty() || list.contains(v);
+    a_enabled
+        .iter()
+        .chain(a_supported)
+        .chain(b_enabled)
+        .chain(b_supported)
+        .copied()
+        .filter(|v| {
+            allowed(v, a_enabled)
+                && allowed(v, a_supported)
+                && allowed(v, b_enabled)
+                && allowed(v, b_supported)
+        })
+        .max()
+}
+
+fn best_common_width(a: &Port, b: &Port) -> Option<LinkWidth> {
+    // LinkWidth's discriminants are mask bits, not lane order.
+    let lanes = |w: &[LinkWidth]| w.iter().map(|w| w.lanes()).collect::<Vec<_>>();
+    best_common(
+        &lanes(&a.link_width_enabled),
+        &lanes(&a.link_width_supported),
+        &lanes(&b.link_width_enabled),
+        &lanes(&b.link_width_supported),
+    )
+    .and_then(LinkWidth::from_lanes)
+}
+
+/// Compares the two ends of a link. Returns nothing for links that are not up.
+pub fn link_issues(a: &Port, b: &Port) -> Vec<LinkIssue> {
+    let mut issues = Vec::new();
+    if a.link_state == enums::IbPortLinkLayerState::Down
+        || b.link_state == enums::IbPortLinkLayerState::Down
+    {
+        return issues;
+    }
+
+    if let (Some(local), Some(remote)) = (a.link_speed_active, b.link_speed_active)
+        && local != remote
+    {
+        issues.push(LinkIssue::SpeedMismatch { local, remote });
+    }
+    if let (Some(local), Some(remote)) = (a.link_width_active, b.link_width_active)
+        && local != remote
+    {
+        issues.push(LinkIssue::WidthMismatch { local, remote });
+    }
+
+    // A link runs at the slower of its two ends.
+    let width = match (a.link_width_active, b.link_width_active) {
+        (Some(x), Some(y)) => Some(if x.lanes() <= y.lanes() { x } else { y }),
+        (x, y) => x.or(y),
+    };
+    let speed = match (a.link_speed_active, b.link_speed_active) {
+        (Some(x), Some(y)) => Some(x.min(y)),
+        (x, y) => x.or(y),
+    };
+
+    if let (Some(active), Some(expected)) = (width, best_common_width(a, b))
+        && active.lanes() < expected.lanes()
+    {
+        issues.push(LinkIssue::NarrowWidth { active, expected });
+    }
+    let expected_speed = best_common(
+        &a.link_speed_enabled,
+        &a.link_speed_supported,
+        &b.link_speed_enabled,
+        &b.link_speed_supported,
+    );
+    if let (Some(active), Some(expected)) = (speed, expected_speed)
+        && active < expected
+    {
+        issues.push(LinkIssue::SlowSpeed { active, expected });
+    }
+
+    issues
+}
+
+impl Fabric {
+    /// Returns every link whose ends disagree on speed or width, or that trained
+    /// below what both ends are enabled and capable of. Each link is reported once.
+    pub fn degraded_links(&self) -> Result<Vec<DegradedLink>, io::Error> {
+        let mut links = Vec::new();
+
+        for node_arc in &self.nodes {
+            let ports = node_arc.read().map_err(lock_err)?.ports.clone();
+            for port_arc in ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                let Some(remote_arc) = port.remote() else {
+                    continue;
+                };
+                // Visit each link from the end with the lower address only.
+                if Arc::as_ptr(&port_arc) > Arc::as_ptr(&remote_arc) {
+                    continue;
+                }
+                let remote = remote_arc.read().map_err(lock_err)?;
+
+                let issues = link_issues(&port, &remote);
+                if !issues.is_empty() {
+                    log::debug!(
+                        "Degraded link on port {} (LID {}): {:?}",
+                        port.number,
+                        port.lid,
+                        issues
+                    );
+                    links.push(DegradedLink {
+                        port: port_arc.clone(),
+                        remote: remote_arc.clone(),
+                        issues,
+                    });
+                }
+            }
+        }
+
+        Ok(links)
+    }
+}
@@ -0,0 +1,125 @@
+use std::{collections::HashMap, fmt, io, sync::Arc};
+
+use super::lib::{Fabric, SmpTarget};
+
+/// Callback form of `MKeyProvider`. Receives the SMP target and, once the node has
+/// been discovered, its GUID as stored in `Node::node_guid`.
+pub type MKeyCallback = Arc<dyn Fn(&SmpTarget, Option<u64>) -> u64 + Send + Sync>;
+
+/// Supplies the M_Key placed in every SMP the `Fabric` sends.
+///
+/// Nodes are only known by GUID after their NodeInfo has been read, so a node with
+/// ProtectBits 3 must be reachable with `default` (or the callback's `None` case)
+/// before its per-GUID key can be used.
+#[derive(Clone)]
+pub enum MKeyProvider {
+    Static(u64),
+    PerGuid {
+        keys: HashMap<u64, u64>,
+        default: u64,
+    },
+    Callback(MKeyCallback),
+}
+
+impl Default for MKeyProvider {
+    fn default() -> Self {
+        MKeyProvider::Static(0)
+    }
+}
+
+impl fmt::Debug for MKeyProvider {
+    // Keys are secrets; only the shape of the provider is printed.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MKeyProvider::Static(_) => write!(f, "MKeyProvider::Static(..)"),
+            MKeyProvider::PerGuid { keys, .. } => {
+                write!(f, "MKeyProvider::PerGuid({} keys)", keys.len())
+            }
+            MKeyProvider::Callback(_) => write!(f, "MKeyProvider::Callback(..)"),
+        }
+    }
+}
+
+impl MKeyProvider {
+    pub fn key(&self, target: &SmpTarget, guid: Option<u64>) -> u64 {
+        match self {
+            MKeyProvider::Static(key) => *key,
+            MKeyProvider::PerGuid { keys, default } => {
+                guid.and_then(|g| keys.get(&g).copied()).unwrap_or(*default)
+            }
+            MKeyProvider::Callback(cb) => cb(target, guid),
+        }
+    }
+}
+
+impl Fabric {
+    /// Records `guid` as the node reached by `target`, for `m_key_for`.
+    pub(crate) fn index_target(&mut self, target: SmpTarget, guid: u64) {
+        self.target_guids.insert(target, guid);
+    }
+
+    /// The M_Key to send to `target`, as chosen by `Fabric::m_key`.
+    pub fn m_key_for(&self, target: &SmpTarget) -> u64 {
+        let guid = match self.m_key {
+            MKeyProvider::Static(key) => return key,
+            _ => self.target_guids.get(target).copied(),
+        };
+        self.m_key.key(target, guid)
+    }
+
+    /// M_KeyViolations of the port that checks the M_Key at `target`, read before a
+    /// Set so that `classify_set_timeout` can tell whether the Set was dropped for it.
+    pub(crate) fn m_key_violations_at(&mut self, target: SmpTarget) -> Option<u16> {
+        // Attribute modifier 0 is switch port 0, or the port a CA received the SMP on.
+        self.get_port_info(target, 0)
+            .ok()
+            .map(|pi| pi.m_key_violations())
+    }
+
+    /// Works out whether an unanswered Set was dropped for an M_Key mismatch.
+    ///
+    /// Protected ports silently discard SMPs that fail the M_Key check, so a timeout
+    /// is all we see. The management port is read again, and the Set counts as
+    /// rejected if its M_KeyViolations went up from `violations_before`, or if it
+    /// shows an M_Key other than `m_key` (ProtectBits 1 leaves the key readable).
+    /// The violation is then counted and `PermissionDenied` returned; otherwise `err`
+    /// is passed through.
+    pub(crate) fn classify_set_timeout(
+        &mut self,
+        target: SmpTarget,
+        m_key: u64,
+        violations_before: Option<u16>,
+        err: io::Error,
+    ) -> io::Error {
+        if err.kind() != io::ErrorKind::TimedOut {
+            return err;
+        }
+
+        let pi = match self.get_port_info(target, 0) {
+            Ok(pi) => pi,
+            Err(_) => return err,
+        };
+        let counted = violations_before.is_some_and(|before| pi.m_key_violations() > before);
+        let mismatch = pi.m_key() != 0 && pi.m_key() != m_key;
+        if !counted && !mismatch {
+            return err;
+        }
+
+        self.m_key_violations += 1;
+        log::warn!(
+            "M_Key violation on {}: Set was dropped (ProtectBits {}, M_KeyViolations {})",
+            target,
+            pi.m_key_protect_bits(),
+            pi.m_key_violations()
+        );
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "M_Key violation on {}: Set dropped by port with ProtectBits {} (M_KeyViolations {})",
+                target,
+                pi.m_key_protect_bits(),
+                pi.m_key_violations()
+            ),
+        )
+    }
+}
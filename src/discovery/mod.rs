@@ -1,5 +1,21 @@
+pub mod admin;
+pub mod cabling;
+pub mod cache;
+pub mod diff;
+pub mod graph;
+pub mod guid;
 pub mod ib;
+pub mod ibnetdiscover;
+pub mod json;
 pub mod lib;
+pub mod link;
+pub mod mkey;
 pub mod nvlink;
+pub mod offline;
+pub mod partition;
+pub mod qos;
+pub mod routing;
+pub mod sm;
 
 pub use lib::*;
+pub use mkey::{MKeyCallback, MKeyProvider};
@@ -7,7 +7,7 @@ use std::{
 use super::lib::{lock_err, Fabric, Node, Port, START_PATH};
 use crate::enums;
 
-const NVLINK_RING_PORTS: [u8; 2] = [73, 74];
+pub(crate) const NVLINK_RING_PORTS: [u8; 2] = [73, 74];
 
 fn is_nvlink_ring_port(port_number: u8) -> bool {
     NVLINK_RING_PORTS.contains(&port_number)
@@ -26,6 +26,7 @@ impl Fabric {
         self.switches.clear();
         self.hcas.clear();
         self.dr_paths.clear();
+        self.target_guids.clear();
         self.ni_timings.clear();
         self.mad_errors = 0;
         self.mad_timeouts = 0;
@@ -206,7 +207,6 @@ impl Fabric {
         })?;
 
         let first_node = first_node_arc.read().map_err(lock_err)?;
-        let first_node_is_switch = first_node.node_type == enums::IbNodeType::Switch;
         visited.insert(first_node.node_guid);
 
         for port_arc in first_node.ports.iter() {
@@ -217,11 +217,8 @@ impl Fabric {
             }
 
             let mut path: [u8; 64] = [0; 64];
-            let (path_index, neighbor_hop_cnt) = if first_node_is_switch {
-                (hop_cnt as usize, hop_cnt)
-            } else {
-                (hop_cnt as usize + 1, hop_cnt + 1)
-            };
+            // Index 0 of an initial path is never used; the first hop goes out path[1].
+            let (path_index, neighbor_hop_cnt) = (hop_cnt as usize + 1, hop_cnt + 1);
 
             if path_index >= path.len() {
                 continue;
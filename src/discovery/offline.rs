@@ -0,0 +1,159 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::lib::{Fabric, Node, Port, START_PATH, lock_err};
+use super::mkey::MKeyProvider;
+use crate::enums;
+
+impl Fabric {
+    /// An empty fabric without an `IbMadPort`. Topologies loaded from files end up
+    /// here; anything that sends a MAD fails with `NotConnected`.
+    pub fn offline() -> Fabric {
+        Fabric {
+            port: None,
+            agent_id: 0,
+            node_map: HashMap::new(),
+            nodes: Vec::new(),
+            switches: Vec::new(),
+            hcas: Vec::new(),
+            dr_paths: HashMap::new(),
+            target_guids: HashMap::new(),
+            ni_timings: Vec::new(),
+            retries: 3,
+            timeout: 200,
+            mad_errors: 0,
+            mad_timeouts: 0,
+            mads_sent: 0,
+            tid: 1,
+            m_key: MKeyProvider::default(),
+            m_key_violations: 0,
+        }
+    }
+
+    /// Adds a node and its ports to `nodes` and `node_map`. The node LID is taken
+    /// from port 0 on switches and the first port with a LID otherwise.
+    pub(crate) fn insert_node(
+        &mut self,
+        mut node: Node,
+        ports: Vec<Port>,
+    ) -> Result<Arc<RwLock<Node>>, io::Error> {
+        if node.lid == 0 {
+            node.lid = ports
+                .iter()
+                .filter(|p| node.node_type != enums::IbNodeType::Switch || p.number == 0)
+                .map(|p| p.lid)
+                .find(|&lid| lid != 0)
+                .unwrap_or(0);
+        }
+
+        let node_arc = Arc::new(RwLock::new(node));
+        {
+            let mut node = node_arc.write().map_err(lock_err)?;
+            for mut port in ports {
+                port.parent = Arc::downgrade(&node_arc);
+                node.ports.push(Arc::new(RwLock::new(port)));
+            }
+            self.node_map.insert(node.node_guid, node_arc.clone());
+        }
+        self.nodes.push(node_arc.clone());
+
+        Ok(node_arc)
+    }
+
+    /// Looks up a port by its node's `node_guid` and port number.
+    pub(crate) fn find_port(
+        &self,
+        node_guid: u64,
+        number: u8,
+    ) -> Result<Option<Arc<RwLock<Port>>>, io::Error> {
+        let Some(node_arc) = self.node_map.get(&node_guid) else {
+            return Ok(None);
+        };
+        let node = node_arc.read().map_err(lock_err)?;
+        for port_arc in &node.ports {
+            if port_arc.read().map_err(lock_err)?.number == number {
+                return Ok(Some(port_arc.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn link_ports(
+        a: &Arc<RwLock<Port>>,
+        b: &Arc<RwLock<Port>>,
+    ) -> Result<(), io::Error> {
+        a.write().map_err(lock_err)?.remote_port = Some(Arc::downgrade(b));
+        b.write().map_err(lock_err)?.remote_port = Some(Arc::downgrade(a));
+        Ok(())
+    }
+
+    /// Finishes a fabric built with `insert_node`: orders `nodes` breadth first from
+    /// the node with `start_guid` (or the first node), gives every reachable node the
+    /// DR path discovery would have used, and fills `switches` and `hcas`.
+    pub(crate) fn finish_import(&mut self, start_guid: Option<u64>) -> Result<(), io::Error> {
+        let start = start_guid
+            .and_then(|guid| self.node_map.get(&guid).cloned())
+            .or_else(|| self.nodes.first().cloned());
+
+        let mut ordered = Vec::with_capacity(self.nodes.len());
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        if let Some(start) = start {
+            start.write().map_err(lock_err)?.dr_path = START_PATH;
+            seen.insert(Arc::as_ptr(&start));
+            queue.push_back(start);
+        }
+
+        while let Some(node_arc) = queue.pop_front() {
+            let (path, ports) = {
+                let node = node_arc.read().map_err(lock_err)?;
+                (node.dr_path, node.ports.clone())
+            };
+            ordered.push(node_arc);
+
+            let hop_cnt = Fabric::get_hop_count(&path) as usize;
+            for port_arc in ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                let Some(remote_node) = port
+                    .remote()
+                    .and_then(|r| r.read().ok().and_then(|r| r.parent.upgrade()))
+                else {
+                    continue;
+                };
+                if hop_cnt + 1 >= path.len() || !seen.insert(Arc::as_ptr(&remote_node)) {
+                    continue;
+                }
+                let mut remote_path = path;
+                remote_path[hop_cnt + 1] = port.number;
+                remote_node.write().map_err(lock_err)?.dr_path = remote_path;
+                queue.push_back(remote_node);
+            }
+        }
+
+        // Nodes not connected to the start node keep an empty path.
+        for node_arc in &self.nodes {
+            if !seen.contains(&Arc::as_ptr(node_arc)) {
+                ordered.push(node_arc.clone());
+            }
+        }
+        self.nodes = ordered;
+        self.categorize_nodes()
+    }
+
+    /// Rebuilds `switches` and `hcas` from `nodes`.
+    pub(crate) fn categorize_nodes(&mut self) -> Result<(), io::Error> {
+        self.switches.clear();
+        self.hcas.clear();
+        for node_arc in &self.nodes {
+            match node_arc.read().map_err(lock_err)?.node_type {
+                enums::IbNodeType::Switch => self.switches.push(Arc::downgrade(node_arc)),
+                _ => self.hcas.push(Arc::downgrade(node_arc)),
+            }
+        }
+
+        Ok(())
+    }
+}
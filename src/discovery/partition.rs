@@ -0,0 +1,160 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::lib::{Fabric, Port, lock_err};
+use crate::{
+    enums,
+    mad::{PKey, pkey::PKEY_BLOCK_SIZE, pkey_table},
+};
+
+/// A port carrying a given partition, with its membership type.
+pub type PartitionMember = (Arc<RwLock<Port>>, enums::PKeyMembership);
+
+impl Fabric {
+    /// Fetches one 32-entry block of a port's P_KeyTable.
+    ///
+    /// On switches the port number is carried in the upper 16 bits of the attribute
+    /// modifier; CAs ignore it and answer for the port the SMP arrived on.
+    pub fn fetch_pkey_table(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        port_num: u8,
+        block: u16,
+    ) -> Result<pkey_table, io::Error> {
+        log::debug!(
+            "Fetching P_KeyTable block {} for port {} on path: [{}]",
+            block,
+            port_num,
+            Fabric::format_path(&path)
+        );
+
+        let attr_mod = ((port_num as u32) << 16) | block as u32;
+        let attr = self.fetch_attr(path, hop_cnt, enums::SmiAttrID::PKeyTable, attr_mod)?;
+
+        pkey_table::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "could not parse pkeytable data.",
+            )
+        })
+    }
+
+    /// Reads `num_entries` P_Keys from a port, one block at a time.
+    /// Unused (zero) slots are dropped from the result.
+    pub fn query_port_pkeys(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        port_num: u8,
+        num_entries: u16,
+    ) -> Result<Vec<PKey>, io::Error> {
+        let num_blocks = (num_entries as usize).div_ceil(PKEY_BLOCK_SIZE);
+        let mut pkeys = Vec::new();
+
+        for block in 0..num_blocks {
+            let table = self.fetch_pkey_table(path, hop_cnt, port_num, block as u16)?;
+            let remaining = num_entries as usize - block * PKEY_BLOCK_SIZE;
+            pkeys.extend(
+                table
+                    .entries()
+                    .into_iter()
+                    .take(remaining)
+                    .filter(|pk| pk.is_valid()),
+            );
+        }
+
+        Ok(pkeys)
+    }
+
+    /// Populates `Port.pkeys` for every switch port and the port each CA was reached on.
+    ///
+    /// P_KeyTable is answered for the port the SMP arrived on, so other ports of a
+    /// multi-port CA are left empty. CA ports and switch port 0 are sized by NodeInfo
+    /// `partition_cap`. External switch ports are sized by the PartitionEnforcementCap
+    /// of the SwitchInfo read during discovery and are skipped when the switch does not
+    /// enforce partitions or its SwitchInfo could not be read.
+    pub fn discover_partitions(&mut self) -> Result<(), io::Error> {
+        let nodes = self.nodes.clone();
+
+        for node_arc in nodes {
+            let (path, node_type, local_port, partition_cap, enforcement_cap, description) = {
+                let node = node_arc.read().map_err(lock_err)?;
+                (
+                    node.dr_path,
+                    node.node_type.clone(),
+                    node.local_port,
+                    node.partition_cap,
+                    node.switch_info
+                        .map_or(0, |si| si.partition_enforcement_cap()),
+                    node.description.clone(),
+                )
+            };
+            let hop_cnt = Fabric::get_hop_count(&path);
+            let is_switch = node_type == enums::IbNodeType::Switch;
+
+            let ports: Vec<Arc<RwLock<Port>>> = node_arc.read().map_err(lock_err)?.ports.clone();
+
+            for port_arc in ports {
+                let (port_num, link_state) = {
+                    let port = port_arc.read().map_err(lock_err)?;
+                    (port.number, port.link_state.clone())
+                };
+                if !is_switch && port_num != local_port {
+                    continue;
+                }
+
+                let num_entries = if is_switch && port_num != 0 {
+                    enforcement_cap
+                } else {
+                    partition_cap
+                };
+                if num_entries == 0 || link_state == enums::IbPortLinkLayerState::Down {
+                    continue;
+                }
+
+                match self.query_port_pkeys(path, hop_cnt, port_num, num_entries) {
+                    Ok(pkeys) => port_arc.write().map_err(lock_err)?.pkeys = pkeys,
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to fetch P_KeyTable for port {} on '{}': {}",
+                            port_num,
+                            description.as_deref().unwrap_or("N/A"),
+                            e
+                        );
+                        self.mad_errors += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns every port whose P_KeyTable contains `pkey`, along with its membership.
+    /// The membership bit of `pkey` is ignored when matching.
+    pub fn partition_members(&self, pkey: u16) -> Result<Vec<PartitionMember>, io::Error> {
+        let mut members = Vec::new();
+
+        for node_arc in &self.nodes {
+            let node = node_arc.read().map_err(lock_err)?;
+            for port_arc in &node.ports {
+                let port = port_arc.read().map_err(lock_err)?;
+                // A port may carry both the full and limited entry; report full if so.
+                let membership = port
+                    .pkeys
+                    .iter()
+                    .filter(|pk| pk.same_partition(pkey))
+                    .map(|pk| pk.membership())
+                    .max_by_key(|m| *m as u8);
+                if let Some(membership) = membership {
+                    members.push((port_arc.clone(), membership));
+                }
+            }
+        }
+
+        Ok(members)
+    }
+}
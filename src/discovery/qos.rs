@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::lib::{Fabric, Port, lock_err};
+use crate::{
+    enums,
+    mad::{
+        VlArbEntry, VlArbitration, port_info,
+        qos::{
+            self, VL_ARB_BLOCK_SIZE, VL_ARB_HIGH_0_31, VL_ARB_HIGH_32_63, VL_ARB_LOW_0_31,
+            VL_ARB_LOW_32_63,
+        },
+        sl2vl_table, vl_arb_table,
+    },
+};
+
+/// An SL2VL table that maps one or more SLs to a VL the output port does not operate.
+#[derive(Debug, Clone)]
+pub struct Sl2VlViolation {
+    pub port: Arc<RwLock<Port>>,
+    pub input_port: u8,
+    pub operational_vls: u8,
+    pub sls: Vec<u8>,
+}
+
+type PortQos = (port_info, HashMap<u8, sl2vl_table>, Option<VlArbitration>);
+
+impl Fabric {
+    /// Fetches the SLtoVLMappingTable used for traffic entering `in_port` and
+    /// leaving `out_port`. CAs and routers ignore the port numbers.
+    pub fn fetch_sl2vl_table(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        in_port: u8,
+        out_port: u8,
+    ) -> Result<sl2vl_table, io::Error> {
+        log::debug!(
+            "Fetching SL2VL table for {} -> {} on path: [{}]",
+            in_port,
+            out_port,
+            Fabric::format_path(&path)
+        );
+
+        let attr_mod = ((in_port as u32) << 8) | out_port as u32;
+        let attr = self.fetch_attr(
+            path,
+            hop_cnt,
+            enums::SmiAttrID::SLtoVLMappingTable,
+            attr_mod,
+        )?;
+
+        sl2vl_table::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "could not parse sl2vl data.")
+        })
+    }
+
+    /// Fetches every input/output port pair of a switch, including port 0 as an input.
+    pub fn query_switch_sl2vl(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        nports: u8,
+    ) -> Result<HashMap<(u8, u8), sl2vl_table>, io::Error> {
+        let mut tables = HashMap::new();
+        for out_port in 1..=nports {
+            for in_port in 0..=nports {
+                let table = self.fetch_sl2vl_table(path, hop_cnt, in_port, out_port)?;
+                tables.insert((in_port, out_port), table);
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Fetches one VLArbitrationTable block (see `mad::qos::VL_ARB_*`).
+    pub fn fetch_vl_arb_table(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        port_num: u8,
+        block: u16,
+    ) -> Result<vl_arb_table, io::Error> {
+        log::debug!(
+            "Fetching VLArbitration block {} for port {} on path: [{}]",
+            block,
+            port_num,
+            Fabric::format_path(&path)
+        );
+
+        let attr_mod = ((port_num as u32) << 16) | block as u32;
+        let attr = self.fetch_attr(
+            path,
+            hop_cnt,
+            enums::SmiAttrID::VLArbitrationTable,
+            attr_mod,
+        )?;
+
+        vl_arb_table::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "could not parse vlarb data.")
+        })
+    }
+
+    fn query_vl_arb_entries(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        port_num: u8,
+        blocks: [u16; 2],
+        cap: u8,
+    ) -> Result<Vec<VlArbEntry>, io::Error> {
+        let mut entries = Vec::with_capacity(cap as usize);
+        for (i, block) in blocks.into_iter().enumerate() {
+            let remaining = (cap as usize).saturating_sub(i * VL_ARB_BLOCK_SIZE);
+            if remaining == 0 {
+                break;
+            }
+            let table = self.fetch_vl_arb_table(path, hop_cnt, port_num, block)?;
+            entries.extend(table.entries().into_iter().take(remaining));
+        }
+        Ok(entries)
+    }
+
+    /// Reads the high and low priority arbitration tables of a port, sized by the
+    /// VLArbitrationHighCap/LowCap in `pi`. Ports with a single data VL have no
+    /// arbitration table and return `None`.
+    pub fn query_vl_arbitration(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        port_num: u8,
+        pi: &port_info,
+    ) -> Result<Option<VlArbitration>, io::Error> {
+        if qos::data_vls(pi.vl_cap()) <= 1 {
+            return Ok(None);
+        }
+
+        let high = self.query_vl_arb_entries(
+            path,
+            hop_cnt,
+            port_num,
+            [VL_ARB_HIGH_0_31, VL_ARB_HIGH_32_63],
+            pi.vl_arbitration_high_cap(),
+        )?;
+        let low = self.query_vl_arb_entries(
+            path,
+            hop_cnt,
+            port_num,
+            [VL_ARB_LOW_0_31, VL_ARB_LOW_32_63],
+            pi.vl_arbitration_low_cap(),
+        )?;
+
+        Ok(Some(VlArbitration {
+            vl_high_limit: pi.vl_high_limit(),
+            high,
+            low,
+        }))
+    }
+
+    fn fetch_port_qos(
+        &mut self,
+        path: [u8; 64],
+        hop_cnt: u8,
+        port_num: u8,
+        switch_nports: Option<u8>,
+    ) -> Result<PortQos, io::Error> {
+        let pi = self.query_port_info(path, hop_cnt, port_num)?;
+
+        let mut sl2vl = HashMap::new();
+        if let Some(nports) = switch_nports {
+            for in_port in 0..=nports {
+                let table = self.fetch_sl2vl_table(path, hop_cnt, in_port, port_num)?;
+                sl2vl.insert(in_port, table);
+            }
+        } else {
+            let table = self.fetch_sl2vl_table(path, hop_cnt, 0, 0)?;
+            sl2vl.insert(port_num, table);
+        }
+
+        let vl_arb = self.query_vl_arbitration(path, hop_cnt, port_num, &pi)?;
+        Ok((pi, sl2vl, vl_arb))
+    }
+
+    /// Populates `Port.sl2vl` and `Port.vl_arbitration` for every active port, and
+    /// returns the SL2VL tables that map SLs onto VLs beyond the output port's
+    /// OperationalVLs.
+    ///
+    /// Switch ports get one SL2VL table per input port (0..=nports); CA ports get
+    /// their single table. Errors on individual ports are logged and counted.
+    pub fn discover_qos(&mut self) -> Result<Vec<Sl2VlViolation>, io::Error> {
+        let mut violations = Vec::new();
+        let nodes = self.nodes.clone();
+
+        for node_arc in nodes {
+            let (path, is_switch, nports, description, ports) = {
+                let node = node_arc.read().map_err(lock_err)?;
+                (
+                    node.dr_path,
+                    node.node_type == enums::IbNodeType::Switch,
+                    node.nports,
+                    node.description.clone(),
+                    node.ports.clone(),
+                )
+            };
+            let hop_cnt = Fabric::get_hop_count(&path);
+            let switch_nports = if is_switch { Some(nports) } else { None };
+
+            for port_arc in ports {
+                let (port_num, link_state) = {
+                    let port = port_arc.read().map_err(lock_err)?;
+                    (port.number, port.link_state.clone())
+                };
+                if (is_switch && port_num == 0) || link_state != enums::IbPortLinkLayerState::Active
+                {
+                    continue;
+                }
+
+                match self.fetch_port_qos(path, hop_cnt, port_num, switch_nports) {
+                    Ok((pi, sl2vl, vl_arb)) => {
+                        let mut inputs: Vec<_> = sl2vl.iter().collect();
+                        inputs.sort_by_key(|(in_port, _)| **in_port);
+                        for (in_port, table) in inputs {
+                            let sls = table.invalid_sls(pi.operational_vls());
+                            if !sls.is_empty() {
+                                violations.push(Sl2VlViolation {
+                                    port: port_arc.clone(),
+                                    input_port: *in_port,
+                                    operational_vls: pi.operational_vls(),
+                                    sls,
+                                });
+                            }
+                        }
+
+                        let mut port = port_arc.write().map_err(lock_err)?;
+                        port.sl2vl = sl2vl;
+                        port.vl_arbitration = vl_arb;
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to fetch QoS tables for port {} on '{}': {}",
+                            port_num,
+                            description.as_deref().unwrap_or("N/A"),
+                            e
+                        );
+                        self.mad_errors += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(violations)
+    }
+}
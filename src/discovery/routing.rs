@@ -0,0 +1,83 @@
+use std::io;
+
+use super::lib::{Fabric, SmpTarget};
+use crate::{
+    enums,
+    mad::{lft_block, switch::LFT_BLOCK_SIZE, switch_info},
+};
+
+impl Fabric {
+    /// Fetches SwitchInfo from a switch on a DR path or LID.
+    pub fn get_switch_info(&mut self, target: SmpTarget) -> Result<switch_info, io::Error> {
+        log::debug!("Fetching SwitchInfo from {}", target);
+
+        let attr = self.smp_request(
+            target,
+            enums::Methods::Get,
+            enums::SmiAttrID::SwitchInfo,
+            0,
+            [0; 64],
+        )?;
+        let si = switch_info::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "could not parse switchinfo data.",
+            )
+        })?;
+
+        log::trace!("<- Received SwitchInfo: {:?}", si);
+        Ok(si)
+    }
+
+    /// Fetches one 64-entry block of a switch's LinearForwardingTable, covering LIDs
+    /// `block * 64` to `block * 64 + 63`.
+    pub fn get_lft_block(&mut self, target: SmpTarget, block: u32) -> Result<lft_block, io::Error> {
+        log::debug!("Fetching LFT block {} from {}", block, target);
+
+        let attr = self.smp_request(
+            target,
+            enums::Methods::Get,
+            enums::SmiAttrID::LinearForwardingTable,
+            block,
+            [0; 64],
+        )?;
+        lft_block::from_bytes(&attr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "could not parse LFT data."))
+    }
+
+    /// Reads a switch's whole LinearForwardingTable, indexed by LID.
+    ///
+    /// SwitchInfo LinearFDBTop bounds the table; entries past it are not read. LIDs
+    /// the switch has no route for hold `LFT_NO_ROUTE` (0xFF).
+    pub fn get_lft(&mut self, target: SmpTarget) -> Result<Vec<u8>, io::Error> {
+        let top = self.get_switch_info(target)?.linear_fdb_top() as usize;
+
+        let mut lft = Vec::with_capacity(top + 1);
+        for block in 0..=(top / LFT_BLOCK_SIZE) {
+            lft.extend(self.get_lft_block(target, block as u32)?.entries());
+        }
+        lft.truncate(top + 1);
+        Ok(lft)
+    }
+
+    /// Writes one block of a switch's LinearForwardingTable and returns the block from
+    /// the GetResp.
+    pub fn set_lft_block(
+        &mut self,
+        target: SmpTarget,
+        block: u32,
+        lft: &lft_block,
+    ) -> Result<lft_block, io::Error> {
+        log::debug!("Setting LFT block {} on {}", block, target);
+
+        let attr = self.smp_request(
+            target,
+            enums::Methods::Set,
+            enums::SmiAttrID::LinearForwardingTable,
+            block,
+            lft.data,
+        )?;
+        lft_block::from_bytes(&attr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "could not parse LFT data."))
+    }
+}
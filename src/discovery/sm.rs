@@ -0,0 +1,150 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::lib::{Fabric, Node, lock_err};
+use crate::{enums, mad::sm_info};
+
+/// A subnet manager found by `Fabric::discover_subnet_managers`.
+#[derive(Debug, Clone)]
+pub struct SubnetManager {
+    pub node: Arc<RwLock<Node>>,
+    /// Port the SM answered on; 0 for a switch-hosted SM.
+    pub port: u8,
+    pub guid: u64,
+    /// The SM_Key is only returned to requesters that present it, so a non-zero
+    /// value means the SM runs unprotected or we are trusted.
+    pub sm_key_present: bool,
+    pub act_count: u32,
+    pub priority: u8,
+    pub state: enums::SmState,
+}
+
+/// Result of `Fabric::discover_subnet_managers`.
+#[derive(Debug, Clone, Default)]
+pub struct SubnetManagerReport {
+    pub managers: Vec<SubnetManager>,
+    /// GUIDs of the SMs in Master state, if there is more than one. Such SMs cannot
+    /// see each other and each tries to manage the subnet.
+    pub duplicate_masters: Vec<u64>,
+}
+
+impl SubnetManagerReport {
+    /// The master SM, unless there is none or more than one.
+    pub fn master(&self) -> Option<&SubnetManager> {
+        let mut masters = self
+            .managers
+            .iter()
+            .filter(|sm| sm.state == enums::SmState::Master);
+        match (masters.next(), masters.next()) {
+            (Some(master), None) => Some(master),
+            _ => None,
+        }
+    }
+}
+
+impl Fabric {
+    pub fn fetch_sm_info(&mut self, path: [u8; 64], hop_cnt: u8) -> Result<sm_info, io::Error> {
+        log::debug!("Fetching SMInfo for path: [{}]", Fabric::format_path(&path));
+
+        let attr = self.fetch_attr(path, hop_cnt, enums::SmiAttrID::SMInfo, 0)?;
+        let smi = sm_info::from_bytes(&attr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "could not parse sminfo data.")
+        })?;
+
+        log::trace!("<- Received SMInfo: {:?}", smi);
+        Ok(smi)
+    }
+
+    /// Probes every discovered node for an SM and returns the ones that answered.
+    ///
+    /// Nodes without an SM reject SMInfo with a MAD status and are skipped; an SM
+    /// reporting a reserved SMState is skipped and counted in `mad_errors`. More than
+    /// one master on the subnet is reported in `duplicate_masters` and logged as a
+    /// warning, since it means the SMs cannot see each other.
+    pub fn discover_subnet_managers(&mut self) -> Result<SubnetManagerReport, io::Error> {
+        let mut sms = Vec::new();
+        let nodes = self.nodes.clone();
+
+        for node_arc in nodes {
+            let (path, port, description) = {
+                let node = node_arc.read().map_err(lock_err)?;
+                let port = if node.node_type == enums::IbNodeType::Switch {
+                    0
+                } else {
+                    node.local_port
+                };
+                (node.dr_path, port, node.description.clone())
+            };
+
+            let smi = match self.fetch_sm_info(path, Fabric::get_hop_count(&path)) {
+                Ok(smi) => smi,
+                Err(e) => {
+                    log::trace!(
+                        "No SM on '{}' port {}: {}",
+                        description.as_deref().unwrap_or("N/A"),
+                        port,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let Ok(state) = enums::SmState::try_from(smi.sm_state()) else {
+                log::warn!(
+                    "SM on '{}' port {} reported invalid SMState {}",
+                    description.as_deref().unwrap_or("N/A"),
+                    port,
+                    smi.sm_state()
+                );
+                self.mad_errors += 1;
+                continue;
+            };
+
+            log::debug!(
+                "Found SM on '{}' port {}: GUID 0x{:016X}, state {:?}, priority {}, ActCount {}",
+                description.as_deref().unwrap_or("N/A"),
+                port,
+                smi.guid(),
+                state,
+                smi.priority(),
+                smi.act_count()
+            );
+
+            sms.push(SubnetManager {
+                node: node_arc.clone(),
+                port,
+                guid: smi.guid(),
+                sm_key_present: smi.sm_key() != 0,
+                act_count: smi.act_count(),
+                priority: smi.priority(),
+                state,
+            });
+        }
+
+        let mut masters: Vec<u64> = sms
+            .iter()
+            .filter(|sm| sm.state == enums::SmState::Master)
+            .map(|sm| sm.guid)
+            .collect();
+        if masters.len() > 1 {
+            log::warn!(
+                "Found {} master SMs on the subnet: {}",
+                masters.len(),
+                masters
+                    .iter()
+                    .map(|g| format!("0x{:016X}", g))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        } else {
+            masters.clear();
+        }
+
+        Ok(SubnetManagerReport {
+            managers: sms,
+            duplicate_masters: masters,
+        })
+    }
+}
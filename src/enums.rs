@@ -1,4 +1,6 @@
-#[derive(Debug, Clone, PartialEq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum IbPortPhyState {
     Sleep = 1,
     Polling = 2,
@@ -26,7 +28,7 @@ impl TryFrom<u8> for IbPortPhyState {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum IbPortLinkLayerState {
     Nop = 0,
     Down = 1,
@@ -53,6 +55,28 @@ impl TryFrom<u8> for IbPortLinkLayerState {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+pub enum SmState {
+    NotActive = 0,
+    Discovering = 1,
+    Standby = 2,
+    Master = 3,
+}
+
+impl TryFrom<u8> for SmState {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SmState::NotActive),
+            1 => Ok(SmState::Discovering),
+            2 => Ok(SmState::Standby),
+            3 => Ok(SmState::Master),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum IbNodeType {
     CA = 1,
     Switch = 2,
@@ -76,17 +100,315 @@ impl TryFrom<u8> for IbNodeType {
 
 #[derive(Debug, Clone)]
 pub enum MadClasses {
+    LidRouted = 0x01,
+    SubnAdm = 0x03,
     DirecteRoute = 0x81,
 }
 
 #[derive(Debug, Clone)]
 pub enum Methods {
     Get = 0x1,
+    Set = 0x2,
 }
 
 #[derive(Debug, Clone)]
 pub enum SmiAttrID {
     NodeDesc = 0x10,
     NodeInfo = 0x11,
+    SwitchInfo = 0x12,
+    GUIDInfo = 0x14,
     PortInfo = 0x15,
+    PKeyTable = 0x16,
+    SLtoVLMappingTable = 0x17,
+    VLArbitrationTable = 0x18,
+    LinearForwardingTable = 0x19,
+    SMInfo = 0x20,
+}
+
+/// Subnet Administration attributes, queried from the SA with SubnAdmGet/GetTable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaAttrID {
+    NodeRecord = 0x11,
+    PortInfoRecord = 0x12,
+    LinkRecord = 0x20,
+    PathRecord = 0x35,
+}
+
+/// Performance Management attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfAttrID {
+    PortCounters = 0x12,
+    PortCountersExtended = 0x1D,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PKeyMembership {
+    Limited = 0,
+    Full = 1,
+}
+
+/// Link width, as the single-bit PortInfo LinkWidth encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LinkWidth {
+    #[serde(rename = "1X")]
+    X1 = 0x01,
+    #[serde(rename = "4X")]
+    X4 = 0x02,
+    #[serde(rename = "8X")]
+    X8 = 0x04,
+    #[serde(rename = "12X")]
+    X12 = 0x08,
+    #[serde(rename = "2X")]
+    X2 = 0x10,
+}
+
+impl LinkWidth {
+    pub const ALL: [LinkWidth; 5] = [
+        LinkWidth::X1,
+        LinkWidth::X2,
+        LinkWidth::X4,
+        LinkWidth::X8,
+        LinkWidth::X12,
+    ];
+
+    pub fn lanes(&self) -> u8 {
+        match self {
+            LinkWidth::X1 => 1,
+            LinkWidth::X2 => 2,
+            LinkWidth::X4 => 4,
+            LinkWidth::X8 => 8,
+            LinkWidth::X12 => 12,
+        }
+    }
+
+    pub fn from_lanes(lanes: u8) -> Option<Self> {
+        LinkWidth::ALL.into_iter().find(|w| w.lanes() == lanes)
+    }
+
+    /// Decodes a LinkWidthSupported/Enabled bit mask, narrowest first.
+    pub fn from_mask(mask: u8) -> Vec<LinkWidth> {
+        LinkWidth::ALL
+            .into_iter()
+            .filter(|w| mask & *w as u8 != 0)
+            .collect()
+    }
+}
+
+impl TryFrom<u8> for LinkWidth {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(LinkWidth::X1),
+            0x02 => Ok(LinkWidth::X4),
+            0x04 => Ok(LinkWidth::X8),
+            0x08 => Ok(LinkWidth::X12),
+            0x10 => Ok(LinkWidth::X2),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for LinkWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}X", self.lanes())
+    }
+}
+
+/// Per-lane link speed, ordered slowest to fastest.
+///
+/// SDR-QDR come from PortInfo LinkSpeed, FDR-NDR from LinkSpeedExt and XDR from
+/// LinkSpeedExt2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum LinkSpeed {
+    Sdr,
+    Ddr,
+    Qdr,
+    Fdr,
+    Edr,
+    Hdr,
+    Ndr,
+    Xdr,
+}
+
+impl LinkSpeed {
+    pub const ALL: [LinkSpeed; 8] = [
+        LinkSpeed::Sdr,
+        LinkSpeed::Ddr,
+        LinkSpeed::Qdr,
+        LinkSpeed::Fdr,
+        LinkSpeed::Edr,
+        LinkSpeed::Hdr,
+        LinkSpeed::Ndr,
+        LinkSpeed::Xdr,
+    ];
+
+    /// Decodes a single LinkSpeed value (1 = SDR, 2 = DDR, 4 = QDR).
+    pub fn from_link_speed(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(LinkSpeed::Sdr),
+            2 => Some(LinkSpeed::Ddr),
+            4 => Some(LinkSpeed::Qdr),
+            _ => None,
+        }
+    }
+
+    /// Decodes a single LinkSpeedExt value (1 = FDR, 2 = EDR, 4 = HDR, 8 = NDR).
+    pub fn from_link_speed_ext(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(LinkSpeed::Fdr),
+            2 => Some(LinkSpeed::Edr),
+            4 => Some(LinkSpeed::Hdr),
+            8 => Some(LinkSpeed::Ndr),
+            _ => None,
+        }
+    }
+
+    /// Decodes a single LinkSpeedExt2 value (1 = XDR).
+    pub fn from_link_speed_ext2(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(LinkSpeed::Xdr),
+            _ => None,
+        }
+    }
+
+    /// Active speed of a port: LinkSpeedExt2Active wins when it is non-zero, then
+    /// LinkSpeedExtActive.
+    pub fn from_active(link_speed: u8, link_speed_ext: u8, link_speed_ext2: u8) -> Option<Self> {
+        if link_speed_ext2 != 0 {
+            LinkSpeed::from_link_speed_ext2(link_speed_ext2)
+        } else if link_speed_ext != 0 {
+            LinkSpeed::from_link_speed_ext(link_speed_ext)
+        } else {
+            LinkSpeed::from_link_speed(link_speed)
+        }
+    }
+
+    /// Decodes LinkSpeed, LinkSpeedExt and LinkSpeedExt2 Supported/Enabled bit masks,
+    /// slowest first.
+    pub fn from_masks(link_speed: u8, link_speed_ext: u8, link_speed_ext2: u8) -> Vec<LinkSpeed> {
+        let legacy = [1, 2, 4]
+            .into_iter()
+            .filter(|bit| link_speed & bit != 0)
+            .filter_map(LinkSpeed::from_link_speed);
+        let ext = [1, 2, 4, 8]
+            .into_iter()
+            .filter(|bit| link_speed_ext & bit != 0)
+            .filter_map(LinkSpeed::from_link_speed_ext);
+        let ext2 = [1]
+            .into_iter()
+            .filter(|bit| link_speed_ext2 & bit != 0)
+            .filter_map(LinkSpeed::from_link_speed_ext2);
+        legacy.chain(ext).chain(ext2).collect()
+    }
+
+    /// Encodes speeds as LinkSpeed, LinkSpeedExt and LinkSpeedExt2 bit masks, the
+    /// inverse of `from_masks`.
+    pub fn to_masks(speeds: &[LinkSpeed]) -> (u8, u8, u8) {
+        speeds
+            .iter()
+            .fold((0, 0, 0), |(legacy, ext, ext2), speed| match speed {
+                LinkSpeed::Sdr => (legacy | 1, ext, ext2),
+                LinkSpeed::Ddr => (legacy | 2, ext, ext2),
+                LinkSpeed::Qdr => (legacy | 4, ext, ext2),
+                LinkSpeed::Fdr => (legacy, ext | 1, ext2),
+                LinkSpeed::Edr => (legacy, ext | 2, ext2),
+                LinkSpeed::Hdr => (legacy, ext | 4, ext2),
+                LinkSpeed::Ndr => (legacy, ext | 8, ext2),
+                LinkSpeed::Xdr => (legacy, ext, ext2 | 1),
+            })
+    }
+
+    /// Signalling rate per lane in Gb/s.
+    pub fn lane_gbps(&self) -> f64 {
+        match self {
+            LinkSpeed::Sdr => 2.5,
+            LinkSpeed::Ddr => 5.0,
+            LinkSpeed::Qdr => 10.0,
+            LinkSpeed::Fdr => 14.0625,
+            LinkSpeed::Edr => 25.78125,
+            LinkSpeed::Hdr => 53.125,
+            LinkSpeed::Ndr => 106.25,
+            LinkSpeed::Xdr => 212.5,
+        }
+    }
+
+    /// Data rate per lane in Gb/s, after line encoding (8b/10b up to QDR, 64b/66b
+    /// for FDR/EDR, PAM4 with FEC beyond).
+    pub fn lane_data_gbps(&self) -> f64 {
+        match self {
+            LinkSpeed::Sdr => 2.0,
+            LinkSpeed::Ddr => 4.0,
+            LinkSpeed::Qdr => 8.0,
+            LinkSpeed::Fdr => 14.0625 * 64.0 / 66.0,
+            LinkSpeed::Edr => 25.0,
+            LinkSpeed::Hdr => 50.0,
+            LinkSpeed::Ndr => 100.0,
+            LinkSpeed::Xdr => 200.0,
+        }
+    }
+}
+
+impl std::fmt::Display for LinkSpeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            LinkSpeed::Sdr => "SDR",
+            LinkSpeed::Ddr => "DDR",
+            LinkSpeed::Qdr => "QDR",
+            LinkSpeed::Fdr => "FDR",
+            LinkSpeed::Edr => "EDR",
+            LinkSpeed::Hdr => "HDR",
+            LinkSpeed::Ndr => "NDR",
+            LinkSpeed::Xdr => "XDR",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::str::FromStr for LinkSpeed {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LinkSpeed::ALL
+            .into_iter()
+            .find(|speed| speed.to_string().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+/// Data rate of a link in Gb/s: lanes times the per-lane data rate.
+pub fn link_data_rate_gbps(width: LinkWidth, speed: LinkSpeed) -> f64 {
+    width.lanes() as f64 * speed.lane_data_gbps()
+}
+
+/// PortInfo MTU encoding (MTUCap, NeighborMTU).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IbMtu {
+    Mtu256 = 1,
+    Mtu512 = 2,
+    Mtu1024 = 3,
+    Mtu2048 = 4,
+    Mtu4096 = 5,
+}
+
+impl IbMtu {
+    pub fn bytes(&self) -> u16 {
+        256 << (*self as u8 - 1)
+    }
+}
+
+impl TryFrom<u8> for IbMtu {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(IbMtu::Mtu256),
+            2 => Ok(IbMtu::Mtu512),
+            3 => Ok(IbMtu::Mtu1024),
+            4 => Ok(IbMtu::Mtu2048),
+            5 => Ok(IbMtu::Mtu4096),
+            _ => Err(()),
+        }
+    }
 }
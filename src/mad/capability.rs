@@ -0,0 +1,147 @@
+use std::fmt;
+use std::ops::BitOr;
+
+macro_rules! capability_flags {
+    ($ty:ident, $int:ty, { $($flag:ident = $bit:expr, $name:expr;)* }) => {
+        impl $ty {
+            $(pub const $flag: $ty = $ty(1 << $bit);)*
+
+            const NAMES: &'static [(&'static str, $ty)] = &[$(($name, $ty::$flag)),*];
+
+            pub fn bits(&self) -> $int {
+                self.0
+            }
+
+            pub fn contains(&self, other: $ty) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            pub fn insert(&mut self, other: $ty) {
+                self.0 |= other.0;
+            }
+
+            pub fn remove(&mut self, other: $ty) {
+                self.0 &= !other.0;
+            }
+
+            /// Names of the known flags that are set, lowest bit first.
+            pub fn names(&self) -> Vec<&'static str> {
+                $ty::NAMES
+                    .iter()
+                    .filter(|(_, flag)| self.contains(*flag))
+                    .map(|(name, _)| *name)
+                    .collect()
+            }
+        }
+
+        impl BitOr for $ty {
+            type Output = $ty;
+
+            fn bitor(self, rhs: $ty) -> $ty {
+                $ty(self.0 | rhs.0)
+            }
+        }
+
+        impl From<$int> for $ty {
+            fn from(value: $int) -> Self {
+                $ty(value)
+            }
+        }
+
+        impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.names().join(", "))
+            }
+        }
+    };
+}
+
+/// PortInfo CapabilityMask, also exposed by sysfs as `ports/<n>/cap_mask`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct CapabilityMask(pub u32);
+
+capability_flags!(CapabilityMask, u32, {
+    IS_SM = 1, "IsSM";
+    IS_NOTICE_SUPPORTED = 2, "IsNoticeSupported";
+    IS_TRAP_SUPPORTED = 3, "IsTrapSupported";
+    IS_OPTIONAL_IPD_SUPPORTED = 4, "IsOptionalIPDSupported";
+    IS_AUTOMATIC_MIGRATION_SUPPORTED = 5, "IsAutomaticMigrationSupported";
+    IS_SL_MAPPING_SUPPORTED = 6, "IsSLMappingSupported";
+    IS_MKEY_NVRAM = 7, "IsMKeyNVRAM";
+    IS_PKEY_NVRAM = 8, "IsPKeyNVRAM";
+    IS_LED_INFO_SUPPORTED = 9, "IsLEDInfoSupported";
+    IS_SM_DISABLED = 10, "IsSMdisabled";
+    IS_SYSTEM_IMAGE_GUID_SUPPORTED = 11, "IsSystemImageGUIDSupported";
+    IS_PKEY_SWITCH_EXTERNAL_PORT_TRAP_SUPPORTED = 12, "IsPKeySwitchExternalPortTrapSupported";
+    IS_CABLE_INFO_SUPPORTED = 13, "IsCableInfoSupported";
+    IS_EXTENDED_SPEEDS_SUPPORTED = 14, "IsExtendedSpeedsSupported";
+    IS_CAPABILITY_MASK2_SUPPORTED = 15, "IsCapabilityMask2Supported";
+    IS_COMMUNICATION_MANAGEMENT_SUPPORTED = 16, "IsCommunicationManagementSupported";
+    IS_SNMP_TUNNELING_SUPPORTED = 17, "IsSNMPTunnelingSupported";
+    IS_REINIT_SUPPORTED = 18, "IsReinitSupported";
+    IS_DEVICE_MANAGEMENT_SUPPORTED = 19, "IsDeviceManagementSupported";
+    IS_VENDOR_CLASS_SUPPORTED = 20, "IsVendorClassSupported";
+    IS_DR_NOTICE_SUPPORTED = 21, "IsDRNoticeSupported";
+    IS_CAPABILITY_MASK_NOTICE_SUPPORTED = 22, "IsCapabilityMaskNoticeSupported";
+    IS_BOOT_MANAGEMENT_SUPPORTED = 23, "IsBootManagementSupported";
+    IS_LINK_ROUND_TRIP_LATENCY_SUPPORTED = 24, "IsLinkRoundTripLatencySupported";
+    IS_CLIENT_REREGISTRATION_SUPPORTED = 25, "IsClientReregistrationSupported";
+    IS_OTHER_LOCAL_CHANGES_NOTICE_SUPPORTED = 26, "IsOtherLocalChangesNoticeSupported";
+    IS_LINK_SPEED_WIDTH_PAIRS_TABLE_SUPPORTED = 27, "IsLinkSpeedWidthPairsTableSupported";
+    IS_VENDOR_SPECIFIC_MADS_TABLE_SUPPORTED = 28, "IsVendorSpecificMadsTableSupported";
+    IS_MCAST_PKEY_TRAP_SUPPRESSION_SUPPORTED = 29, "IsMcastPkeyTrapSuppressionSupported";
+    IS_MULTICAST_FDB_TOP_SUPPORTED = 30, "IsMulticastFDBTopSupported";
+    IS_HIERARCHY_INFO_SUPPORTED = 31, "IsHierarchyInfoSupported";
+});
+
+impl CapabilityMask {
+    pub fn is_sm(&self) -> bool {
+        self.contains(CapabilityMask::IS_SM)
+    }
+
+    pub fn is_sm_disabled(&self) -> bool {
+        self.contains(CapabilityMask::IS_SM_DISABLED)
+    }
+
+    pub fn is_trap_supported(&self) -> bool {
+        self.contains(CapabilityMask::IS_TRAP_SUPPORTED)
+    }
+
+    pub fn is_extended_speeds_supported(&self) -> bool {
+        self.contains(CapabilityMask::IS_EXTENDED_SPEEDS_SUPPORTED)
+    }
+
+    pub fn is_capability_mask2_supported(&self) -> bool {
+        self.contains(CapabilityMask::IS_CAPABILITY_MASK2_SUPPORTED)
+    }
+}
+
+/// PortInfo CapabilityMask2. Only valid when `CapabilityMask` has
+/// IsCapabilityMask2Supported set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct CapabilityMask2(pub u16);
+
+capability_flags!(CapabilityMask2, u16, {
+    IS_SET_NODE_DESCRIPTION_SUPPORTED = 0, "IsSetNodeDescriptionSupported";
+    IS_PORT_INFO_EXTENDED_SUPPORTED = 1, "IsPortInfoExtendedSupported";
+    IS_VIRTUALIZATION_SUPPORTED = 2, "IsVirtualizationSupported";
+    IS_SWITCH_PORT_STATE_TABLE_SUPPORTED = 3, "IsSwitchPortStateTableSupported";
+    IS_LINK_WIDTH_2X_SUPPORTED = 4, "IsLinkWidth2xSupported";
+    IS_LINK_SPEED_HDR_SUPPORTED = 5, "IsLinkSpeedHDRSupported";
+    IS_MKEY_PROTECT_BITS_EXT_SUPPORTED = 6, "IsMKeyProtectBitsExtSupported";
+    IS_ENHANCED_TRAP128_SUPPORTED = 7, "IsEnhancedTrap128Supported";
+    IS_PARTITION_TOP_SUPPORTED = 8, "IsPartitionTopSupported";
+    IS_LINK_SPEED_NDR_SUPPORTED = 10, "IsLinkSpeedNDRSupported";
+    IS_EXTENDED_SPEEDS2_SUPPORTED = 11, "IsExtendedSpeeds2Supported";
+    IS_LINK_SPEED_XDR_SUPPORTED = 12, "IsLinkSpeedXDRSupported";
+});
+
+impl CapabilityMask2 {
+    pub fn is_port_info_extended_supported(&self) -> bool {
+        self.contains(CapabilityMask2::IS_PORT_INFO_EXTENDED_SUPPORTED)
+    }
+
+    pub fn is_virtualization_supported(&self) -> bool {
+        self.contains(CapabilityMask2::IS_VIRTUALIZATION_SUPPORTED)
+    }
+}
@@ -1,5 +1,12 @@
 use std::mem::MaybeUninit;
 
+/// Status bit set on a DR SMP travelling back to the requester (the D bit).
+pub const DR_SMP_DIRECTION: u16 = 0x8000;
+/// Most hops a directed route can take.
+pub const DR_SMP_MAX_HOPS: u8 = 63;
+/// DrSLID/DrDLID of a path that is directed route at that end.
+pub const PERMISSIVE_LID: u16 = 0xffff;
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 #[allow(non_camel_case_types)]
@@ -0,0 +1,63 @@
+use std::mem::MaybeUninit;
+
+use crate::mad::helpers::{get_bitfield, set_bitfield};
+
+/// Number of GUIDs carried in a single GUIDInfo block.
+pub const GUID_BLOCK_SIZE: usize = 8;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct guid_info {
+    pub data: [u8; 64],
+}
+
+impl guid_info {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const guid_info as *const u8,
+                std::mem::size_of::<guid_info>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<guid_info>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<guid_info>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<guid_info>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    /// Builds a block from up to `GUID_BLOCK_SIZE` GUIDs.
+    pub fn from_entries(entries: &[u64]) -> Self {
+        let mut block = guid_info { data: [0; 64] };
+        for (i, guid) in entries.iter().take(GUID_BLOCK_SIZE).enumerate() {
+            block.set_entry(i, *guid);
+        }
+        block
+    }
+
+    pub fn entry(&self, index: usize) -> u64 {
+        assert!(index < GUID_BLOCK_SIZE, "GUID index out of range");
+        get_bitfield(&self.data, index * 64, 64)
+    }
+
+    pub fn set_entry(&mut self, index: usize, guid: u64) {
+        assert!(index < GUID_BLOCK_SIZE, "GUID index out of range");
+        set_bitfield(&mut self.data, index * 64, 64, guid);
+    }
+
+    pub fn entries(&self) -> Vec<u64> {
+        (0..GUID_BLOCK_SIZE).map(|i| self.entry(i)).collect()
+    }
+}
@@ -5,26 +5,48 @@ use std::path::Path;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::io;
 
+use crate::enums::PerfAttrID;
 use crate::{ca::IbCa, ib_user_mad_reg_req2};
 use crate::{dump_bytes, ib_user_mad_register_agent2};
 
+pub mod capability;
 pub mod dr_smp;
+pub mod guid;
 pub mod helpers;
 pub mod node;
 pub mod perf;
+pub mod pkey;
 pub mod port;
+pub mod qos;
+pub mod sa;
+pub mod sm;
+pub mod smp;
+pub mod switch;
+pub mod trap;
 pub mod types;
 
+pub use capability::{CapabilityMask, CapabilityMask2};
 pub use dr_smp::dr_smp_mad;
+pub use guid::guid_info;
 pub use node::node_info;
-pub use perf::perf_mad;
-pub use port::port_info;
+pub use perf::{perf_mad, port_counters};
+pub use pkey::{PKey, pkey_table};
+pub use port::{LinkTraining, port_info};
+pub use qos::{VlArbEntry, VlArbitration, sl2vl_table, vl_arb_table};
+pub use sa::{
+    link_record, node_record, path_record, port_info_record, query_sa_table, sa_mad,
+};
+pub use sm::sm_info;
+pub use smp::smp_mad;
+pub use switch::{lft_block, switch_info};
+pub use trap::notice;
 pub use types::{ib_mad, ib_mad_addr, ib_user_mad};
 
 use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
 
 pub const IB_MGMT_CLASS_PERFORMANCE: u8 = 0x4;
 pub const IB_MGMT_CLASS_LID_ROUTED_SMP: u8 = 0x1;
+pub const IB_MGMT_CLASS_SUBN_ADM: u8 = 0x3;
 pub const IB_MGMT_CLASS_DIRECT_ROUTED_SMP: u8 = 0x81;
 pub const IB_DEFAULT_QKEY: u32 = 0x80010000;
 
@@ -143,25 +165,185 @@ pub fn query_port_counters_extended(
     perf_payload.set_counter_select(0);
     perf_payload.set_counter_select2(0);
 
+    let data = perf_request(
+        port,
+        agent_id,
+        timeout_ms,
+        retries,
+        lid,
+        pkey_index,
+        0x01,
+        PerfAttrID::PortCountersExtended,
+        &perf_payload.to_bytes(),
+        port_select,
+    )?;
+
+    perf_mad::from_bytes(&data).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to parse PortCountersExtended payload",
+        )
+    })
+}
+
+/// Resets the PortCountersExtended counters picked by `counter_select` (data
+/// counters) and `counter_select2` (error counters) and returns what is left.
+/// `port_select` 0xFF resets every port of the node.
+#[allow(clippy::too_many_arguments)]
+pub fn clear_port_counters_extended(
+    port: &mut IbMadPort,
+    agent_id: u32,
+    timeout_ms: u32,
+    retries: u32,
+    lid: u16,
+    port_select: u8,
+    counter_select: u16,
+    counter_select2: u32,
+) -> Result<perf_mad, io::Error> {
+    let mut perf_payload = perf_mad {
+        pm_key: 0,
+        reserved: [0; 32],
+        data: [0; 192],
+    };
+    perf_payload.set_port_select(port_select);
+    perf_payload.set_counter_select(counter_select);
+    perf_payload.set_counter_select2(counter_select2);
+
+    let data = perf_request(
+        port,
+        agent_id,
+        timeout_ms,
+        retries,
+        lid,
+        0,
+        0x02,
+        PerfAttrID::PortCountersExtended,
+        &perf_payload.to_bytes(),
+        port_select,
+    )?;
+
+    perf_mad::from_bytes(&data).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to parse PortCountersExtended payload",
+        )
+    })
+}
+
+/// Reads the 32-bit PortCounters of `port_select`; 0xFF sums every port of the node.
+pub fn query_port_counters(
+    port: &mut IbMadPort,
+    agent_id: u32,
+    timeout_ms: u32,
+    retries: u32,
+    lid: u16,
+    port_select: u8,
+    pkey_index: u16,
+) -> Result<port_counters, io::Error> {
+    let mut perf_payload = port_counters {
+        pm_key: 0,
+        reserved: [0; 32],
+        data: [0; 192],
+    };
+    perf_payload.set_port_select(port_select);
+
+    let data = perf_request(
+        port,
+        agent_id,
+        timeout_ms,
+        retries,
+        lid,
+        pkey_index,
+        0x01,
+        PerfAttrID::PortCounters,
+        &perf_payload.to_bytes(),
+        port_select,
+    )?;
+
+    port_counters::from_bytes(&data).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to parse PortCounters payload",
+        )
+    })
+}
+
+/// Resets the PortCounters picked by `counter_select`, and PortXmitWait when bit 0 of
+/// `counter_select2` is set. Returns the counters after the reset.
+#[allow(clippy::too_many_arguments)]
+pub fn clear_port_counters(
+    port: &mut IbMadPort,
+    agent_id: u32,
+    timeout_ms: u32,
+    retries: u32,
+    lid: u16,
+    port_select: u8,
+    counter_select: u16,
+    counter_select2: u8,
+) -> Result<port_counters, io::Error> {
+    let mut perf_payload = port_counters {
+        pm_key: 0,
+        reserved: [0; 32],
+        data: [0; 192],
+    };
+    perf_payload.set_port_select(port_select);
+    perf_payload.set_counter_select(counter_select);
+    perf_payload.set_counter_select2(counter_select2);
+
+    let data = perf_request(
+        port,
+        agent_id,
+        timeout_ms,
+        retries,
+        lid,
+        0,
+        0x02,
+        PerfAttrID::PortCounters,
+        &perf_payload.to_bytes(),
+        port_select,
+    )?;
+
+    port_counters::from_bytes(&data).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Failed to parse PortCounters payload",
+        )
+    })
+}
+
+/// Sends one PerfMgt request to `lid` and returns the attribute data of the response.
+#[allow(clippy::too_many_arguments)]
+fn perf_request(
+    port: &mut IbMadPort,
+    agent_id: u32,
+    timeout_ms: u32,
+    retries: u32,
+    lid: u16,
+    pkey_index: u16,
+    method: u8,
+    attr_id: PerfAttrID,
+    payload: &[u8],
+    port_select: u8,
+) -> Result<[u8; 232], io::Error> {
     let tid = next_tid();
+    let attr_id = attr_id as u16;
 
     let mut ib_mad_payload = ib_mad {
         base_version: 0x1,
         mgmt_class: IB_MGMT_CLASS_PERFORMANCE,
         class_version: 0x1,
-        method: 0x01,
+        method,
         status: 0,
         hop_ptr: 0,
         hop_cnt: 0,
         tid: tid.to_be(),
-        attr_id: 0x001d_u16.to_be(),
+        attr_id: attr_id.to_be(),
         additional_status: 0,
         attr_mod: 0,
         data: [0; 232],
     };
 
-    let perf_bytes = perf_payload.to_bytes();
-    ib_mad_payload.data[..perf_bytes.len()].copy_from_slice(&perf_bytes);
+    ib_mad_payload.data[..payload.len()].copy_from_slice(payload);
 
     let request = ib_user_mad {
         agent_id,
@@ -246,22 +428,18 @@ pub fn query_port_counters_extended(
         ));
     }
 
-    if recv_mad.attr_id != 0x001d_u16.to_be() {
+    if recv_mad.attr_id != attr_id.to_be() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             format!(
-                "Unexpected attribute ID: expected 0x001d, got 0x{:04x}",
+                "Unexpected attribute ID: expected 0x{:04x}, got 0x{:04x}",
+                attr_id,
                 u16::from_be(recv_mad.attr_id)
             ),
         ));
     }
 
-    perf_mad::from_bytes(&recv_mad.data).ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Failed to parse PortCountersExtended payload",
-        )
-    })
+    Ok(recv_mad.data)
 }
 pub fn register_agent(port: &mut IbMadPort, mgmt_class: u8) -> Result<u32, io::Error> {
     let mut req = ib_user_mad_reg_req2 {
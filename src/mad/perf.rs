@@ -151,3 +151,105 @@ impl perf_mad {
     bitfield!(port_xmit_wait, set_port_xmit_wait, 1344, 64, u64);
     bitfield!(qp1_dropped, set_qp1_dropped, 1408, 64, u64);
 }
+
+/// PortCounters (0x0012): the original 32-bit counters. Every counter sticks at the
+/// largest value its field holds instead of wrapping.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_camel_case_types)]
+pub struct port_counters {
+    pub pm_key: u64,
+    pub reserved: [u8; 32],
+    pub data: [u8; 192],
+}
+
+#[allow(non_camel_case_types)]
+impl port_counters {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const port_counters as *const u8,
+                std::mem::size_of::<port_counters>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<port_counters>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<port_counters>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<port_counters>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    bitfield!(port_select, set_port_select, 8, 8, u8);
+    bitfield!(counter_select, set_counter_select, 16, 16, u16);
+    bitfield!(symbol_error_counter, set_symbol_error_counter, 32, 16, u16);
+    bitfield!(
+        link_error_recovery_counter,
+        set_link_error_recovery_counter,
+        48,
+        8,
+        u8
+    );
+    bitfield!(link_downed_counter, set_link_downed_counter, 56, 8, u8);
+    bitfield!(port_rcv_errors, set_port_rcv_errors, 64, 16, u16);
+    bitfield!(
+        port_rcv_remote_physical_errors,
+        set_port_rcv_remote_physical_errors,
+        80,
+        16,
+        u16
+    );
+    bitfield!(
+        port_rcv_switch_relay_errors,
+        set_port_rcv_switch_relay_errors,
+        96,
+        16,
+        u16
+    );
+    bitfield!(port_xmit_discards, set_port_xmit_discards, 112, 16, u16);
+    bitfield!(
+        port_xmit_constraint_errors,
+        set_port_xmit_constraint_errors,
+        128,
+        8,
+        u8
+    );
+    bitfield!(
+        port_rcv_constraint_errors,
+        set_port_rcv_constraint_errors,
+        136,
+        8,
+        u8
+    );
+    bitfield!(counter_select2, set_counter_select2, 144, 8, u8);
+    bitfield!(
+        local_link_integrity_errors,
+        set_local_link_integrity_errors,
+        152,
+        4,
+        u8
+    );
+    bitfield!(
+        excessive_buffer_overrun_errors,
+        set_excessive_buffer_overrun_errors,
+        156,
+        4,
+        u8
+    );
+    bitfield!(vl15_dropped, set_vl15_dropped, 176, 16, u16);
+    bitfield!(port_xmit_data, set_port_xmit_data, 192, 32, u32);
+    bitfield!(port_rcv_data, set_port_rcv_data, 224, 32, u32);
+    bitfield!(port_xmit_pkts, set_port_xmit_pkts, 256, 32, u32);
+    bitfield!(port_rcv_pkts, set_port_rcv_pkts, 288, 32, u32);
+    bitfield!(port_xmit_wait, set_port_xmit_wait, 320, 32, u32);
+}
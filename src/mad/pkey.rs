@@ -0,0 +1,93 @@
+use std::mem::MaybeUninit;
+
+use crate::enums::PKeyMembership;
+use crate::mad::helpers::get_bitfield;
+
+/// Number of P_Key entries carried in a single P_KeyTable block.
+pub const PKEY_BLOCK_SIZE: usize = 32;
+
+pub const PKEY_DEFAULT: u16 = 0xffff;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct pkey_table {
+    pub data: [u8; 64],
+}
+
+impl pkey_table {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const pkey_table as *const u8,
+                std::mem::size_of::<pkey_table>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<pkey_table>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<pkey_table>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<pkey_table>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    /// Builds a block from up to `PKEY_BLOCK_SIZE` raw P_Key values.
+    pub fn from_entries(entries: &[u16]) -> Self {
+        let mut table = pkey_table { data: [0; 64] };
+        for (i, pkey) in entries.iter().take(PKEY_BLOCK_SIZE).enumerate() {
+            table.data[i * 2..i * 2 + 2].copy_from_slice(&pkey.to_be_bytes());
+        }
+        table
+    }
+
+    pub fn entry(&self, index: usize) -> PKey {
+        assert!(index < PKEY_BLOCK_SIZE, "P_Key index out of range");
+        PKey(get_bitfield(&self.data, index * 16, 16) as u16)
+    }
+
+    pub fn entries(&self) -> Vec<PKey> {
+        (0..PKEY_BLOCK_SIZE).map(|i| self.entry(i)).collect()
+    }
+}
+
+/// A single P_KeyTable entry. Bit 15 is the membership type, bits 0-14 the base key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PKey(pub u16);
+
+impl PKey {
+    pub fn key(&self) -> u16 {
+        self.0 & 0x7fff
+    }
+
+    pub fn membership(&self) -> PKeyMembership {
+        if self.0 & 0x8000 != 0 {
+            PKeyMembership::Full
+        } else {
+            PKeyMembership::Limited
+        }
+    }
+
+    pub fn is_full_member(&self) -> bool {
+        self.membership() == PKeyMembership::Full
+    }
+
+    /// An entry with a base key of zero is an unused slot.
+    pub fn is_valid(&self) -> bool {
+        self.key() != 0
+    }
+
+    /// Compares base keys only, so 0x8001 and 0x0001 are the same partition.
+    pub fn same_partition(&self, other: u16) -> bool {
+        self.key() == other & 0x7fff
+    }
+}
@@ -1,3 +1,5 @@
+use crate::enums::{LinkSpeed, LinkWidth, link_data_rate_gbps};
+use crate::mad::capability::{CapabilityMask, CapabilityMask2};
 use crate::mad::helpers::{get_bitfield, set_bitfield};
 use std::mem::MaybeUninit;
 
@@ -144,6 +146,20 @@ impl port_info {
     bitfield!(local_phy_errors, set_local_phy_errors, 424, 4, u8);
     bitfield!(overrun_errors, set_overrun_errors, 428, 4, u8);
     bitfield!(max_credit_hint, set_max_credit_hint, 432, 16, u16);
+    bitfield!(
+        link_speed_ext2_active,
+        set_link_speed_ext2_active,
+        448,
+        4,
+        u8
+    );
+    bitfield!(
+        link_speed_ext2_supported,
+        set_link_speed_ext2_supported,
+        452,
+        4,
+        u8
+    );
     bitfield!(
         link_round_trip_latency,
         set_link_round_trip_latency,
@@ -160,11 +176,212 @@ impl port_info {
         4,
         u8
     );
+    bitfield!(
+        link_speed_ext2_enabled,
+        set_link_speed_ext2_enabled,
+        504,
+        3,
+        u8
+    );
     bitfield!(
         link_speed_ext_enabled,
         set_link_speed_ext_enabled,
-        504,
+        507,
         5,
         u8
     );
+
+    pub fn capabilities(&self) -> CapabilityMask {
+        CapabilityMask(self.capability_mask())
+    }
+
+    /// CapabilityMask2, or empty if the port does not set IsCapabilityMask2Supported.
+    pub fn capabilities2(&self) -> CapabilityMask2 {
+        if self.capabilities().is_capability_mask2_supported() {
+            CapabilityMask2(self.capability_mask2())
+        } else {
+            CapabilityMask2::default()
+        }
+    }
+
+    // The LinkSpeedExt fields are reserved unless IsExtendedSpeedsSupported is set.
+    fn link_speed_ext(&self, value: u8) -> u8 {
+        if self.capabilities().is_extended_speeds_supported() {
+            value
+        } else {
+            0
+        }
+    }
+
+    // The LinkSpeedExt2 fields are reserved unless IsExtendedSpeeds2Supported is set.
+    fn link_speed_ext2(&self, value: u8) -> u8 {
+        if self
+            .capabilities2()
+            .contains(CapabilityMask2::IS_EXTENDED_SPEEDS2_SUPPORTED)
+        {
+            value
+        } else {
+            0
+        }
+    }
+
+    pub fn active_link_width(&self) -> Option<LinkWidth> {
+        LinkWidth::try_from(self.link_width_active()).ok()
+    }
+
+    pub fn enabled_link_widths(&self) -> Vec<LinkWidth> {
+        LinkWidth::from_mask(self.link_width_enabled())
+    }
+
+    pub fn supported_link_widths(&self) -> Vec<LinkWidth> {
+        LinkWidth::from_mask(self.link_width_supported())
+    }
+
+    pub fn active_link_speed(&self) -> Option<LinkSpeed> {
+        LinkSpeed::from_active(
+            self.link_speed_active(),
+            self.link_speed_ext(self.link_speed_ext_active()),
+            self.link_speed_ext2(self.link_speed_ext2_active()),
+        )
+    }
+
+    pub fn enabled_link_speeds(&self) -> Vec<LinkSpeed> {
+        LinkSpeed::from_masks(
+            self.link_speed_enabled(),
+            self.link_speed_ext(self.link_speed_ext_enabled()),
+            self.link_speed_ext2(self.link_speed_ext2_enabled()),
+        )
+    }
+
+    pub fn supported_link_speeds(&self) -> Vec<LinkSpeed> {
+        LinkSpeed::from_masks(
+            self.link_speed_supported(),
+            self.link_speed_ext(self.link_speed_ext_supported()),
+            self.link_speed_ext2(self.link_speed_ext2_supported()),
+        )
+    }
+
+    /// Sets the three active speed fields; see `set_supported_link_speeds`.
+    pub fn set_active_link_speed(&mut self, speed: Option<LinkSpeed>) {
+        let (legacy, ext, ext2) = LinkSpeed::to_masks(speed.as_slice());
+        self.set_link_speed_active(legacy);
+        self.set_link_speed_ext_active(ext);
+        self.set_link_speed_ext2_active(ext2);
+        self.enable_extended_speeds(ext, ext2);
+    }
+
+    pub fn set_enabled_link_speeds(&mut self, speeds: &[LinkSpeed]) {
+        let (legacy, ext, ext2) = LinkSpeed::to_masks(speeds);
+        self.set_link_speed_enabled(legacy);
+        self.set_link_speed_ext_enabled(ext);
+        self.set_link_speed_ext2_enabled(ext2);
+        self.enable_extended_speeds(ext, ext2);
+    }
+
+    /// Sets the LinkSpeed, LinkSpeedExt and LinkSpeedExt2 masks. FDR to NDR also set
+    /// IsExtendedSpeedsSupported, and XDR IsExtendedSpeeds2Supported in
+    /// CapabilityMask2; without them the extended fields are ignored.
+    pub fn set_supported_link_speeds(&mut self, speeds: &[LinkSpeed]) {
+        let (legacy, ext, ext2) = LinkSpeed::to_masks(speeds);
+        self.set_link_speed_supported(legacy);
+        self.set_link_speed_ext_supported(ext);
+        self.set_link_speed_ext2_supported(ext2);
+        self.enable_extended_speeds(ext, ext2);
+    }
+
+    fn enable_extended_speeds(&mut self, ext: u8, ext2: u8) {
+        let mut caps = self.capabilities();
+        if ext != 0 {
+            caps.insert(CapabilityMask::IS_EXTENDED_SPEEDS_SUPPORTED);
+        }
+        if ext2 != 0 {
+            caps.insert(CapabilityMask::IS_CAPABILITY_MASK2_SUPPORTED);
+            let mut caps2 = CapabilityMask2(self.capability_mask2());
+            caps2.insert(CapabilityMask2::IS_EXTENDED_SPEEDS2_SUPPORTED);
+            self.set_capability_mask2(caps2.bits());
+        }
+        self.set_capability_mask(caps.bits());
+    }
+
+    /// Data rate of the active link in Gb/s, `None` while the link is not up.
+    pub fn data_rate_gbps(&self) -> Option<f64> {
+        Some(link_data_rate_gbps(
+            self.active_link_width()?,
+            self.active_link_speed()?,
+        ))
+    }
+
+    pub fn link_training(&self) -> LinkTraining {
+        LinkTraining::new(
+            self.active_link_width(),
+            &self.enabled_link_widths(),
+            &self.supported_link_widths(),
+            self.active_link_speed(),
+            &self.enabled_link_speeds(),
+            &self.supported_link_speeds(),
+        )
+    }
+}
+
+/// A port's active width and speed next to the best it is both enabled and capable
+/// of. Only this end of the link is considered; the peer may be the limiting side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkTraining {
+    pub active_width: Option<LinkWidth>,
+    pub active_speed: Option<LinkSpeed>,
+    pub max_width: Option<LinkWidth>,
+    pub max_speed: Option<LinkSpeed>,
+}
+
+impl LinkTraining {
+    pub fn new(
+        active_width: Option<LinkWidth>,
+        enabled_widths: &[LinkWidth],
+        supported_widths: &[LinkWidth],
+        active_speed: Option<LinkSpeed>,
+        enabled_speeds: &[LinkSpeed],
+        supported_speeds: &[LinkSpeed],
+    ) -> Self {
+        LinkTraining {
+            active_width,
+            active_speed,
+            max_width: supported_widths
+                .iter()
+                .filter(|w| enabled_widths.contains(w))
+                .max_by_key(|w| w.lanes())
+                .copied(),
+            max_speed: supported_speeds
+                .iter()
+                .filter(|s| enabled_speeds.contains(s))
+                .max()
+                .copied(),
+        }
+    }
+
+    pub fn is_width_degraded(&self) -> bool {
+        match (self.active_width, self.max_width) {
+            (Some(active), Some(max)) => active.lanes() < max.lanes(),
+            _ => false,
+        }
+    }
+
+    pub fn is_speed_degraded(&self) -> bool {
+        match (self.active_speed, self.max_speed) {
+            (Some(active), Some(max)) => active < max,
+            _ => false,
+        }
+    }
+
+    /// True if the link trained below what this port is enabled and capable of.
+    pub fn is_degraded(&self) -> bool {
+        self.is_width_degraded() || self.is_speed_degraded()
+    }
+
+    pub fn active_data_rate_gbps(&self) -> Option<f64> {
+        Some(link_data_rate_gbps(self.active_width?, self.active_speed?))
+    }
+
+    pub fn max_data_rate_gbps(&self) -> Option<f64> {
+        Some(link_data_rate_gbps(self.max_width?, self.max_speed?))
+    }
 }
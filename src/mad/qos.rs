@@ -0,0 +1,177 @@
+use std::mem::MaybeUninit;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mad::helpers::{get_bitfield, set_bitfield};
+
+/// Number of entries carried in a single VLArbitrationTable block.
+pub const VL_ARB_BLOCK_SIZE: usize = 32;
+
+/// VLArbitrationTable attribute modifier block numbers.
+pub const VL_ARB_LOW_0_31: u16 = 1;
+pub const VL_ARB_HIGH_0_31: u16 = 2;
+pub const VL_ARB_LOW_32_63: u16 = 3;
+pub const VL_ARB_HIGH_32_63: u16 = 4;
+
+/// VL15 is the management VL; mapping a data SL to it means "drop".
+pub const VL_DROP: u8 = 15;
+
+/// Number of data VLs for a PortInfo VLCap/OperationalVLs encoding.
+pub fn data_vls(encoding: u8) -> u8 {
+    match encoding {
+        1 => 1,
+        2 => 2,
+        3 => 4,
+        4 => 8,
+        5 => 15,
+        _ => 0,
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+pub struct sl2vl_table {
+    pub data: [u8; 8],
+}
+
+impl sl2vl_table {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<sl2vl_table>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<sl2vl_table>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<sl2vl_table>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    /// Maps each SL to `sl % vls`, the default an SM programs for `vls` data VLs.
+    pub fn uniform(vls: u8) -> Self {
+        let mut table = sl2vl_table { data: [0; 8] };
+        for sl in 0..16 {
+            table.set_vl(sl, sl % vls.max(1));
+        }
+        table
+    }
+
+    pub fn vl(&self, sl: u8) -> u8 {
+        assert!(sl < 16, "SL must be 0-15");
+        get_bitfield(&self.data, sl as usize * 4, 4) as u8
+    }
+
+    pub fn set_vl(&mut self, sl: u8, vl: u8) {
+        assert!(sl < 16, "SL must be 0-15");
+        set_bitfield(&mut self.data, sl as usize * 4, 4, vl as u64);
+    }
+
+    /// VL for SL0..SL15.
+    pub fn mapping(&self) -> [u8; 16] {
+        let mut out = [0; 16];
+        for (sl, vl) in out.iter_mut().enumerate() {
+            *vl = self.vl(sl as u8);
+        }
+        out
+    }
+
+    /// SLs mapped to a VL the port does not operate. VL15 is allowed, it drops the packet.
+    pub fn invalid_sls(&self, operational_vls: u8) -> Vec<u8> {
+        let vls = data_vls(operational_vls);
+        (0..16)
+            .filter(|&sl| {
+                let vl = self.vl(sl);
+                vl != VL_DROP && vl >= vls
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VlArbEntry {
+    pub vl: u8,
+    pub weight: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct vl_arb_table {
+    pub data: [u8; 64],
+}
+
+impl vl_arb_table {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const vl_arb_table as *const u8,
+                std::mem::size_of::<vl_arb_table>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<vl_arb_table>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<vl_arb_table>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<vl_arb_table>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    pub fn from_entries(entries: &[VlArbEntry]) -> Self {
+        let mut table = vl_arb_table { data: [0; 64] };
+        for (i, e) in entries.iter().take(VL_ARB_BLOCK_SIZE).enumerate() {
+            table.set_entry(i, *e);
+        }
+        table
+    }
+
+    // Each entry is 4 reserved bits, a 4 bit VL and an 8 bit weight.
+    pub fn entry(&self, index: usize) -> VlArbEntry {
+        assert!(
+            index < VL_ARB_BLOCK_SIZE,
+            "VLArbitration index out of range"
+        );
+        VlArbEntry {
+            vl: get_bitfield(&self.data, index * 16 + 4, 4) as u8,
+            weight: get_bitfield(&self.data, index * 16 + 8, 8) as u8,
+        }
+    }
+
+    pub fn set_entry(&mut self, index: usize, entry: VlArbEntry) {
+        assert!(
+            index < VL_ARB_BLOCK_SIZE,
+            "VLArbitration index out of range"
+        );
+        set_bitfield(&mut self.data, index * 16 + 4, 4, entry.vl as u64);
+        set_bitfield(&mut self.data, index * 16 + 8, 8, entry.weight as u64);
+    }
+
+    pub fn entries(&self) -> Vec<VlArbEntry> {
+        (0..VL_ARB_BLOCK_SIZE).map(|i| self.entry(i)).collect()
+    }
+}
+
+/// A port's full VL arbitration configuration, trimmed to its advertised capacities.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VlArbitration {
+    pub vl_high_limit: u8,
+    pub high: Vec<VlArbEntry>,
+    pub low: Vec<VlArbEntry>,
+}
@@ -0,0 +1,477 @@
+use std::io;
+use std::mem::MaybeUninit;
+
+use crate::enums::SaAttrID;
+use crate::mad::helpers::{get_bitfield, set_bitfield};
+use crate::mad::{
+    IB_DEFAULT_QKEY, IB_MGMT_CLASS_SUBN_ADM, IbMadPort, ib_mad, ib_mad_addr, ib_user_mad, next_tid,
+    node_info, port_info, recv, send,
+};
+
+/// Bytes of SMKey, AttributeOffset and ComponentMask, repeated in every segment.
+pub const SA_HEADER_SIZE: usize = 20;
+/// Bytes of record data carried by one SA MAD.
+pub const SA_DATA_SIZE: usize = 200;
+pub const SA_CLASS_VERSION: u8 = 2;
+
+pub const SA_METHOD_GET: u8 = 0x01;
+pub const SA_METHOD_GET_RESP: u8 = 0x81;
+pub const SA_METHOD_GET_TABLE: u8 = 0x12;
+pub const SA_METHOD_GET_TABLE_RESP: u8 = 0x92;
+
+/// SA class status, in bits 8-14 of the MAD status.
+pub const SA_STATUS_REQ_INVALID: u16 = 0x0200;
+pub const SA_STATUS_NO_RECORDS: u16 = 0x0300;
+pub const SA_STATUS_TOO_MANY_RECORDS: u16 = 0x0400;
+
+pub const RMPP_VERSION: u8 = 1;
+pub const RMPP_TYPE_DATA: u8 = 1;
+pub const RMPP_TYPE_ACK: u8 = 2;
+pub const RMPP_TYPE_STOP: u8 = 3;
+pub const RMPP_TYPE_ABORT: u8 = 4;
+pub const RMPP_FLAG_ACTIVE: u8 = 0x01;
+pub const RMPP_FLAG_FIRST: u8 = 0x02;
+pub const RMPP_FLAG_LAST: u8 = 0x04;
+
+pub const NODE_RECORD_SIZE: usize = 108;
+pub const PORT_INFO_RECORD_SIZE: usize = 68;
+pub const LINK_RECORD_SIZE: usize = 8;
+pub const PATH_RECORD_SIZE: usize = 64;
+
+// ComponentMask bits of the fields the simulator's SA can match on.
+pub const NR_COMP_LID: u64 = 1 << 0;
+pub const NR_COMP_NODE_TYPE: u64 = 1 << 4;
+pub const NR_COMP_SYSTEM_GUID: u64 = 1 << 6;
+pub const NR_COMP_NODE_GUID: u64 = 1 << 7;
+pub const NR_COMP_PORT_GUID: u64 = 1 << 8;
+pub const NR_COMP_NODE_DESC: u64 = 1 << 14;
+pub const PIR_COMP_LID: u64 = 1 << 0;
+pub const PIR_COMP_PORT_NUM: u64 = 1 << 1;
+pub const LR_COMP_FROM_LID: u64 = 1 << 0;
+pub const LR_COMP_FROM_PORT: u64 = 1 << 1;
+pub const LR_COMP_TO_PORT: u64 = 1 << 2;
+pub const LR_COMP_TO_LID: u64 = 1 << 3;
+pub const PR_COMP_DGID: u64 = 1 << 2;
+pub const PR_COMP_SGID: u64 = 1 << 3;
+pub const PR_COMP_DLID: u64 = 1 << 4;
+pub const PR_COMP_SLID: u64 = 1 << 5;
+
+macro_rules! bitfield {
+    ($getter:ident, $setter:ident, $offset:expr, $width:expr, $type:ty) => {
+        pub fn $getter(&self) -> $type {
+            get_bitfield(&self.data, $offset, $width) as $type
+        }
+
+        pub fn $setter(&mut self, val: $type) {
+            set_bitfield(&mut self.data, $offset, $width, val as u64);
+        }
+    };
+}
+
+macro_rules! wire_bytes {
+    ($name:ident) => {
+        impl $name {
+            pub fn to_bytes(&self) -> Vec<u8> {
+                unsafe {
+                    std::slice::from_raw_parts(
+                        self as *const $name as *const u8,
+                        std::mem::size_of::<$name>(),
+                    )
+                    .to_vec()
+                }
+            }
+
+            pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+                if bytes.len() < std::mem::size_of::<$name>() {
+                    return None;
+                }
+                let mut val = MaybeUninit::<$name>::uninit();
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        bytes.as_ptr(),
+                        val.as_mut_ptr() as *mut u8,
+                        std::mem::size_of::<$name>(),
+                    );
+                    Some(val.assume_init())
+                }
+            }
+        }
+    };
+}
+
+/// Payload of an SA MAD: the RMPP header, the SA header and the record data.
+/// Multi-byte fields are big endian on the wire.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_camel_case_types)]
+pub struct sa_mad {
+    pub rmpp_version: u8,
+    pub rmpp_type: u8,
+    /// RRespTime in the upper five bits, RMPP flags in the lower three.
+    pub rmpp_rtime_flags: u8,
+    pub rmpp_status: u8,
+    /// SegmentNumber for DATA and ACK.
+    pub seg_num: u32,
+    /// PayloadLength for DATA, NewWindowLast for ACK.
+    pub paylen_newwin: u32,
+    pub sm_key: u64,
+    /// Distance between records, in 8-byte words.
+    pub attr_offset: u16,
+    pub reserved: u16,
+    pub comp_mask: u64,
+    pub data: [u8; SA_DATA_SIZE],
+}
+
+wire_bytes!(sa_mad);
+
+impl sa_mad {
+    pub fn new() -> Self {
+        sa_mad {
+            rmpp_version: 0,
+            rmpp_type: 0,
+            rmpp_rtime_flags: 0,
+            rmpp_status: 0,
+            seg_num: 0,
+            paylen_newwin: 0,
+            sm_key: 0,
+            attr_offset: 0,
+            reserved: 0,
+            comp_mask: 0,
+            data: [0; SA_DATA_SIZE],
+        }
+    }
+
+    pub fn rmpp_flags(&self) -> u8 {
+        self.rmpp_rtime_flags & 0x07
+    }
+}
+
+impl Default for sa_mad {
+    fn default() -> Self {
+        sa_mad::new()
+    }
+}
+
+/// NodeRecord: the NodeInfo and NodeDescription of a node, keyed by LID.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct node_record {
+    pub data: [u8; NODE_RECORD_SIZE],
+}
+
+wire_bytes!(node_record);
+
+impl node_record {
+    bitfield!(lid, set_lid, 0, 16, u16);
+
+    /// NodeInfo is 40 bytes in the record; the rest of `node_info` is zero.
+    pub fn node_info(&self) -> node_info {
+        let mut bytes = [0u8; 64];
+        bytes[..40].copy_from_slice(&self.data[4..44]);
+        node_info::from_bytes(&bytes).unwrap_or_default()
+    }
+
+    pub fn set_node_info(&mut self, ni: &node_info) {
+        self.data[4..44].copy_from_slice(&ni.to_bytes()[..40]);
+    }
+
+    pub fn node_description(&self) -> String {
+        let desc = &self.data[44..];
+        let end = desc.iter().position(|&b| b == 0).unwrap_or(desc.len());
+        String::from_utf8_lossy(&desc[..end]).to_string()
+    }
+
+    pub fn set_node_description(&mut self, desc: &str) {
+        let n = desc.len().min(64);
+        self.data[44..].fill(0);
+        self.data[44..44 + n].copy_from_slice(&desc.as_bytes()[..n]);
+    }
+}
+
+/// PortInfoRecord: a port's PortInfo, keyed by the LID of its end port and the port
+/// number.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct port_info_record {
+    pub data: [u8; PORT_INFO_RECORD_SIZE],
+}
+
+wire_bytes!(port_info_record);
+
+impl port_info_record {
+    bitfield!(endport_lid, set_endport_lid, 0, 16, u16);
+    bitfield!(port_num, set_port_num, 16, 8, u8);
+
+    pub fn port_info(&self) -> port_info {
+        let mut data = [0u8; 64];
+        data.copy_from_slice(&self.data[4..68]);
+        port_info { data }
+    }
+
+    pub fn set_port_info(&mut self, pi: &port_info) {
+        self.data[4..68].copy_from_slice(&pi.data);
+    }
+}
+
+/// LinkRecord: one direction of a link, between the end port LIDs of two nodes.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct link_record {
+    pub data: [u8; LINK_RECORD_SIZE],
+}
+
+wire_bytes!(link_record);
+
+impl link_record {
+    bitfield!(from_lid, set_from_lid, 0, 16, u16);
+    bitfield!(from_port, set_from_port, 16, 8, u8);
+    bitfield!(to_port, set_to_port, 24, 8, u8);
+    bitfield!(to_lid, set_to_lid, 32, 16, u16);
+}
+
+/// PathRecord: the properties of the route from SGID/SLID to DGID/DLID.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct path_record {
+    pub data: [u8; PATH_RECORD_SIZE],
+}
+
+wire_bytes!(path_record);
+
+impl path_record {
+    bitfield!(service_id, set_service_id, 0, 64, u64);
+    bitfield!(dlid, set_dlid, 320, 16, u16);
+    bitfield!(slid, set_slid, 336, 16, u16);
+    bitfield!(raw_traffic, set_raw_traffic, 352, 1, u8);
+    bitfield!(flow_label, set_flow_label, 356, 20, u32);
+    bitfield!(hop_limit, set_hop_limit, 376, 8, u8);
+    bitfield!(tclass, set_tclass, 384, 8, u8);
+    bitfield!(reversible, set_reversible, 392, 1, u8);
+    bitfield!(numb_path, set_numb_path, 393, 7, u8);
+    bitfield!(pkey, set_pkey, 400, 16, u16);
+    bitfield!(qos_class, set_qos_class, 416, 12, u16);
+    bitfield!(sl, set_sl, 428, 4, u8);
+    bitfield!(mtu_selector, set_mtu_selector, 432, 2, u8);
+    bitfield!(mtu, set_mtu, 434, 6, u8);
+    bitfield!(rate_selector, set_rate_selector, 440, 2, u8);
+    bitfield!(rate, set_rate, 442, 6, u8);
+    bitfield!(
+        packet_life_time_selector,
+        set_packet_life_time_selector,
+        448,
+        2,
+        u8
+    );
+    bitfield!(packet_life_time, set_packet_life_time, 450, 6, u8);
+    bitfield!(preference, set_preference, 456, 8, u8);
+
+    pub fn dgid(&self) -> [u8; 16] {
+        self.data[8..24].try_into().unwrap()
+    }
+
+    pub fn set_dgid(&mut self, gid: [u8; 16]) {
+        self.data[8..24].copy_from_slice(&gid);
+    }
+
+    pub fn sgid(&self) -> [u8; 16] {
+        self.data[24..40].try_into().unwrap()
+    }
+
+    pub fn set_sgid(&mut self, gid: [u8; 16]) {
+        self.data[24..40].copy_from_slice(&gid);
+    }
+}
+
+/// Sends a SubnAdmGetTable for `attr_id` to the SA at `sm_lid` and returns the
+/// matching records, one buffer each.
+///
+/// `template` is the record to match on and `comp_mask` selects which of its fields
+/// count. The response arrives as RMPP DATA segments, which are acknowledged one at a
+/// time and reassembled here; the agent must be registered without kernel RMPP.
+pub fn query_sa_table(
+    port: &mut IbMadPort,
+    agent_id: u32,
+    timeout_ms: u32,
+    sm_lid: u16,
+    attr_id: SaAttrID,
+    comp_mask: u64,
+    template: &[u8],
+) -> Result<Vec<Vec<u8>>, io::Error> {
+    if template.len() > SA_DATA_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SA record template is larger than a MAD",
+        ));
+    }
+
+    let tid = next_tid();
+    let attr_id = attr_id as u16;
+
+    let mut sa = sa_mad::new();
+    sa.comp_mask = comp_mask.to_be();
+    sa.data[..template.len()].copy_from_slice(template);
+
+    let mut request = sa_umad(
+        agent_id,
+        timeout_ms,
+        sm_lid,
+        SA_METHOD_GET_TABLE,
+        tid,
+        attr_id,
+    );
+    write_sa(&mut request, &sa);
+    send(port, &request)?;
+
+    let mut payload = Vec::new();
+    let mut paylen = None;
+    let mut next_seg = 1u32;
+    let mut stride = 0usize;
+
+    loop {
+        let mut response = sa_umad(agent_id, 0, 0, 0, 0, 0);
+        recv(port, &mut response, timeout_ms)?;
+        if !response.is_response() {
+            log::debug!("query_sa_table - discarding an unsolicited MAD");
+            continue;
+        }
+        if !response.is_tid_equal(&request) {
+            log::debug!("query_sa_table - discarding response with a different TID");
+            continue;
+        }
+
+        let recv_mad = ib_mad::from_bytes(&response.data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Failed to parse response MAD")
+        })?;
+        let status = u16::from_be(recv_mad.status);
+        if status != 0 {
+            return Err(io::Error::other(format!(
+                "SA returned MAD status {:#x} for AttrID 0x{:04X}",
+                status, attr_id
+            )));
+        }
+
+        let seg = sa_mad::from_bytes(&recv_mad.data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to parse SA MAD"))?;
+        if seg.rmpp_flags() & RMPP_FLAG_ACTIVE == 0 || seg.rmpp_type != RMPP_TYPE_DATA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "GetTableResp is not an RMPP DATA segment",
+            ));
+        }
+
+        let seg_num = u32::from_be(seg.seg_num);
+        if seg_num < next_seg {
+            log::debug!("query_sa_table - discarding duplicate segment {}", seg_num);
+            continue;
+        }
+        if seg_num > next_seg {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("RMPP segment {} arrived before {}", seg_num, next_seg),
+            ));
+        }
+
+        if seg.rmpp_flags() & RMPP_FLAG_FIRST != 0 {
+            paylen = Some(u32::from_be(seg.paylen_newwin) as usize);
+            stride = u16::from_be(seg.attr_offset) as usize * 8;
+        }
+        payload.extend_from_slice(&seg.data);
+
+        // Acknowledge the segment and open the window for the next one.
+        let mut ack = sa_mad::new();
+        ack.rmpp_version = RMPP_VERSION;
+        ack.rmpp_type = RMPP_TYPE_ACK;
+        ack.rmpp_rtime_flags = RMPP_FLAG_ACTIVE;
+        ack.seg_num = seg_num.to_be();
+        ack.paylen_newwin = (seg_num + 1).to_be();
+        let mut ack_umad = sa_umad(
+            agent_id,
+            timeout_ms,
+            sm_lid,
+            SA_METHOD_GET_TABLE_RESP,
+            tid,
+            attr_id,
+        );
+        write_sa(&mut ack_umad, &ack);
+        send(port, &ack_umad)?;
+
+        if seg.rmpp_flags() & RMPP_FLAG_LAST != 0 {
+            break;
+        }
+        next_seg += 1;
+    }
+
+    let paylen = paylen.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "RMPP transfer had no first segment",
+        )
+    })?;
+    // The first PayloadLength counts the SA header of every segment.
+    payload.truncate(paylen.saturating_sub(next_seg as usize * SA_HEADER_SIZE));
+    if stride == 0 {
+        return Ok(Vec::new());
+    }
+    Ok(payload.chunks_exact(stride).map(|r| r.to_vec()).collect())
+}
+
+fn sa_umad(
+    agent_id: u32,
+    timeout_ms: u32,
+    lid: u16,
+    method: u8,
+    tid: u64,
+    attr_id: u16,
+) -> ib_user_mad {
+    let mad = ib_mad {
+        base_version: 0x1,
+        mgmt_class: IB_MGMT_CLASS_SUBN_ADM,
+        class_version: SA_CLASS_VERSION,
+        method,
+        status: 0,
+        hop_ptr: 0,
+        hop_cnt: 0,
+        tid: tid.to_be(),
+        attr_id: attr_id.to_be(),
+        additional_status: 0,
+        attr_mod: 0,
+        data: [0; 232],
+    };
+
+    let mut umad = ib_user_mad {
+        agent_id,
+        status: 0,
+        timeout_ms,
+        retries: 0,
+        length: std::mem::size_of::<ib_mad>() as u32,
+        addr: ib_mad_addr {
+            qpn: 1u32.to_be(),
+            qkey: IB_DEFAULT_QKEY.to_be(),
+            lid: lid.to_be(),
+            sl: 0,
+            path_bits: 0,
+            grh_present: 0,
+            gid_index: 0,
+            hop_limit: 0,
+            traffic_class: 0,
+            gid: [0; 16],
+            flow_label: 0,
+            pkey_index: 0,
+            reserved: [0; 6],
+        },
+        data: [0; 256],
+    };
+    let mad_bytes = mad.to_bytes();
+    umad.data[..mad_bytes.len()].copy_from_slice(&mad_bytes);
+    umad
+}
+
+fn write_sa(umad: &mut ib_user_mad, sa: &sa_mad) {
+    // The SA payload follows the 24-byte common MAD header.
+    let sa_bytes = sa.to_bytes();
+    umad.data[24..24 + sa_bytes.len()].copy_from_slice(&sa_bytes);
+}
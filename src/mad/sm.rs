@@ -0,0 +1,55 @@
+use crate::mad::helpers::{get_bitfield, set_bitfield};
+use std::mem::MaybeUninit;
+
+macro_rules! bitfield {
+    ($getter:ident, $setter:ident, $offset:expr, $width:expr, $type:ty) => {
+        pub fn $getter(&self) -> $type {
+            get_bitfield(&self.data, $offset, $width) as $type
+        }
+
+        pub fn $setter(&mut self, val: $type) {
+            set_bitfield(&mut self.data, $offset, $width, val as u64);
+        }
+    };
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct sm_info {
+    pub data: [u8; 64],
+}
+
+impl sm_info {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const sm_info as *const u8,
+                std::mem::size_of::<sm_info>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<sm_info>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<sm_info>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<sm_info>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    // Bit Fields
+    bitfield!(guid, set_guid, 0, 64, u64);
+    bitfield!(sm_key, set_sm_key, 64, 64, u64);
+    bitfield!(act_count, set_act_count, 128, 32, u32);
+    bitfield!(priority, set_priority, 160, 4, u8);
+    bitfield!(sm_state, set_sm_state, 164, 4, u8);
+}
@@ -0,0 +1,40 @@
+use std::mem::MaybeUninit;
+
+/// Payload of a LID-routed SMP. The attribute sits at the same offset as in a
+/// `dr_smp_mad`, the directed route fields are reserved.
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+#[allow(non_camel_case_types)]
+pub struct smp_mad {
+    pub m_key: u64,
+    pub reserved: [u8; 32],
+    pub attr_layout: [u8; 64],
+    pub reserved2: [u8; 128],
+}
+
+impl smp_mad {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const smp_mad as *const u8,
+                std::mem::size_of::<smp_mad>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<smp_mad>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<smp_mad>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<smp_mad>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+}
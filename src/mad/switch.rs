@@ -0,0 +1,177 @@
+use crate::mad::helpers::{get_bitfield, set_bitfield};
+use std::mem::MaybeUninit;
+
+/// Number of LID entries carried in a single LinearForwardingTable block.
+pub const LFT_BLOCK_SIZE: usize = 64;
+
+/// LinearForwardingTable entry for a LID with no route.
+pub const LFT_NO_ROUTE: u8 = 0xff;
+
+macro_rules! bitfield {
+    ($getter:ident, $setter:ident, $offset:expr, $width:expr, $type:ty) => {
+        pub fn $getter(&self) -> $type {
+            get_bitfield(&self.data, $offset, $width) as $type
+        }
+
+        pub fn $setter(&mut self, val: $type) {
+            set_bitfield(&mut self.data, $offset, $width, val as u64);
+        }
+    };
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct switch_info {
+    pub data: [u8; 64],
+}
+
+impl switch_info {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const switch_info as *const u8,
+                std::mem::size_of::<switch_info>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<switch_info>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<switch_info>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<switch_info>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    // Bit Fields
+    bitfield!(linear_fdb_cap, set_linear_fdb_cap, 0, 16, u16);
+    bitfield!(random_fdb_cap, set_random_fdb_cap, 16, 16, u16);
+    bitfield!(multicast_fdb_cap, set_multicast_fdb_cap, 32, 16, u16);
+    bitfield!(linear_fdb_top, set_linear_fdb_top, 48, 16, u16);
+    bitfield!(default_port, set_default_port, 64, 8, u8);
+    bitfield!(
+        default_multicast_primary_port,
+        set_default_multicast_primary_port,
+        72,
+        8,
+        u8
+    );
+    bitfield!(
+        default_multicast_not_primary_port,
+        set_default_multicast_not_primary_port,
+        80,
+        8,
+        u8
+    );
+    bitfield!(life_time_value, set_life_time_value, 88, 5, u8);
+    bitfield!(port_state_change, set_port_state_change, 93, 1, u8);
+    bitfield!(
+        optimized_sl_to_vl_mapping_programming,
+        set_optimized_sl_to_vl_mapping_programming,
+        94,
+        2,
+        u8
+    );
+    bitfield!(lids_per_port, set_lids_per_port, 96, 16, u16);
+    bitfield!(
+        partition_enforcement_cap,
+        set_partition_enforcement_cap,
+        112,
+        16,
+        u16
+    );
+    bitfield!(
+        inbound_enforcement_cap,
+        set_inbound_enforcement_cap,
+        128,
+        1,
+        u8
+    );
+    bitfield!(
+        outbound_enforcement_cap,
+        set_outbound_enforcement_cap,
+        129,
+        1,
+        u8
+    );
+    bitfield!(
+        filter_raw_inbound_cap,
+        set_filter_raw_inbound_cap,
+        130,
+        1,
+        u8
+    );
+    bitfield!(
+        filter_raw_outbound_cap,
+        set_filter_raw_outbound_cap,
+        131,
+        1,
+        u8
+    );
+    bitfield!(enhanced_port0, set_enhanced_port0, 132, 1, u8);
+    bitfield!(multicast_fdb_top, set_multicast_fdb_top, 136, 16, u16);
+}
+
+/// One block of a switch's LinearForwardingTable: the egress port for 64 consecutive
+/// LIDs, starting at LID `block * LFT_BLOCK_SIZE`.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct lft_block {
+    pub data: [u8; 64],
+}
+
+impl lft_block {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const lft_block as *const u8,
+                std::mem::size_of::<lft_block>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<lft_block>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<lft_block>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<lft_block>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    /// Builds a block from up to `LFT_BLOCK_SIZE` ports; missing entries have no route.
+    pub fn from_entries(entries: &[u8]) -> Self {
+        let mut block = lft_block {
+            data: [LFT_NO_ROUTE; 64],
+        };
+        let n = entries.len().min(LFT_BLOCK_SIZE);
+        block.data[..n].copy_from_slice(&entries[..n]);
+        block
+    }
+
+    pub fn entry(&self, index: usize) -> u8 {
+        assert!(index < LFT_BLOCK_SIZE, "LFT index out of range");
+        self.data[index]
+    }
+
+    pub fn entries(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+}
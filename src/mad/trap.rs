@@ -0,0 +1,115 @@
+use std::mem::MaybeUninit;
+
+use crate::mad::helpers::{get_bitfield, set_bitfield};
+
+macro_rules! bitfield {
+    ($getter:ident, $setter:ident, $offset:expr, $width:expr, $type:ty) => {
+        pub fn $getter(&self) -> $type {
+            get_bitfield(&self.data, $offset, $width) as $type
+        }
+
+        pub fn $setter(&mut self, val: $type) {
+            set_bitfield(&mut self.data, $offset, $width, val as u64);
+        }
+    };
+}
+
+/// Method an SMA uses to send a Notice to the SM unasked.
+pub const IB_METHOD_TRAP: u8 = 0x05;
+/// Attribute ID of Notice.
+pub const NOTICE_ATTR_ID: u16 = 0x0002;
+
+/// Generic notice type of traps the SM must act on, such as trap 128.
+pub const NOTICE_TYPE_URGENT: u8 = 1;
+/// Generic notice type of traps that only inform the SM, such as trap 144.
+pub const NOTICE_TYPE_INFO: u8 = 4;
+
+/// Generic ProducerType of a channel adapter.
+pub const PRODUCER_TYPE_CA: u32 = 1;
+/// Generic ProducerType of a switch.
+pub const PRODUCER_TYPE_SWITCH: u32 = 2;
+
+/// The link state of at least one port of a switch changed.
+pub const TRAP_LINK_STATE_CHANGE: u16 = 128;
+/// A port's CapabilityMask, NodeDescription or other local attributes changed.
+pub const TRAP_LOCAL_CHANGES: u16 = 144;
+
+/// Trap 144 ChangeFlags bit: NodeDescription changed.
+pub const TRAP144_NODE_DESCRIPTION_CHANGE: u16 = 0x0001;
+
+/// Notice attribute of an SMP Trap. The IssuerGID that follows DataDetails in the SA
+/// form does not fit in an SMP and is left out.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct notice {
+    pub data: [u8; 64],
+}
+
+impl notice {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const notice as *const u8,
+                std::mem::size_of::<notice>(),
+            )
+            .to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < std::mem::size_of::<notice>() {
+            return None;
+        }
+        let mut val = MaybeUninit::<notice>::uninit();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                val.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<notice>(),
+            );
+            Some(val.assume_init())
+        }
+    }
+
+    /// A generic notice for `trap_number`, issued by the port at `issuer_lid`.
+    pub fn generic(notice_type: u8, producer_type: u32, trap_number: u16, issuer_lid: u16) -> Self {
+        let mut n = notice { data: [0; 64] };
+        n.set_is_generic(1);
+        n.set_notice_type(notice_type);
+        n.set_producer_type(producer_type);
+        n.set_trap_number(trap_number);
+        n.set_issuer_lid(issuer_lid);
+        n
+    }
+
+    // Bit Fields
+    bitfield!(is_generic, set_is_generic, 0, 1, u8);
+    bitfield!(notice_type, set_notice_type, 1, 7, u8);
+    bitfield!(producer_type, set_producer_type, 8, 24, u32);
+    bitfield!(trap_number, set_trap_number, 32, 16, u16);
+    bitfield!(issuer_lid, set_issuer_lid, 48, 16, u16);
+    bitfield!(notice_toggle, set_notice_toggle, 64, 1, u8);
+    bitfield!(notice_count, set_notice_count, 65, 15, u16);
+
+    // DataDetails of trap 128
+    bitfield!(trap128_lid, set_trap128_lid, 80, 16, u16);
+
+    // DataDetails of trap 144
+    bitfield!(trap144_lid, set_trap144_lid, 96, 16, u16);
+    bitfield!(
+        trap144_other_local_changes,
+        set_trap144_other_local_changes,
+        127,
+        1,
+        u8
+    );
+    bitfield!(
+        trap144_capability_mask,
+        set_trap144_capability_mask,
+        128,
+        32,
+        u32
+    );
+    bitfield!(trap144_change_flags, set_trap144_change_flags, 160, 16, u16);
+}
@@ -99,6 +99,12 @@ impl ib_user_mad {
         Ok(u64::from_be_bytes(tid_bytes))
     }
 
+    /// Whether the MAD is a response (R bit of the method set), as opposed to a
+    /// request or an unsolicited Trap.
+    pub fn is_response(&self) -> bool {
+        self.data[3] & 0x80 != 0
+    }
+
     pub fn is_tid_equal(&self, other: &ib_user_mad) -> bool {
         match (self.get_tid(), other.get_tid()) {
             (Ok(self_tid), Ok(other_tid)) => {
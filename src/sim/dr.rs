@@ -0,0 +1,283 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::{Fabric, Node, NodePort, Port, Shared, linked_remote};
+use crate::mad::{
+    dr_smp::{DR_SMP_DIRECTION, DR_SMP_MAX_HOPS, PERMISSIVE_LID},
+    dr_smp_mad, ib_mad, ib_user_mad,
+};
+
+/// The way a DR SMP reached its responder, to carry the response back.
+#[derive(Debug)]
+pub(super) struct DrRoute {
+    /// HopPointer when the SMP was delivered, HopCount + 1.
+    hop_ptr: u8,
+    return_path: [u8; 64],
+    dr_slid: u16,
+    /// The requester's port.
+    requester: Arc<RwLock<Port>>,
+    /// Port 0 of the switch a LID routed part went on from after the directed route.
+    dr_end: Option<Arc<RwLock<Port>>>,
+    /// The port the SMP was delivered at.
+    responder: Arc<RwLock<Port>>,
+}
+
+/// Port `num` of `node` to send a DR SMP out of. Port 0 never leads anywhere.
+fn egress_port(node: &Node, num: u8) -> Option<Arc<RwLock<Port>>> {
+    if num == 0 {
+        return None;
+    }
+    node.ports
+        .iter()
+        .find(|p| p.read_lock().num == num)
+        .cloned()
+}
+
+/// Port 0 of a switch; None for CAs.
+pub(super) fn switch_port0(node: &Node) -> Option<Arc<RwLock<Port>>> {
+    node.switch_info?;
+    node.ports.iter().find(|p| p.read_lock().num == 0).cloned()
+}
+
+fn is_switch(node: &Arc<RwLock<Node>>) -> bool {
+    node.read_lock().switch_info.is_some()
+}
+
+impl Fabric {
+    /// Carries a DR SMP out to the node it addresses and answers it there. The
+    /// response is carried back by `write_response`.
+    pub(super) fn process_dr_smp(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+    ) -> Result<(), io::Error> {
+        let dr_smp = dr_smp_mad::from_bytes(&mad.data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to parse DR SMP"))?;
+
+        if u16::from_be(mad.status) & DR_SMP_DIRECTION != 0 {
+            log::debug!(
+                "[tid: {}] Returning DR SMP sent as a request, dropping.",
+                tid
+            );
+            return Ok(());
+        }
+        log::trace!("[tid: {}] Initial Path: {:?}", tid, dr_smp.initial_path);
+
+        let Some(((node, port), route)) = self.dr_outgoing(tid, umad, mad, &dr_smp) else {
+            return Ok(());
+        };
+        self.dr_route = Some(route);
+
+        self.process_smp(
+            tid,
+            umad,
+            mad,
+            Some(node),
+            Some(port),
+            u64::from_be(dr_smp.m_key),
+            dr_smp.attr_layout,
+        )
+    }
+
+    /// Outgoing DR processing (C14-9): walks the initial path from the requester,
+    /// recording the ports it enters by in the return path. `None` means the SMP was
+    /// discarded, as an SMI does for a bad HopPointer or HopCount, a port out of
+    /// range, a link that is down or a CA in the middle of the path.
+    fn dr_outgoing(
+        &self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        smp: &dr_smp_mad,
+    ) -> Option<(NodePort, DrRoute)> {
+        let hop_cnt = mad.hop_cnt;
+        let mut hop_ptr = mad.hop_ptr;
+        if hop_cnt > DR_SMP_MAX_HOPS || hop_ptr != 0 {
+            log::debug!(
+                "[tid: {}] DR SMP with HopCount {} and HopPointer {}, dropping.",
+                tid,
+                hop_cnt,
+                hop_ptr
+            );
+            return None;
+        }
+        let dr_slid = u16::from_be(smp.drslid);
+        let dr_dlid = u16::from_be(smp.drdlid);
+
+        // A LID routed part may lead up to the switch the directed route starts at.
+        let requester = self.first_hop()?;
+        let dlid = u16::from_be(umad.addr.lid);
+        let (mut node, mut port) = if dlid == PERMISSIVE_LID {
+            let node = requester.read_lock().parent.upgrade()?;
+            (node, requester.clone())
+        } else {
+            self.forward_lid(tid, requester.clone(), dlid, &mut Vec::new())?
+        };
+
+        let mut return_path = [0u8; 64];
+        let mut dr_end = None;
+        loop {
+            if hop_ptr == hop_cnt {
+                // The end of the directed route: deliver here or LID route on to DrDLID.
+                hop_ptr += 1;
+                if dr_dlid != PERMISSIVE_LID {
+                    let Some(port0) = switch_port0(&node.read_lock()) else {
+                        log::debug!(
+                            "[tid: {}] DrDLID {} set on a path ending at CA '{}', dropping.",
+                            tid,
+                            dr_dlid,
+                            node.read_lock().description
+                        );
+                        return None;
+                    };
+                    (node, port) =
+                        self.forward_lid(tid, port0.clone(), dr_dlid, &mut Vec::new())?;
+                    dr_end = Some(port0);
+                }
+                break;
+            }
+
+            hop_ptr += 1;
+            let egress = smp.initial_path[hop_ptr as usize];
+            let egress_rc = {
+                let n = node.read_lock();
+                // A CA only sends out of its own port.
+                egress_port(&n, egress)
+                    .filter(|_| n.switch_info.is_some() || port.read_lock().num == egress)
+            };
+            let Some(egress_rc) = egress_rc else {
+                log::debug!(
+                    "[tid: {}] DR hop {}: no port {} on '{}', dropping.",
+                    tid,
+                    hop_ptr,
+                    egress,
+                    node.read_lock().description
+                );
+                return None;
+            };
+            let Some(next) = linked_remote(&egress_rc) else {
+                log::debug!(
+                    "[tid: {}] DR hop {}: port {} on '{}' is down, dropping.",
+                    tid,
+                    hop_ptr,
+                    egress,
+                    node.read_lock().description
+                );
+                return None;
+            };
+
+            let next_node = next.read_lock().parent.upgrade()?;
+            let must_forward = hop_ptr < hop_cnt || dr_dlid != PERMISSIVE_LID;
+            if must_forward && !is_switch(&next_node) {
+                log::debug!(
+                    "[tid: {}] DR hop {}: CA '{}' can't forward, dropping.",
+                    tid,
+                    hop_ptr,
+                    next_node.read_lock().description
+                );
+                return None;
+            }
+            return_path[hop_ptr as usize] = next.read_lock().num;
+            node = next_node;
+            port = next;
+        }
+
+        let route = DrRoute {
+            hop_ptr,
+            return_path,
+            dr_slid,
+            requester,
+            dr_end,
+            responder: port.clone(),
+        };
+        Some(((node, port), route))
+    }
+
+    /// Returning DR processing (C14-13): carries a response back along the return
+    /// path, sets the D bit, HopPointer and ReturnPath, and checks it reaches the
+    /// requester. Returns false if it is lost on the way.
+    pub(super) fn dr_returning(&self, tid: u64, route: &DrRoute, resp_mad: &mut ib_mad) -> bool {
+        match self.walk_return(tid, route) {
+            Some(hop_ptr) => {
+                let Some(mut resp_dr) = dr_smp_mad::from_bytes(&resp_mad.data) else {
+                    return false;
+                };
+                resp_dr.return_path = route.return_path;
+                let dr_bytes = resp_dr.to_bytes();
+                resp_mad.data[..dr_bytes.len()].copy_from_slice(&dr_bytes);
+                resp_mad.hop_ptr = hop_ptr;
+                resp_mad.status = (u16::from_be(resp_mad.status) | DR_SMP_DIRECTION).to_be();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Walks the response back and returns the HopPointer it arrives with.
+    fn walk_return(&self, tid: u64, route: &DrRoute) -> Option<u8> {
+        let mut node = route.responder.read_lock().parent.upgrade()?;
+
+        // Back along the LID routed part to the end of the directed route.
+        if let Some(port0) = &route.dr_end {
+            let lid = port0.read_lock().port_info.lid();
+            let (arrived, _) =
+                self.forward_lid(tid, route.responder.clone(), lid, &mut Vec::new())?;
+            let end_node = port0.read_lock().parent.upgrade()?;
+            if !Arc::ptr_eq(&arrived, &end_node) {
+                log::debug!(
+                    "[tid: {}] DR response LID routed off the path, dropping.",
+                    tid
+                );
+                return None;
+            }
+            node = end_node;
+        }
+
+        let mut hop_ptr = route.hop_ptr;
+        while hop_ptr > 1 {
+            hop_ptr -= 1;
+            let egress = route.return_path[hop_ptr as usize];
+            let next = egress_port(&node.read_lock(), egress).and_then(|p| linked_remote(&p));
+            let Some(next) = next else {
+                log::debug!(
+                    "[tid: {}] DR response hop {}: port {} on '{}' is down, dropping.",
+                    tid,
+                    hop_ptr,
+                    egress,
+                    node.read_lock().description
+                );
+                return None;
+            };
+            node = next.read_lock().parent.upgrade()?;
+            if hop_ptr > 1 && !is_switch(&node) {
+                log::debug!(
+                    "[tid: {}] DR response hop {} reached a CA, dropping.",
+                    tid,
+                    hop_ptr
+                );
+                return None;
+            }
+        }
+
+        // The start of the directed route: hand over here or LID route on to DrSLID.
+        hop_ptr = 0;
+        if route.dr_slid != PERMISSIVE_LID {
+            let port0 = switch_port0(&node.read_lock())?;
+            (node, _) = self.forward_lid(tid, port0, route.dr_slid, &mut Vec::new())?;
+        }
+
+        let requester_node = route.requester.read_lock().parent.upgrade()?;
+        if !Arc::ptr_eq(&node, &requester_node) {
+            log::debug!(
+                "[tid: {}] DR response ended at '{}' instead of the requester, dropping.",
+                tid,
+                node.read_lock().description
+            );
+            return None;
+        }
+        Some(hop_ptr)
+    }
+}
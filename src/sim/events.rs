@@ -0,0 +1,464 @@
+use std::{
+    io, mem,
+    sync::{Arc, RwLock, Weak},
+    time,
+};
+
+use super::{
+    DEFAULT_CLIENT, Fabric, Node, Port, Response, Shared, connect_ports, dr::switch_port0,
+    perf::Counter,
+};
+use crate::mad::{
+    self, ib_mad, ib_mad_addr, ib_user_mad, notice, smp_mad,
+    trap::{
+        IB_METHOD_TRAP, NOTICE_ATTR_ID, NOTICE_TYPE_INFO, NOTICE_TYPE_URGENT, PRODUCER_TYPE_CA,
+        PRODUCER_TYPE_SWITCH, TRAP_LINK_STATE_CHANGE, TRAP_LOCAL_CHANGES,
+        TRAP144_NODE_DESCRIPTION_CHANGE,
+    },
+};
+
+/// Traps kept for a client that sends no MADs; older ones are dropped first.
+const MAX_PENDING_TRAPS: usize = 64;
+
+/// A change to the fabric, as an admin or a failing part would make it. Nodes are
+/// given by GUID and ports by number, like `Fabric::inject_counter`.
+///
+/// Switches whose ports change link state raise trap 128, and LID or NodeDescription
+/// changes raise trap 144 from the ports concerned. Every client is sent the traps
+/// ahead of the response to its next MAD.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FabricEvent {
+    /// Takes the link at a port down; both ends go to Polling and count a LinkDowned.
+    /// The cable stays, so `LinkUp` can retrain it.
+    LinkDown { node_guid: u64, port: u8 },
+    /// Retrains the cable at a port, unless the far end is Disabled or either node is
+    /// powered off.
+    LinkUp { node_guid: u64, port: u8 },
+    /// Takes down every link of a node, which can then be reached no more. The links
+    /// stay down, even through `LinkUp` or `SwapCables`, until `PowerOn`.
+    PowerOff { node_guid: u64 },
+    /// Powers a node back on and retrains its cables.
+    PowerOn { node_guid: u64 },
+    /// Gives a port a new LID. On a switch every port takes it, since they share the
+    /// LID of port 0. Forwarding tables are left alone until `compute_lfts`.
+    SetLid { node_guid: u64, port: u8, lid: u16 },
+    /// Swaps the far ends of the cables in two ports: what `a` was linked to is
+    /// linked to `b` and the other way around. Either port may be uncabled.
+    SwapCables { a: (u64, u8), b: (u64, u8) },
+    /// Renames a node, as seen in NodeDescription.
+    SetNodeDescription { node_guid: u64, description: String },
+}
+
+/// When a scheduled `FabricEvent` fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventTrigger {
+    /// Once this long has passed since the schedule was created. `Fabric::events`
+    /// is created by `Fabric::new`; assign a new `EventSchedule` to count from later.
+    At(time::Duration),
+    /// Once this many MADs have been processed, before the next is answered.
+    AfterMads(u64),
+}
+
+#[derive(Debug, Clone)]
+struct Scheduled {
+    trigger: EventTrigger,
+    event: FabricEvent,
+}
+
+/// Events for `sim::Fabric` to apply while it runs.
+///
+/// Triggers are checked whenever a MAD arrives, from any client, so a fabric nobody
+/// talks to does not change. Counting MADs gives the same results on every run.
+#[derive(Debug, Clone)]
+pub struct EventSchedule {
+    pending: Vec<Scheduled>,
+    start: time::Instant,
+    mads: u64,
+}
+
+impl Default for EventSchedule {
+    fn default() -> Self {
+        EventSchedule::new()
+    }
+}
+
+impl EventSchedule {
+    pub fn new() -> Self {
+        EventSchedule {
+            pending: Vec::new(),
+            start: time::Instant::now(),
+            mads: 0,
+        }
+    }
+
+    pub fn add(&mut self, trigger: EventTrigger, event: FabricEvent) -> &mut Self {
+        self.pending.push(Scheduled { trigger, event });
+        self
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Events that have not fired yet.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// MADs processed since the schedule was created, to trigger relative to.
+    pub fn mads_processed(&self) -> u64 {
+        self.mads
+    }
+
+    /// Counts a MAD and returns the events due before it is answered, in the order
+    /// they were added.
+    pub(crate) fn on_mad(&mut self) -> Vec<FabricEvent> {
+        let elapsed = self.start.elapsed();
+        let mads = self.mads;
+        self.mads += 1;
+
+        let (due, pending): (Vec<_>, Vec<_>) =
+            mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|s| match s.trigger {
+                    EventTrigger::At(at) => elapsed >= at,
+                    EventTrigger::AfterMads(n) => mads >= n,
+                });
+        self.pending = pending;
+        due.into_iter().map(|s| s.event).collect()
+    }
+}
+
+/// Takes the link at a port down to Polling, counting a LinkDowned if it was up.
+fn link_down(port_rc: &Arc<RwLock<Port>>) {
+    let mut port = port_rc.write_lock();
+    if port.port_info.port_physical_state() == 5 {
+        port.counters.add(Counter::LinkDowned, 1);
+    }
+    port.port_info.set_port_physical_state(2); // Polling
+    port.port_info.set_port_state(1); // Down
+}
+
+fn remote_of(port_rc: &Arc<RwLock<Port>>) -> Option<Arc<RwLock<Port>>> {
+    port_rc
+        .read_lock()
+        .remote_port
+        .as_ref()
+        .and_then(Weak::upgrade)
+}
+
+/// Retrains the cable at a port if the far end is not Disabled and neither node is
+/// powered off.
+fn link_up(port_rc: &Arc<RwLock<Port>>) -> bool {
+    let Some(remote) = remote_of(port_rc) else {
+        return false;
+    };
+    if remote.read_lock().port_info.port_physical_state() == 3
+        || port_rc.read_lock().powered_off
+        || remote.read_lock().powered_off
+    {
+        return false;
+    }
+    connect_ports(port_rc, &remote);
+    true
+}
+
+impl Fabric {
+    /// Applies the scheduled events that are due as a MAD arrives.
+    pub(super) fn fire_events(&mut self) {
+        for event in self.events.on_mad() {
+            if let Err(e) = self.apply_event(&event) {
+                log::warn!("Could not apply {:?}: {}", event, e);
+            }
+        }
+    }
+
+    /// Applies an event right away.
+    pub fn apply_event(&mut self, event: &FabricEvent) -> Result<(), io::Error> {
+        log::info!("Applying fabric event {:?}", event);
+        match event {
+            FabricEvent::LinkDown { node_guid, port } => {
+                let port_rc = self.find_port(*node_guid, *port)?;
+                let remote = remote_of(&port_rc);
+                link_down(&port_rc);
+                if let Some(remote) = &remote {
+                    link_down(remote);
+                }
+                self.link_state_traps([Some(&port_rc), remote.as_ref()].into_iter().flatten());
+            }
+            FabricEvent::LinkUp { node_guid, port } => {
+                let port_rc = self.find_port(*node_guid, *port)?;
+                if !link_up(&port_rc) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Port {} of {:#x} has no cable that can train",
+                            port, node_guid
+                        ),
+                    ));
+                }
+                let remote = remote_of(&port_rc);
+                self.link_state_traps([Some(&port_rc), remote.as_ref()].into_iter().flatten());
+            }
+            FabricEvent::PowerOff { node_guid } => {
+                let ports = self.find_node(*node_guid)?.read_lock().ports.clone();
+                let mut remotes = Vec::new();
+                for port_rc in ports {
+                    port_rc.write_lock().powered_off = true;
+                    if let Some(remote) = remote_of(&port_rc) {
+                        link_down(&port_rc);
+                        link_down(&remote);
+                        remotes.push(remote);
+                    }
+                }
+                self.link_state_traps(&remotes);
+            }
+            FabricEvent::PowerOn { node_guid } => {
+                let ports = self.find_node(*node_guid)?.read_lock().ports.clone();
+                for port_rc in &ports {
+                    port_rc.write_lock().powered_off = false;
+                }
+                let mut trained = Vec::new();
+                for port_rc in ports {
+                    if link_up(&port_rc) {
+                        trained.extend(remote_of(&port_rc));
+                        trained.push(port_rc);
+                    }
+                }
+                self.link_state_traps(&trained);
+            }
+            FabricEvent::SetLid {
+                node_guid,
+                port,
+                lid,
+            } => {
+                let port_rc = self.find_port(*node_guid, *port)?;
+                let node_rc = self.find_node(*node_guid)?;
+                let issuer = {
+                    let mut node = node_rc.write_lock();
+                    node.lid = *lid;
+                    if node.switch_info.is_some() {
+                        for p in &node.ports {
+                            p.write_lock().port_info.set_lid(*lid);
+                        }
+                        switch_port0(&node)
+                    } else {
+                        port_rc.write_lock().port_info.set_lid(*lid);
+                        Some(port_rc)
+                    }
+                };
+                if let Some(issuer) = issuer {
+                    self.local_changes_trap(&node_rc, &issuer, 0);
+                }
+            }
+            FabricEvent::SwapCables { a, b } => {
+                let a = self.find_port(a.0, a.1)?;
+                let b = self.find_port(b.0, b.1)?;
+                let (remote_a, remote_b) = (remote_of(&a), remote_of(&b));
+                let same_cable = remote_a.as_ref().is_some_and(|r| Arc::ptr_eq(r, &b));
+                let affected: Vec<_> = [Some(&a), Some(&b), remote_a.as_ref(), remote_b.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .cloned()
+                    .collect();
+
+                for port_rc in &affected {
+                    link_down(port_rc);
+                    port_rc.write_lock().remote_port = None;
+                }
+                if same_cable {
+                    connect_ports(&a, &b);
+                } else {
+                    if let Some(remote_b) = &remote_b {
+                        connect_ports(&a, remote_b);
+                    }
+                    if let Some(remote_a) = &remote_a {
+                        connect_ports(&b, remote_a);
+                    }
+                }
+                self.link_state_traps(&affected);
+            }
+            FabricEvent::SetNodeDescription {
+                node_guid,
+                description,
+            } => {
+                let node_rc = self.find_node(*node_guid)?;
+                let issuers = {
+                    let mut node = node_rc.write_lock();
+                    node.description = description.clone();
+                    if node.switch_info.is_some() {
+                        switch_port0(&node).into_iter().collect()
+                    } else {
+                        node.ports.clone()
+                    }
+                };
+                for issuer in issuers {
+                    self.local_changes_trap(&node_rc, &issuer, TRAP144_NODE_DESCRIPTION_CHANGE);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Raises trap 128 from every switch owning one of `ports` and flags it in the
+    /// switch's SwitchInfo PortStateChange. CAs don't raise trap 128 and powered off
+    /// switches can't.
+    fn link_state_traps<'a>(&mut self, ports: impl IntoIterator<Item = &'a Arc<RwLock<Port>>>) {
+        let mut switches: Vec<Arc<RwLock<Node>>> = Vec::new();
+        for port_rc in ports {
+            let port = port_rc.read_lock();
+            if port.powered_off {
+                continue;
+            }
+            if let Some(node_rc) = port.parent.upgrade()
+                && !switches.iter().any(|n| Arc::ptr_eq(n, &node_rc))
+            {
+                switches.push(node_rc);
+            }
+        }
+
+        for node_rc in switches {
+            let lid = {
+                let mut node = node_rc.write_lock();
+                let lid = node.lid;
+                let Some(si) = node.switch_info.as_mut() else {
+                    continue;
+                };
+                si.set_port_state_change(1);
+                lid
+            };
+            let mut trap = notice::generic(
+                NOTICE_TYPE_URGENT,
+                PRODUCER_TYPE_SWITCH,
+                TRAP_LINK_STATE_CHANGE,
+                lid,
+            );
+            trap.set_trap128_lid(lid);
+            self.queue_trap(&trap);
+        }
+    }
+
+    /// Raises trap 144 from `port_rc` of `node_rc`. A `change_flags` of 0 reports
+    /// OtherLocalChanges, such as a new LID.
+    fn local_changes_trap(
+        &mut self,
+        node_rc: &Arc<RwLock<Node>>,
+        port_rc: &Arc<RwLock<Port>>,
+        change_flags: u16,
+    ) {
+        let producer_type = if node_rc.read_lock().switch_info.is_some() {
+            PRODUCER_TYPE_SWITCH
+        } else {
+            PRODUCER_TYPE_CA
+        };
+        let port = port_rc.read_lock();
+        if port.powered_off {
+            return;
+        }
+        let lid = port.port_info.lid();
+        let mut trap = notice::generic(NOTICE_TYPE_INFO, producer_type, TRAP_LOCAL_CHANGES, lid);
+        trap.set_trap144_lid(lid);
+        trap.set_trap144_capability_mask(port.port_info.capability_mask());
+        trap.set_trap144_change_flags(change_flags);
+        trap.set_trap144_other_local_changes((change_flags == 0) as u8);
+        drop(port);
+        self.queue_trap(&trap);
+    }
+
+    /// Queues a Trap carrying `trap` for every client. It is sent from the LID of its
+    /// issuer, with a TID of its own.
+    fn queue_trap(&mut self, trap: &notice) {
+        self.trap_tid += 1;
+        log::debug!(
+            "Raising trap {} from LID {} (TID {})",
+            trap.trap_number(),
+            trap.issuer_lid(),
+            self.trap_tid
+        );
+
+        let smp = smp_mad {
+            m_key: 0,
+            reserved: [0; 32],
+            attr_layout: trap.data,
+            reserved2: [0; 128],
+        };
+        let mut mad = ib_mad {
+            base_version: 1,
+            mgmt_class: mad::IB_MGMT_CLASS_LID_ROUTED_SMP,
+            class_version: 1,
+            method: IB_METHOD_TRAP,
+            status: 0,
+            hop_ptr: 0,
+            hop_cnt: 0,
+            tid: self.trap_tid.to_be(),
+            attr_id: NOTICE_ATTR_ID.to_be(),
+            additional_status: 0,
+            attr_mod: 0,
+            data: [0; 232],
+        };
+        let smp_bytes = smp.to_bytes();
+        mad.data[..smp_bytes.len()].copy_from_slice(&smp_bytes);
+
+        let mut umad = ib_user_mad {
+            agent_id: 0,
+            status: 0,
+            timeout_ms: 0,
+            retries: 0,
+            length: std::mem::size_of::<ib_mad>() as u32,
+            addr: ib_mad_addr {
+                qpn: 0,
+                qkey: mad::IB_DEFAULT_QKEY.to_be(),
+                lid: trap.issuer_lid().to_be(),
+                sl: 0,
+                path_bits: 0,
+                grh_present: 0,
+                gid_index: 0,
+                hop_limit: 0,
+                traffic_class: 0,
+                gid: [0; 16],
+                flow_label: 0,
+                pkey_index: 0,
+                reserved: [0; 6],
+            },
+            data: [0; 256],
+        };
+        let mad_bytes = mad.to_bytes();
+        umad.data[..mad_bytes.len()].copy_from_slice(&mad_bytes);
+        let bytes = umad.to_bytes();
+
+        for client in DEFAULT_CLIENT..=self.clients.len() {
+            let queue = self.pending_traps.entry(client).or_default();
+            if queue.len() == MAX_PENDING_TRAPS {
+                queue.pop_front();
+            }
+            queue.push_back(bytes.clone());
+        }
+    }
+
+    /// Moves the traps waiting for the current client to the front of its responses.
+    pub(super) fn send_pending_traps(&mut self) {
+        if let Some(traps) = self.pending_traps.remove(&self.client) {
+            self.outbox.extend(traps.into_iter().map(|bytes| Response {
+                bytes,
+                delay: time::Duration::ZERO,
+            }));
+        }
+    }
+
+    pub(super) fn find_node(&self, node_guid: u64) -> Result<Arc<RwLock<Node>>, io::Error> {
+        self.nodes
+            .iter()
+            .find(|n| n.read_lock().node_info.node_guid == node_guid)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No node with GUID {:#x}", node_guid),
+                )
+            })
+    }
+}
@@ -0,0 +1,160 @@
+use std::time;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+/// MAD status: the device is busy, try again later.
+pub const MAD_STATUS_BUSY: u16 = 0x0001;
+
+/// Selects the requests a fault applies to. `None` matches anything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultMatch {
+    /// GUID of the node the request is for, as given to `Node::new_switch`/`new_hca`.
+    pub node_guid: Option<u64>,
+    /// The port the request is about: the PortInfo attribute modifier, the PerfMgt
+    /// PortSelect, otherwise the port the SMP arrived on.
+    pub port: Option<u8>,
+    /// Attribute ID in host order, e.g. 0x0015 for PortInfo.
+    pub attr_id: Option<u16>,
+}
+
+impl FaultMatch {
+    pub fn any() -> Self {
+        FaultMatch::default()
+    }
+
+    pub fn node(node_guid: u64) -> Self {
+        FaultMatch {
+            node_guid: Some(node_guid),
+            ..FaultMatch::default()
+        }
+    }
+
+    pub fn port(mut self, port: u8) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn attr(mut self, attr_id: u16) -> Self {
+        self.attr_id = Some(attr_id);
+        self
+    }
+
+    fn matches(&self, node_guid: Option<u64>, port: Option<u8>, attr_id: u16) -> bool {
+        self.node_guid.is_none_or(|g| Some(g) == node_guid)
+            && self.port.is_none_or(|p| Some(p) == port)
+            && self.attr_id.is_none_or(|a| a == attr_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultKind {
+    /// Drops the first `n` matching requests.
+    DropFirst(u32),
+    /// Drops each matching request with this probability.
+    Loss(f64),
+    /// Holds the response back for this long.
+    Delay(time::Duration),
+    /// Answers with a different TID, so the client has to discard the response.
+    WrongTid,
+    /// Sends every response twice.
+    Duplicate,
+    /// Cuts the response MAD after its first `n` bytes, so the client reads a UMAD
+    /// that is too short.
+    Truncate(usize),
+    /// Answers with this MAD status, e.g. `MAD_STATUS_BUSY`, instead of the attribute.
+    Status(u16),
+}
+
+#[derive(Debug, Clone)]
+struct Fault {
+    matches: FaultMatch,
+    kind: FaultKind,
+    hits: u32,
+}
+
+/// What to do with the response to one request; the combination of every fault that
+/// matched it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct FaultAction {
+    pub(crate) drop: bool,
+    pub(crate) status: Option<u16>,
+    pub(crate) delay: time::Duration,
+    pub(crate) wrong_tid: bool,
+    pub(crate) duplicate: bool,
+    pub(crate) truncate: Option<usize>,
+}
+
+/// Per-node, per-port and per-attribute faults for `sim::Fabric`.
+///
+/// Random loss draws from a generator seeded by `new`, so the same seed and the same
+/// request sequence fail the same requests.
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    faults: Vec<Fault>,
+    rng: StdRng,
+}
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        FaultInjector::new(0)
+    }
+}
+
+impl FaultInjector {
+    pub fn new(seed: u64) -> Self {
+        FaultInjector {
+            faults: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn add(&mut self, matches: FaultMatch, kind: FaultKind) -> &mut Self {
+        self.faults.push(Fault {
+            matches,
+            kind,
+            hits: 0,
+        });
+        self
+    }
+
+    pub fn clear(&mut self) {
+        self.faults.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faults.is_empty()
+    }
+
+    /// Counts the request against every matching fault and returns what to do with it.
+    pub(crate) fn on_request(
+        &mut self,
+        node_guid: Option<u64>,
+        port: Option<u8>,
+        attr_id: u16,
+    ) -> FaultAction {
+        let mut action = FaultAction::default();
+
+        for fault in &mut self.faults {
+            if !fault.matches.matches(node_guid, port, attr_id) {
+                continue;
+            }
+            fault.hits = fault.hits.saturating_add(1);
+
+            match fault.kind {
+                FaultKind::DropFirst(n) => action.drop |= fault.hits <= n,
+                FaultKind::Loss(rate) => action.drop |= self.rng.random_bool(rate.clamp(0.0, 1.0)),
+                FaultKind::Delay(delay) => action.delay += delay,
+                FaultKind::WrongTid => action.wrong_tid = true,
+                FaultKind::Duplicate => action.duplicate = true,
+                FaultKind::Truncate(n) => {
+                    action.truncate = Some(action.truncate.map_or(n, |t: usize| t.min(n)))
+                }
+                FaultKind::Status(status) => {
+                    action.status.get_or_insert(status);
+                }
+            }
+        }
+
+        action
+    }
+}
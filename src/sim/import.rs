@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::{Fabric, Node, Port, Shared, connect_ports};
+use crate::discovery::{self, json::FabricJson, lib::lock_err};
+use crate::enums::{IbNodeType, IbPortLinkLayerState};
+
+impl Fabric {
+    /// Adds a simulated copy of a fabric loaded with `discovery::Fabric::from_json`,
+    /// `from_ibnetdiscover` or a live discovery.
+    ///
+    /// Nodes keep their descriptions, GUIDs, LIDs, port numbers, and the width and
+    /// speed each link trained at. Switches get every port up to NumPorts; ports the
+    /// source does not list stay Down. When the source is the only fabric, the agent
+    /// sits on its first node, the one it was discovered from. LFTs are computed, since
+    /// neither file format records them.
+    pub fn import_discovered(&mut self, source: &discovery::Fabric) -> Result<(), io::Error> {
+        let first_node = self.nodes.len();
+        // Simulated ports by (node GUID, port number), to cable them afterwards.
+        let mut ports: HashMap<(u64, u8), Arc<RwLock<Port>>> = HashMap::new();
+
+        for node_arc in &source.nodes {
+            let src = node_arc.read().map_err(lock_err)?;
+            let node_rc = add_node(self, &src);
+
+            let is_switch = src.node_type == IbNodeType::Switch;
+            let listed: HashMap<u8, discovery::Port> = src
+                .ports
+                .iter()
+                .map(|p| p.read().map(|p| (p.number, p.clone())).map_err(lock_err))
+                .collect::<Result<_, _>>()?;
+            let nports = listed
+                .keys()
+                .copied()
+                .chain([src.nports])
+                .max()
+                .unwrap_or(0);
+
+            let first = if is_switch { 0 } else { 1 };
+            for num in first..=nports {
+                let lid = if is_switch {
+                    src.lid
+                } else {
+                    listed.get(&num).map_or(0, |p| p.lid)
+                };
+                let mut port = Port::new_port(num, lid, node_rc.clone());
+                if let Some(src_port) = listed.get(&num) {
+                    apply_port(&mut port, src_port);
+                }
+                let port_rc = Arc::new(RwLock::new(port));
+                node_rc.write_lock().ports.push(port_rc.clone());
+                ports.insert((src.node_guid, num), port_rc);
+            }
+            node_rc.write_lock().node_info.nports = nports;
+
+            if self.nodes.len() == first_node + 1 {
+                let local = if is_switch {
+                    Some(0)
+                } else {
+                    // The CA port the source reached the fabric through.
+                    listed
+                        .get(&src.local_port)
+                        .or_else(|| {
+                            listed
+                                .values()
+                                .filter(|p| p.remote_port.is_some())
+                                .min_by_key(|p| p.number)
+                        })
+                        .map(|p| p.number)
+                };
+                if let Some(port_rc) = local.and_then(|n| ports.get(&(src.node_guid, n))) {
+                    self.dr_paths.insert([0; 64], Arc::downgrade(port_rc));
+                }
+            }
+        }
+
+        for node_arc in &source.nodes {
+            let src = node_arc.read().map_err(lock_err)?;
+            for port_arc in &src.ports {
+                let src_port = port_arc.read().map_err(lock_err)?;
+                let Some(remote_arc) = src_port.remote() else {
+                    continue;
+                };
+                // Each cable is listed from both ends; connect it from the lower one.
+                if Arc::as_ptr(&remote_arc) < Arc::as_ptr(port_arc) {
+                    continue;
+                }
+                let remote = remote_arc.read().map_err(lock_err)?;
+                let Some(remote_node) = remote.parent.upgrade() else {
+                    continue;
+                };
+                let remote_guid = remote_node.read().map_err(lock_err)?.node_guid;
+
+                let (Some(a), Some(b)) = (
+                    ports.get(&(src.node_guid, src_port.number)),
+                    ports.get(&(remote_guid, remote.number)),
+                ) else {
+                    continue;
+                };
+                connect_ports(a, b);
+                for (port, recorded) in [(a, &*src_port), (b, &*remote)] {
+                    restore_link(&mut port.write_lock(), recorded);
+                }
+            }
+        }
+
+        self.compute_lfts();
+        Ok(())
+    }
+
+    /// Adds the fabric described by `ibnetdiscover` text, see `import_discovered`.
+    pub fn import_ibnetdiscover(&mut self, text: &str) -> Result<(), io::Error> {
+        self.import_discovered(&discovery::Fabric::from_ibnetdiscover(text)?)
+    }
+
+    /// Adds the fabric of a JSON fabric dump, see `import_discovered`.
+    pub fn import_json(&mut self, json: &FabricJson) -> Result<(), io::Error> {
+        self.import_discovered(&discovery::Fabric::from_json(json)?)
+    }
+}
+
+fn add_node(fabric: &mut Fabric, src: &discovery::Node) -> Arc<RwLock<Node>> {
+    let description = src.description.as_deref().unwrap_or("");
+    let mut node = if src.node_type == IbNodeType::Switch {
+        Node::new_switch(description, src.node_guid)
+    } else {
+        Node::new_hca(description, src.node_guid)
+    };
+
+    let ni = &mut node.node_info;
+    ni.node_type = src.node_type.clone() as u8;
+    ni.system_guid = src.system_guid;
+    ni.port_guid = src.port_guid;
+    if src.vendor_id != 0 {
+        ni.vendor_id
+            .copy_from_slice(&src.vendor_id.to_be_bytes()[1..]);
+    }
+    if src.device_id != 0 {
+        ni.device_id = src.device_id.to_be();
+    }
+    ni.revision = src.revision.to_be();
+    if src.partition_cap != 0 {
+        ni.partition_cap = src.partition_cap.to_be();
+    }
+    node.lid = src.lid;
+
+    if src.node_type == IbNodeType::Switch {
+        fabric.add_switch(node)
+    } else {
+        fabric.add_hca(node)
+    }
+}
+
+/// Copies what the source knows about a port before it is cabled.
+fn apply_port(port: &mut Port, src: &discovery::Port) {
+    let pi = &mut port.port_info;
+    pi.set_lmc(src.lmc);
+
+    // Without recorded capabilities, the active width and speed are all the port can do.
+    let widths = if src.link_width_supported.is_empty() {
+        src.link_width_active.into_iter().collect()
+    } else {
+        src.link_width_supported.clone()
+    };
+    let enabled_widths = if src.link_width_enabled.is_empty() {
+        widths.clone()
+    } else {
+        src.link_width_enabled.clone()
+    };
+    if !widths.is_empty() {
+        pi.set_link_width_supported(widths.iter().fold(0, |m, w| m | *w as u8));
+        pi.set_link_width_enabled(enabled_widths.iter().fold(0, |m, w| m | *w as u8));
+    }
+
+    let speeds = if src.link_speed_supported.is_empty() {
+        src.link_speed_active.into_iter().collect()
+    } else {
+        src.link_speed_supported.clone()
+    };
+    let enabled_speeds = if src.link_speed_enabled.is_empty() {
+        speeds.clone()
+    } else {
+        src.link_speed_enabled.clone()
+    };
+    if !speeds.is_empty() {
+        pi.set_supported_link_speeds(&speeds);
+        pi.set_enabled_link_speeds(&enabled_speeds);
+    }
+
+    if let Some(mtu) = src.mtu_cap {
+        pi.set_mtu_cap(mtu as u8);
+    }
+    if src.vl_cap != 0 {
+        pi.set_vl_cap(src.vl_cap);
+        pi.set_operational_vls(src.vl_cap);
+    }
+    if !src.guids.is_empty() {
+        port.guids = src.guids.clone();
+    }
+    if !src.pkeys.is_empty() {
+        port.pkeys = src.pkeys.iter().map(|p| p.0).collect();
+    }
+}
+
+/// Puts back the state, width and speed a link was recorded with after
+/// `connect_ports` trained it, so degraded links stay degraded.
+fn restore_link(port: &mut Port, src: &discovery::Port) {
+    let pi = &mut port.port_info;
+    if let Some(width) = src.link_width_active {
+        pi.set_link_width_active(width as u8);
+    }
+    if src.link_speed_active.is_some() {
+        pi.set_active_link_speed(src.link_speed_active);
+    }
+    if let Some(mtu) = src.mtu {
+        pi.set_neighbor_mtu(mtu as u8);
+    }
+    match src.link_state {
+        IbPortLinkLayerState::Init => pi.set_port_state(2),
+        IbPortLinkLayerState::Armed => pi.set_port_state(3),
+        _ => {}
+    }
+}
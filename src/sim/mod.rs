@@ -1,61 +1,239 @@
+mod dr;
+pub mod events;
+pub mod fault;
+mod import;
+pub mod perf;
+mod sa;
+pub mod server;
+pub mod topology;
+
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fs,
     io::{self, Read, Write},
-    rc::{Rc, Weak},
-    sync, time,
+    sync::{self, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak},
+    time,
 };
 
-use crate::mad::{self, ib_mad, ib_user_mad, node_info, port_info};
+use crate::mad::{
+    self, CapabilityMask2, VlArbEntry, ib_mad, ib_user_mad, lft_block, node_info, pkey_table,
+    port_info, sl2vl_table,
+    switch::{LFT_BLOCK_SIZE, LFT_NO_ROUTE},
+    switch_info, vl_arb_table,
+};
+use dr::DrRoute;
+use events::EventSchedule;
+use fault::{FaultAction, FaultInjector};
+use perf::{Counter, PortCounters, TrafficModel};
+use sa::SaTransfer;
 
 const MIN_UMAD_SIZE: usize = 320;
+
+/// MAD status: the method/attribute combination is not supported.
+pub const MAD_STATUS_UNSUP_METHOD_ATTR: u16 = 0x000c;
+/// MAD status: a field of the attribute holds an invalid value.
+pub const MAD_STATUS_INVALID_ATTR_VALUE: u16 = 0x001c;
+const METHOD_GET: u8 = 0x01;
+const METHOD_SET: u8 = 0x02;
+const METHOD_GET_RESP: u8 = 0x81;
 const FIRST_HOP: [u8; 64] = [0; 64];
+/// Switch hops a LID routed packet may take before it is treated as looping.
+const MAX_LID_ROUTED_HOPS: usize = 64;
+
+/// Access to the locks around simulated nodes and ports, which make the fabric
+/// `Send + Sync`. Unlike a `RefCell` borrow, locking a node or port again while it is
+/// held blocks the server, so guards must be dropped before calling into code that
+/// may lock the same object. Handlers never panic with a lock held on purpose, so a
+/// poisoned lock is logged and taken over as is.
+pub(crate) trait Shared<T> {
+    fn read_lock(&self) -> RwLockReadGuard<'_, T>;
+    fn write_lock(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> Shared<T> for RwLock<T> {
+    fn read_lock(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(|e| {
+            log::warn!("Taking over a poisoned simulator lock");
+            e.into_inner()
+        })
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(|e| {
+            log::warn!("Taking over a poisoned simulator lock");
+            e.into_inner()
+        })
+    }
+}
+
+/// Identifies where a client's MADs enter the fabric, see `Fabric::attach_client`.
+pub type ClientId = usize;
+/// The client behind `Fabric::file`, whose first hop is `dr_paths[[0; 64]]`.
+pub const DEFAULT_CLIENT: ClientId = 0;
+
+/// A UMAD the fabric answered with, to be sent back to the requesting client.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub bytes: Vec<u8>,
+    /// How long to hold the response back before sending it.
+    pub delay: time::Duration,
+}
+
+/// A node and one of its ports, e.g. where a packet was delivered.
+type NodePort = (Arc<RwLock<Node>>, Arc<RwLock<Port>>);
 
 #[derive(Debug, Clone)]
 pub struct Port {
     pub num: u8,
     pub port_info: mad::port_info,
-    pub remote_port: Option<Weak<RefCell<Port>>>,
-    pub parent: Weak<RefCell<Node>>,
+    /// GUIDInfo entries, in the same byte order as `node_info`. When empty the node's
+    /// port GUID is reported at index 0.
+    pub guids: Vec<u64>,
+    pub pkeys: Vec<u16>,
+    /// Same table is reported for every input port.
+    pub sl2vl: mad::sl2vl_table,
+    /// VLArbitrationTable blocks 1-4 (low 0-31, high 0-31, low 32-63, high 32-63).
+    pub vl_arb: [mad::vl_arb_table; 4],
+    pub remote_port: Option<Weak<RwLock<Port>>>,
+    pub parent: Weak<RwLock<Node>>,
+    pub counters: PortCounters,
+    /// Errors this port picks up on top of `TrafficModel::errors`, per second.
+    pub error_rates: Vec<(Counter, f64)>,
+    /// Set on every port of a node by `FabricEvent::PowerOff`. The link doesn't
+    /// train until `FabricEvent::PowerOn`.
+    pub powered_off: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Node {
     pub description: String,
     pub node_info: mad::node_info,
-    pub ports: Vec<Rc<RefCell<Port>>>,
+    pub switch_info: Option<mad::switch_info>,
+    /// Present when an SM runs on this node.
+    pub sm_info: Option<mad::sm_info>,
+    pub ports: Vec<Arc<RwLock<Port>>>,
     pub lid: u16, // Cache LID for easier lookup
+    /// LinearForwardingTable of a switch, indexed by LID. LIDs past the end or above
+    /// LinearFDBTop, and `LFT_NO_ROUTE` entries, are dropped.
+    pub lft: Vec<u8>,
 }
 
 #[derive(Debug)]
 pub struct Fabric {
     pub file: fs::File,
-    pub nodes: Vec<Rc<RefCell<Node>>>,
-    pub switches: Vec<Weak<RefCell<Node>>>,
-    pub hcas: Vec<Weak<RefCell<Node>>>,
-    pub dr_paths: HashMap<[u8; 64], Weak<RefCell<Port>>>,
+    pub nodes: Vec<Arc<RwLock<Node>>>,
+    pub switches: Vec<Weak<RwLock<Node>>>,
+    pub hcas: Vec<Weak<RwLock<Node>>>,
+    pub dr_paths: HashMap<[u8; 64], Weak<RwLock<Port>>>,
     pub response_delay: Option<u64>,
+    pub faults: FaultInjector,
+    /// Changes to apply to the fabric as it runs.
+    pub events: EventSchedule,
+    /// Traps raised by fabric events, by client, to send ahead of its next response.
+    pending_traps: HashMap<ClientId, VecDeque<Vec<u8>>>,
+    /// TID of the last trap raised.
+    trap_tid: u64,
+    /// First hops of the clients from `attach_client`, by `ClientId` - 1.
+    clients: Vec<Weak<RwLock<Port>>>,
+    /// Client that sent the request being processed.
+    client: ClientId,
+    /// Responses to the request being processed.
+    outbox: Vec<Response>,
+    /// Random hold back of the next response, see `response_delay`.
+    pending_delay: time::Duration,
+    /// Faults applying to the request being processed.
+    fault: FaultAction,
+    /// How the DR SMP being processed reached its responder.
+    dr_route: Option<DrRoute>,
+    /// SA GetTable responses still being sent, by client and TID.
+    sa_transfers: HashMap<(ClientId, u64), SaTransfer>,
+    /// How long an SA GetTable response waits for the requester's next ACK before it
+    /// is dropped.
+    pub rmpp_timeout: time::Duration,
+    /// Drives the PerfMgt counters of every linked port.
+    pub traffic: TrafficModel,
+    /// Simulated time the traffic model has run for.
+    counter_time: time::Duration,
+    /// When the counters were last advanced in real time.
+    counter_clock: time::Instant,
 }
 
-pub fn connect_ports(port_a_rc: &Rc<RefCell<Port>>, port_b_rc: &Rc<RefCell<Port>>) {
-    let mut port_a = port_a_rc.borrow_mut();
-    let mut port_b = port_b_rc.borrow_mut();
+/// Cables two ports to each other and trains the link. If either end is powered off
+/// the cable is left in place with both ends Polling.
+pub fn connect_ports(port_a_rc: &Arc<RwLock<Port>>, port_b_rc: &Arc<RwLock<Port>>) {
+    let mut port_a = port_a_rc.write_lock();
+    let mut port_b = port_b_rc.write_lock();
 
     // Link the ports to each other
-    port_a.remote_port = Some(Rc::downgrade(port_b_rc));
-    port_b.remote_port = Some(Rc::downgrade(port_a_rc));
+    port_a.remote_port = Some(Arc::downgrade(port_b_rc));
+    port_b.remote_port = Some(Arc::downgrade(port_a_rc));
 
-    // Set port states to ACTIVE and LINK_UP now that they are connected
-    port_a.port_info.set_port_state(4); // ACTIVE
-    port_a.port_info.set_port_physical_state(5); // LINK_UP
-    port_a.port_info.set_link_speed_active(1); // Set active link params
-    port_a.port_info.set_link_width_active(1);
+    if port_a.powered_off || port_b.powered_off {
+        for port in [&mut port_a, &mut port_b] {
+            port.port_info.set_port_physical_state(2); // Polling
+            port.port_info.set_port_state(1); // Down
+        }
+        log::info!(
+            "Cabled port {} to port {}, a node is powered off",
+            port_a.num,
+            port_b.num
+        );
+        return;
+    }
 
-    port_b.port_info.set_port_state(4); // ACTIVE
-    port_b.port_info.set_port_physical_state(5); // LINK_UP
-    port_b.port_info.set_link_speed_active(1); // Set active link params
-    port_b.port_info.set_link_width_active(1);
+    // Train to the best width and speed both ends have enabled and supported
+    let width = negotiate_link_width(
+        port_a.port_info.link_width_enabled()
+            & port_a.port_info.link_width_supported()
+            & port_b.port_info.link_width_enabled()
+            & port_b.port_info.link_width_supported(),
+    );
+    let speed = highest_bit(
+        port_a.port_info.link_speed_enabled()
+            & port_a.port_info.link_speed_supported()
+            & port_b.port_info.link_speed_enabled()
+            & port_b.port_info.link_speed_supported(),
+    );
+    // FDR and up only train when both ends have IsExtendedSpeedsSupported.
+    let ext_speeds = [&port_a, &port_b]
+        .iter()
+        .all(|p| p.port_info.capabilities().is_extended_speeds_supported());
+    let ext_mask = port_a.port_info.link_speed_ext_enabled()
+        & port_a.port_info.link_speed_ext_supported()
+        & port_b.port_info.link_speed_ext_enabled()
+        & port_b.port_info.link_speed_ext_supported();
+    let ext_speed = if ext_speeds && ext_mask != 0 {
+        highest_bit(ext_mask)
+    } else {
+        0
+    };
+    // XDR likewise needs IsExtendedSpeeds2Supported at both ends.
+    let ext2_speeds = [&port_a, &port_b].iter().all(|p| {
+        p.port_info
+            .capabilities2()
+            .contains(CapabilityMask2::IS_EXTENDED_SPEEDS2_SUPPORTED)
+    });
+    let ext2_mask = port_a.port_info.link_speed_ext2_enabled()
+        & port_a.port_info.link_speed_ext2_supported()
+        & port_b.port_info.link_speed_ext2_enabled()
+        & port_b.port_info.link_speed_ext2_supported();
+    let ext2_speed = if ext2_speeds && ext2_mask != 0 {
+        highest_bit(ext2_mask)
+    } else {
+        0
+    };
+    let mtu = port_a.port_info.mtu_cap().min(port_b.port_info.mtu_cap());
+
+    // Set port states to ACTIVE and LINK_UP now that they are connected
+    for port in [&mut port_a, &mut port_b] {
+        port.port_info.set_port_state(4); // ACTIVE
+        port.port_info.set_port_physical_state(5); // LINK_UP
+        port.port_info.set_link_speed_active(speed);
+        port.port_info.set_link_speed_ext_active(ext_speed);
+        port.port_info.set_link_speed_ext2_active(ext2_speed);
+        port.port_info.set_link_width_active(width);
+        port.port_info.set_neighbor_mtu(mtu);
+    }
 
     log::info!(
         "Connected port {} on node '{}' to port {} on node '{}'",
@@ -63,15 +241,61 @@ pub fn connect_ports(port_a_rc: &Rc<RefCell<Port>>, port_b_rc: &Rc<RefCell<Port>
         port_a
             .parent
             .upgrade()
-            .map_or("?".to_string(), |p| p.borrow().description.clone()),
+            .map_or("?".to_string(), |p| p.read_lock().description.clone()),
         port_b.num,
         port_b
             .parent
             .upgrade()
-            .map_or("?".to_string(), |p| p.borrow().description.clone())
+            .map_or("?".to_string(), |p| p.read_lock().description.clone())
     );
 }
 
+/// The far end of a link that is physically up, which SMPs can cross in any
+/// PortState; `None` when the port is not cabled or not LinkUp.
+fn linked_remote(port_rc: &Arc<RwLock<Port>>) -> Option<Arc<RwLock<Port>>> {
+    let remote = {
+        let port = port_rc.read_lock();
+        if port.port_info.port_physical_state() != 5 {
+            return None;
+        }
+        port.remote_port.as_ref()?.upgrade()?
+    };
+    // A peer that has since been cabled to another port no longer links back.
+    let back = remote.read_lock().remote_port.as_ref()?.upgrade()?;
+    Arc::ptr_eq(&back, port_rc).then_some(remote)
+}
+
+/// The far end of an Active link; `None` when the port is not cabled or not Active.
+fn active_remote(port_rc: &Arc<RwLock<Port>>) -> Option<Arc<RwLock<Port>>> {
+    if port_rc.read_lock().port_info.port_state() != 4 {
+        return None;
+    }
+    linked_remote(port_rc)
+}
+
+/// Whether `dlid` is one of the 2^LMC LIDs assigned to the port.
+fn lid_matches(pi: &port_info, dlid: u16) -> bool {
+    let base = pi.lid() as u32;
+    let dlid = dlid as u32;
+    base != 0 && dlid >= base && dlid - base < (1 << pi.lmc())
+}
+
+fn highest_bit(mask: u8) -> u8 {
+    if mask == 0 {
+        1
+    } else {
+        1 << (7 - mask.leading_zeros())
+    }
+}
+
+// Width bits are not in lane order: 2X (0x10) sits above 12X (0x08).
+fn negotiate_link_width(mask: u8) -> u8 {
+    [0x08, 0x04, 0x02, 0x10, 0x01]
+        .into_iter()
+        .find(|w| mask & w != 0)
+        .unwrap_or(1)
+}
+
 pub fn build_standard_fabric(fabric: &mut Fabric) {
     // build sixteen spine switches
     let mut spines = Vec::new();
@@ -85,11 +309,11 @@ pub fn build_standard_fabric(fabric: &mut Fabric) {
         let spine_rc = fabric.add_switch(spine);
 
         {
-            let mut spine_ref = spine_rc.borrow_mut();
+            let mut spine_ref = spine_rc.write_lock();
             spine_ref.lid = lid;
             for i in 0..=65 {
                 let port = Port::new_port(i, lid, spine_rc.clone());
-                spine_ref.ports.push(Rc::new(RefCell::new(port)));
+                spine_ref.ports.push(Arc::new(RwLock::new(port)));
             }
         }
         spines.push(spine_rc);
@@ -109,7 +333,7 @@ pub fn build_standard_fabric(fabric: &mut Fabric) {
         let leaf_rc = fabric.add_switch(leaf);
 
         {
-            let mut leaf_ref = leaf_rc.borrow_mut();
+            let mut leaf_ref = leaf_rc.write_lock();
             leaf_ref.lid = lid;
             for i in 0..=65 {
                 let port = Port::new_port(i as u8, lid, leaf_rc.clone());
@@ -118,49 +342,18 @@ pub fn build_standard_fabric(fabric: &mut Fabric) {
                     port.port_info.port_state(),
                     port.port_info.port_physical_state(),
                 );
-                leaf_ref.ports.push(Rc::new(RefCell::new(port)));
+                leaf_ref.ports.push(Arc::new(RwLock::new(port)));
             }
         }
 
         lid += 1;
 
-        // connect leaf to all spines for a non blocking fabric
-        // 4*16 = 64 spine ports
-        // Simplified connection logic from tests
-        for i in 0..32 {
-            for (spine_idx, spine_rc) in spines.iter().enumerate() {
-                // Determine ports to connect
-                // This logic mirrors the test logic but ensures safe indexing
-                // Spine ports used: 1..33 (roughly)
-                // Leaf ports used: 33..65 (roughly)
-                
-                let spine_port_idx = leaf_idx + 1 + i;
-                if spine_port_idx >= 65 { continue; } // Safety check
-
-                let spine_port_rc = {
-                    let spine_ref = spine_rc.borrow();
-                    if spine_port_idx < spine_ref.ports.len() {
-                        spine_ref.ports[spine_port_idx].clone()
-                    } else {
-                        continue;
-                    }
-                };
-
-                // Leaf ports for uplinks usually start after HCA ports
-                // 32 HCAs on 1-32. Uplinks on 33+.
-                let leaf_port_idx = 33 + spine_idx + (i / 2); 
-                if leaf_port_idx >= 65 { continue; }
-
-                // Now we get an immutable borrow which is fine.
-                let leaf_port_rc = {
-                    let leaf_ref = leaf_rc.borrow();
-                    if leaf_port_idx < leaf_ref.ports.len() {
-                        leaf_ref.ports[leaf_port_idx].clone()
-                    } else {
-                        continue;
-                    }
-                };
-
+        // connect leaf to all spines for a non blocking fabric: two links to each
+        // spine on leaf ports 33-64, spine ports 2 * leaf + 1 and 2 * leaf + 2
+        for (spine_idx, spine_rc) in spines.iter().enumerate() {
+            for k in 0..2 {
+                let spine_port_rc = spine_rc.read_lock().ports[2 * leaf_idx + 1 + k].clone();
+                let leaf_port_rc = leaf_rc.read_lock().ports[33 + 2 * spine_idx + k].clone();
                 connect_ports(&spine_port_rc, &leaf_port_rc);
             }
         }
@@ -176,26 +369,24 @@ pub fn build_standard_fabric(fabric: &mut Fabric) {
             
             // Assign LID to HCA (simplification: sequential LIDs starting after switches)
             let hca_lid = 4000 + hca_count as u16;
-            hca_rc.borrow_mut().lid = hca_lid;
+            hca_rc.write_lock().lid = hca_lid;
 
-            let hca_port = Rc::new(RefCell::new(Port::new_port(
-                1,
-                hca_lid,
-                hca_rc.clone(),
-            )));
-            hca_rc.borrow_mut().ports.push(hca_port.clone());
+            let hca_port = Arc::new(RwLock::new(Port::new_port(1, hca_lid, hca_rc.clone())));
+            hca_rc.write_lock().ports.push(hca_port.clone());
 
             // connect HCA to leaf
-            let leaf_hca_port_rc = leaf_rc.borrow().ports[h + 1].clone();
+            let leaf_hca_port_rc = leaf_rc.read_lock().ports[h + 1].clone();
 
             connect_ports(&leaf_hca_port_rc, &hca_port);
 
             // first HCA becomes the first hop in dr_paths
             if hca_count == 1 {
-                fabric.dr_paths.insert([0; 64], Rc::downgrade(&hca_port));
+                fabric.dr_paths.insert([0; 64], Arc::downgrade(&hca_port));
             }
         }
     }
+
+    fabric.compute_lfts();
 }
 
 impl Fabric {
@@ -207,401 +398,1130 @@ impl Fabric {
             hcas: Vec::new(),
             dr_paths: HashMap::new(),
             response_delay: None,
+            faults: FaultInjector::default(),
+            events: EventSchedule::default(),
+            pending_traps: HashMap::new(),
+            trap_tid: 0,
+            clients: Vec::new(),
+            client: DEFAULT_CLIENT,
+            outbox: Vec::new(),
+            pending_delay: time::Duration::ZERO,
+            fault: FaultAction::default(),
+            dr_route: None,
+            sa_transfers: HashMap::new(),
+            rmpp_timeout: time::Duration::from_secs(1),
+            traffic: TrafficModel::default(),
+            counter_time: time::Duration::ZERO,
+            counter_clock: time::Instant::now(),
         }
     }
 
-    pub fn add_switch(&mut self, switch: Node) -> Rc<RefCell<Node>> {
-        let hca_switch_rc = Rc::new(RefCell::new(switch));
-        self.switches.push(Rc::downgrade(&hca_switch_rc));
+    pub fn add_switch(&mut self, switch: Node) -> Arc<RwLock<Node>> {
+        let hca_switch_rc = Arc::new(RwLock::new(switch));
+        self.switches.push(Arc::downgrade(&hca_switch_rc));
 
         self.nodes.push(hca_switch_rc.clone());
 
         return hca_switch_rc.clone();
     }
 
-    pub fn add_hca(&mut self, hca: Node) -> Rc<RefCell<Node>> {
-        let hca_rc = Rc::new(RefCell::new(hca));
-        self.hcas.push(Rc::downgrade(&hca_rc));
+    pub fn add_hca(&mut self, hca: Node) -> Arc<RwLock<Node>> {
+        let hca_rc = Arc::new(RwLock::new(hca));
+        self.hcas.push(Arc::downgrade(&hca_rc));
 
         self.nodes.push(hca_rc.clone());
 
         return hca_rc.clone();
     }
 
-    fn send_dr_response(
+    /// Attaches another client whose MADs enter the fabric at `first_hop`, a CA port
+    /// or a switch's port 0. Several agents may share one `ClientId`.
+    pub fn attach_client(&mut self, first_hop: &Arc<RwLock<Port>>) -> ClientId {
+        self.clients.push(Arc::downgrade(first_hop));
+        self.clients.len()
+    }
+
+    /// The port the current client's MADs enter the fabric at.
+    fn first_hop(&self) -> Option<Arc<RwLock<Port>>> {
+        match self.client.checked_sub(1) {
+            None => self.dr_paths.get(&FIRST_HOP)?.upgrade(),
+            Some(i) => self.clients.get(i)?.upgrade(),
+        }
+    }
+
+    /// Programs every switch's LinearForwardingTable with min-hop routes to each
+    /// switch and CA port LID, as an SM would after a sweep, and sets LinearFDBTop to
+    /// the highest LID. Only Active links carry routes; call it again after cabling
+    /// or port state changes.
+    pub fn compute_lfts(&mut self) {
+        let switches: Vec<Arc<RwLock<Node>>> = self
+            .nodes
+            .iter()
+            .filter(|n| n.read_lock().switch_info.is_some())
+            .cloned()
+            .collect();
+        let index: HashMap<*const RwLock<Node>, usize> = switches
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (Arc::as_ptr(n), i))
+            .collect();
+
+        // (switch, port on it that delivers the LIDs, base LID, LMC)
+        let mut dests: Vec<(usize, u8, u16, u8)> = Vec::new();
+        for node_rc in &self.nodes {
+            let node = node_rc.read_lock();
+            if let Some(&sw) = index.get(&Arc::as_ptr(node_rc)) {
+                if let Some(port0) = node.ports.iter().find(|p| p.read_lock().num == 0) {
+                    let pi = port0.read_lock().port_info;
+                    dests.push((sw, 0, pi.lid(), pi.lmc()));
+                }
+                continue;
+            }
+            for port_rc in &node.ports {
+                let Some(remote_rc) = active_remote(port_rc) else {
+                    continue;
+                };
+                let port = port_rc.read_lock();
+                let remote = remote_rc.read_lock();
+                if let Some(peer) = remote.parent.upgrade()
+                    && let Some(&sw) = index.get(&Arc::as_ptr(&peer))
+                {
+                    dests.push((sw, remote.num, port.port_info.lid(), port.port_info.lmc()));
+                }
+            }
+        }
+
+        // Active switch-to-switch links as (neighbour, port on the neighbour back to us).
+        let mut adjacency: Vec<Vec<(usize, u8)>> = vec![Vec::new(); switches.len()];
+        for (i, sw_rc) in switches.iter().enumerate() {
+            for port_rc in &sw_rc.read_lock().ports {
+                let Some(remote_rc) = active_remote(port_rc) else {
+                    continue;
+                };
+                let remote = remote_rc.read_lock();
+                if let Some(peer) = remote.parent.upgrade()
+                    && let Some(&j) = index.get(&Arc::as_ptr(&peer))
+                {
+                    adjacency[i].push((j, remote.num));
+                }
+            }
+        }
+
+        let mut lfts: Vec<Vec<u8>> = vec![Vec::new(); switches.len()];
+        for (sw, port, base, lmc) in dests {
+            if base == 0 {
+                continue;
+            }
+
+            // Breadth first from the switch the destination hangs off, so every switch
+            // learns the port of its first shortest path towards it.
+            let mut egress: Vec<Option<u8>> = vec![None; switches.len()];
+            egress[sw] = Some(port);
+            let mut queue = VecDeque::from([sw]);
+            while let Some(i) = queue.pop_front() {
+                for &(j, back) in &adjacency[i] {
+                    if egress[j].is_none() {
+                        egress[j] = Some(back);
+                        queue.push_back(j);
+                    }
+                }
+            }
+
+            let start = base as usize;
+            let end = (start + (1 << lmc)).min(u16::MAX as usize + 1);
+            for (lft, port) in lfts.iter_mut().zip(egress) {
+                if let Some(port) = port {
+                    if lft.len() < end {
+                        lft.resize(end, LFT_NO_ROUTE);
+                    }
+                    lft[start..end].fill(port);
+                }
+            }
+        }
+
+        for (sw_rc, lft) in switches.iter().zip(lfts) {
+            let mut sw = sw_rc.write_lock();
+            let top = lft.len().saturating_sub(1) as u16;
+            if let Some(si) = sw.switch_info.as_mut() {
+                si.set_linear_fdb_top(top);
+            }
+            log::debug!("Programmed LFT of '{}' up to LID {}", sw.description, top);
+            sw.lft = lft;
+        }
+    }
+
+    /// Forwards a LID routed packet from the agent's port (the first hop) through the
+    /// switches' LFTs. Returns the node it is delivered to and the port it arrives on.
+    ///
+    /// `None` means the packet was dropped on the way: a missing LFT entry, a link that
+    /// is not Active, a CA that doesn't own the LID or a forwarding loop. Nothing is
+    /// answered, so the requester times out as it would on a real subnet.
+    fn route_lid(&self, tid: u64, dlid: u16) -> Option<NodePort> {
+        let first_hop = self.first_hop()?;
+        self.forward_lid(tid, first_hop, dlid, &mut Vec::new())
+    }
+
+    /// Forwards a packet for `dlid` injected at `start`, a CA port or a switch's port 0,
+    /// and pushes the egress port of every link it crosses onto `links`.
+    fn forward_lid(
+        &self,
+        tid: u64,
+        start: Arc<RwLock<Port>>,
+        dlid: u16,
+        links: &mut Vec<Arc<RwLock<Port>>>,
+    ) -> Option<NodePort> {
+        let mut port_rc = start;
+        let mut node_rc = port_rc.read_lock().parent.upgrade()?;
+
+        for hop in 0..MAX_LID_ROUTED_HOPS {
+            let egress = {
+                let node = node_rc.read_lock();
+                if let Some(si) = node.switch_info {
+                    let port0 = node.ports.iter().find(|p| p.read_lock().num == 0)?;
+                    if lid_matches(&port0.read_lock().port_info, dlid) {
+                        return Some((node_rc.clone(), port_rc.clone()));
+                    }
+                    match node.lft.get(dlid as usize) {
+                        Some(&p) if p != LFT_NO_ROUTE && dlid <= si.linear_fdb_top() => p,
+                        _ => {
+                            log::debug!(
+                                "[tid: {}] '{}' has no route to LID {}, dropping.",
+                                tid,
+                                node.description,
+                                dlid
+                            );
+                            return None;
+                        }
+                    }
+                } else {
+                    let port = port_rc.read_lock();
+                    if lid_matches(&port.port_info, dlid) {
+                        return Some((node_rc.clone(), port_rc.clone()));
+                    }
+                    if hop > 0 {
+                        log::debug!(
+                            "[tid: {}] LID {} delivered to '{}' which doesn't own it, dropping.",
+                            tid,
+                            dlid,
+                            node.description
+                        );
+                        return None;
+                    }
+                    // A CA sends everything out of the port it was injected at.
+                    port.num
+                }
+            };
+
+            let next = {
+                let node = node_rc.read_lock();
+                let egress_rc = node.ports.iter().find(|p| p.read_lock().num == egress);
+                let next = egress_rc.and_then(active_remote);
+                match (egress_rc, next) {
+                    (Some(egress_rc), Some(next)) => {
+                        links.push(egress_rc.clone());
+                        next
+                    }
+                    _ => {
+                        log::debug!(
+                            "[tid: {}] Port {} on '{}' towards LID {} is not Active, dropping.",
+                            tid,
+                            egress,
+                            node.description,
+                            dlid
+                        );
+                        return None;
+                    }
+                }
+            };
+            node_rc = next.read_lock().parent.upgrade()?;
+            port_rc = next;
+        }
+
+        log::warn!(
+            "[tid: {}] Forwarding loop towards LID {}, dropping.",
+            tid,
+            dlid
+        );
+        None
+    }
+
+    /// Serializes `resp_mad` into `resp_umad` and writes it, applying the faults
+    /// chosen for the current request.
+    fn write_response(
         &mut self,
         tid: u64,
-        umad: &ib_user_mad,
-        mad: &ib_mad,
-        dr_smp: &mad::dr_smp_mad,
-        attr_data: &[u8],
+        mut resp_umad: ib_user_mad,
+        mut resp_mad: ib_mad,
     ) -> Result<(), io::Error> {
-        if let Some(max_delay) = self.response_delay {
-            if max_delay > 0 {
-                let delay = rand::random_range(0..=max_delay);
-                log::trace!("[tid: {}] Delaying response by {}ms", tid, delay);
-                std::thread::sleep(time::Duration::from_micros(delay));
-            }
+        if let Some(route) = &self.dr_route
+            && resp_mad.mgmt_class == mad::IB_MGMT_CLASS_DIRECT_ROUTED_SMP
+            && !self.dr_returning(tid, route, &mut resp_mad)
+        {
+            return Ok(());
         }
 
-        let mut resp_umad = umad.clone();
-        let mut resp_mad = *mad;
-        let mut resp_dr = *dr_smp;
+        let fault = self.fault;
+        if fault.wrong_tid {
+            log::debug!("[tid: {}] Fault: answering with a wrong TID", tid);
+            resp_mad.tid = !resp_mad.tid;
+        }
 
-        resp_dr.attr_layout[..attr_data.len()].copy_from_slice(attr_data);
-        let dr_bytes = resp_dr.to_bytes();
-        resp_mad.data[..dr_bytes.len()].copy_from_slice(&dr_bytes);
         let mad_bytes = resp_mad.to_bytes();
         resp_umad.data[..mad_bytes.len()].copy_from_slice(&mad_bytes);
 
-        self.file.write_all(&resp_umad.to_bytes())
+        if !fault.delay.is_zero() {
+            log::debug!(
+                "[tid: {}] Fault: delaying response by {:?}",
+                tid,
+                fault.delay
+            );
+        }
+
+        let mut bytes = resp_umad.to_bytes();
+        if let Some(len) = fault.truncate {
+            log::debug!("[tid: {}] Fault: truncating response to {} bytes", tid, len);
+            let umad_header = bytes.len() - resp_umad.data.len();
+            bytes.truncate(umad_header + len.min(mad_bytes.len()));
+        }
+        let delay = fault.delay + std::mem::take(&mut self.pending_delay);
+        if fault.duplicate {
+            log::debug!("[tid: {}] Fault: duplicating response", tid);
+            self.outbox.push(Response {
+                bytes: bytes.clone(),
+                delay,
+            });
+            self.outbox.push(Response {
+                bytes,
+                delay: time::Duration::ZERO,
+            });
+        } else {
+            self.outbox.push(Response { bytes, delay });
+        }
+        Ok(())
+    }
+
+    /// Holds the next response back by up to `response_delay` microseconds.
+    fn hold_response(&mut self, tid: u64) {
+        if let Some(max_delay) = self.response_delay
+            && max_delay > 0
+        {
+            let delay = rand::random_range(0..=max_delay);
+            log::trace!("[tid: {}] Delaying response by {}us", tid, delay);
+            self.pending_delay = time::Duration::from_micros(delay);
+        }
     }
 
-    fn send_perf_response(
+    /// Chooses the faults for the current request. Returns true if the request was
+    /// dropped or answered with an error status and needs no further processing.
+    fn inject_faults(
         &mut self,
         tid: u64,
         umad: &ib_user_mad,
         mad: &ib_mad,
-        perf_data: &mad::perf_mad,
-    ) -> Result<(), io::Error> {
-        if let Some(max_delay) = self.response_delay {
-            if max_delay > 0 {
-                let delay = rand::random_range(0..=max_delay);
-                log::trace!("[tid: {}] Delaying response by {}ms", tid, delay);
-                std::thread::sleep(time::Duration::from_micros(delay));
-            }
+        node_guid: Option<u64>,
+        port: Option<u8>,
+    ) -> Result<bool, io::Error> {
+        if self.faults.is_empty() {
+            return Ok(false);
+        }
+
+        self.fault = self
+            .faults
+            .on_request(node_guid, port, u16::from_be(mad.attr_id));
+        if self.fault.drop {
+            log::debug!("[tid: {}] Fault: dropping request", tid);
+            return Ok(true);
         }
+        if let Some(status) = self.fault.status {
+            self.send_dr_error(tid, umad, mad, status)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Answers an SMP with GetResp carrying `attr_data`. The attribute goes where the
+    /// request's class puts it, so one responder serves DR and LID routed SMPs.
+    fn send_smp_response(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        attr_data: &[u8],
+    ) -> Result<(), io::Error> {
+        self.hold_response(tid);
 
-        let mut resp_umad = umad.clone();
+        let resp_umad = umad.clone();
         let mut resp_mad = *mad;
-        
-        // Mark as response (method | 0x80)
-        resp_mad.method = mad.method | 0x80;
-        resp_mad.status = 0; // Success
-
-        let perf_bytes = perf_data.to_bytes();
-        resp_mad.data[..perf_bytes.len()].copy_from_slice(&perf_bytes);
-        
-        let mad_bytes = resp_mad.to_bytes();
-        resp_umad.data[..mad_bytes.len()].copy_from_slice(&mad_bytes);
+        resp_mad.method = METHOD_GET_RESP;
+
+        let smp_bytes = if mad.mgmt_class == mad::IB_MGMT_CLASS_DIRECT_ROUTED_SMP {
+            let mut resp_dr = mad::dr_smp_mad::from_bytes(&mad.data).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Unable to parse DR SMP")
+            })?;
+            resp_dr.attr_layout[..attr_data.len()].copy_from_slice(attr_data);
+            resp_dr.to_bytes()
+        } else {
+            let mut resp_smp = mad::smp_mad::from_bytes(&mad.data)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to parse SMP"))?;
+            resp_smp.attr_layout[..attr_data.len()].copy_from_slice(attr_data);
+            resp_smp.to_bytes()
+        };
+        resp_mad.data[..smp_bytes.len()].copy_from_slice(&smp_bytes);
 
-        self.file.write_all(&resp_umad.to_bytes())
+        self.write_response(tid, resp_umad, resp_mad)
     }
 
-    pub fn process_one_umad(&mut self) -> Result<(), io::Error> {
-        let mut buf: [u8; 320] = [0; 320];
-        let r = self.file.read(&mut buf)?;
-        log::trace!("Read {} bytes from UMAD file.", r);
+    fn send_dr_error(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        status: u16,
+    ) -> Result<(), io::Error> {
+        log::debug!("[tid: {}] Responding with MAD status 0x{:04X}", tid, status);
 
-        if r < MIN_UMAD_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                format!(
-                    "UMAD too small: expected at least {} bytes, got {}",
-                    MIN_UMAD_SIZE, r
-                ),
-            ));
-        }
+        let resp_umad = *umad;
+        let mut resp_mad = *mad;
+        resp_mad.method = METHOD_GET_RESP;
+        resp_mad.status = status.to_be();
 
-        let umad = ib_user_mad::from_bytes(&buf).ok_or_else(|| {
-            io::Error::new(io::ErrorKind::InvalidData, "Failed to parse ib_user_mad")
-        })?;
+        self.write_response(tid, resp_umad, resp_mad)
+    }
 
-        let mad = ib_mad::from_bytes(&umad.data)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Failed to parse ib_mad"))?;
+    /// Applies the M_Key check of the node's management port (port 0 on a switch, the
+    /// receiving port on a CA). Returns `None` if the SMP must be dropped, otherwise
+    /// whether PortInfo responses should hide the M_Key (ProtectBits 2, wrong key).
+    fn check_m_key(
+        tid: u64,
+        node_rc: &Arc<RwLock<Node>>,
+        current_port: Option<&Arc<RwLock<Port>>>,
+        method: u8,
+        m_key: u64,
+    ) -> Option<bool> {
+        let node_ref = node_rc.read_lock();
+        let mgmt_port_rc = if node_ref.switch_info.is_some() {
+            node_ref
+                .ports
+                .iter()
+                .find(|p| p.read_lock().num == 0)
+                .cloned()
+        } else {
+            current_port.cloned()
+        };
+        let Some(mgmt_port_rc) = mgmt_port_rc else {
+            return Some(false);
+        };
+        let mut mgmt_port = mgmt_port_rc.write_lock();
 
-        // Use the transaction ID for correlated logging
-        let tid = mad.tid;
-        let attr_id = mad.attr_id;
+        let port_key = mgmt_port.port_info.m_key();
+        let protect_bits = mgmt_port.port_info.m_key_protect_bits();
+        if port_key == 0 || port_key == m_key || protect_bits == 0 {
+            return Some(false);
+        }
+
+        let is_get = method == METHOD_GET;
+        if is_get && protect_bits < 3 {
+            return Some(protect_bits == 2);
+        }
+
+        let violations = mgmt_port.port_info.m_key_violations().saturating_add(1);
+        mgmt_port.port_info.set_m_key_violations(violations);
         log::debug!(
-            "[tid: {}] Received MAD. Class: 0x{:02X}, AttrID: 0x{:04X}",
+            "[tid: {}] M_Key violation on '{}' (method 0x{:02X}), dropping SMP",
             tid,
-            mad.mgmt_class,
-            attr_id
+            node_ref.description,
+            method
         );
+        None
+    }
 
-        match mad.mgmt_class {
-            0x81 => {
-                // SubnAdm (Directed Route)
-                log::trace!("[tid: {}] Processing SubnAdm Directed Route MAD.", tid);
+    /// Applies a SubnSet(PortInfo) to a port. Writable fields are copied from the
+    /// request; a zero PortState, PortPhysicalState, LinkDownDefaultState,
+    /// LinkWidthEnabled, LinkSpeedEnabled or OperationalVLs means "no change".
+    ///
+    /// Disabling a port takes its peer down to Polling. Moving a port to Polling, or
+    /// its PortState to Down, retrains the link with the current enabled masks.
+    fn apply_port_info_set(
+        tid: u64,
+        port_rc: &Arc<RwLock<Port>>,
+        req: &port_info,
+    ) -> Result<(), u16> {
+        let port_state = req.port_state();
+        let phys_state = req.port_physical_state();
+        if port_state > 4 || !matches!(phys_state, 0 | 2 | 3) {
+            log::debug!(
+                "[tid: {}] Rejecting PortInfo Set with PortState {} PortPhysicalState {}",
+                tid,
+                port_state,
+                phys_state
+            );
+            return Err(MAD_STATUS_INVALID_ATTR_VALUE);
+        }
 
-                let dr_smp = mad::dr_smp_mad::from_bytes(&mad.data).ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::InvalidData, "Unable to parse DR SMP")
-                })?;
+        let (num, remote_rc) = {
+            let mut port = port_rc.write_lock();
+            let pi = &mut port.port_info;
+            pi.set_mkey(req.m_key());
+            pi.set_gid_prefix(req.gid_prefix());
+            pi.set_lid(req.lid());
+            pi.set_master_sm_lid(req.master_sm_lid());
+            pi.set_m_key_lease_period(req.m_key_lease_period());
+            pi.set_m_key_protect_bits(req.m_key_protect_bits());
+            pi.set_lmc(req.lmc());
+            pi.set_neighbor_mtu(req.neighbor_mtu());
+            pi.set_master_sm_sl(req.master_sm_sl());
+            pi.set_vl_high_limit(req.vl_high_limit());
+            pi.set_hoq_life(req.hoq_life());
+            pi.set_vl_stall_count(req.vl_stall_count());
+            pi.set_partition_enforcement_inbound(req.partition_enforcement_inbound());
+            pi.set_partition_enforcement_outbound(req.partition_enforcement_outbound());
+            pi.set_filter_raw_inbound(req.filter_raw_inbound());
+            pi.set_filter_raw_outbound(req.filter_raw_outbound());
+            pi.set_m_key_violations(req.m_key_violations());
+            pi.set_p_key_violations(req.p_key_violations());
+            pi.set_q_key_violations(req.q_key_violations());
+            pi.set_client_register(req.client_register());
+            pi.set_subnet_timeout(req.subnet_timeout());
+            pi.set_resp_time_value(req.resp_time_value());
+            pi.set_local_phy_errors(req.local_phy_errors());
+            pi.set_overrun_errors(req.overrun_errors());
+
+            // 0xFF / 0xF select everything supported.
+            match req.link_width_enabled() {
+                0 => {}
+                0xff => pi.set_link_width_enabled(pi.link_width_supported()),
+                width => pi.set_link_width_enabled(width),
+            }
+            match req.link_speed_enabled() {
+                0 => {}
+                0xf => pi.set_link_speed_enabled(pi.link_speed_supported()),
+                speed => pi.set_link_speed_enabled(speed),
+            }
+            if req.link_down_default_state() != 0 {
+                pi.set_link_down_default_state(req.link_down_default_state());
+            }
+            if req.operational_vls() != 0 {
+                pi.set_operational_vls(req.operational_vls());
+            }
 
-                log::trace!("[tid: {}] Initial Path: {:?}", tid, dr_smp.initial_path);
+            (port.num, port.remote_port.as_ref().and_then(Weak::upgrade))
+        };
 
-                let mut current_node: Option<Rc<RefCell<Node>>> = None;
-                let mut current_port: Option<Rc<RefCell<Port>>> = None;
+        // Switch port 0 and CA ports carry the LID the node is looked up by.
+        if let Some(parent) = port_rc.read_lock().parent.upgrade() {
+            let mut node = parent.write_lock();
+            if num == 0 || node.switch_info.is_none() {
+                node.lid = req.lid();
+            }
+        }
 
-                // Initialize current_node/port from the agent's location (FIRST_HOP)
-                // This allows paths starting with a port number (non-zero) to work.
-                if let Some(node_weak) = self.dr_paths.get(&FIRST_HOP) {
-                    if let Some(first_hop_port) = node_weak.upgrade() {
-                        let port_ref = first_hop_port.borrow();
-                        if let Some(parent_node) = port_ref.parent.upgrade() {
-                            current_node = Some(parent_node);
-                            current_port = Some(first_hop_port.clone());
-                        }
-                    }
+        if phys_state == 3 {
+            log::info!("[tid: {}] Disabling port {}", tid, num);
+            let mut port = port_rc.write_lock();
+            let was_up = port.port_info.port_physical_state() == 5;
+            if was_up {
+                port.counters.add(Counter::LinkDowned, 1);
+            }
+            port.port_info.set_port_physical_state(3); // Disabled
+            port.port_info.set_port_state(1); // Down
+            if let Some(remote_rc) = &remote_rc {
+                let mut remote = remote_rc.write_lock();
+                if was_up {
+                    remote.counters.add(Counter::LinkDowned, 1);
                 }
+                remote.port_info.set_port_physical_state(2); // Polling
+                remote.port_info.set_port_state(1); // Down
+            }
+        } else if phys_state == 2 || port_state == 1 {
+            let peer = remote_rc.filter(|r| r.read_lock().port_info.port_physical_state() != 3);
+            match peer {
+                Some(peer) => {
+                    log::info!("[tid: {}] Retraining link on port {}", tid, num);
+                    connect_ports(port_rc, &peer);
+                }
+                None => {
+                    let mut port = port_rc.write_lock();
+                    port.port_info.set_port_physical_state(2); // Polling
+                    port.port_info.set_port_state(1); // Down
+                }
+            }
+        } else if port_state >= 3 {
+            // Armed and Active only make sense on a trained link.
+            let mut port = port_rc.write_lock();
+            if port.port_info.port_physical_state() == 5 {
+                port.port_info.set_port_state(port_state);
+            }
+        }
 
-                // --- Path Traversal ---
-                for (index, portnum) in dr_smp.initial_path.iter().enumerate() {
-                    if index == 0 && *portnum == 0 {
-                        log::trace!(
-                            "[tid: {}] Path[{}]: Port 0, initiating traversal from first hop.",
-                            tid,
-                            index
-                        );
+        Ok(())
+    }
 
-                        let node_weak = self.dr_paths.get(&FIRST_HOP).ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("[tid: {}] Unable to find first hop in dr_paths.", tid),
-                            )
-                        })?;
-
-                        let first_hop_port = node_weak.upgrade().ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("[tid: {}] First hop reference is stale.", tid),
-                            )
-                        })?;
-
-                        let port_ref = first_hop_port.borrow();
-
-                        let parent_node = port_ref.parent.upgrade().ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!(
-                                    "[tid: {}] First hop port {} has no parent.",
-                                    tid, port_ref.num
-                                ),
-                            )
-                        })?;
-
-                        log::trace!(
-                            "[tid: {}] Path[{}]: Starting at node '{}'",
-                            tid,
-                            index,
-                            parent_node.borrow().description
-                        );
+    /// Answers an SMP that has reached `current_node`, arriving on `current_port`.
+    /// Directed route and LID routed SMPs share everything past the routing.
+    #[allow(clippy::too_many_arguments)]
+    fn process_smp(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        current_node: Option<Arc<RwLock<Node>>>,
+        current_port: Option<Arc<RwLock<Port>>>,
+        m_key: u64,
+        attr_layout: [u8; 64],
+    ) -> Result<(), io::Error> {
+        let attr_id = mad.attr_id;
 
-                        current_node = Some(parent_node);
-                        current_port = Some(first_hop_port.clone());
-                        continue;
-                    }
+        // PortInfo faults are keyed on the port in the attribute modifier.
+        let fault_node = current_node
+            .as_ref()
+            .map(|n| n.read_lock().node_info.node_guid);
+        let fault_port = if attr_id == 0x1500 {
+            Some(mad.attr_mod.to_be() as u8)
+        } else {
+            current_port.as_ref().map(|p| p.read_lock().num)
+        };
+        if self.inject_faults(tid, umad, mad, fault_node, fault_port)? {
+            return Ok(());
+        }
 
-                    // A port number of 0 signifies the end of the path.
-                    if *portnum == 0 {
-                        log::trace!(
-                            "[tid: {}] Path[{}]: Encountered port 0, path traversal complete.",
-                            tid,
-                            index
-                        );
-                        break;
-                    }
+        let mut hide_m_key = false;
+        if let Some(node_rc) = &current_node {
+            match Fabric::check_m_key(tid, node_rc, current_port.as_ref(), mad.method, m_key) {
+                Some(hide) => hide_m_key = hide,
+                None => return Ok(()),
+            }
+        }
 
-                    let node_rc = current_node.clone().ok_or_else(|| {
-                        io::Error::new(io::ErrorKind::NotFound, format!("[tid: {}] Path traversal failed: current_node is None at index {}.", tid, index))
-                    })?;
+        // Only NodeDescription, PortInfo and LinearForwardingTable can be written.
+        let is_set = mad.method == METHOD_SET;
+        if is_set && attr_id != 0x1000 && attr_id != 0x1500 && attr_id != 0x1900 {
+            log::warn!("[tid: {}] Unhandled Set of AttrID: 0x{:04X}", tid, attr_id);
+            return self.send_dr_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR);
+        }
+
+        if let Some(cn) = &current_node {
+            log::debug!(
+                "[tid: {}] Path traversal finished. Final node: '{}'. Processing AttrID: 0x{:04X}",
+                tid,
+                cn.read_lock().description,
+                attr_id
+            );
+        } else {
+            log::debug!(
+                "[tid: {}] Path traversal finished. Final node: None. Processing AttrID: 0x{:04X}",
+                tid,
+                attr_id
+            );
+        }
+
+        match attr_id {
+            0x1000 => {
+                // NodeDesc
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for NodeDesc query", tid),
+                    )
+                })?;
 
-                    let node_ref = node_rc.borrow();
-                    log::trace!(
-                        "[tid: {}] Path[{}]: Traversing from node '{}' via port {}.",
+                if is_set {
+                    let layout = attr_layout;
+                    let end = layout.iter().position(|&b| b == 0).unwrap_or(layout.len());
+                    let desc = String::from_utf8_lossy(&layout[..end]).to_string();
+                    log::debug!(
+                        "[tid: {}] Setting NodeDesc of '{}' to '{}'",
                         tid,
-                        index,
-                        node_ref.description,
-                        *portnum
+                        node_rc.read_lock().description,
+                        desc
                     );
+                    node_rc.write_lock().description = desc;
+                }
+                let node_ref = node_rc.read_lock();
 
-                    let egress_port_rc = &node_ref
-                        .ports
-                        .iter()
-                        .find(|p| p.borrow().num == *portnum)
-                        .ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!(
-                                    "[tid: {}] Could not find egress port {} on node '{}'",
-                                    tid, *portnum, node_ref.description
-                                ),
-                            )
-                        })?;
-
-                    let egress_port_ref = egress_port_rc.borrow();
-
-                    let remote_port_weak = egress_port_ref.remote_port.as_ref().ok_or_else(|| {
-                        io::Error::new(io::ErrorKind::NotFound, format!("[tid: {}] Port {} on node '{}' is not connected (no remote_port).", tid, *portnum, node_ref.description))
-                    })?;
+                log::debug!(
+                    "[tid: {}] Responding with NodeDesc for '{}': '{}'",
+                    tid,
+                    node_ref.description,
+                    node_ref.description
+                );
 
-                    let remote_port_rc = remote_port_weak.upgrade().ok_or_else(|| {
-                        io::Error::new(
-                            io::ErrorKind::NotFound,
-                            format!(
-                                "[tid: {}] Remote port reference from '{}' port {} is stale.",
-                                tid, node_ref.description, *portnum
-                            ),
-                        )
-                    })?;
+                // NodeDescription is a NUL padded 64 byte string.
+                let mut nd_bytes = [0u8; 64];
+                let desc = node_ref.description.as_bytes();
+                let len = desc.len().min(nd_bytes.len());
+                nd_bytes[..len].copy_from_slice(&desc[..len]);
 
-                    let next_node_rc = {
-                        // Scoped borrow
-                        let remote_port_ref = remote_port_rc.borrow();
-                        remote_port_ref.parent.upgrade().ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!(
-                                    "[tid: {}] Remote port {} has no parent node.",
-                                    tid, remote_port_ref.num
-                                ),
-                            )
-                        })?
-                    };
+                self.send_smp_response(tid, umad, mad, &nd_bytes)?;
+                log::trace!("[tid: {}] Wrote NodeDesc response.", tid);
+            }
+
+            0x1100 => {
+                // NodeInfo
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for NodeInfo query", tid),
+                    )
+                })?;
+                let port_rc = current_port.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target port not found for NodeInfo query", tid),
+                    )
+                })?;
+
+                let node_ref = node_rc.read_lock();
+                let port_ref = port_rc.read_lock();
+
+                log::debug!(
+                    "[tid: {}] Responding with NodeInfo for '{}' from perspective of port {}",
+                    tid,
+                    node_ref.description,
+                    port_ref.num
+                );
+
+                let mut resp_ni = node_ref.node_info.clone();
+                resp_ni.local_port = port_ref.num;
+
+                let ni_bytes = resp_ni.to_bytes();
+
+                self.send_smp_response(tid, umad, mad, &ni_bytes)?;
+                log::trace!("[tid: {}] Wrote NodeInfo response.", tid);
+            }
 
-                    log::trace!(
-                        "[tid: {}] Path[{}]: Arrived at node '{}'",
+            0x1500 => {
+                // PortInfo
+
+                let mut portnum = mad.attr_mod.to_be() as u8;
+
+                log::debug!("[tid: {}] Received PortInfo for port {}", tid, portnum,);
+
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for NodeInfo query", tid),
+                    )
+                })?;
+                let node_ref = node_rc.read_lock();
+
+                // Port 0 is the switch management port; on a CA it means the
+                // port the SMP arrived on.
+                let has_port0 = node_ref.switch_info.is_some()
+                    && node_ref.ports.iter().any(|p| p.read_lock().num == 0);
+                if portnum == 0 && !has_port0 {
+                    portnum = current_port
+                        .as_ref()
+                        .map(|p| p.read_lock().num)
+                        .filter(|_| node_ref.switch_info.is_none())
+                        .unwrap_or(1);
+                }
+
+                let Some(target_port_rc) = node_ref
+                    .ports
+                    .iter()
+                    .find(|p| p.read_lock().num == portnum)
+                    .cloned()
+                else {
+                    log::debug!(
+                        "[tid: {}] No port {} on node '{}'",
                         tid,
-                        index,
-                        next_node_rc.borrow().description
+                        portnum,
+                        node_ref.description
                     );
-                    current_node = Some(next_node_rc);
-                    current_port = Some(remote_port_rc);
+                    drop(node_ref);
+                    return self.send_dr_error(tid, umad, mad, MAD_STATUS_INVALID_ATTR_VALUE);
+                };
+
+                if is_set {
+                    // The Set may touch the node LID and the peer port.
+                    drop(node_ref);
+                    let req = port_info::from_bytes(&attr_layout).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Unable to parse PortInfo")
+                    })?;
+                    if let Err(status) = Fabric::apply_port_info_set(tid, &target_port_rc, &req) {
+                        return self.send_dr_error(tid, umad, mad, status);
+                    }
                 }
+                let node_ref = node_rc.read_lock();
+                let target_port_ref = target_port_rc.read_lock();
 
-                let attr_id = mad.attr_id;
+                log::debug!(
+                    "[tid: {}] Responding with PortInfo for port {} on node '{}' (LID: {}) logical_state: {}, phy_state: {}",
+                    tid,
+                    portnum,
+                    node_ref.description,
+                    target_port_ref.port_info.lid(),
+                    target_port_ref.port_info.port_state(),
+                    target_port_ref.port_info.port_physical_state(),
+                );
 
-                if let Some(cn) = &current_node {
+                let mut resp_pi = target_port_ref.port_info;
+                if hide_m_key {
+                    resp_pi.set_mkey(0);
+                }
+                let pi_bytes = resp_pi.to_bytes();
+
+                self.send_smp_response(tid, umad, mad, &pi_bytes)?;
+                log::trace!("[tid: {}] Wrote PortInfo response.", tid);
+            }
+            0x1200 => {
+                // SwitchInfo
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for SwitchInfo query", tid),
+                    )
+                })?;
+                let node_ref = node_rc.read_lock();
+
+                let Some(si) = node_ref.switch_info else {
                     log::debug!(
-                        "[tid: {}] Path traversal finished. Final node: '{}'. Processing AttrID: 0x{:04X}",
+                        "[tid: {}] SwitchInfo requested from non-switch '{}'",
                         tid,
-                        cn.borrow().description,
-                        attr_id
+                        node_ref.description
                     );
+                    drop(node_ref);
+                    return self.send_dr_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR);
+                };
+
+                log::debug!(
+                    "[tid: {}] Responding with SwitchInfo for '{}'",
+                    tid,
+                    node_ref.description
+                );
+
+                self.send_smp_response(tid, umad, mad, &si.to_bytes())?;
+                log::trace!("[tid: {}] Wrote SwitchInfo response.", tid);
+            }
+
+            0x1400 => {
+                // GUIDInfo
+                let block = mad.attr_mod.to_be() as usize;
+
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for GUIDInfo query", tid),
+                    )
+                })?;
+                let node_ref = node_rc.read_lock();
+
+                // Only switch port 0 and CA ports carry GUIDs.
+                let target_port_rc = if node_ref.switch_info.is_some() {
+                    node_ref
+                        .ports
+                        .iter()
+                        .find(|p| p.read_lock().num == 0)
+                        .cloned()
                 } else {
-                    log::debug!(
-                        "[tid: {}] Path traversal finished. Final node: None. Processing AttrID: 0x{:04X}",
-                        tid,
-                        attr_id
-                    );
+                    current_port
                 }
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "[tid: {}] Target port not found for GUIDInfo query on '{}'",
+                            tid, node_ref.description
+                        ),
+                    )
+                })?;
 
-                match attr_id {
-                    0x1000 => {
-                        // NodeDesc
-                        let node_rc = current_node.ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("[tid: {}] Target node not found for NodeDesc query", tid),
-                            )
-                        })?;
-                        let node_ref = node_rc.borrow();
+                let target_port_ref = target_port_rc.read_lock();
+                let guids = if target_port_ref.guids.is_empty() {
+                    vec![node_ref.node_info.port_guid]
+                } else {
+                    target_port_ref.guids.clone()
+                };
+                let start = block * mad::guid::GUID_BLOCK_SIZE;
+                // NodeInfo is sent as stored, so the entries are too.
+                let entries: Vec<u64> = guids
+                    .get(start..)
+                    .unwrap_or(&[])
+                    .iter()
+                    .map(|guid| guid.to_be())
+                    .collect();
 
-                        log::debug!(
-                            "[tid: {}] Responding with NodeDesc for '{}': '{}'",
-                            tid,
-                            node_ref.description,
-                            node_ref.description
-                        );
+                log::debug!(
+                    "[tid: {}] Responding with GUIDInfo block {} for port {} on node '{}'",
+                    tid,
+                    block,
+                    target_port_ref.num,
+                    node_ref.description
+                );
 
-                        let resp_nd_str = &node_ref.description;
-                        let nd_bytes = resp_nd_str.as_bytes();
+                let table = mad::guid_info::from_entries(&entries);
+                self.send_smp_response(tid, umad, mad, &table.to_bytes())?;
+                log::trace!("[tid: {}] Wrote GUIDInfo response.", tid);
+            }
+            0x1600 => {
+                // P_KeyTable
+                let attr_mod = mad.attr_mod.to_be();
+                let block = (attr_mod & 0xffff) as usize;
+
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for P_KeyTable query", tid),
+                    )
+                })?;
+                let node_ref = node_rc.read_lock();
 
-                        self.send_dr_response(tid, &umad, &mad, &dr_smp, &nd_bytes)?;
-                        log::trace!("[tid: {}] Wrote NodeDesc response.", tid);
-                    }
+                // Switches address the port through the attribute modifier,
+                // CAs answer for the port the SMP arrived on.
+                let target_port_rc = if node_ref.switch_info.is_some() {
+                    let portnum = (attr_mod >> 16) as u8;
+                    node_ref
+                        .ports
+                        .iter()
+                        .find(|p| p.read_lock().num == portnum)
+                        .cloned()
+                } else {
+                    current_port
+                };
+                let Some(target_port_rc) = target_port_rc else {
+                    log::debug!(
+                        "[tid: {}] Target port not found for P_KeyTable query on '{}'",
+                        tid,
+                        node_ref.description
+                    );
+                    drop(node_ref);
+                    return self.send_dr_error(tid, umad, mad, MAD_STATUS_INVALID_ATTR_VALUE);
+                };
 
-                    0x1100 => {
-                        // NodeInfo
-                        let node_rc = current_node.ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("[tid: {}] Target node not found for NodeInfo query", tid),
-                            )
-                        })?;
-                        let port_rc = current_port.ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("[tid: {}] Target port not found for NodeInfo query", tid),
-                            )
-                        })?;
-
-                        let node_ref = node_rc.borrow();
-                        let port_ref = port_rc.borrow();
+                let target_port_ref = target_port_rc.read_lock();
+                let start = block * mad::pkey::PKEY_BLOCK_SIZE;
+                let entries: &[u16] = target_port_ref.pkeys.get(start..).unwrap_or(&[]);
 
-                        log::debug!(
-                            "[tid: {}] Responding with NodeInfo for '{}' from perspective of port {}",
-                            tid,
-                            node_ref.description,
-                            port_ref.num
-                        );
+                log::debug!(
+                    "[tid: {}] Responding with P_KeyTable block {} for port {} on node '{}'",
+                    tid,
+                    block,
+                    target_port_ref.num,
+                    node_ref.description
+                );
 
-                        let mut resp_ni = node_ref.node_info.clone();
-                        resp_ni.local_port = port_ref.num;
+                let table = pkey_table::from_entries(entries);
+                self.send_smp_response(tid, umad, mad, &table.to_bytes())?;
+                log::trace!("[tid: {}] Wrote P_KeyTable response.", tid);
+            }
+            0x1700 | 0x1800 => {
+                // SLtoVLMappingTable / VLArbitrationTable
+                let attr_mod = mad.attr_mod.to_be();
+                let is_sl2vl = attr_id == 0x1700;
+
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for QoS query", tid),
+                    )
+                })?;
+                let node_ref = node_rc.read_lock();
 
-                        let ni_bytes = resp_ni.to_bytes();
+                let target_port_rc = if node_ref.switch_info.is_some() {
+                    let portnum = if is_sl2vl {
+                        (attr_mod & 0xff) as u8
+                    } else {
+                        (attr_mod >> 16) as u8
+                    };
+                    node_ref
+                        .ports
+                        .iter()
+                        .find(|p| p.read_lock().num == portnum)
+                        .cloned()
+                } else {
+                    current_port
+                };
+                let Some(target_port_rc) = target_port_rc else {
+                    log::debug!(
+                        "[tid: {}] Target port not found for QoS query on '{}'",
+                        tid,
+                        node_ref.description
+                    );
+                    drop(node_ref);
+                    return self.send_dr_error(tid, umad, mad, MAD_STATUS_INVALID_ATTR_VALUE);
+                };
+                let target_port_ref = target_port_rc.read_lock();
 
-                        self.send_dr_response(tid, &umad, &mad, &dr_smp, &ni_bytes)?;
-                        log::trace!("[tid: {}] Wrote NodeInfo response.", tid);
+                let attr_bytes = if is_sl2vl {
+                    target_port_ref.sl2vl.to_bytes()
+                } else {
+                    let block = (attr_mod & 0xffff) as usize;
+                    if !(1..=4).contains(&block) {
+                        log::debug!("[tid: {}] Invalid VLArbitration block {}", tid, block);
+                        drop(target_port_ref);
+                        drop(node_ref);
+                        return self.send_dr_error(tid, umad, mad, MAD_STATUS_INVALID_ATTR_VALUE);
                     }
+                    target_port_ref.vl_arb[block - 1].to_bytes()
+                };
 
-                    0x1500 => {
-                        // PortInfo
+                log::debug!(
+                    "[tid: {}] Responding with AttrID 0x{:04X} for port {} on node '{}'",
+                    tid,
+                    u16::from_be(attr_id),
+                    target_port_ref.num,
+                    node_ref.description
+                );
 
-                        let mut portnum = mad.attr_mod.to_be() as u8;
+                self.send_smp_response(tid, umad, mad, &attr_bytes)?;
+            }
+            0x1900 => {
+                // LinearForwardingTable
+                let block = mad.attr_mod.to_be() as usize;
+
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for LFT query", tid),
+                    )
+                })?;
+                let Some(si) = node_rc.read_lock().switch_info else {
+                    return self.send_dr_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR);
+                };
 
-                        log::debug!("[tid: {}] Received PortInfo for port {}", tid, portnum,);
+                let start = block * LFT_BLOCK_SIZE;
+                let end = start + LFT_BLOCK_SIZE;
+                if start >= si.linear_fdb_cap() as usize {
+                    log::debug!("[tid: {}] LFT block {} is past LinearFDBCap", tid, block);
+                    return self.send_dr_error(tid, umad, mad, MAD_STATUS_INVALID_ATTR_VALUE);
+                }
 
-                        if portnum == 0 {
-                            portnum = 1;
-                        }
+                if is_set {
+                    let mut node = node_rc.write_lock();
+                    log::debug!(
+                        "[tid: {}] Setting LFT block {} of '{}'",
+                        tid,
+                        block,
+                        node.description
+                    );
+                    if node.lft.len() < end {
+                        node.lft.resize(end, LFT_NO_ROUTE);
+                    }
+                    node.lft[start..end].copy_from_slice(&attr_layout);
+                    // SwitchInfo can't be Set here, so a block past LinearFDBTop raises it.
+                    if let Some(si) = node.switch_info.as_mut()
+                        && si.linear_fdb_top() < (end - 1) as u16
+                    {
+                        si.set_linear_fdb_top((end - 1) as u16);
+                    }
+                }
 
-                        let node_rc = current_node.ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::NotFound,
-                                format!("[tid: {}] Target node not found for NodeInfo query", tid),
-                            )
-                        })?;
-                        let node_ref = node_rc.borrow();
-
-                        let target_port_rc = node_ref
-                            .ports
-                            .iter()
-                            .find(|p| p.borrow().num == portnum)
-                            .ok_or_else(|| {
-                                io::Error::new(
-                                    io::ErrorKind::NotFound,
-                                    format!(
-                                        "[tid: {}] Could not find egress port {} on node '{}'",
-                                        tid, portnum, node_ref.description
-                                    ),
-                                )
-                            })?;
-
-                        let target_port_ref = target_port_rc.borrow();
+                let resp = {
+                    let node = node_rc.read_lock();
+                    let entries = node.lft.get(start..end.min(node.lft.len()));
+                    lft_block::from_entries(entries.unwrap_or_default())
+                };
+                log::debug!(
+                    "[tid: {}] Responding with LFT block {} for '{}'",
+                    tid,
+                    block,
+                    node_rc.read_lock().description
+                );
 
+                self.send_smp_response(tid, umad, mad, &resp.to_bytes())?;
+            }
+            0x2000 => {
+                // SMInfo
+                let node_rc = current_node.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("[tid: {}] Target node not found for SMInfo query", tid),
+                    )
+                })?;
+                let sm_info = node_rc.read_lock().sm_info;
+
+                match sm_info {
+                    Some(smi) => {
                         log::debug!(
-                            "[tid: {}] Responding with PortInfo for port {} on node '{}' (LID: {}) logical_state: {}, phy_state: {}",
+                            "[tid: {}] Responding with SMInfo for '{}'",
                             tid,
-                            portnum,
-                            node_ref.description,
-                            target_port_ref.port_info.lid(),
-                            target_port_ref.port_info.port_state(),
-                            target_port_ref.port_info.port_physical_state(),
+                            node_rc.read_lock().description
                         );
-
-                        let resp_pi = target_port_ref.port_info;
-                        let pi_bytes = resp_pi.to_bytes();
-
-                        self.send_dr_response(tid, &umad, &mad, &dr_smp, &pi_bytes)?;
-                        log::trace!("[tid: {}] Wrote PortInfo response.", tid);
+                        self.send_smp_response(tid, umad, mad, &smi.to_bytes())?;
                     }
-                    _ => {
-                        log::warn!("[tid: {}] Unhandled SubnAdm AttrID: 0x{:04X}", tid, attr_id);
+                    None => {
+                        self.send_dr_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR)?;
                     }
                 }
             }
+            _ => {
+                log::warn!("[tid: {}] Unhandled SubnAdm AttrID: 0x{:04X}", tid, attr_id);
+                self.send_dr_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads one UMAD from `file` and writes the responses back to it.
+    pub fn process_one_umad(&mut self) -> Result<(), io::Error> {
+        let mut buf: [u8; 320] = [0; 320];
+        let r = self.file.read(&mut buf)?;
+        log::trace!("Read {} bytes from UMAD file.", r);
+
+        for response in self.process_umad(DEFAULT_CLIENT, &buf[..r])? {
+            if !response.delay.is_zero() {
+                std::thread::sleep(response.delay);
+            }
+            self.file.write_all(&response.bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Processes one UMAD sent by `client` and returns the responses, in the order
+    /// they are to be sent. Dropped requests have none. Traps raised by fabric events
+    /// since the client's last MAD go first.
+    pub fn process_umad(
+        &mut self,
+        client: ClientId,
+        buf: &[u8],
+    ) -> Result<Vec<Response>, io::Error> {
+        self.client = client;
+        self.fire_events();
+        self.expire_sa_transfers();
+        self.dr_route = None;
+        self.outbox.clear();
+        self.send_pending_traps();
+        self.pending_delay = time::Duration::ZERO;
+        self.handle_umad(buf)?;
+        Ok(std::mem::take(&mut self.outbox))
+    }
+
+    fn handle_umad(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        let r = buf.len();
+        if r < MIN_UMAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "UMAD too small: expected at least {} bytes, got {}",
+                    MIN_UMAD_SIZE, r
+                ),
+            ));
+        }
+
+        let umad = ib_user_mad::from_bytes(buf).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Failed to parse ib_user_mad")
+        })?;
+
+        let mad = ib_mad::from_bytes(&umad.data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Failed to parse ib_mad"))?;
+
+        // Use the transaction ID for correlated logging
+        let tid = mad.tid;
+        self.fault = FaultAction::default();
+        let attr_id = mad.attr_id;
+        log::debug!(
+            "[tid: {}] Received MAD. Class: 0x{:02X}, AttrID: 0x{:04X}",
+            tid,
+            mad.mgmt_class,
+            attr_id
+        );
+
+        match mad.mgmt_class {
+            0x81 => {
+                // SubnMgt (Directed Route)
+                self.process_dr_smp(tid, &umad, &mad)?;
+            }
             0x4 => {
                 // Performance Management
                 log::trace!("[tid: {}] Processing Performance Management MAD.", tid);
@@ -612,58 +1532,80 @@ impl Fabric {
                 let dest_lid = u16::from_be(umad.addr.lid);
                 let port_select = perf_req.port_select();
 
-                // Find node by LID
-                // Since we don't implement full forwarding, we cheat and search all nodes for the LID.
-                let mut target_node: Option<Rc<RefCell<Node>>> = None;
-                for node_rc in &self.nodes {
-                    if node_rc.borrow().lid == dest_lid {
-                         target_node = Some(node_rc.clone());
-                         break;
-                    }
-                    // Check ports too if needed, but simplified model assumes node LID match or port LID match
-                    // Port 0 usually has the Node LID.
-                }
+                // GMPs are forwarded like SMPs; an unreachable LID is never answered.
+                let target_node = self.route_lid(tid, dest_lid).map(|(node, _)| node);
 
                 if let Some(node) = target_node {
-                    match attr_id {
-                        0x001D => {
-                            // PortCountersExtended
-                            log::debug!("[tid: {}] Received PortCountersExtended for Node '{}' Port {}", tid, node.borrow().description, port_select);
-                            
-                            // Check if port exists
-                            let node_ref = node.borrow();
-                            let port_exists = node_ref.ports.iter().any(|p| p.borrow().num == port_select);
-                            
-                            if !port_exists {
-                                log::warn!("[tid: {}] Port {} not found on node '{}'", tid, port_select, node_ref.description);
-                                // Should return error MAD...
-                            } else {
-                                // Construct dummy response
-                                let mut resp = perf_req; // Copy request to preserve other fields
-                                
-                                // Set some dummy counters based on port number to verify unique data
-                                resp.set_port_xmit_data((1000 * port_select as u64) + 1);
-                                resp.set_port_rcv_data((2000 * port_select as u64) + 2);
-                                resp.set_port_xmit_pkts((10 * port_select as u64) + 3);
-                                resp.set_port_rcv_pkts((20 * port_select as u64) + 4);
-                                
-                                self.send_perf_response(tid, &umad, &mad, &resp)?;
-                            }
-                        }
-                        _ => {
-                             log::warn!("[tid: {}] Unhandled Perf AttrID: 0x{:04X}", tid, attr_id);
-                        }
+                    let node_guid = node.read_lock().node_info.node_guid;
+                    if self.inject_faults(tid, &umad, &mad, Some(node_guid), Some(port_select))? {
+                        return Ok(());
                     }
+                    self.process_perf(tid, &umad, &mad, &node)?;
                 } else {
-                    log::warn!("[tid: {}] Target LID {} not found in fabric.", tid, dest_lid);
+                    log::debug!("[tid: {}] Target LID {} is unreachable.", tid, dest_lid);
                 }
 
             }
+            0x3 => {
+                // Subnet Administration
+                let sa_req = mad::sa_mad::from_bytes(&mad.data).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Unable to parse SA MAD")
+                })?;
+
+                // ACKs steer a GetTable response that is already under way.
+                if sa_req.rmpp_rtime_flags & mad::sa::RMPP_FLAG_ACTIVE != 0
+                    && sa_req.rmpp_type != mad::sa::RMPP_TYPE_DATA
+                {
+                    return self.process_sa_rmpp(tid, &sa_req);
+                }
+
+                let dest_lid = u16::from_be(umad.addr.lid);
+                let Some((node_rc, _)) = self.route_lid(tid, dest_lid) else {
+                    return Ok(());
+                };
+
+                // The SA runs alongside the master SM.
+                let is_master = node_rc
+                    .read_lock()
+                    .sm_info
+                    .is_some_and(|smi| smi.sm_state() == crate::enums::SmState::Master as u8);
+                if !is_master {
+                    log::debug!("[tid: {}] No master SM at LID {}, dropping.", tid, dest_lid);
+                    return Ok(());
+                }
+
+                let node_guid = node_rc.read_lock().node_info.node_guid;
+                if self.inject_faults(tid, &umad, &mad, Some(node_guid), None)? {
+                    return Ok(());
+                }
+                self.process_sa(tid, &umad, &mad, &sa_req)?;
+            }
             0x1 => {
-                log::debug!(
-                    "[tid: {}] Received LID-Routed MAD. (Currently unhandled)",
-                    tid
+                // SubnMgt (LID Routed)
+                let smp = mad::smp_mad::from_bytes(&mad.data).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Unable to parse SMP")
+                })?;
+
+                let dest_lid = u16::from_be(umad.addr.lid);
+                log::trace!(
+                    "[tid: {}] Processing LID routed SMP for LID {}.",
+                    tid,
+                    dest_lid
                 );
+
+                let Some((node_rc, port_rc)) = self.route_lid(tid, dest_lid) else {
+                    return Ok(());
+                };
+
+                self.process_smp(
+                    tid,
+                    &umad,
+                    &mad,
+                    Some(node_rc),
+                    Some(port_rc),
+                    u64::from_be(smp.m_key),
+                    smp.attr_layout,
+                )?;
             }
 
             _ => {
@@ -707,14 +1649,20 @@ impl Fabric {
 }
 
 impl Port {
-    pub fn new_port(num: u8, lid: u16, parent: Rc<RefCell<Node>>) -> Port {
+    pub fn new_port(num: u8, lid: u16, parent: Arc<RwLock<Node>>) -> Port {
         let mut port_info = port_info { data: [0; 64] };
 
         port_info.set_local_portnum(num);
         port_info.set_lid(lid);
 
-        port_info.set_port_state(1); // Down 
-        port_info.set_port_physical_state(2); // Polling
+        if num == 0 {
+            // Switch management port, always up.
+            port_info.set_port_state(4); // Active
+            port_info.set_port_physical_state(5); // LinkUp
+        } else {
+            port_info.set_port_state(1); // Down
+            port_info.set_port_physical_state(2); // Polling
+        }
 
         port_info.set_link_speed_supported(1);
         port_info.set_link_speed_enabled(1);
@@ -723,11 +1671,32 @@ impl Port {
         port_info.set_link_width_enabled(1);
         port_info.set_link_width_active(1);
 
+        port_info.set_mtu_cap(5); // 4096
+        port_info.set_neighbor_mtu(5);
+
+        port_info.set_guid_cap(8);
+
+        port_info.set_vl_cap(4); // VL0-7
+        port_info.set_operational_vls(4);
+        port_info.set_vl_high_limit(4);
+        port_info.set_vl_arbitration_high_cap(8);
+        port_info.set_vl_arbitration_low_cap(8);
+
+        let low: Vec<VlArbEntry> = (0..8).map(|vl| VlArbEntry { vl, weight: 64 }).collect();
+        let empty = vl_arb_table { data: [0; 64] };
+
         let port = Port {
             num: num,
             port_info: port_info,
+            guids: Vec::new(),
+            pkeys: vec![mad::pkey::PKEY_DEFAULT],
+            sl2vl: sl2vl_table::uniform(8),
+            vl_arb: [vl_arb_table::from_entries(&low), empty, empty, empty],
             remote_port: None,
-            parent: Rc::downgrade(&parent),
+            parent: Arc::downgrade(&parent),
+            counters: PortCounters::default(),
+            error_rates: Vec::new(),
+            powered_off: false,
         };
 
         port
@@ -746,21 +1715,32 @@ impl Node {
                 system_guid: guid,
                 node_guid: guid,
                 port_guid: guid,
-                partition_cap: 128,
-                device_id: 128,
+                partition_cap: 128u16.to_be(),
+                device_id: 128u16.to_be(),
                 revision: 0,
                 local_port: 1,
                 vendor_id: [0x00, 0x02, 0xc9],
                 reserved: [0; 24],
             },
+            switch_info: None,
+            sm_info: None,
             ports: Vec::new(),
             lid: 0, // Will be set by port later ideally, but simpler here
+            lft: Vec::new(),
         };
 
         hca
     }
 
     pub fn new_switch(description: &str, guid: u64) -> Node {
+        let mut si = switch_info { data: [0; 64] };
+        si.set_linear_fdb_cap(0xc000);
+        si.set_multicast_fdb_cap(0x4000);
+        si.set_lids_per_port(1);
+        si.set_partition_enforcement_cap(32);
+        si.set_inbound_enforcement_cap(1);
+        si.set_outbound_enforcement_cap(1);
+
         let switch = Node {
             description: description.to_owned(),
             node_info: node_info {
@@ -771,15 +1751,18 @@ impl Node {
                 system_guid: guid,
                 node_guid: guid,
                 port_guid: guid,
-                partition_cap: 8,
-                device_id: 0xd2f2,
-                revision: 0x0000_00a0,
+                partition_cap: 8u16.to_be(),
+                device_id: 0xd2f2u16.to_be(),
+                revision: 0x0000_00a0u32.to_be(),
                 local_port: 0, // Port 0 is the management port
                 vendor_id: [0x00, 0xcf, 0x09],
                 reserved: [0; 24],
             },
+            switch_info: Some(si),
+            sm_info: None,
             ports: Vec::new(),
             lid: 0,
+            lft: Vec::new(),
         };
 
         switch
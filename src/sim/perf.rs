@@ -0,0 +1,418 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    time,
+};
+
+use super::{
+    Fabric, MAD_STATUS_INVALID_ATTR_VALUE, MAD_STATUS_UNSUP_METHOD_ATTR, METHOD_GET,
+    METHOD_GET_RESP, METHOD_SET, Node, Port, Shared, active_remote,
+};
+use crate::mad::{ib_mad, ib_user_mad, perf_mad, port_counters};
+
+/// PortSelect that addresses every port of the node at once.
+pub const ALL_PORT_SELECT: u8 = 0xff;
+
+/// A PerfMgt counter of a simulated port.
+///
+/// The first 17 are in PortCounters CounterSelect bit order, PortXmitWait being bit 0
+/// of CounterSelect2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Counter {
+    SymbolErrors,
+    LinkErrorRecovery,
+    LinkDowned,
+    RcvErrors,
+    RcvRemotePhysicalErrors,
+    RcvSwitchRelayErrors,
+    XmitDiscards,
+    XmitConstraintErrors,
+    RcvConstraintErrors,
+    LocalLinkIntegrityErrors,
+    ExcessiveBufferOverrunErrors,
+    Vl15Dropped,
+    XmitData,
+    RcvData,
+    XmitPkts,
+    RcvPkts,
+    XmitWait,
+    UnicastXmitPkts,
+    UnicastRcvPkts,
+    MulticastXmitPkts,
+    MulticastRcvPkts,
+    Qp1Dropped,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 22] = [
+        Counter::SymbolErrors,
+        Counter::LinkErrorRecovery,
+        Counter::LinkDowned,
+        Counter::RcvErrors,
+        Counter::RcvRemotePhysicalErrors,
+        Counter::RcvSwitchRelayErrors,
+        Counter::XmitDiscards,
+        Counter::XmitConstraintErrors,
+        Counter::RcvConstraintErrors,
+        Counter::LocalLinkIntegrityErrors,
+        Counter::ExcessiveBufferOverrunErrors,
+        Counter::Vl15Dropped,
+        Counter::XmitData,
+        Counter::RcvData,
+        Counter::XmitPkts,
+        Counter::RcvPkts,
+        Counter::XmitWait,
+        Counter::UnicastXmitPkts,
+        Counter::UnicastRcvPkts,
+        Counter::MulticastXmitPkts,
+        Counter::MulticastRcvPkts,
+        Counter::Qp1Dropped,
+    ];
+
+    /// Width of the counter's PortCounters field; `None` for counters only
+    /// PortCountersExtended reports.
+    fn width(self) -> Option<u32> {
+        match self {
+            Counter::LinkErrorRecovery
+            | Counter::LinkDowned
+            | Counter::XmitConstraintErrors
+            | Counter::RcvConstraintErrors => Some(8),
+            Counter::LocalLinkIntegrityErrors | Counter::ExcessiveBufferOverrunErrors => Some(4),
+            Counter::XmitData
+            | Counter::RcvData
+            | Counter::XmitPkts
+            | Counter::RcvPkts
+            | Counter::XmitWait => Some(32),
+            Counter::UnicastXmitPkts
+            | Counter::UnicastRcvPkts
+            | Counter::MulticastXmitPkts
+            | Counter::MulticastRcvPkts
+            | Counter::Qp1Dropped => None,
+            _ => Some(16),
+        }
+    }
+
+    /// Whether a PortCounters Set with these selects resets the counter.
+    fn port_counters_selected(self, select: u16, select2: u8) -> bool {
+        let mask = u32::from(select) | (u32::from(select2) << 16);
+        self.width().is_some() && mask & (1 << self as u32) != 0
+    }
+
+    /// Whether a PortCountersExtended Set with these selects resets the counter:
+    /// CounterSelect picks the data counters, CounterSelect2 the error counters.
+    fn extended_selected(self, select: u16, select2: u32) -> bool {
+        let i = self as u32;
+        match self {
+            Counter::XmitData | Counter::RcvData | Counter::XmitPkts | Counter::RcvPkts => {
+                select & (1 << (i - Counter::XmitData as u32)) != 0
+            }
+            Counter::UnicastXmitPkts
+            | Counter::UnicastRcvPkts
+            | Counter::MulticastXmitPkts
+            | Counter::MulticastRcvPkts => {
+                select & (1 << (4 + i - Counter::UnicastXmitPkts as u32)) != 0
+            }
+            Counter::XmitWait => select2 & (1 << 12) != 0,
+            Counter::Qp1Dropped => select2 & (1 << 13) != 0,
+            _ => select2 & (1 << i) != 0,
+        }
+    }
+}
+
+/// Counter state of a simulated port. Values are kept at 64 bits; PortCounters reports
+/// them saturated to its narrower fields.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortCounters([u64; Counter::ALL.len()]);
+
+impl PortCounters {
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.0[counter as usize]
+    }
+
+    pub fn add(&mut self, counter: Counter, n: u64) {
+        let value = &mut self.0[counter as usize];
+        *value = value.saturating_add(n);
+    }
+
+    pub fn reset(&mut self, counter: Counter) {
+        self.0[counter as usize] = 0;
+    }
+
+    fn saturated(&self, counter: Counter) -> u64 {
+        let max = counter.width().map_or(u64::MAX, |w| (1 << w) - 1);
+        self.get(counter).min(max)
+    }
+
+    fn fill_port_counters(&self, resp: &mut port_counters) {
+        let get = |c| self.saturated(c);
+        resp.set_symbol_error_counter(get(Counter::SymbolErrors) as u16);
+        resp.set_link_error_recovery_counter(get(Counter::LinkErrorRecovery) as u8);
+        resp.set_link_downed_counter(get(Counter::LinkDowned) as u8);
+        resp.set_port_rcv_errors(get(Counter::RcvErrors) as u16);
+        resp.set_port_rcv_remote_physical_errors(get(Counter::RcvRemotePhysicalErrors) as u16);
+        resp.set_port_rcv_switch_relay_errors(get(Counter::RcvSwitchRelayErrors) as u16);
+        resp.set_port_xmit_discards(get(Counter::XmitDiscards) as u16);
+        resp.set_port_xmit_constraint_errors(get(Counter::XmitConstraintErrors) as u8);
+        resp.set_port_rcv_constraint_errors(get(Counter::RcvConstraintErrors) as u8);
+        resp.set_local_link_integrity_errors(get(Counter::LocalLinkIntegrityErrors) as u8);
+        resp.set_excessive_buffer_overrun_errors(get(Counter::ExcessiveBufferOverrunErrors) as u8);
+        resp.set_vl15_dropped(get(Counter::Vl15Dropped) as u16);
+        resp.set_port_xmit_data(get(Counter::XmitData) as u32);
+        resp.set_port_rcv_data(get(Counter::RcvData) as u32);
+        resp.set_port_xmit_pkts(get(Counter::XmitPkts) as u32);
+        resp.set_port_rcv_pkts(get(Counter::RcvPkts) as u32);
+        resp.set_port_xmit_wait(get(Counter::XmitWait) as u32);
+    }
+
+    fn fill_extended(&self, resp: &mut perf_mad) {
+        let get = |c| self.get(c);
+        resp.set_port_xmit_data(get(Counter::XmitData));
+        resp.set_port_rcv_data(get(Counter::RcvData));
+        resp.set_port_xmit_pkts(get(Counter::XmitPkts));
+        resp.set_port_rcv_pkts(get(Counter::RcvPkts));
+        resp.set_port_unicast_xmit_pkts(get(Counter::UnicastXmitPkts));
+        resp.set_port_unicast_rcv_pkts(get(Counter::UnicastRcvPkts));
+        resp.set_port_multicast_xmit_pkts(get(Counter::MulticastXmitPkts));
+        resp.set_port_multicast_rcv_pkts(get(Counter::MulticastRcvPkts));
+        resp.set_symbol_error_counter(get(Counter::SymbolErrors));
+        resp.set_link_error_recovery_counter(get(Counter::LinkErrorRecovery));
+        resp.set_link_downed_counter(get(Counter::LinkDowned));
+        resp.set_port_rcv_errors(get(Counter::RcvErrors));
+        resp.set_port_rcv_remote_physical_errors(get(Counter::RcvRemotePhysicalErrors));
+        resp.set_port_rcv_switch_relay_errors(get(Counter::RcvSwitchRelayErrors));
+        resp.set_port_xmit_discards(get(Counter::XmitDiscards));
+        resp.set_port_xmit_constraint_errors(get(Counter::XmitConstraintErrors));
+        resp.set_port_rcv_constraint_errors(get(Counter::RcvConstraintErrors));
+        resp.set_local_link_integrity_errors(get(Counter::LocalLinkIntegrityErrors));
+        resp.set_excessive_buffer_overrun_errors(get(Counter::ExcessiveBufferOverrunErrors));
+        resp.set_vl15_dropped(get(Counter::Vl15Dropped));
+        resp.set_port_xmit_wait(get(Counter::XmitWait));
+        resp.set_qp1_dropped(get(Counter::Qp1Dropped));
+    }
+
+    fn sum(ports: &[Arc<RwLock<Port>>]) -> PortCounters {
+        let mut total = PortCounters::default();
+        for port in ports {
+            let port = port.read_lock();
+            for counter in Counter::ALL {
+                total.add(counter, port.counters.get(counter));
+            }
+        }
+        total
+    }
+}
+
+/// Traffic every Active link carries in each direction, and the errors every link
+/// picks up. Rates are per second of simulated time; `Port::error_rates` adds errors
+/// to single ports.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrafficModel {
+    pub pkts_per_sec: f64,
+    /// PortXmitData and PortRcvData count 4-byte words of this.
+    pub bytes_per_pkt: u32,
+    /// Share of the packets sent as multicast, 0.0 to 1.0.
+    pub multicast_ratio: f64,
+    /// Counters that grow on every Active port, e.g. `(Counter::SymbolErrors, 0.5)`.
+    pub errors: Vec<(Counter, f64)>,
+    /// Advances the counters by the wall-clock time between PerfMgt requests. Otherwise
+    /// only `Fabric::advance_counters` moves them.
+    pub realtime: bool,
+}
+
+impl Fabric {
+    /// Runs the traffic model for `elapsed` of simulated time over every port with an
+    /// Active link.
+    pub fn advance_counters(&mut self, elapsed: time::Duration) {
+        let from = self.counter_time.as_secs_f64();
+        self.counter_time += elapsed;
+        let to = self.counter_time.as_secs_f64();
+        // Counting whole events since time zero keeps slow rates from being rounded
+        // away when the counters are advanced in small steps.
+        let events = |rate: f64| ((rate * to).floor() - (rate * from).floor()).max(0.0) as u64;
+
+        let model = &self.traffic;
+        let pkts = events(model.pkts_per_sec);
+        let multicast =
+            events(model.pkts_per_sec * model.multicast_ratio.clamp(0.0, 1.0)).min(pkts);
+        let words = pkts.saturating_mul(u64::from(model.bytes_per_pkt.div_ceil(4)));
+        let errors: Vec<(Counter, u64)> = model
+            .errors
+            .iter()
+            .map(|&(c, rate)| (c, events(rate)))
+            .collect();
+
+        for node in &self.nodes {
+            for port_rc in &node.read_lock().ports {
+                if active_remote(port_rc).is_none() {
+                    continue;
+                }
+                let mut port = port_rc.write_lock();
+                let port_errors: Vec<(Counter, u64)> = port
+                    .error_rates
+                    .iter()
+                    .map(|&(c, rate)| (c, events(rate)))
+                    .collect();
+
+                let counters = &mut port.counters;
+                counters.add(Counter::XmitPkts, pkts);
+                counters.add(Counter::RcvPkts, pkts);
+                counters.add(Counter::XmitData, words);
+                counters.add(Counter::RcvData, words);
+                counters.add(Counter::UnicastXmitPkts, pkts - multicast);
+                counters.add(Counter::UnicastRcvPkts, pkts - multicast);
+                counters.add(Counter::MulticastXmitPkts, multicast);
+                counters.add(Counter::MulticastRcvPkts, multicast);
+                for &(counter, n) in errors.iter().chain(&port_errors) {
+                    counters.add(counter, n);
+                }
+            }
+        }
+    }
+
+    /// Adds `n` to a counter of a port, e.g. 500 symbol errors on a bad switch port.
+    pub fn inject_counter(
+        &mut self,
+        node_guid: u64,
+        port: u8,
+        counter: Counter,
+        n: u64,
+    ) -> Result<(), io::Error> {
+        let port = self.find_port(node_guid, port)?;
+        port.write_lock().counters.add(counter, n);
+        Ok(())
+    }
+
+    pub fn port_counters(&self, node_guid: u64, port: u8) -> Result<PortCounters, io::Error> {
+        Ok(self.find_port(node_guid, port)?.read_lock().counters)
+    }
+
+    pub(super) fn find_port(
+        &self,
+        node_guid: u64,
+        num: u8,
+    ) -> Result<Arc<RwLock<Port>>, io::Error> {
+        let node = self.find_node(node_guid)?;
+        let node = node.read_lock();
+        node.ports
+            .iter()
+            .find(|p| p.read_lock().num == num)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Port {} not found on node '{}'", num, node.description),
+                )
+            })
+    }
+
+    fn tick_counters(&mut self) {
+        let now = time::Instant::now();
+        let elapsed = now - self.counter_clock;
+        self.counter_clock = now;
+        if self.traffic.realtime {
+            self.advance_counters(elapsed);
+        }
+    }
+
+    /// Answers PortCounters and PortCountersExtended Get and Set for `node`. A Set
+    /// resets the selected counters and returns what is left, like a Get.
+    pub(super) fn process_perf(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        node: &Arc<RwLock<Node>>,
+    ) -> Result<(), io::Error> {
+        let attr_id = u16::from_be(mad.attr_id);
+        if !matches!(attr_id, 0x0012 | 0x001D) || !matches!(mad.method, METHOD_GET | METHOD_SET) {
+            log::warn!(
+                "[tid: {}] Unhandled Perf method 0x{:02X} AttrID: 0x{:04X}",
+                tid,
+                mad.method,
+                attr_id
+            );
+            return self.send_dr_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR);
+        }
+        self.tick_counters();
+
+        // Both attributes keep PortSelect and CounterSelect in the same place.
+        let perf_req = perf_mad::from_bytes(&mad.data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Unable to parse Perf MAD")
+        })?;
+        let port_select = perf_req.port_select();
+        log::debug!(
+            "[tid: {}] Perf 0x{:04X} method 0x{:02X} for Node '{}' Port {}",
+            tid,
+            attr_id,
+            mad.method,
+            node.read_lock().description,
+            port_select
+        );
+
+        let ports: Vec<Arc<RwLock<Port>>> = node
+            .read_lock()
+            .ports
+            .iter()
+            .filter(|p| port_select == ALL_PORT_SELECT || p.read_lock().num == port_select)
+            .cloned()
+            .collect();
+        if ports.is_empty() {
+            log::warn!(
+                "[tid: {}] Port {} not found on node '{}'",
+                tid,
+                port_select,
+                node.read_lock().description
+            );
+            return self.send_dr_error(tid, umad, mad, MAD_STATUS_INVALID_ATTR_VALUE);
+        }
+
+        let data = if attr_id == 0x0012 {
+            let mut resp = port_counters::from_bytes(&mad.data).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Unable to parse PortCounters")
+            })?;
+            if mad.method == METHOD_SET {
+                let (select, select2) = (resp.counter_select(), resp.counter_select2());
+                reset_counters(&ports, |c| c.port_counters_selected(select, select2));
+            }
+            PortCounters::sum(&ports).fill_port_counters(&mut resp);
+            resp.to_bytes()
+        } else {
+            let mut resp = perf_req;
+            if mad.method == METHOD_SET {
+                let (select, select2) = (resp.counter_select(), resp.counter_select2());
+                reset_counters(&ports, |c| c.extended_selected(select, select2));
+            }
+            PortCounters::sum(&ports).fill_extended(&mut resp);
+            resp.to_bytes()
+        };
+
+        self.send_perf_response(tid, umad, mad, &data)
+    }
+
+    fn send_perf_response(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        perf_data: &[u8],
+    ) -> Result<(), io::Error> {
+        self.hold_response(tid);
+
+        let resp_umad = *umad;
+        let mut resp_mad = *mad;
+        resp_mad.method = METHOD_GET_RESP;
+        resp_mad.status = 0;
+        resp_mad.data[..perf_data.len()].copy_from_slice(perf_data);
+
+        self.write_response(tid, resp_umad, resp_mad)
+    }
+}
+
+fn reset_counters(ports: &[Arc<RwLock<Port>>], selected: impl Fn(Counter) -> bool) {
+    for port in ports {
+        let mut port = port.write_lock();
+        for counter in Counter::ALL.into_iter().filter(|&c| selected(c)) {
+            port.counters.reset(counter);
+        }
+    }
+}
@@ -0,0 +1,574 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    time,
+};
+
+use super::{ClientId, Fabric, MAD_STATUS_UNSUP_METHOD_ATTR, Node, Port, Shared, active_remote};
+use crate::enums::{LinkSpeed, LinkWidth, link_data_rate_gbps};
+use crate::mad::{
+    ib_mad, ib_user_mad, link_record, node_record, path_record, port_info_record,
+    sa::{
+        LINK_RECORD_SIZE, LR_COMP_FROM_LID, LR_COMP_FROM_PORT, LR_COMP_TO_LID, LR_COMP_TO_PORT,
+        NODE_RECORD_SIZE, NR_COMP_LID, NR_COMP_NODE_DESC, NR_COMP_NODE_GUID, NR_COMP_NODE_TYPE,
+        NR_COMP_PORT_GUID, NR_COMP_SYSTEM_GUID, PATH_RECORD_SIZE, PIR_COMP_LID, PIR_COMP_PORT_NUM,
+        PORT_INFO_RECORD_SIZE, PR_COMP_DGID, PR_COMP_DLID, PR_COMP_SGID, PR_COMP_SLID,
+        RMPP_FLAG_ACTIVE, RMPP_FLAG_FIRST, RMPP_FLAG_LAST, RMPP_TYPE_ABORT, RMPP_TYPE_ACK,
+        RMPP_TYPE_DATA, RMPP_TYPE_STOP, RMPP_VERSION, SA_DATA_SIZE, SA_HEADER_SIZE, SA_METHOD_GET,
+        SA_METHOD_GET_TABLE, SA_STATUS_NO_RECORDS, SA_STATUS_TOO_MANY_RECORDS,
+    },
+    sa_mad,
+};
+
+/// GID prefix of the simulated subnet; port GIDs are this plus the port GUID.
+const SUBNET_PREFIX: u64 = 0xfe80_0000_0000_0000;
+
+/// A GetTableResp waiting for the requester's ACKs to open the RMPP window.
+#[derive(Debug)]
+pub(super) struct SaTransfer {
+    umad: ib_user_mad,
+    mad: ib_mad,
+    attr_offset: u16,
+    payload: Vec<u8>,
+    segments: u32,
+    last_sent: u32,
+    /// When the request or the requester's last ACK arrived.
+    last_heard: time::Instant,
+}
+
+/// An end port with its LID and GID: port 0 of a switch or a CA port with a LID.
+struct EndPort {
+    port: Arc<RwLock<Port>>,
+    lid: u16,
+    gid: [u8; 16],
+}
+
+/// The LID that addresses `port`: a switch's port 0 LID for every switch port.
+fn endport_lid(node: &Node, port: &Port) -> u16 {
+    if node.switch_info.is_some() {
+        node.ports
+            .iter()
+            .find(|p| p.read_lock().num == 0)
+            .map_or(0, |p| p.read_lock().port_info.lid())
+    } else {
+        port.port_info.lid()
+    }
+}
+
+fn port_guid(node: &Node, port: &Port) -> u64 {
+    port.guids
+        .first()
+        .copied()
+        .unwrap_or(node.node_info.port_guid)
+}
+
+fn port_gid(node: &Node, port: &Port) -> [u8; 16] {
+    let mut gid = [0u8; 16];
+    gid[..8].copy_from_slice(&SUBNET_PREFIX.to_be_bytes());
+    gid[8..].copy_from_slice(&port_guid(node, port).to_be_bytes());
+    gid
+}
+
+/// IBA rate codes, as used by PathRecord Rate, each with a link that runs at that
+/// rate. Rates shared by several widths and speeds are listed once.
+const RATE_CODES: [(u8, LinkWidth, LinkSpeed); 22] = [
+    (2, LinkWidth::X1, LinkSpeed::Sdr),
+    (5, LinkWidth::X1, LinkSpeed::Ddr),
+    (3, LinkWidth::X4, LinkSpeed::Sdr),
+    (11, LinkWidth::X1, LinkSpeed::Fdr),
+    (6, LinkWidth::X4, LinkSpeed::Ddr),
+    (4, LinkWidth::X12, LinkSpeed::Sdr),
+    (15, LinkWidth::X1, LinkSpeed::Edr),
+    (7, LinkWidth::X4, LinkSpeed::Qdr),
+    (8, LinkWidth::X12, LinkSpeed::Ddr),
+    (20, LinkWidth::X1, LinkSpeed::Hdr),
+    (12, LinkWidth::X4, LinkSpeed::Fdr),
+    (9, LinkWidth::X8, LinkSpeed::Qdr),
+    (10, LinkWidth::X12, LinkSpeed::Qdr),
+    (16, LinkWidth::X4, LinkSpeed::Edr),
+    (13, LinkWidth::X8, LinkSpeed::Fdr),
+    (14, LinkWidth::X12, LinkSpeed::Fdr),
+    (17, LinkWidth::X4, LinkSpeed::Hdr),
+    (18, LinkWidth::X12, LinkSpeed::Edr),
+    (21, LinkWidth::X4, LinkSpeed::Ndr),
+    (22, LinkWidth::X12, LinkSpeed::Hdr),
+    (23, LinkWidth::X8, LinkSpeed::Ndr),
+    (24, LinkWidth::X12, LinkSpeed::Ndr),
+];
+
+/// Data rate of a link in Gb/s, or 0 if its active width or speed is unknown.
+fn link_data_rate(port: &Port) -> f64 {
+    port.port_info.data_rate_gbps().unwrap_or(0.0)
+}
+
+/// IBA rate code for the highest standard rate not above `data_rate` (Gb/s).
+fn rate_code(data_rate: f64) -> u8 {
+    RATE_CODES
+        .into_iter()
+        .map(|(code, width, speed)| (code, link_data_rate_gbps(width, speed)))
+        .filter(|&(_, rate)| rate <= data_rate)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(2, |(code, _)| code)
+}
+
+fn matches(comp_mask: u64, bit: u64, equal: bool) -> bool {
+    comp_mask & bit == 0 || equal
+}
+
+impl Fabric {
+    /// Answers an SA MAD (class 0x03) that reached the master SM.
+    ///
+    /// SubnAdmGet returns the single matching record. SubnAdmGetTable returns all of
+    /// them as RMPP DATA segments; the first is sent right away and each ACK from the
+    /// requester releases the segments up to its NewWindowLast.
+    pub(super) fn process_sa(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        sa_req: &sa_mad,
+    ) -> Result<(), io::Error> {
+        let attr_id = u16::from_be(mad.attr_id);
+        let comp_mask = u64::from_be(sa_req.comp_mask);
+
+        let (size, records) = match attr_id {
+            0x11 => (NODE_RECORD_SIZE, self.node_records(comp_mask, &sa_req.data)),
+            0x12 => (
+                PORT_INFO_RECORD_SIZE,
+                self.port_info_records(comp_mask, &sa_req.data),
+            ),
+            0x20 => (LINK_RECORD_SIZE, self.link_records(comp_mask, &sa_req.data)),
+            0x35 => (
+                PATH_RECORD_SIZE,
+                self.path_records(tid, comp_mask, &sa_req.data),
+            ),
+            _ => {
+                log::warn!("[tid: {}] Unhandled SA AttrID: 0x{:04X}", tid, attr_id);
+                return self.send_sa_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR);
+            }
+        };
+        log::debug!(
+            "[tid: {}] SA query for AttrID 0x{:04X} (mask 0x{:x}) matched {} records",
+            tid,
+            attr_id,
+            comp_mask,
+            records.len()
+        );
+
+        let attr_offset = size.div_ceil(8) as u16;
+        match mad.method {
+            SA_METHOD_GET => match records.as_slice() {
+                [record] => {
+                    let mut resp = *sa_req;
+                    resp.attr_offset = attr_offset.to_be();
+                    resp.data = [0; SA_DATA_SIZE];
+                    resp.data[..record.len()].copy_from_slice(record);
+
+                    let mut resp_mad = *mad;
+                    resp_mad.method = mad.method | 0x80;
+                    let sa_bytes = resp.to_bytes();
+                    resp_mad.data[..sa_bytes.len()].copy_from_slice(&sa_bytes);
+                    self.write_response(tid, *umad, resp_mad)
+                }
+                [] => self.send_sa_error(tid, umad, mad, SA_STATUS_NO_RECORDS),
+                _ => self.send_sa_error(tid, umad, mad, SA_STATUS_TOO_MANY_RECORDS),
+            },
+            SA_METHOD_GET_TABLE => {
+                let stride = attr_offset as usize * 8;
+                let mut payload = Vec::with_capacity(records.len() * stride);
+                for record in &records {
+                    payload.extend_from_slice(record);
+                    payload.resize(payload.len() + stride - record.len(), 0);
+                }
+
+                let transfer = SaTransfer {
+                    umad: *umad,
+                    mad: *mad,
+                    attr_offset,
+                    segments: payload.len().div_ceil(SA_DATA_SIZE).max(1) as u32,
+                    payload,
+                    last_sent: 0,
+                    last_heard: time::Instant::now(),
+                };
+                self.sa_transfers.insert((self.client, tid), transfer);
+                self.send_sa_segments(tid, 1)
+            }
+            _ => self.send_sa_error(tid, umad, mad, MAD_STATUS_UNSUP_METHOD_ATTR),
+        }
+    }
+
+    /// Handles an RMPP ACK, STOP or ABORT from the requester of a GetTable.
+    pub(super) fn process_sa_rmpp(&mut self, tid: u64, sa_req: &sa_mad) -> Result<(), io::Error> {
+        match sa_req.rmpp_type {
+            RMPP_TYPE_ACK => {
+                let acked = u32::from_be(sa_req.seg_num);
+                let window = u32::from_be(sa_req.paylen_newwin);
+                let Some(transfer) = self.sa_transfers.get_mut(&(self.client, tid)) else {
+                    log::trace!("[tid: {}] ACK for an unknown RMPP transfer", tid);
+                    return Ok(());
+                };
+                transfer.last_heard = time::Instant::now();
+                if acked >= transfer.segments {
+                    log::trace!("[tid: {}] RMPP transfer complete", tid);
+                    self.sa_transfers.remove(&(self.client, tid));
+                    return Ok(());
+                }
+                self.send_sa_segments(tid, window)
+            }
+            RMPP_TYPE_STOP | RMPP_TYPE_ABORT => {
+                log::debug!(
+                    "[tid: {}] Requester ended the RMPP transfer (type {})",
+                    tid,
+                    sa_req.rmpp_type
+                );
+                self.sa_transfers.remove(&(self.client, tid));
+                Ok(())
+            }
+            t => {
+                log::warn!("[tid: {}] Unexpected RMPP type {} from requester", tid, t);
+                Ok(())
+            }
+        }
+    }
+
+    /// Drops the GetTable responses whose requester has not ACKed within
+    /// `rmpp_timeout`, e.g. because it went away or the ACK was lost.
+    pub(super) fn expire_sa_transfers(&mut self) {
+        let timeout = self.rmpp_timeout;
+        self.sa_transfers.retain(|(client, tid), transfer| {
+            let alive = transfer.last_heard.elapsed() < timeout;
+            if !alive {
+                log::debug!(
+                    "[tid: {}] Dropping the RMPP transfer to client {} after {:?} without an ACK",
+                    tid,
+                    client,
+                    timeout
+                );
+            }
+            alive
+        });
+    }
+
+    /// Drops the GetTable responses still being sent to `client`.
+    pub(super) fn drop_sa_transfers(&mut self, client: ClientId) {
+        self.sa_transfers.retain(|(c, _), _| *c != client);
+    }
+
+    /// Number of SA GetTable responses still waiting for ACKs.
+    pub fn pending_sa_transfers(&self) -> usize {
+        self.sa_transfers.len()
+    }
+
+    /// Sends the segments of a GetTableResp up to `window` that haven't been sent yet.
+    fn send_sa_segments(&mut self, tid: u64, window: u32) -> Result<(), io::Error> {
+        let Some(transfer) = self.sa_transfers.get_mut(&(self.client, tid)) else {
+            return Ok(());
+        };
+        let first = transfer.last_sent + 1;
+        let last = window.min(transfer.segments);
+        if first > last {
+            return Ok(());
+        }
+        transfer.last_sent = last;
+
+        let mut responses = Vec::new();
+        for seg in first..=last {
+            let start = (seg as usize - 1) * SA_DATA_SIZE;
+            let chunk = transfer
+                .payload
+                .get(start..(start + SA_DATA_SIZE).min(transfer.payload.len()))
+                .unwrap_or_default();
+
+            let mut flags = RMPP_FLAG_ACTIVE;
+            let mut paylen = 0;
+            if seg == transfer.segments {
+                flags |= RMPP_FLAG_LAST;
+                paylen = SA_HEADER_SIZE + chunk.len();
+            }
+            if seg == 1 {
+                // Like the kernel, count the SA header of every segment.
+                flags |= RMPP_FLAG_FIRST;
+                paylen = transfer.segments as usize * SA_HEADER_SIZE + transfer.payload.len();
+            }
+
+            let mut resp = sa_mad::from_bytes(&transfer.mad.data).unwrap_or_default();
+            resp.rmpp_version = RMPP_VERSION;
+            resp.rmpp_type = RMPP_TYPE_DATA;
+            resp.rmpp_rtime_flags = flags;
+            resp.rmpp_status = 0;
+            resp.seg_num = seg.to_be();
+            resp.paylen_newwin = (paylen as u32).to_be();
+            resp.attr_offset = transfer.attr_offset.to_be();
+            resp.data = [0; SA_DATA_SIZE];
+            resp.data[..chunk.len()].copy_from_slice(chunk);
+
+            let mut resp_mad = transfer.mad;
+            resp_mad.method = transfer.mad.method | 0x80;
+            let sa_bytes = resp.to_bytes();
+            resp_mad.data[..sa_bytes.len()].copy_from_slice(&sa_bytes);
+            responses.push((transfer.umad, resp_mad));
+        }
+
+        self.hold_response(tid);
+
+        log::trace!("[tid: {}] Sending RMPP segments {}-{}", tid, first, last);
+        for (resp_umad, resp_mad) in responses {
+            self.write_response(tid, resp_umad, resp_mad)?;
+        }
+        Ok(())
+    }
+
+    fn send_sa_error(
+        &mut self,
+        tid: u64,
+        umad: &ib_user_mad,
+        mad: &ib_mad,
+        status: u16,
+    ) -> Result<(), io::Error> {
+        log::debug!("[tid: {}] Responding with SA status 0x{:04X}", tid, status);
+
+        let mut resp_mad = *mad;
+        resp_mad.method = mad.method | 0x80;
+        resp_mad.status = status.to_be();
+        self.write_response(tid, *umad, resp_mad)
+    }
+
+    fn end_ports(&self) -> Vec<EndPort> {
+        let mut end_ports = Vec::new();
+        for node_rc in &self.nodes {
+            let node = node_rc.read_lock();
+            for port_rc in &node.ports {
+                let port = port_rc.read_lock();
+                if node.switch_info.is_some() && port.num != 0 {
+                    continue;
+                }
+                let lid = endport_lid(&node, &port);
+                if lid != 0 {
+                    end_ports.push(EndPort {
+                        port: port_rc.clone(),
+                        lid,
+                        gid: port_gid(&node, &port),
+                    });
+                }
+            }
+        }
+        end_ports
+    }
+
+    /// One NodeRecord per switch and one per CA port, as an SM reports them.
+    fn node_records(&self, comp_mask: u64, template: &[u8]) -> Vec<Vec<u8>> {
+        let Some(want) = node_record::from_bytes(template) else {
+            return Vec::new();
+        };
+        let want_ni = want.node_info();
+
+        let mut records = Vec::new();
+        for end_port in self.end_ports() {
+            let port = end_port.port.read_lock();
+            let Some(node_rc) = port.parent.upgrade() else {
+                continue;
+            };
+            let node = node_rc.read_lock();
+
+            let mut ni = node.node_info;
+            ni.local_port = port.num;
+            ni.port_guid = port_guid(&node, &port);
+
+            let mut rec = node_record {
+                data: [0; NODE_RECORD_SIZE],
+            };
+            rec.set_lid(end_port.lid);
+            rec.set_node_info(&ni);
+            rec.set_node_description(&node.description);
+
+            if matches(comp_mask, NR_COMP_LID, rec.lid() == want.lid())
+                && matches(
+                    comp_mask,
+                    NR_COMP_NODE_TYPE,
+                    ni.node_type == want_ni.node_type,
+                )
+                && matches(
+                    comp_mask,
+                    NR_COMP_SYSTEM_GUID,
+                    { ni.system_guid } == { want_ni.system_guid },
+                )
+                && matches(
+                    comp_mask,
+                    NR_COMP_NODE_GUID,
+                    { ni.node_guid } == { want_ni.node_guid },
+                )
+                && matches(
+                    comp_mask,
+                    NR_COMP_PORT_GUID,
+                    { ni.port_guid } == { want_ni.port_guid },
+                )
+                && matches(
+                    comp_mask,
+                    NR_COMP_NODE_DESC,
+                    rec.data[44..] == want.data[44..],
+                )
+            {
+                records.push(rec.to_bytes());
+            }
+        }
+        records
+    }
+
+    /// One PortInfoRecord per port, switch ports keyed by the switch's LID. M_Keys are
+    /// not given out.
+    fn port_info_records(&self, comp_mask: u64, template: &[u8]) -> Vec<Vec<u8>> {
+        let Some(want) = port_info_record::from_bytes(template) else {
+            return Vec::new();
+        };
+
+        let mut records = Vec::new();
+        for node_rc in &self.nodes {
+            let node = node_rc.read_lock();
+            for port_rc in &node.ports {
+                let port = port_rc.read_lock();
+                let lid = endport_lid(&node, &port);
+                if lid == 0 {
+                    continue;
+                }
+
+                let mut pi = port.port_info;
+                pi.set_mkey(0);
+                let mut rec = port_info_record {
+                    data: [0; PORT_INFO_RECORD_SIZE],
+                };
+                rec.set_endport_lid(lid);
+                rec.set_port_num(port.num);
+                rec.set_port_info(&pi);
+
+                if matches(comp_mask, PIR_COMP_LID, lid == want.endport_lid())
+                    && matches(comp_mask, PIR_COMP_PORT_NUM, port.num == want.port_num())
+                {
+                    records.push(rec.to_bytes());
+                }
+            }
+        }
+        records
+    }
+
+    /// Both directions of every Active link.
+    fn link_records(&self, comp_mask: u64, template: &[u8]) -> Vec<Vec<u8>> {
+        let Some(want) = link_record::from_bytes(template) else {
+            return Vec::new();
+        };
+
+        let mut records = Vec::new();
+        for node_rc in &self.nodes {
+            let node = node_rc.read_lock();
+            for port_rc in &node.ports {
+                let Some(remote_rc) = active_remote(port_rc) else {
+                    continue;
+                };
+                let port = port_rc.read_lock();
+                let remote = remote_rc.read_lock();
+                let Some(peer_rc) = remote.parent.upgrade() else {
+                    continue;
+                };
+
+                let mut rec = link_record {
+                    data: [0; LINK_RECORD_SIZE],
+                };
+                rec.set_from_lid(endport_lid(&node, &port));
+                rec.set_from_port(port.num);
+                rec.set_to_port(remote.num);
+                rec.set_to_lid(endport_lid(&peer_rc.read_lock(), &remote));
+
+                if matches(
+                    comp_mask,
+                    LR_COMP_FROM_LID,
+                    rec.from_lid() == want.from_lid(),
+                ) && matches(
+                    comp_mask,
+                    LR_COMP_FROM_PORT,
+                    rec.from_port() == want.from_port(),
+                ) && matches(comp_mask, LR_COMP_TO_PORT, rec.to_port() == want.to_port())
+                    && matches(comp_mask, LR_COMP_TO_LID, rec.to_lid() == want.to_lid())
+                {
+                    records.push(rec.to_bytes());
+                }
+            }
+        }
+        records
+    }
+
+    /// Paths between the matching end ports, following the LFTs. Without SGID or SLID
+    /// the source is the requester's port; without DGID or DLID every reachable end
+    /// port is a destination.
+    fn path_records(&self, tid: u64, comp_mask: u64, template: &[u8]) -> Vec<Vec<u8>> {
+        let Some(want) = path_record::from_bytes(template) else {
+            return Vec::new();
+        };
+
+        let end_ports = self.end_ports();
+        let requester = self.first_hop();
+        let sources: Vec<&EndPort> = if comp_mask & (PR_COMP_SGID | PR_COMP_SLID) != 0 {
+            end_ports
+                .iter()
+                .filter(|e| {
+                    matches(comp_mask, PR_COMP_SGID, e.gid == want.sgid())
+                        && matches(comp_mask, PR_COMP_SLID, e.lid == want.slid())
+                })
+                .collect()
+        } else {
+            end_ports
+                .iter()
+                .filter(|e| requester.as_ref().is_some_and(|r| Arc::ptr_eq(r, &e.port)))
+                .collect()
+        };
+
+        let mut records = Vec::new();
+        for src in &sources {
+            for dst in &end_ports {
+                if !matches(comp_mask, PR_COMP_DGID, dst.gid == want.dgid())
+                    || !matches(comp_mask, PR_COMP_DLID, dst.lid == want.dlid())
+                {
+                    continue;
+                }
+
+                let mut links = Vec::new();
+                if self
+                    .forward_lid(tid, src.port.clone(), dst.lid, &mut links)
+                    .is_none()
+                {
+                    continue;
+                }
+                let reversible = self
+                    .forward_lid(tid, dst.port.clone(), src.lid, &mut Vec::new())
+                    .is_some();
+
+                let src_port = src.port.read_lock();
+                let mtu = links
+                    .iter()
+                    .map(|p| p.read_lock().port_info.neighbor_mtu())
+                    .fold(src_port.port_info.mtu_cap(), u8::min);
+                let rate = links
+                    .iter()
+                    .map(|p| link_data_rate(&p.read_lock()))
+                    .reduce(f64::min)
+                    .unwrap_or_else(|| link_data_rate(&src_port));
+
+                let mut rec = path_record {
+                    data: [0; PATH_RECORD_SIZE],
+                };
+                rec.set_dgid(dst.gid);
+                rec.set_sgid(src.gid);
+                rec.set_dlid(dst.lid);
+                rec.set_slid(src.lid);
+                rec.set_reversible(reversible as u8);
+                rec.set_pkey(crate::mad::pkey::PKEY_DEFAULT);
+                // Selector 2: exactly the value given.
+                rec.set_mtu_selector(2);
+                rec.set_mtu(mtu);
+                rec.set_rate_selector(2);
+                rec.set_rate(rate_code(rate));
+                rec.set_packet_life_time_selector(2);
+                records.push(rec.to_bytes());
+            }
+        }
+        records
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::{fd::OwnedFd, unix::net},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::mpsc,
+    time::Instant,
+};
+
+use super::{ClientId, Fabric, MIN_UMAD_SIZE};
+
+/// Serves a simulated fabric to any number of clients at once on a tokio runtime.
+///
+/// Every client talks over its own stream, and MADs are processed one at a time in
+/// the order they arrive across all of them. `Fabric::file` is not used.
+#[derive(Debug, Clone)]
+pub struct Server {
+    fabric: Arc<Mutex<Fabric>>,
+    /// Streams being served, by client.
+    streams: Arc<Mutex<HashMap<ClientId, usize>>>,
+}
+
+impl Server {
+    pub fn new(fabric: Fabric) -> Server {
+        Server {
+            fabric: Arc::new(Mutex::new(fabric)),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The served fabric; lock it to inspect or change it between MADs.
+    pub fn fabric(&self) -> &Arc<Mutex<Fabric>> {
+        &self.fabric
+    }
+
+    /// Serves `client` on a new socket pair from a spawned task and returns the
+    /// client's end, for an `IbMadPort` or `AsyncFd`. Must be called from within a
+    /// tokio runtime.
+    pub fn connect(&self, client: ClientId) -> Result<fs::File, io::Error> {
+        let (client_end, server_end) = net::UnixStream::pair()?;
+        server_end.set_nonblocking(true)?;
+        let stream = UnixStream::from_std(server_end)?;
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.serve(client, stream).await {
+                log::error!("Serving client {} failed: {}", client, e);
+            }
+        });
+        Ok(fs::File::from(OwnedFd::from(client_end)))
+    }
+
+    /// Serves `client` over `stream` until the other end closes it.
+    ///
+    /// Responses go out in the order their requests arrived. A response held back by
+    /// `Response::delay` also holds back the ones after it, as with `Fabric::run`,
+    /// while the MADs behind it are still processed. Once the last stream of a client
+    /// closes, the SA GetTable responses still being sent to it are dropped.
+    pub async fn serve(&self, client: ClientId, stream: UnixStream) -> Result<(), io::Error> {
+        log::info!("Serving client {}", client);
+        *self.lock_streams().entry(client).or_default() += 1;
+        let result = self.serve_stream(client, stream).await;
+
+        let last = {
+            let mut streams = self.lock_streams();
+            let open = streams.entry(client).or_default();
+            *open -= 1;
+            *open == 0
+        };
+        if last {
+            let mut fabric = self.fabric.lock().unwrap_or_else(PoisonError::into_inner);
+            fabric.drop_sa_transfers(client);
+        }
+        result
+    }
+
+    fn lock_streams(&self) -> MutexGuard<'_, HashMap<ClientId, usize>> {
+        self.streams.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn serve_stream(&self, client: ClientId, stream: UnixStream) -> Result<(), io::Error> {
+        let (mut reader, mut writer) = stream.into_split();
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+        let write_task = tokio::spawn(async move {
+            while let Some((due, bytes)) = rx.recv().await {
+                tokio::time::sleep_until(due).await;
+                writer.write_all(&bytes).await?;
+            }
+            Ok::<(), io::Error>(())
+        });
+
+        let mut buf = [0u8; MIN_UMAD_SIZE];
+        loop {
+            match reader.read_exact(&mut buf).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+
+            let responses = {
+                let mut fabric = self.fabric.lock().unwrap_or_else(PoisonError::into_inner);
+                fabric.process_umad(client, &buf)
+            };
+            let responses = match responses {
+                Ok(responses) => responses,
+                Err(e) => {
+                    log::error!("Error processing UMAD from client {}: {}", client, e);
+                    continue;
+                }
+            };
+
+            // Delays add up within a batch, as each response is slept on in turn.
+            let mut due = Instant::now();
+            for response in responses {
+                due += response.delay;
+                let _ = tx.send((due, response.bytes));
+            }
+        }
+
+        log::info!("Client {} closed its stream", client);
+        drop(tx);
+        write_task.await.map_err(io::Error::other)?
+    }
+}
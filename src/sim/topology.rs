@@ -0,0 +1,422 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
+
+use super::{Fabric, Node, Port, Shared, connect_ports};
+use crate::discovery::nvlink::NVLINK_RING_PORTS;
+
+/// Largest unicast LID.
+const MAX_UNICAST_LID: u16 = 0xbfff;
+/// Ports an NVLink switch has; the last two are the ring ports.
+const NVLINK_SWITCH_PORTS: u8 = 74;
+
+/// Where a generator starts numbering. Nodes are numbered in the order they are
+/// created, all switches before any CA: node `i` gets GUID `guid_base + i + 1` and
+/// LID `first_lid + i`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Numbering {
+    pub guid_base: u64,
+    pub first_lid: u16,
+}
+
+impl Default for Numbering {
+    fn default() -> Self {
+        Numbering {
+            guid_base: 0x7ffc_0000_0000_0000,
+            first_lid: 1,
+        }
+    }
+}
+
+/// A fabric shape `build` can generate.
+///
+/// Every switch port that is not cabled stays Down. CAs have a single port 1, and the
+/// first CA is where the agent sits; without CAs it is port 0 of the first switch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Topology {
+    /// k-ary n-tree: `n` levels of k^(n-1) switches with 2k ports, and k^n CAs. Level
+    /// 0 is the top; switches on adjacent levels are linked when their base-k indexes
+    /// differ only in the digit of the upper level. Down ports are 1..=k, up ports
+    /// k+1..=2k.
+    FatTree { k: usize, n: usize },
+    /// Two-level leaf/spine. Each leaf has CAs on ports 1..=hosts_per_leaf and spreads
+    /// its uplinks round-robin over the spines on the ports after them, so
+    /// `hosts_per_leaf / uplinks_per_leaf` is the oversubscription.
+    LeafSpine {
+        spines: usize,
+        leaves: usize,
+        hosts_per_leaf: usize,
+        uplinks_per_leaf: usize,
+    },
+    /// Groups of routers linked all-to-all, with global links between groups. Router
+    /// ports are the CAs, then the other routers of the group, then the global links.
+    /// Global ports are assigned so that with `groups == routers * global_links + 1`
+    /// every pair of groups shares exactly one link.
+    Dragonfly {
+        groups: usize,
+        routers_per_group: usize,
+        hosts_per_router: usize,
+        global_links_per_router: usize,
+    },
+    /// Wrap-around mesh, e.g. `dims: vec![4, 4]` or `vec![4, 4, 4]`. Switch ports are
+    /// the CAs, then a +/- pair per dimension.
+    Torus {
+        dims: Vec<usize>,
+        hosts_per_switch: usize,
+    },
+    /// NVLink spine/leaf: every switch has 74 ports and all of them are chained into a
+    /// ring through ports 73 and 74. Each leaf has GPUs on ports 1..=gpus_per_leaf and
+    /// one trunk to every spine on the ports after them; spine port `l + 1` goes to
+    /// leaf `l`. The agent sits on the first spine.
+    NvLink {
+        spines: usize,
+        leaves: usize,
+        gpus_per_leaf: usize,
+    },
+}
+
+impl Topology {
+    /// Adds the nodes and links to `fabric` and programs the switch LFTs.
+    pub fn build(&self, fabric: &mut Fabric, numbering: Numbering) -> Result<(), io::Error> {
+        let mut builder = Builder {
+            fabric,
+            next_guid: numbering.guid_base,
+            next_lid: numbering.first_lid,
+        };
+
+        match *self {
+            Topology::FatTree { k, n } => builder.fat_tree(k, n)?,
+            Topology::LeafSpine {
+                spines,
+                leaves,
+                hosts_per_leaf,
+                uplinks_per_leaf,
+            } => builder.leaf_spine(spines, leaves, hosts_per_leaf, uplinks_per_leaf)?,
+            Topology::Dragonfly {
+                groups,
+                routers_per_group,
+                hosts_per_router,
+                global_links_per_router,
+            } => builder.dragonfly(
+                groups,
+                routers_per_group,
+                hosts_per_router,
+                global_links_per_router,
+            )?,
+            Topology::Torus {
+                ref dims,
+                hosts_per_switch,
+            } => builder.torus(dims, hosts_per_switch)?,
+            Topology::NvLink {
+                spines,
+                leaves,
+                gpus_per_leaf,
+            } => builder.nvlink(spines, leaves, gpus_per_leaf)?,
+        }
+
+        fabric.compute_lfts();
+        Ok(())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Port count of a switch, refusing anything a PortInfo port number cannot address.
+fn switch_ports(nports: usize) -> Result<u8, io::Error> {
+    u8::try_from(nports)
+        .ok()
+        .filter(|&n| n > 0 && n < 255)
+        .ok_or_else(|| invalid(format!("A switch cannot have {} ports", nports)))
+}
+
+fn link(a: &Arc<RwLock<Node>>, port_a: usize, b: &Arc<RwLock<Node>>, port_b: usize) {
+    let port_a = a.read_lock().ports[port_a].clone();
+    let port_b = b.read_lock().ports[port_b].clone();
+    connect_ports(&port_a, &port_b);
+}
+
+struct Builder<'a> {
+    fabric: &'a mut Fabric,
+    next_guid: u64,
+    next_lid: u16,
+}
+
+impl Builder<'_> {
+    fn next_ids(&mut self) -> Result<(u64, u16), io::Error> {
+        let lid = self.next_lid;
+        if lid == 0 || lid > MAX_UNICAST_LID {
+            return Err(invalid(format!(
+                "Topology needs more LIDs than fit below {:#x}",
+                MAX_UNICAST_LID
+            )));
+        }
+        self.next_lid += 1;
+        self.next_guid += 1;
+        Ok((self.next_guid, lid))
+    }
+
+    /// A switch with ports 0..=nports. Port `p` is `ports[p]`.
+    fn switch(&mut self, description: String, nports: u8) -> Result<Arc<RwLock<Node>>, io::Error> {
+        let (guid, lid) = self.next_ids()?;
+        let mut sw = Node::new_switch(&description, guid);
+        sw.node_info.nports = nports;
+        sw.lid = lid;
+        let sw_rc = self.fabric.add_switch(sw);
+
+        let ports = (0..=nports)
+            .map(|p| Arc::new(RwLock::new(Port::new_port(p, lid, sw_rc.clone()))))
+            .collect();
+        sw_rc.write_lock().ports = ports;
+        Ok(sw_rc)
+    }
+
+    fn switches(
+        &mut self,
+        count: usize,
+        nports: u8,
+        description: impl Fn(usize) -> String,
+    ) -> Result<Vec<Arc<RwLock<Node>>>, io::Error> {
+        (0..count)
+            .map(|i| self.switch(description(i), nports))
+            .collect()
+    }
+
+    /// Creates `per_switch` CAs for every switch, cabled to ports 1..=per_switch.
+    fn hosts(
+        &mut self,
+        switches: &[Arc<RwLock<Node>>],
+        per_switch: usize,
+        name: &str,
+    ) -> Result<(), io::Error> {
+        let mut count = 0;
+        for sw in switches {
+            for p in 1..=per_switch {
+                count += 1;
+                let (guid, lid) = self.next_ids()?;
+                let mut hca = Node::new_hca(&format!("{}-{:05}", name, count), guid);
+                hca.lid = lid;
+                let hca_rc = self.fabric.add_hca(hca);
+                let port = Arc::new(RwLock::new(Port::new_port(1, lid, hca_rc.clone())));
+                hca_rc.write_lock().ports.push(port.clone());
+
+                let sw_port = sw.read_lock().ports[p].clone();
+                connect_ports(&sw_port, &port);
+                if count == 1 {
+                    self.fabric.dr_paths.insert([0; 64], Arc::downgrade(&port));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Without CAs the agent sits on port 0 of the first switch.
+    fn attach_to_switch(&mut self, sw: Option<&Arc<RwLock<Node>>>) {
+        if let Some(sw) = sw {
+            let port0 = sw.read_lock().ports[0].clone();
+            self.fabric
+                .dr_paths
+                .entry([0; 64])
+                .or_insert_with(|| Arc::downgrade(&port0));
+        }
+    }
+
+    fn fat_tree(&mut self, k: usize, n: usize) -> Result<(), io::Error> {
+        if k == 0 || n == 0 {
+            return Err(invalid(
+                "A fat tree needs k and n of at least 1".to_string(),
+            ));
+        }
+        let nports = switch_ports(2 * k)?;
+        let per_level = k
+            .checked_pow(n as u32 - 1)
+            .filter(|&s| s.saturating_mul(n) <= MAX_UNICAST_LID as usize)
+            .ok_or_else(|| invalid(format!("A {}-ary {}-tree is too large", k, n)))?;
+
+        let mut levels = Vec::new();
+        for l in 0..n {
+            levels.push(self.switches(per_level, nports, |i| format!("level{}-{}", l, i))?);
+        }
+
+        // Replacing digit `l` of an index spans the k switches one level down.
+        let stride = |l: usize| k.pow(l as u32);
+        for l in 0..n - 1 {
+            for (w, upper) in levels[l].iter().enumerate() {
+                let digit = (w / stride(l)) % k;
+                for d in 0..k {
+                    let lower = w - digit * stride(l) + d * stride(l);
+                    link(upper, 1 + d, &levels[l + 1][lower], k + 1 + digit);
+                }
+            }
+        }
+
+        self.hosts(&levels[n - 1], k, "host")?;
+        self.attach_to_switch(levels[0].first());
+        Ok(())
+    }
+
+    fn leaf_spine(
+        &mut self,
+        spines: usize,
+        leaves: usize,
+        hosts_per_leaf: usize,
+        uplinks_per_leaf: usize,
+    ) -> Result<(), io::Error> {
+        if spines == 0 && uplinks_per_leaf > 0 {
+            return Err(invalid("Leaf uplinks need at least one spine".to_string()));
+        }
+        let links_per_spine = uplinks_per_leaf.div_ceil(spines.max(1));
+        let spine_ports = switch_ports((leaves * links_per_spine).max(1))?;
+        let leaf_ports = switch_ports(hosts_per_leaf + uplinks_per_leaf)?;
+
+        let spine_sws = self.switches(spines, spine_ports, |i| format!("spine-{}", i))?;
+        let leaf_sws = self.switches(leaves, leaf_ports, |i| format!("leaf-{}", i))?;
+
+        for (l, leaf) in leaf_sws.iter().enumerate() {
+            for u in 0..uplinks_per_leaf {
+                let spine_port = l * links_per_spine + u / spines + 1;
+                link(
+                    leaf,
+                    hosts_per_leaf + 1 + u,
+                    &spine_sws[u % spines],
+                    spine_port,
+                );
+            }
+        }
+
+        self.hosts(&leaf_sws, hosts_per_leaf, "host")?;
+        self.attach_to_switch(spine_sws.first().or(leaf_sws.first()));
+        Ok(())
+    }
+
+    fn dragonfly(
+        &mut self,
+        groups: usize,
+        routers: usize,
+        hosts: usize,
+        global: usize,
+    ) -> Result<(), io::Error> {
+        let nports = switch_ports(hosts + routers.saturating_sub(1) + global)?;
+        let mut group_sws = Vec::new();
+        for g in 0..groups {
+            group_sws.push(self.switches(routers, nports, |r| format!("group{}-router{}", g, r))?);
+        }
+
+        for sws in &group_sws {
+            for r in 0..routers {
+                for s in r + 1..routers {
+                    link(&sws[r], hosts + s, &sws[s], hosts + 1 + r);
+                }
+            }
+        }
+
+        // Global port `gp` of group `i` leads `gp % (groups - 1) + 1` groups onwards; the
+        // far end uses the mirror offset, so both ends agree on the cable.
+        let global_ports = routers * global;
+        let router_port = |gp: usize| (gp / global, hosts + routers + gp % global);
+        if groups > 1 {
+            for i in 0..groups {
+                for gp in 0..global_ports {
+                    let (copy, d) = (gp / (groups - 1), gp % (groups - 1));
+                    let t = (i + 1 + d) % groups;
+                    let far_gp = copy * (groups - 1) + (groups - 2 - d);
+                    if i < t && far_gp < global_ports {
+                        let (r, p) = router_port(gp);
+                        let (far_r, far_p) = router_port(far_gp);
+                        link(&group_sws[i][r], p, &group_sws[t][far_r], far_p);
+                    }
+                }
+            }
+        }
+
+        let all: Vec<_> = group_sws.into_iter().flatten().collect();
+        self.hosts(&all, hosts, "host")?;
+        self.attach_to_switch(all.first());
+        Ok(())
+    }
+
+    fn torus(&mut self, dims: &[usize], hosts: usize) -> Result<(), io::Error> {
+        if dims.is_empty() || dims.contains(&0) {
+            return Err(invalid(format!("Invalid torus dimensions {:?}", dims)));
+        }
+        let nports = switch_ports(hosts + 2 * dims.len())?;
+        let count = dims
+            .iter()
+            .try_fold(1usize, |acc, &d| acc.checked_mul(d))
+            .filter(|&c| c <= MAX_UNICAST_LID as usize)
+            .ok_or_else(|| invalid(format!("A {:?} torus is too large", dims)))?;
+
+        // Index of a switch is its coordinates in mixed radix, first dimension fastest.
+        let coords = |mut i: usize| {
+            dims.iter()
+                .map(|&d| {
+                    let c = i % d;
+                    i /= d;
+                    c.to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("-")
+        };
+        let sws = self.switches(count, nports, |i| format!("torus-{}", coords(i)))?;
+
+        let mut stride = 1;
+        for (dim, &size) in dims.iter().enumerate() {
+            if size > 1 {
+                for (i, sw) in sws.iter().enumerate() {
+                    let c = (i / stride) % size;
+                    let next = i - c * stride + ((c + 1) % size) * stride;
+                    link(sw, hosts + 1 + 2 * dim, &sws[next], hosts + 2 + 2 * dim);
+                }
+            }
+            stride *= size;
+        }
+
+        self.hosts(&sws, hosts, "host")?;
+        self.attach_to_switch(sws.first());
+        Ok(())
+    }
+
+    fn nvlink(&mut self, spines: usize, leaves: usize, gpus: usize) -> Result<(), io::Error> {
+        let trunk_ports = NVLINK_RING_PORTS[0] as usize - 1;
+        if leaves > trunk_ports || gpus + spines > trunk_ports {
+            return Err(invalid(format!(
+                "NVLink switches have {} ports besides the ring ports",
+                trunk_ports
+            )));
+        }
+
+        let spine_sws =
+            self.switches(spines, NVLINK_SWITCH_PORTS, |i| format!("nvsw-spine-{}", i))?;
+        let leaf_sws =
+            self.switches(leaves, NVLINK_SWITCH_PORTS, |i| format!("nvsw-leaf-{}", i))?;
+
+        let ring: Vec<_> = spine_sws.iter().chain(&leaf_sws).collect();
+        if ring.len() > 1 {
+            for (i, sw) in ring.iter().enumerate() {
+                let next = ring[(i + 1) % ring.len()];
+                link(
+                    sw,
+                    NVLINK_RING_PORTS[0] as usize,
+                    next,
+                    NVLINK_RING_PORTS[1] as usize,
+                );
+            }
+        }
+
+        for (l, leaf) in leaf_sws.iter().enumerate() {
+            for (s, spine) in spine_sws.iter().enumerate() {
+                link(leaf, gpus + 1 + s, spine, l + 1);
+            }
+        }
+
+        self.hosts(&leaf_sws, gpus, "gpu")?;
+        // NVLink discovery starts on a switch.
+        if let Some(first) = spine_sws.first().or(leaf_sws.first()) {
+            let port0 = first.read_lock().ports[0].clone();
+            self.fabric.dr_paths.insert([0; 64], Arc::downgrade(&port0));
+        }
+        Ok(())
+    }
+}
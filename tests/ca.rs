@@ -95,4 +95,53 @@ mod ca_tests {
             }
         }
     }
+
+    #[test]
+    fn ca_port_rate_parsing_success() {
+        use ibmad::enums::{IbPortLinkLayerState, IbPortPhyState, LinkSpeed, LinkWidth};
+
+        let _ = env_logger::try_init();
+
+        let port_with_rate = |rate: &str| ibmad::ca::IbCaPort {
+            path: String::new(),
+            number: 1,
+            phy_state: IbPortPhyState::LinkUp,
+            link_layer: Some("InfiniBand".to_string()),
+            rate: Some(rate.to_string()),
+            sm_lid: 1,
+            sm_sl: 0,
+            state: IbPortLinkLayerState::Active,
+            lid: 1,
+            lmc: 0,
+            cap_mask: 0,
+            gid: 0,
+            pkeys: Vec::new(),
+        };
+
+        let port = port_with_rate("100 Gb/sec (4X EDR)");
+        assert_eq!(port.rate_gbps(), Some(100.0));
+        assert_eq!(port.link_width(), Some(LinkWidth::X4));
+        assert_eq!(port.link_speed(), Some(LinkSpeed::Edr));
+        assert_eq!(port.data_rate_gbps(), Some(100.0));
+
+        let port = port_with_rate("400 Gb/sec (4X NDR)");
+        assert_eq!(port.link_speed(), Some(LinkSpeed::Ndr));
+
+        let port = port_with_rate("56 Gb/sec (4X FDR)");
+        assert_eq!(port.link_speed(), Some(LinkSpeed::Fdr));
+        assert!((port.data_rate_gbps().unwrap() - 54.545).abs() < 0.01);
+
+        // Older kernels leave out the speed for SDR.
+        let port = port_with_rate("10 Gb/sec (4X)");
+        assert_eq!(port.link_speed(), Some(LinkSpeed::Sdr));
+        assert_eq!(port.data_rate_gbps(), Some(8.0));
+
+        assert_eq!(port_with_rate("garbage").link_width(), None);
+
+        let mut port = port_with_rate("100 Gb/sec (4X EDR)");
+        port.cap_mask = 0x2651_e84a;
+        assert!(port.capabilities().is_sm());
+        assert!(port.capabilities().is_extended_speeds_supported());
+        assert!(!port.capabilities().is_sm_disabled());
+    }
 }
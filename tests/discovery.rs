@@ -3,61 +3,32 @@ mod common;
 
 #[cfg(test)]
 mod discovery_tests {
-    use std::cell::RefCell;
     use std::collections::{HashMap, HashSet};
     use std::os::fd::{FromRawFd, IntoRawFd};
     use std::os::unix::net::UnixStream;
-    use std::rc::Rc;
     use std::sync::mpsc::channel;
-    use std::{fs, sync, thread};
-
-    use ibmad::enums::IbPortLinkLayerState;
+    use std::sync::{Arc, RwLock};
+    use std::{fs, io, sync, thread, time};
+
+    use ibmad::discovery::link::LinkIssue;
+    use ibmad::discovery::{MKeyProvider, SmpTarget};
+    use ibmad::enums::{
+        IbMtu, IbPortLinkLayerState, IbPortPhyState, LinkSpeed, LinkWidth, PKeyMembership, SmState,
+    };
     use ibmad::mad::{self, IB_MGMT_CLASS_PERFORMANCE, IbMadPort, open_port, open_smp_port};
     use ibmad::sim::Port;
+    use ibmad::sim::fault::{FaultKind, FaultMatch, MAD_STATUS_BUSY};
     use super::common;
 
     #[test]
     fn test_seq_discovery_sim_success() {
         common::setup();
 
-        let (client, server) = UnixStream::pair().unwrap();
-
-        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
-        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
-
-        let (tx, rx) = channel::<bool>();
-        let barrier = sync::Arc::new(sync::Barrier::new(2));
-        let barrier_clone: sync::Arc<sync::Barrier> = barrier.clone();
-
-        thread::spawn(move || {
-            let mut fabric = ibmad::sim::Fabric::new(server_file);
-            fabric.response_delay = Some(600);
-            ibmad::sim::build_standard_fabric(&mut fabric);
-            barrier_clone.wait();
-            let _ = fabric.run(rx);
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            sim.response_delay = Some(600);
+            ibmad::sim::build_standard_fabric(sim);
         });
 
-        let port = IbMadPort { file: client_file };
-
-        let mut fabric = ibmad::discovery::Fabric {
-            port: port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 1,
-            timeout: 50,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
-
-        barrier.wait();
-
         let r = fabric.seq_discover();
 
         if let Err(e) = r {
@@ -71,44 +42,11 @@ mod discovery_tests {
     fn test_switch_enumeration() {
         common::setup();
 
-        let (client, server) = UnixStream::pair().unwrap();
-
-        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
-        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
-
-        let (tx, rx) = channel::<bool>();
-        let barrier = sync::Arc::new(sync::Barrier::new(2));
-        let barrier_clone: sync::Arc<sync::Barrier> = barrier.clone();
-
-        thread::spawn(move || {
-            let mut fabric = ibmad::sim::Fabric::new(server_file);
-            fabric.response_delay = Some(600);
-            ibmad::sim::build_standard_fabric(&mut fabric);
-            barrier_clone.wait();
-            let _ = fabric.run(rx);
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            sim.response_delay = Some(600);
+            ibmad::sim::build_standard_fabric(sim);
         });
 
-        let port = IbMadPort { file: client_file };
-
-        let mut fabric = ibmad::discovery::Fabric {
-            port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 1,
-            timeout: 50,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
-
-        barrier.wait();
-
         fabric.seq_discover().expect("Discovery should succeed");
 
         let total_switch_entries = fabric.switches.len();
@@ -196,10 +134,10 @@ mod discovery_tests {
             sw.node_info.nports = spine_nports as u8;
             let sw_rc = fabric.add_switch(sw);
             {
-                let mut n = sw_rc.borrow_mut();
+                let mut n = sw_rc.write().unwrap();
                 for p in 0..=(spine_nports as u8) {
                     n.ports
-                        .push(Rc::new(RefCell::new(Port::new_port(p, lid, sw_rc.clone()))));
+                        .push(Arc::new(RwLock::new(Port::new_port(p, lid, sw_rc.clone()))));
                 }
             }
             spines.push(sw_rc);
@@ -216,10 +154,10 @@ mod discovery_tests {
             sw.node_info.nports = leaf_nports as u8;
             let sw_rc = fabric.add_switch(sw);
             {
-                let mut n = sw_rc.borrow_mut();
+                let mut n = sw_rc.write().unwrap();
                 for p in 0..=(leaf_nports as u8) {
                     n.ports
-                        .push(Rc::new(RefCell::new(Port::new_port(p, lid, sw_rc.clone()))));
+                        .push(Arc::new(RwLock::new(Port::new_port(p, lid, sw_rc.clone()))));
                 }
             }
             lid += 1;
@@ -230,8 +168,8 @@ mod discovery_tests {
                     let leaf_port = hcas_per_leaf + 1 + s_idx * links_per_spine + link;
                     let spine_port = l * links_per_spine + link + 1;
 
-                    let leaf_port_rc = sw_rc.borrow().ports[leaf_port].clone();
-                    let spine_port_rc = spine_rc.borrow().ports[spine_port].clone();
+                    let leaf_port_rc = sw_rc.read().unwrap().ports[leaf_port].clone();
+                    let spine_port_rc = spine_rc.read().unwrap().ports[spine_port].clone();
                     ibmad::sim::connect_ports(&leaf_port_rc, &spine_port_rc);
                 }
             }
@@ -243,18 +181,18 @@ mod discovery_tests {
                     0x3000_0000_0000_0000 + hca_count as u64,
                 );
                 let hca_rc = fabric.add_hca(hca);
-                let hca_port = Rc::new(RefCell::new(Port::new_port(
+                let hca_port = Arc::new(RwLock::new(Port::new_port(
                     1,
                     1000 + hca_count,
                     hca_rc.clone(),
                 )));
-                hca_rc.borrow_mut().ports.push(hca_port.clone());
+                hca_rc.write().unwrap().ports.push(hca_port.clone());
 
-                let leaf_port_rc = sw_rc.borrow().ports[h + 1].clone();
+                let leaf_port_rc = sw_rc.read().unwrap().ports[h + 1].clone();
                 ibmad::sim::connect_ports(&leaf_port_rc, &hca_port);
 
                 if hca_count == 1 {
-                    fabric.dr_paths.insert([0; 64], Rc::downgrade(&hca_port));
+                    fabric.dr_paths.insert([0; 64], Arc::downgrade(&hca_port));
                 }
             }
         }
@@ -264,41 +202,10 @@ mod discovery_tests {
     fn test_dual_uplink_discovery() {
         let _ = env_logger::try_init();
 
-        let (client, server) = UnixStream::pair().unwrap();
-        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
-        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
-
-        let (tx, rx) = channel::<bool>();
-        let barrier = sync::Arc::new(sync::Barrier::new(2));
-        let barrier_clone = barrier.clone();
-
-        thread::spawn(move || {
-            let mut fabric = ibmad::sim::Fabric::new(server_file);
-            fabric.response_delay = Some(100);
-            build_dual_uplink_fabric(&mut fabric);
-            barrier_clone.wait();
-            let _ = fabric.run(rx);
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            sim.response_delay = Some(100);
+            build_dual_uplink_fabric(sim);
         });
-
-        let port = IbMadPort { file: client_file };
-        let mut fabric = ibmad::discovery::Fabric {
-            port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 1,
-            timeout: 50,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
-
-        barrier.wait();
         fabric.seq_discover().expect("Dual-uplink discovery should succeed");
 
         assert_eq!(fabric.switches.len(), 6, "Expected 2 spines + 4 leaves");
@@ -375,10 +282,10 @@ mod discovery_tests {
             sw.node_info.nports = k as u8;
             let sw_rc = fabric.add_switch(sw);
             {
-                let mut n = sw_rc.borrow_mut();
+                let mut n = sw_rc.write().unwrap();
                 for p in 0..=(k as u8) {
                     n.ports
-                        .push(Rc::new(RefCell::new(Port::new_port(p, lid, sw_rc.clone()))));
+                        .push(Arc::new(RwLock::new(Port::new_port(p, lid, sw_rc.clone()))));
                 }
             }
             cores.push(sw_rc);
@@ -398,13 +305,10 @@ mod discovery_tests {
                 sw.node_info.nports = k as u8;
                 let sw_rc = fabric.add_switch(sw);
                 {
-                    let mut n = sw_rc.borrow_mut();
+                    let mut n = sw_rc.write().unwrap();
                     for p in 0..=(k as u8) {
-                        n.ports.push(Rc::new(RefCell::new(Port::new_port(
-                            p,
-                            lid,
-                            sw_rc.clone(),
-                        ))));
+                        n.ports
+                            .push(Arc::new(RwLock::new(Port::new_port(p, lid, sw_rc.clone()))));
                     }
                 }
 
@@ -415,9 +319,9 @@ mod discovery_tests {
                     let agg_port = edges_per_pod + 1 + c_off;
                     let core_port = pod + 1;
 
-                    let agg_port_rc = sw_rc.borrow().ports[agg_port].clone();
+                    let agg_port_rc = sw_rc.read().unwrap().ports[agg_port].clone();
                     let core_port_rc =
-                        cores[core_base + c_off].borrow().ports[core_port].clone();
+                        cores[core_base + c_off].read().unwrap().ports[core_port].clone();
                     ibmad::sim::connect_ports(&agg_port_rc, &core_port_rc);
                 }
 
@@ -433,13 +337,10 @@ mod discovery_tests {
                 sw.node_info.nports = k as u8;
                 let sw_rc = fabric.add_switch(sw);
                 {
-                    let mut n = sw_rc.borrow_mut();
+                    let mut n = sw_rc.write().unwrap();
                     for p in 0..=(k as u8) {
-                        n.ports.push(Rc::new(RefCell::new(Port::new_port(
-                            p,
-                            lid,
-                            sw_rc.clone(),
-                        ))));
+                        n.ports
+                            .push(Arc::new(RwLock::new(Port::new_port(p, lid, sw_rc.clone()))));
                     }
                 }
 
@@ -448,8 +349,8 @@ mod discovery_tests {
                     let edge_port = hcas_per_edge + 1 + a_idx;
                     let agg_port = e + 1;
 
-                    let edge_port_rc = sw_rc.borrow().ports[edge_port].clone();
-                    let agg_port_rc = agg_rc.borrow().ports[agg_port].clone();
+                    let edge_port_rc = sw_rc.read().unwrap().ports[edge_port].clone();
+                    let agg_port_rc = agg_rc.read().unwrap().ports[agg_port].clone();
                     ibmad::sim::connect_ports(&edge_port_rc, &agg_port_rc);
                 }
 
@@ -460,18 +361,18 @@ mod discovery_tests {
                         0x5000_0000_0000_0000 + hca_count as u64,
                     );
                     let hca_rc = fabric.add_hca(hca);
-                    let hca_port = Rc::new(RefCell::new(Port::new_port(
+                    let hca_port = Arc::new(RwLock::new(Port::new_port(
                         1,
                         1000 + hca_count,
                         hca_rc.clone(),
                     )));
-                    hca_rc.borrow_mut().ports.push(hca_port.clone());
+                    hca_rc.write().unwrap().ports.push(hca_port.clone());
 
-                    let edge_port_rc = sw_rc.borrow().ports[h + 1].clone();
+                    let edge_port_rc = sw_rc.read().unwrap().ports[h + 1].clone();
                     ibmad::sim::connect_ports(&edge_port_rc, &hca_port);
 
                     if hca_count == 1 {
-                        fabric.dr_paths.insert([0; 64], Rc::downgrade(&hca_port));
+                        fabric.dr_paths.insert([0; 64], Arc::downgrade(&hca_port));
                     }
                 }
 
@@ -484,41 +385,10 @@ mod discovery_tests {
     fn test_3level_fat_tree_discovery() {
         let _ = env_logger::try_init();
 
-        let (client, server) = UnixStream::pair().unwrap();
-        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
-        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
-
-        let (tx, rx) = channel::<bool>();
-        let barrier = sync::Arc::new(sync::Barrier::new(2));
-        let barrier_clone = barrier.clone();
-
-        thread::spawn(move || {
-            let mut fabric = ibmad::sim::Fabric::new(server_file);
-            fabric.response_delay = Some(100);
-            build_3level_fat_tree(&mut fabric);
-            barrier_clone.wait();
-            let _ = fabric.run(rx);
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            sim.response_delay = Some(100);
+            build_3level_fat_tree(sim);
         });
-
-        let port = IbMadPort { file: client_file };
-        let mut fabric = ibmad::discovery::Fabric {
-            port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 1,
-            timeout: 50,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
-
-        barrier.wait();
         fabric
             .seq_discover()
             .expect("3-level fat tree discovery should succeed");
@@ -650,22 +520,9 @@ mod discovery_tests {
             return;
         }
 
-        let mut fabric = ibmad::discovery::Fabric {
-            port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 2,
-            timeout: 100,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
+        let mut fabric = discovery_client(port);
+        fabric.retries = 2;
+        fabric.timeout = 100;
 
         match fabric.seq_discover() {
             Ok(_) => {}
@@ -729,22 +586,9 @@ mod discovery_tests {
             return;
         }
 
-        let mut fabric = ibmad::discovery::Fabric {
-            port: smp_port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 2,
-            timeout: 100,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
+        let mut fabric = discovery_client(smp_port);
+        fabric.retries = 2;
+        fabric.timeout = 100;
 
         if let Err(e) = fabric.seq_discover() {
             log::warn!("Discovery encountered an error: {:?}", e);
@@ -875,22 +719,9 @@ mod discovery_tests {
             return;
         }
 
-        let mut fabric = ibmad::discovery::Fabric {
-            port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 3,
-            timeout: 200,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
+        let mut fabric = discovery_client(port);
+        fabric.retries = 3;
+        fabric.timeout = 200;
 
         match fabric.seq_discover() {
             Ok(_) => {}
@@ -947,22 +778,8 @@ mod discovery_tests {
                     Ok(mut port) => {
                         let _ = mad::register_agent(&mut port, 0x81);
                         let agent_id = mad::register_agent(&mut port, 0x81).unwrap_or(0);
-                        let mut fabric = ibmad::discovery::Fabric {
-                            port: port,
-                            agent_id,
-                            node_map: HashMap::new(),
-                            nodes: Vec::new(),
-                            hcas: Vec::new(),
-                            switches: Vec::new(),
-                            dr_paths: HashMap::new(),
-                            ni_timings: Vec::new(),
-                            retries: 1,
-                            timeout: 50,
-                            mad_errors: 0,
-                            mad_timeouts: 0,
-                            mads_sent: 0,
-                            tid: 1,
-                        };
+                        let mut fabric = discovery_client(port);
+                        fabric.agent_id = agent_id;
 
                         let r = fabric.seq_discover();
 
@@ -1006,22 +823,8 @@ mod discovery_tests {
                 match open_smp_port(hca) {
                     Ok(mut port) => {
                         let agent_id = mad::register_agent(&mut port, 0x81).unwrap_or(0);
-                        let mut fabric = ibmad::discovery::Fabric {
-                            port: port,
-                            agent_id,
-                            node_map: HashMap::new(),
-                            nodes: Vec::new(),
-                            hcas: Vec::new(),
-                            switches: Vec::new(),
-                            dr_paths: HashMap::new(),
-                            ni_timings: Vec::new(),
-                            retries: 1,
-                            timeout: 50,
-                            mad_errors: 0,
-                            mad_timeouts: 0,
-                            mads_sent: 0,
-                            tid: 1,
-                        };
+                        let mut fabric = discovery_client(port);
+                        fabric.agent_id = agent_id;
 
                         let r = fabric.seq_discover_nvlink();
 
@@ -1065,72 +868,38 @@ mod discovery_tests {
 
         // Add ports to switch1
         {
-            let mut s1 = switch1_rc.borrow_mut();
+            let mut s1 = switch1_rc.write().unwrap();
             for i in 0..=5 {
                 let port = Port::new_port(i, 100, switch1_rc.clone());
-                s1.ports.push(Rc::new(RefCell::new(port)));
+                s1.ports.push(Arc::new(RwLock::new(port)));
             }
         }
 
         // Add ports to switch2
         {
-            let mut s2 = switch2_rc.borrow_mut();
+            let mut s2 = switch2_rc.write().unwrap();
             for i in 0..=5 {
                 let port = Port::new_port(i, 200, switch2_rc.clone());
-                s2.ports.push(Rc::new(RefCell::new(port)));
+                s2.ports.push(Arc::new(RwLock::new(port)));
             }
         }
 
         // Connect switch1 port 1 to switch2 port 1
-        let s1_p1 = switch1_rc.borrow().ports[1].clone(); // Port 0 is at index 0
-        let s2_p1 = switch2_rc.borrow().ports[1].clone();
+        let s1_p1 = switch1_rc.read().unwrap().ports[1].clone(); // Port 0 is at index 0
+        let s2_p1 = switch2_rc.read().unwrap().ports[1].clone();
         ibmad::sim::connect_ports(&s1_p1, &s2_p1);
 
         // Set switch1 port 0 as the entry point (simulating running on switch1)
-        let s1_p0 = switch1_rc.borrow().ports[0].clone();
+        let s1_p0 = switch1_rc.read().unwrap().ports[0].clone();
         // Insert as FIRST_HOP ([0; 64])
-        fabric.dr_paths.insert([0; 64], Rc::downgrade(&s1_p0));
+        fabric.dr_paths.insert([0; 64], Arc::downgrade(&s1_p0));
     }
 
     #[test]
     fn test_switch_root_discovery() {
         common::setup();
 
-        let (client, server) = UnixStream::pair().unwrap();
-        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
-        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
-
-        let (tx, rx) = channel::<bool>();
-        let barrier = sync::Arc::new(sync::Barrier::new(2));
-        let barrier_clone = barrier.clone();
-
-        thread::spawn(move || {
-            let mut fabric = ibmad::sim::Fabric::new(server_file);
-            build_switch_root_fabric(&mut fabric);
-            barrier_clone.wait();
-            let _ = fabric.run(rx);
-        });
-
-        let port = IbMadPort { file: client_file };
-
-        let mut fabric = ibmad::discovery::Fabric {
-            port,
-            agent_id: 0,
-            node_map: HashMap::new(),
-            nodes: Vec::new(),
-            hcas: Vec::new(),
-            switches: Vec::new(),
-            dr_paths: HashMap::new(),
-            ni_timings: Vec::new(),
-            retries: 1,
-            timeout: 50,
-            mad_errors: 0,
-            mad_timeouts: 0,
-            mads_sent: 0,
-            tid: 1,
-        };
-
-        barrier.wait();
+        let (mut fabric, tx) = connect_to_sim(build_switch_root_fabric);
 
         // Run discovery
         fabric.seq_discover().expect("Discovery should not fail hard");
@@ -1147,4 +916,1432 @@ mod discovery_tests {
         let s2 = fabric.nodes.iter().find(|n| n.read().unwrap().node_guid == 0x1002);
         assert!(s2.is_some(), "Should find switch-2");
     }
+
+    /// switch-1 (GUID 0x1001, LID 1) with CAs host-0, host-1, ... (GUID 0x2000 + i,
+    /// LID 2 + i) on its ports 1 and up. host-0 is the local node. Tests adjust the
+    /// nodes through `sim_node` and `sim_port` afterwards.
+    fn build_star_fabric(fabric: &mut ibmad::sim::Fabric, hosts: u16) {
+        let mut sw = ibmad::sim::Node::new_switch("switch-1", 0x1001);
+        sw.node_info.nports = hosts as u8;
+        sw.lid = 1;
+        let sw_rc = fabric.add_switch(sw);
+        for p in 0..=hosts as u8 {
+            let port = Port::new_port(p, 1, sw_rc.clone());
+            sw_rc
+                .write()
+                .unwrap()
+                .ports
+                .push(Arc::new(RwLock::new(port)));
+        }
+
+        for i in 0..hosts {
+            let hca = ibmad::sim::Node::new_hca(&format!("host-{}", i), 0x2000 + i as u64);
+            let hca_rc = fabric.add_hca(hca);
+            let hca_port = Arc::new(RwLock::new(Port::new_port(1, 2 + i, hca_rc.clone())));
+            hca_rc.write().unwrap().ports.push(hca_port.clone());
+
+            let sw_port = sw_rc.read().unwrap().ports[i as usize + 1].clone();
+            ibmad::sim::connect_ports(&sw_port, &hca_port);
+
+            if i == 0 {
+                fabric.dr_paths.insert([0; 64], Arc::downgrade(&hca_port));
+            }
+        }
+    }
+
+    fn sim_node(fabric: &ibmad::sim::Fabric, guid: u64) -> Arc<RwLock<ibmad::sim::Node>> {
+        fabric
+            .nodes
+            .iter()
+            .find(|n| n.read().unwrap().node_info.node_guid == guid)
+            .unwrap_or_else(|| panic!("no sim node {:#x}", guid))
+            .clone()
+    }
+
+    fn sim_port(fabric: &ibmad::sim::Fabric, guid: u64, num: u8) -> Arc<RwLock<Port>> {
+        let node = sim_node(fabric, guid);
+        let node = node.read().unwrap();
+        node.ports
+            .iter()
+            .find(|p| p.read().unwrap().num == num)
+            .unwrap_or_else(|| panic!("no port {} on sim node {:#x}", num, guid))
+            .clone()
+    }
+
+    /// A star fabric whose links are 1X/4X and SDR/DDR/QDR capable on both ends.
+    /// host-0's link came up at 1X on both ends; host-1's has the switch end stuck at
+    /// DDR.
+    fn build_degraded_link_fabric(fabric: &mut ibmad::sim::Fabric) {
+        build_star_fabric(fabric, 2);
+        for i in 0..2u8 {
+            let sw_port = sim_port(fabric, 0x1001, i + 1);
+            let hca_port = sim_port(fabric, 0x2000 + i as u64, 1);
+            for port in [&sw_port, &hca_port] {
+                let pi = &mut port.write().unwrap().port_info;
+                pi.set_link_width_supported(0x3);
+                pi.set_link_width_enabled(0x3);
+                pi.set_link_speed_supported(0x7);
+                pi.set_link_speed_enabled(0x7);
+            }
+            ibmad::sim::connect_ports(&sw_port, &hca_port);
+        }
+        for port in [sim_port(fabric, 0x1001, 1), sim_port(fabric, 0x2000, 1)] {
+            port.write().unwrap().port_info.set_link_width_active(1);
+        }
+        sim_port(fabric, 0x1001, 2)
+            .write()
+            .unwrap()
+            .port_info
+            .set_link_speed_active(2);
+    }
+
+    #[test]
+    fn test_partition_membership_discovery() {
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_star_fabric(sim, 2);
+            sim_port(sim, 0x2000, 1).write().unwrap().pkeys = vec![0xffff, 0x8001];
+            sim_port(sim, 0x2001, 1).write().unwrap().pkeys = vec![0x7fff, 0x0001, 0x0002];
+        });
+        fabric.seq_discover().expect("Discovery should succeed");
+        let switch = discovered_node(&fabric, "switch-1");
+        let enforcement_cap = switch.switch_info.unwrap().partition_enforcement_cap();
+        assert_ne!(enforcement_cap, 0, "SwitchInfo is kept from discovery");
+        fabric
+            .discover_partitions()
+            .expect("Partition discovery should succeed");
+
+        let _ = tx.send(true);
+
+        let describe = |port: &sync::Arc<sync::RwLock<ibmad::discovery::Port>>| {
+            let port = port.read().unwrap();
+            let parent = port.parent.upgrade().unwrap();
+            let desc = parent.read().unwrap().description.clone().unwrap();
+            (desc, port.number)
+        };
+
+        let mut members: Vec<(String, u8, PKeyMembership)> = fabric
+            .partition_members(0x8001)
+            .unwrap()
+            .iter()
+            .map(|(p, m)| {
+                let (desc, num) = describe(p);
+                (desc, num, *m)
+            })
+            .collect();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            members,
+            vec![
+                ("host-0".to_string(), 1, PKeyMembership::Full),
+                ("host-1".to_string(), 1, PKeyMembership::Limited),
+            ]
+        );
+
+        // Default partition: host-1 is only a limited member, everyone else full.
+        let default_members = fabric.partition_members(0xffff).unwrap();
+        assert_eq!(default_members.len(), 5, "2 HCA ports + 3 switch ports");
+        for (p, m) in &default_members {
+            let (desc, _) = describe(p);
+            let expected = if desc == "host-1" {
+                PKeyMembership::Limited
+            } else {
+                PKeyMembership::Full
+            };
+            assert_eq!(*m, expected, "Unexpected membership for '{}'", desc);
+        }
+
+        assert!(fabric.partition_members(0x0003).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_partition_discovery_multi_port_ca() {
+        common::setup();
+
+        // host-0 gets a second port, cabled to a third switch port, with its own table.
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_star_fabric(sim, 2);
+            let sw_rc = sim_node(sim, 0x1001);
+            sw_rc.write().unwrap().node_info.nports = 3;
+            let sw_port = Arc::new(RwLock::new(Port::new_port(3, 1, sw_rc.clone())));
+            sw_rc.write().unwrap().ports.push(sw_port.clone());
+
+            let hca_rc = sim_node(sim, 0x2000);
+            hca_rc.write().unwrap().node_info.nports = 2;
+            let hca_port = Arc::new(RwLock::new(Port::new_port(2, 4, hca_rc.clone())));
+            hca_rc.write().unwrap().ports.push(hca_port.clone());
+            ibmad::sim::connect_ports(&sw_port, &hca_port);
+
+            sim_port(sim, 0x2000, 1).write().unwrap().pkeys = vec![0xffff, 0x8001];
+            hca_port.write().unwrap().pkeys = vec![0xffff, 0x8002];
+        });
+        fabric.seq_discover().expect("Discovery should succeed");
+        fabric
+            .discover_partitions()
+            .expect("Partition discovery should succeed");
+
+        let _ = tx.send(true);
+
+        let host = discovered_node(&fabric, "host-0");
+        assert_eq!(host.local_port, 1);
+        let pkeys = |num: u8| -> Vec<u16> {
+            let port = host
+                .ports
+                .iter()
+                .find(|p| p.read().unwrap().number == num)
+                .unwrap_or_else(|| panic!("host-0 port {} not discovered", num));
+            port.read().unwrap().pkeys.iter().map(|pk| pk.0).collect()
+        };
+        assert_eq!(pkeys(1), vec![0xffff, 0x8001]);
+        assert!(
+            pkeys(2).is_empty(),
+            "Port 2 can only be read through its own DR path"
+        );
+
+        assert_eq!(fabric.partition_members(0x8001).unwrap().len(), 1);
+        assert!(fabric.partition_members(0x8002).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_qos_tables_discovery() {
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            // The switch's port 2 maps SL3 onto VL9, which is beyond the 8 operational
+            // VLs every sim port runs with.
+            build_star_fabric(sim, 2);
+            let mut bad = ibmad::mad::sl2vl_table::uniform(8);
+            bad.set_vl(3, 9);
+            sim_port(sim, 0x1001, 2).write().unwrap().sl2vl = bad;
+        });
+        fabric.seq_discover().expect("Discovery should succeed");
+        let violations = fabric.discover_qos().expect("QoS discovery should succeed");
+
+        let _ = tx.send(true);
+
+        // Port 2 is misconfigured for every input port (0, 1 and 2).
+        assert_eq!(violations.len(), 3);
+        for v in &violations {
+            assert_eq!(v.port.read().unwrap().number, 2);
+            assert_eq!(v.operational_vls, 4);
+            assert_eq!(v.sls, vec![3]);
+        }
+        let mut inputs: Vec<u8> = violations.iter().map(|v| v.input_port).collect();
+        inputs.sort();
+        assert_eq!(inputs, vec![0, 1, 2]);
+
+        let switch = fabric.switches[0].upgrade().unwrap();
+        let switch = switch.read().unwrap();
+        let sw_port1 = switch
+            .ports
+            .iter()
+            .find(|p| p.read().unwrap().number == 1)
+            .unwrap()
+            .read()
+            .unwrap();
+        assert_eq!(sw_port1.sl2vl.len(), 3, "One table per input port 0..=2");
+        assert_eq!(
+            sw_port1.sl2vl[&1].mapping(),
+            [0, 1, 2, 3, 4, 5, 6, 7, 0, 1, 2, 3, 4, 5, 6, 7]
+        );
+
+        let vl_arb = sw_port1
+            .vl_arbitration
+            .as_ref()
+            .expect("VLArbitration expected");
+        assert_eq!(vl_arb.vl_high_limit, 4);
+        assert_eq!(vl_arb.high.len(), 8);
+        assert_eq!(vl_arb.low.len(), 8);
+        for (vl, entry) in vl_arb.low.iter().enumerate() {
+            assert_eq!(entry.vl, vl as u8);
+            assert_eq!(entry.weight, 64);
+        }
+        assert!(vl_arb.high.iter().all(|e| e.weight == 0));
+
+        for hca in &fabric.hcas {
+            let hca = hca.upgrade().unwrap();
+            let hca = hca.read().unwrap();
+            let port = hca.ports[0].read().unwrap();
+            assert_eq!(port.sl2vl.len(), 1);
+            assert!(port.sl2vl.contains_key(&1));
+        }
+    }
+
+    fn sim_sm_info(guid: u64, priority: u8, state: u8) -> ibmad::mad::sm_info {
+        let mut smi = ibmad::mad::sm_info { data: [0; 64] };
+        smi.set_guid(guid);
+        smi.set_act_count(42);
+        smi.set_priority(priority);
+        smi.set_sm_state(state);
+        smi
+    }
+
+    #[test]
+    fn test_subnet_manager_discovery() {
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            // The switch and host-2 both claim to be master, host-1 is a standby,
+            // host-3 reports a reserved state and host-0 runs no SM.
+            build_star_fabric(sim, 4);
+            for (guid, priority, state) in [
+                (0x1001, 15, 3),
+                (0x2001, 14, 2),
+                (0x2002, 1, 3),
+                (0x2003, 2, 7),
+            ] {
+                sim_node(sim, guid).write().unwrap().sm_info =
+                    Some(sim_sm_info(guid, priority, state));
+            }
+        });
+        fabric.seq_discover().expect("Discovery should succeed");
+        let mad_errors = fabric.mad_errors;
+        let report = fabric
+            .discover_subnet_managers()
+            .expect("SM discovery should succeed");
+        assert_eq!(
+            fabric.mad_errors,
+            mad_errors + 1,
+            "The reserved state is counted, not fatal"
+        );
+
+        let _ = tx.send(true);
+
+        let mut sms = report.managers.clone();
+        assert_eq!(sms.len(), 3, "switch-1, host-1 and host-2 run an SM");
+        sms.sort_by_key(|sm| sm.guid);
+
+        let summary: Vec<(u64, u8, SmState, u8)> = sms
+            .iter()
+            .map(|sm| (sm.guid, sm.port, sm.state.clone(), sm.priority))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0x1001, 0, SmState::Master, 15),
+                (0x2001, 1, SmState::Standby, 14),
+                (0x2002, 1, SmState::Master, 1),
+            ]
+        );
+        assert!(
+            sms.iter()
+                .all(|sm| sm.act_count == 42 && !sm.sm_key_present)
+        );
+
+        assert_eq!(
+            report.duplicate_masters.len(),
+            2,
+            "Duplicate master should be reported"
+        );
+        assert!(report.duplicate_masters.contains(&0x1001));
+        assert!(report.duplicate_masters.contains(&0x2002));
+        assert!(report.master().is_none());
+    }
+
+    #[test]
+    fn test_alias_guid_discovery() {
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            // host-0 has ten alias GUIDs spread over two GUIDInfo blocks; host-1 has
+            // more GUIDs configured than its GUIDCap allows.
+            build_star_fabric(sim, 2);
+            let aliases = [
+                (0x2000, 16, (1..=10).map(|n| 0xa000 + n).collect()),
+                (0x2001, 2, vec![0xb001, 0xb002, 0xb003]),
+            ];
+            for (guid, cap, alias_guids) in aliases {
+                let port = sim_port(sim, guid, 1);
+                let mut port = port.write().unwrap();
+                port.port_info.set_guid_cap(cap);
+                port.guids = std::iter::once(guid).chain(alias_guids).collect();
+            }
+        });
+        fabric.seq_discover().expect("Discovery should succeed");
+        fabric
+            .discover_guids()
+            .expect("GUID discovery should succeed");
+
+        let _ = tx.send(true);
+
+        assert_eq!(fabric.mad_errors, 0);
+
+        let host0 = fabric
+            .port_by_guid(0x2000)
+            .unwrap()
+            .expect("host-0 port GUID should resolve");
+        let guids = host0.read().unwrap().guids.clone();
+        assert_eq!(guids.len(), 11, "port GUID plus ten aliases");
+        assert_eq!(guids[0], 0x2000);
+        assert_eq!(guids[10], 0xa00a, "second GUIDInfo block should be read");
+
+        let host1 = fabric.port_by_guid(0x2001).unwrap().unwrap();
+        assert_eq!(
+            host1.read().unwrap().guids,
+            vec![0x2001, 0xb001],
+            "GUIDs beyond GUIDCap should not be read"
+        );
+
+        let alias_port = fabric
+            .port_by_guid(0xa007)
+            .unwrap()
+            .expect("alias GUID should resolve");
+        assert!(sync::Arc::ptr_eq(&alias_port, &host0));
+        let parent = alias_port.read().unwrap().parent.upgrade().unwrap();
+        assert_eq!(
+            parent.read().unwrap().description.as_deref(),
+            Some("host-0")
+        );
+
+        assert!(fabric.port_by_guid(0xb002).unwrap().is_none());
+
+        // GUIDInfo entries come back in the byte order NodeInfo's GUIDs are kept in.
+        for node_arc in &fabric.nodes {
+            let node = node_arc.read().unwrap();
+            let port = fabric
+                .port_by_guid(node.port_guid)
+                .unwrap()
+                .expect("NodeInfo port GUID should resolve");
+            let expected = match node.node_type {
+                ibmad::enums::IbNodeType::Switch => 0,
+                _ => node.local_port,
+            };
+            assert_eq!(port.read().unwrap().number, expected);
+        }
+
+        let switch = fabric.port_by_guid(0x1001).unwrap().unwrap();
+        assert_eq!(switch.read().unwrap().number, 0);
+    }
+
+    #[test]
+    fn test_m_key_protected_fabric() {
+        use ibmad::discovery::SmpTarget;
+
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            // The switch allows Gets but checks Sets (ProtectBits 1); host-1 checks
+            // everything (ProtectBits 3).
+            build_star_fabric(sim, 2);
+            for (guid, num, key, protect_bits) in [(0x1001, 0, 0x1234, 1), (0x2001, 1, 0x5678, 3)] {
+                let port = sim_port(sim, guid, num);
+                let mut port = port.write().unwrap();
+                port.port_info.set_mkey(key);
+                port.port_info.set_m_key_protect_bits(protect_bits);
+            }
+        });
+
+        let mut host1_path = [0u8; 64];
+        host1_path[1] = 1;
+        host1_path[2] = 2;
+        let mut switch_path = [0u8; 64];
+        switch_path[1] = 1;
+        fabric.m_key = MKeyProvider::Callback(sync::Arc::new(move |target, _guid| match target {
+            SmpTarget::DirectRoute(path) if *path == host1_path => 0x5678,
+            _ => 0x1234,
+        }));
+
+        // host-1 is only reachable with its own key.
+        fabric.seq_discover().expect("Discovery should succeed");
+        assert_eq!(fabric.nodes.len(), 3);
+        assert_eq!(fabric.mad_timeouts, 0);
+
+        let switch = fabric.node_map.get(&0x1001).unwrap().clone();
+        let port0 = switch.read().unwrap().ports[0].clone();
+        assert_eq!(port0.read().unwrap().number, 0);
+        assert_eq!(port0.read().unwrap().m_key_protect_bits, 1);
+        assert_eq!(port0.read().unwrap().m_key_violations, 0);
+
+        // A Set with the wrong key is dropped and reported as a violation.
+        fabric.m_key = MKeyProvider::Static(0xdead);
+        let err = fabric
+            .disable_port(SmpTarget::DirectRoute(switch_path), 2)
+            .expect_err("Set with the wrong M_Key should fail");
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(fabric.m_key_violations, 1);
+
+        let pi = fabric
+            .get_port_info(SmpTarget::DirectRoute(switch_path), 0)
+            .unwrap();
+        assert!(
+            pi.m_key_violations() > 0,
+            "Switch should count the violation"
+        );
+
+        // Per-GUID keys apply once the node is known.
+        let mut keys = HashMap::new();
+        keys.insert(0x1001, 0x1234);
+        fabric.m_key = MKeyProvider::PerGuid { keys, default: 0 };
+        assert_eq!(
+            fabric.m_key_for(&SmpTarget::DirectRoute(switch_path)),
+            0x1234
+        );
+        assert_eq!(fabric.m_key_for(&SmpTarget::DirectRoute(host1_path)), 0);
+        fabric
+            .disable_port(SmpTarget::DirectRoute(switch_path), 2)
+            .expect("Set with the right M_Key should be answered");
+        assert_eq!(fabric.m_key_violations, 1);
+
+        let _ = tx.send(true);
+    }
+
+    #[test]
+    fn test_m_key_set_lost_with_right_key() {
+        use ibmad::discovery::SmpTarget;
+
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_star_fabric(sim, 1);
+            let port = sim_port(sim, 0x1001, 0);
+            let mut port = port.write().unwrap();
+            port.port_info.set_mkey(0x1234);
+            port.port_info.set_m_key_protect_bits(1);
+            drop(port);
+            sim.faults
+                .add(FaultMatch::node(0x1001).attr(0x0010), FaultKind::Loss(1.0));
+        });
+        fabric.m_key = MKeyProvider::Static(0x1234);
+        let mut switch_path = [0u8; 64];
+        switch_path[1] = 1;
+
+        // The port is protected, but the key matched and no violation was counted,
+        // so the lost Set is an ordinary timeout.
+        let err = fabric
+            .set_node_description(SmpTarget::DirectRoute(switch_path), "renamed")
+            .expect_err("Set should be lost");
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(fabric.m_key_violations, 0);
+
+        let _ = tx.send(true);
+    }
+
+    #[test]
+    fn test_degraded_link_detection() {
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_degraded_link_fabric(sim);
+        });
+        fabric.seq_discover().expect("Discovery should succeed");
+        let _ = tx.send(true);
+
+        let hca = fabric.hcas[0].upgrade().unwrap();
+        let hca = hca.read().unwrap();
+        let port = hca.ports[0].read().unwrap();
+        assert_eq!(
+            port.link_width_supported,
+            vec![LinkWidth::X1, LinkWidth::X4]
+        );
+        assert_eq!(
+            port.link_speed_enabled,
+            vec![LinkSpeed::Sdr, LinkSpeed::Ddr, LinkSpeed::Qdr]
+        );
+        assert_eq!(port.mtu, Some(IbMtu::Mtu4096));
+        assert_eq!(port.mtu_cap, Some(IbMtu::Mtu4096));
+        assert_eq!(port.vl_cap, 4);
+        let remote = port.remote().expect("HCA port should be linked");
+        assert_eq!(remote.read().unwrap().number, 1);
+        drop(port);
+        drop(hca);
+
+        let mut links = fabric.degraded_links().expect("Link check should succeed");
+        links.sort_by_key(|l| {
+            let a = l.port.read().unwrap().lid;
+            let b = l.remote.read().unwrap().lid;
+            a.max(b)
+        });
+        assert_eq!(links.len(), 2);
+
+        // Host-0 (LID 2): narrow on both ends.
+        assert_eq!(
+            links[0].issues,
+            vec![LinkIssue::NarrowWidth {
+                active: LinkWidth::X1,
+                expected: LinkWidth::X4
+            }]
+        );
+
+        // Host-1 (LID 3): ends disagree on speed, and the link runs below QDR.
+        assert_eq!(links[1].issues.len(), 2);
+        assert!(matches!(
+            links[1].issues[0],
+            LinkIssue::SpeedMismatch { .. }
+        ));
+        assert_eq!(
+            links[1].issues[1],
+            LinkIssue::SlowSpeed {
+                active: LinkSpeed::Ddr,
+                expected: LinkSpeed::Qdr
+            }
+        );
+    }
+
+    #[test]
+    fn test_ibnetdiscover_export() {
+        common::setup();
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_degraded_link_fabric(sim);
+            let switch = sim.switches[0].upgrade().unwrap();
+            let mut switch = switch.write().unwrap();
+            switch.switch_info.as_mut().unwrap().set_enhanced_port0(1);
+        });
+        fabric.seq_discover().expect("Discovery should succeed");
+        let _ = tx.send(true);
+
+        let topo = fabric.to_ibnetdiscover().expect("Export should succeed");
+        log::debug!("{}", topo);
+
+        // The sim stores GUIDs in host order, discovery prints them in wire order.
+        let sw = 0x1001u64.to_be();
+        let h0 = 0x2000u64.to_be();
+        let h1 = 0x2001u64.to_be();
+
+        let expected_switch = format!(
+            "vendid=0xcf09\ndevid=0xd2f2\nsysimgguid=0x{sw:x}\nswitchguid=0x{sw:x}({sw:x})\n\
+             Switch\t2 \"S-{sw:016x}\"\t\t# \"switch-1\" enhanced port 0 lid 1 lmc 0\n\
+             [1]\t\"H-{h0:016x}\"[1]({h0:x}) \t\t# \"host-0\" lid 2 1xQDR\n\
+             [2]\t\"H-{h1:016x}\"[1]({h1:x}) \t\t# \"host-1\" lid 3 4xDDR\n"
+        );
+        assert!(topo.contains(&expected_switch), "switch block:\n{}", topo);
+
+        let expected_ca = format!(
+            "vendid=0x2c9\ndevid=0x80\nsysimgguid=0x{h0:x}\ncaguid=0x{h0:x}\n\
+             Ca\t1 \"H-{h0:016x}\"\t\t# \"host-0\"\n\
+             [1]({h0:x}) \t\"S-{sw:016x}\"[1]\t\t# lid 2 lmc 0 \"switch-1\" lid 1 1xQDR\n"
+        );
+        assert!(topo.contains(&expected_ca), "CA block:\n{}", topo);
+        assert!(topo.contains(&format!("# Initiated from node {h0:016x} port {h0:016x}")));
+
+        // Switch first, then both CAs.
+        let sw_at = topo.find("Switch\t").unwrap();
+        assert!(topo.match_indices("Ca\t").all(|(i, _)| i > sw_at));
+        assert_eq!(topo.match_indices("Ca\t").count(), 2);
+    }
+
+    /// switch-1 (the local node) is cabled to switch-2 twice, ports 1-1 and 2-2, so
+    /// either end of one link can be reached over the other.
+    fn build_parallel_link_fabric(fabric: &mut ibmad::sim::Fabric) {
+        let mut switches = Vec::new();
+        for (desc, guid, lid) in [("switch-1", 0x1001, 1), ("switch-2", 0x1002, 2)] {
+            let mut sw = ibmad::sim::Node::new_switch(desc, guid);
+            sw.node_info.nports = 2;
+            sw.lid = lid;
+            let sw_rc = fabric.add_switch(sw);
+            for i in 0..=2 {
+                let mut port = Port::new_port(i, lid, sw_rc.clone());
+                port.port_info.set_link_width_supported(0x03); // 1x, 4x
+                port.port_info.set_link_width_enabled(0x03);
+                sw_rc
+                    .write()
+                    .unwrap()
+                    .ports
+                    .push(Arc::new(RwLock::new(port)));
+            }
+            switches.push(sw_rc);
+        }
+
+        for i in 1..=2 {
+            let a = switches[0].read().unwrap().ports[i].clone();
+            let b = switches[1].read().unwrap().ports[i].clone();
+            ibmad::sim::connect_ports(&a, &b);
+        }
+        let s1_p0 = switches[0].read().unwrap().ports[0].clone();
+        fabric.dr_paths.insert([0; 64], Arc::downgrade(&s1_p0));
+    }
+
+    fn connect_to_sim<F>(build: F) -> (ibmad::discovery::Fabric, sync::mpsc::Sender<bool>)
+    where
+        F: FnOnce(&mut ibmad::sim::Fabric) + Send + 'static,
+    {
+        let (client, server) = UnixStream::pair().unwrap();
+        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
+        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
+
+        let (tx, rx) = channel::<bool>();
+        let barrier = sync::Arc::new(sync::Barrier::new(2));
+        let barrier_clone = barrier.clone();
+
+        thread::spawn(move || {
+            let mut fabric = ibmad::sim::Fabric::new(server_file);
+            build(&mut fabric);
+            barrier_clone.wait();
+            let _ = fabric.run(rx);
+        });
+
+        let fabric = discovery_client(IbMadPort { file: client_file });
+        barrier.wait();
+        (fabric, tx)
+    }
+
+    /// A discovery fabric sending its MADs over `port`.
+    fn discovery_client(port: IbMadPort) -> ibmad::discovery::Fabric {
+        ibmad::discovery::Fabric {
+            port: Some(port),
+            agent_id: 0,
+            node_map: HashMap::new(),
+            nodes: Vec::new(),
+            hcas: Vec::new(),
+            switches: Vec::new(),
+            dr_paths: HashMap::new(),
+            target_guids: HashMap::new(),
+            ni_timings: Vec::new(),
+            retries: 1,
+            timeout: 50,
+            mad_errors: 0,
+            mad_timeouts: 0,
+            mads_sent: 0,
+            tid: 1,
+            m_key: MKeyProvider::default(),
+            m_key_violations: 0,
+        }
+    }
+
+    #[test]
+    fn test_port_admin_sim() {
+        common::setup();
+        let (mut fabric, tx) = connect_to_sim(build_parallel_link_fabric);
+
+        let local = SmpTarget::DirectRoute([0; 64]);
+        let mut path = [0; 64];
+        path[1] = 2;
+        let peer = SmpTarget::DirectRoute(path);
+
+        let pi = fabric
+            .disable_port(local, 1)
+            .expect("Disable should succeed");
+        assert_eq!(pi.port_physical_state(), IbPortPhyState::Disabled as u8);
+        assert_eq!(pi.port_state(), IbPortLinkLayerState::Down as u8);
+        let remote = fabric.get_port_info(peer, 1).unwrap();
+        assert_eq!(remote.port_physical_state(), IbPortPhyState::Polling as u8);
+        assert_eq!(remote.port_state(), IbPortLinkLayerState::Down as u8);
+        assert_eq!(
+            fabric.get_port_info(peer, 2).unwrap().port_state(),
+            IbPortLinkLayerState::Active as u8,
+            "The other link is untouched"
+        );
+
+        let pi = fabric.enable_port(local, 1).expect("Enable should succeed");
+        assert_eq!(pi.port_physical_state(), IbPortPhyState::LinkUp as u8);
+        assert_eq!(pi.port_state(), IbPortLinkLayerState::Active as u8);
+        let remote = fabric.get_port_info(peer, 1).unwrap();
+        assert_eq!(remote.port_state(), IbPortLinkLayerState::Active as u8);
+        assert_eq!(
+            remote.link_width_active(),
+            0x02,
+            "4x before the width change"
+        );
+
+        // LinkWidthEnabled applies on the next training, which the reset forces.
+        let pi = fabric.set_port_link_width_enabled(local, 1, 0x01).unwrap();
+        assert_eq!(pi.link_width_enabled(), 0x01);
+        assert_eq!(pi.link_width_active(), 0x02);
+        let pi = fabric.reset_port(local, 1).expect("Reset should succeed");
+        assert_eq!(pi.link_width_active(), 0x01);
+        assert_eq!(
+            fabric.get_port_info(peer, 1).unwrap().link_width_active(),
+            0x01
+        );
+
+        // Writes persist and other fields are preserved.
+        let pi = fabric
+            .modify_port_info(local, 0, |pi| pi.set_lid(9))
+            .unwrap();
+        assert_eq!(pi.lid(), 9);
+        assert_eq!(fabric.get_port_info(local, 0).unwrap().lid(), 9);
+        assert_eq!(
+            fabric.get_port_info(local, 1).unwrap().link_width_enabled(),
+            0x01
+        );
+
+        let mut bad = fabric.get_port_info(local, 2).unwrap();
+        bad.set_port_state(7);
+        let err = fabric.set_port_info(local, 2, &bad).unwrap_err();
+        assert!(err.to_string().contains("0x1c"), "{}", err);
+
+        let desc = fabric
+            .set_node_description(peer, "spine-renamed")
+            .expect("NodeDesc Set should succeed");
+        assert_eq!(desc, "spine-renamed");
+
+        fabric.seq_discover().expect("Discovery should succeed");
+        let _ = tx.send(true);
+
+        let spine = fabric
+            .nodes
+            .iter()
+            .find(|n| n.read().unwrap().node_guid == 0x1002)
+            .expect("switch-2 should be discovered");
+        assert_eq!(
+            spine.read().unwrap().description.as_deref(),
+            Some("spine-renamed")
+        );
+    }
+
+    #[test]
+    fn test_set_node_description() {
+        common::setup();
+        let (mut fabric, tx) = connect_to_sim(build_parallel_link_fabric);
+
+        let local = SmpTarget::DirectRoute([0; 64]);
+        let long = "switch-1 ".repeat(8);
+        assert_eq!(long.len(), 72);
+        let desc = fabric
+            .set_node_description(local, &long)
+            .expect("NodeDesc Set should succeed");
+        assert_eq!(desc, long[..64], "Truncated to the attribute size");
+
+        let desc = fabric.set_node_description(local, "leaf-a").unwrap();
+        assert_eq!(desc, "leaf-a", "A shorter name leaves no trailing bytes");
+
+        let pi = fabric.set_port_link_speed_enabled(local, 1, 0x03).unwrap();
+        assert_eq!(pi.link_speed_enabled(), 0x03);
+        assert_eq!(pi.link_width_enabled(), 0x03, "Other fields are preserved");
+
+        fabric.seq_discover().expect("Discovery should succeed");
+        let _ = tx.send(true);
+
+        let node = discovered_node(&fabric, "leaf-a");
+        assert_eq!(node.node_guid, 0x1001);
+    }
+
+    #[test]
+    fn test_sim_fault_injection() {
+        common::setup();
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_parallel_link_fabric(sim);
+            let port_info = FaultMatch::node(0x1001).attr(0x0015);
+            sim.faults
+                .add(port_info.port(1), FaultKind::DropFirst(1))
+                .add(port_info.port(2), FaultKind::Status(MAD_STATUS_BUSY))
+                .add(port_info.port(0), FaultKind::Duplicate)
+                .add(
+                    FaultMatch::node(0x1002).attr(0x0015).port(1),
+                    FaultKind::WrongTid,
+                )
+                .add(
+                    FaultMatch::node(0x1002).attr(0x0015).port(2),
+                    FaultKind::Truncate(24),
+                )
+                .add(
+                    FaultMatch::node(0x1002).attr(0x0015).port(0),
+                    FaultKind::Delay(time::Duration::from_millis(80)),
+                );
+        });
+
+        let local = SmpTarget::DirectRoute([0; 64]);
+        let mut path = [0; 64];
+        path[1] = 1;
+        let peer = SmpTarget::DirectRoute(path);
+
+        // Without retries every fault surfaces in the call that hit it.
+        fabric.retries = 0;
+
+        let err = fabric.get_port_info(local, 1).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            io::ErrorKind::TimedOut,
+            "First request is dropped"
+        );
+        assert_eq!(fabric.get_port_info(local, 1).unwrap().local_portnum(), 1);
+
+        let err = fabric.get_port_info(local, 2).unwrap_err();
+        assert!(err.to_string().contains("status 0x1 "), "{}", err);
+
+        // The duplicate of the first answer is discarded while waiting for the second.
+        for _ in 0..2 {
+            assert_eq!(fabric.get_port_info(local, 0).unwrap().lid(), 1);
+        }
+
+        let err = fabric.get_port_info(peer, 1).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            io::ErrorKind::TimedOut,
+            "Wrong TID is never matched"
+        );
+
+        // The client rejects the short read and gives up waiting.
+        let err = fabric.get_port_info(peer, 2).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            io::ErrorKind::TimedOut,
+            "Truncated UMAD is not accepted"
+        );
+
+        let err = fabric.get_port_info(peer, 0).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            io::ErrorKind::TimedOut,
+            "80ms is over the 50ms timeout"
+        );
+        fabric.timeout = 200;
+        assert_eq!(fabric.get_port_info(peer, 0).unwrap().lid(), 2);
+
+        let _ = tx.send(true);
+    }
+
+    #[test]
+    fn test_sim_lid_routing() {
+        common::setup();
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_3level_fat_tree(sim);
+            sim.compute_lfts();
+        });
+
+        // The agent is host-001 (LID 1001) under edge-pod0-0 (LID 7); LIDs 1-4 are
+        // the cores and host-016 sits in the last pod.
+        for lid in [1001, 7, 1, 1016] {
+            let pi = fabric
+                .get_port_info(SmpTarget::Lid(lid), 0)
+                .unwrap_or_else(|e| panic!("LID {} should be reachable: {}", lid, e));
+            assert_eq!(pi.lid(), lid);
+        }
+
+        let edge = SmpTarget::Lid(7);
+        let lft = fabric.get_lft(edge).expect("LFT read should succeed");
+        assert_eq!(lft.len(), 1017, "LinearFDBTop is the highest LID");
+        assert_eq!(lft[7], 0, "Own LID goes to the management port");
+        assert_eq!((lft[1001], lft[1002]), (1, 2));
+        assert!((3..=4).contains(&lft[1016]), "Other pods go up");
+        assert_eq!(lft[900], mad::switch::LFT_NO_ROUTE);
+
+        let err = fabric.get_port_info(SmpTarget::Lid(900), 0).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            io::ErrorKind::TimedOut,
+            "Unrouted LIDs are dropped"
+        );
+
+        // Pull host-002's route; it becomes unreachable while host-016 still works.
+        let mut block = fabric.get_lft_block(edge, 1002 / 64).unwrap();
+        block.data[1002 % 64] = mad::switch::LFT_NO_ROUTE;
+        let written = fabric.set_lft_block(edge, 1002 / 64, &block).unwrap();
+        assert_eq!(written.entry(1002 % 64), mad::switch::LFT_NO_ROUTE);
+        let err = fabric.get_port_info(SmpTarget::Lid(1002), 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            fabric.get_port_info(SmpTarget::Lid(1016), 0).unwrap().lid(),
+            1016
+        );
+
+        let _ = tx.send(true);
+
+        // From a switch-hosted agent the peer is one LFT hop away.
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            build_parallel_link_fabric(sim);
+            sim.compute_lfts();
+        });
+        assert_eq!(fabric.get_port_info(SmpTarget::Lid(2), 1).unwrap().lid(), 2);
+        assert_eq!(fabric.get_lft(SmpTarget::Lid(1)).unwrap(), vec![0xff, 0, 1]);
+        let _ = tx.send(true);
+    }
+
+    /// Switches, CAs and cables found by discovery.
+    fn discovered_shape(fabric: &ibmad::discovery::Fabric) -> (usize, usize, usize) {
+        let linked_ports: usize = fabric
+            .nodes
+            .iter()
+            .map(|n| {
+                let node = n.read().unwrap();
+                node.ports
+                    .iter()
+                    .filter(|p| p.read().unwrap().remote_port.is_some())
+                    .count()
+            })
+            .sum();
+        (fabric.switches.len(), fabric.hcas.len(), linked_ports / 2)
+    }
+
+    fn discovered_node(fabric: &ibmad::discovery::Fabric, desc: &str) -> ibmad::discovery::Node {
+        fabric
+            .nodes
+            .iter()
+            .map(|n| n.read().unwrap().clone())
+            .find(|n| n.description.as_deref() == Some(desc))
+            .unwrap_or_else(|| panic!("node '{}' not found", desc))
+    }
+
+    #[test]
+    fn test_sim_topology_generators() {
+        use ibmad::sim::topology::{Numbering, Topology};
+
+        common::setup();
+        let cases = [
+            // 3 levels of 4 switches, 16 switch-to-switch links and 8 CAs.
+            (Topology::FatTree { k: 2, n: 3 }, (12, 8, 24)),
+            (
+                Topology::LeafSpine {
+                    spines: 2,
+                    leaves: 4,
+                    hosts_per_leaf: 3,
+                    uplinks_per_leaf: 4,
+                },
+                (6, 12, 16 + 12),
+            ),
+            // 5 groups of 2 routers: one global link per group pair.
+            (
+                Topology::Dragonfly {
+                    groups: 5,
+                    routers_per_group: 2,
+                    hosts_per_router: 1,
+                    global_links_per_router: 2,
+                },
+                (10, 10, 10 + 5 + 10),
+            ),
+            // A ring of 3 and a pair in the second dimension, which is cabled twice.
+            (
+                Topology::Torus {
+                    dims: vec![3, 2],
+                    hosts_per_switch: 1,
+                },
+                (6, 6, 6 + 6 + 6),
+            ),
+        ];
+
+        for (topology, shape) in cases {
+            let t = topology.clone();
+            let (mut fabric, tx) = connect_to_sim(move |sim| {
+                t.build(sim, Numbering::default()).unwrap();
+            });
+            fabric
+                .seq_discover()
+                .unwrap_or_else(|e| panic!("{:?} discovery failed: {}", topology, e));
+            assert_eq!(discovered_shape(&fabric), shape, "{:?}", topology);
+
+            // Switches are numbered first, so the first CA follows the last switch.
+            let host = discovered_node(&fabric, "host-00001");
+            assert_eq!(host.lid as usize, shape.0 + 1, "{:?}", topology);
+            assert_eq!(host.node_guid, 0x7ffc_0000_0000_0000 + shape.0 as u64 + 1);
+            let _ = tx.send(true);
+        }
+
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            let nvlink = Topology::NvLink {
+                spines: 2,
+                leaves: 3,
+                gpus_per_leaf: 2,
+            };
+            let numbering = Numbering {
+                guid_base: 0xa000,
+                first_lid: 100,
+            };
+            nvlink.build(sim, numbering).unwrap();
+        });
+        // The first hop probes every port of the switch, and the Down ones never answer.
+        fabric.timeout = 5;
+        fabric
+            .seq_discover_nvlink()
+            .expect("NVLink discovery should succeed");
+        // A ring of 5 switches, 6 trunks and 6 GPUs.
+        assert_eq!(discovered_shape(&fabric), (5, 6, 5 + 6 + 6));
+        let spine = discovered_node(&fabric, "nvsw-spine-0");
+        assert_eq!((spine.node_guid, spine.lid), (0xa001, 100));
+        let _ = tx.send(true);
+
+        // Scale: thousands of nodes with LFTs programmed.
+        let mut sim = ibmad::sim::Fabric::new(fs::File::open("/dev/null").unwrap());
+        let leaf_spine = Topology::LeafSpine {
+            spines: 16,
+            leaves: 128,
+            hosts_per_leaf: 24,
+            uplinks_per_leaf: 16,
+        };
+        leaf_spine.build(&mut sim, Numbering::default()).unwrap();
+        assert_eq!((sim.switches.len(), sim.hcas.len()), (144, 3072));
+        let leaf = sim.switches[16].upgrade().unwrap();
+        assert_eq!(leaf.read().unwrap().lft.len(), 144 + 3072 + 1);
+
+        let too_big = Topology::FatTree { k: 64, n: 4 };
+        let err = too_big.build(&mut sim, Numbering::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_nvlink_graph_export() {
+        use ibmad::sim::topology::{Numbering, Topology};
+
+        common::setup();
+        let (mut fabric, tx) = connect_to_sim(|sim| {
+            let nvlink = Topology::NvLink {
+                spines: 2,
+                leaves: 2,
+                gpus_per_leaf: 1,
+            };
+            nvlink.build(sim, Numbering::default()).unwrap();
+        });
+        fabric.timeout = 5;
+        fabric
+            .seq_discover_nvlink()
+            .expect("NVLink discovery should succeed");
+        let _ = tx.send(true);
+
+        let id = |desc: &str| {
+            format!(
+                "0x{:016x}",
+                discovered_node(&fabric, desc).node_guid.to_be()
+            )
+        };
+        let (spine0, spine1) = (id("nvsw-spine-0"), id("nvsw-spine-1"));
+        let (leaf0, leaf1) = (id("nvsw-leaf-0"), id("nvsw-leaf-1"));
+
+        let dot = fabric.to_dot().unwrap();
+        assert!(!dot.contains("// hop"), "NVLink fabrics are ranked by tier");
+        assert!(dot.contains(&format!(
+            "{{ rank=same; \"{}\"; \"{}\"; }} // spine",
+            spine0, spine1
+        )));
+        assert!(dot.contains(&format!(
+            "{{ rank=same; \"{}\"; \"{}\"; }} // leaf",
+            leaf0, leaf1
+        )));
+        assert!(dot.contains("// endpoint"));
+        // The ring runs spine-0, spine-1, leaf-0, leaf-1 from port 73 to port 74.
+        assert!(dot.contains(&format!(
+            "\"{}\" -- \"{}\" [taillabel=\"73\", headlabel=\"74\", label=\"1xSDR\", style=bold, color=darkorange, constraint=false];",
+            spine0, spine1
+        )));
+        assert_eq!(
+            dot.matches("constraint=false").count(),
+            4,
+            "Every ring link is marked"
+        );
+        // Trunks: leaf-0 port 2 goes to spine-0 port 1.
+        assert!(dot.contains(&format!(
+            "\"{}\" -- \"{}\" [taillabel=\"1\", headlabel=\"2\", label=\"1xSDR\"];",
+            spine0, leaf0
+        )));
+
+        let graphml = fabric.to_graphml().unwrap();
+        assert_eq!(graphml.matches("<data key=\"d9\">spine</data>").count(), 2);
+        assert_eq!(graphml.matches("<data key=\"d9\">leaf</data>").count(), 2);
+        assert_eq!(
+            graphml.matches("<data key=\"d9\">endpoint</data>").count(),
+            2
+        );
+        assert_eq!(graphml.matches("<data key=\"d10\">true</data>").count(), 4);
+    }
+
+    /// GUID, LID, description and port count of every node, and each cable as
+    /// (GUID, port, remote GUID, remote port, width, speed).
+    #[allow(clippy::type_complexity)]
+    fn fabric_summary(
+        fabric: &ibmad::discovery::Fabric,
+    ) -> (
+        Vec<(u64, u16, Option<String>, u8)>,
+        Vec<(u64, u8, u64, u8, Option<LinkWidth>, Option<LinkSpeed>)>,
+    ) {
+        let mut nodes = Vec::new();
+        let mut links = Vec::new();
+        for node_arc in &fabric.nodes {
+            let node = node_arc.read().unwrap();
+            nodes.push((
+                node.node_guid,
+                node.lid,
+                node.description.clone(),
+                node.nports,
+            ));
+            for port_arc in &node.ports {
+                let port = port_arc.read().unwrap();
+                let Some(remote) = port.remote() else {
+                    continue;
+                };
+                let remote = remote.read().unwrap();
+                let remote_guid = remote.parent.upgrade().unwrap().read().unwrap().node_guid;
+                links.push((
+                    node.node_guid,
+                    port.number,
+                    remote_guid,
+                    remote.number,
+                    port.link_width_active,
+                    port.link_speed_active,
+                ));
+            }
+        }
+        nodes.sort();
+        links.sort_by_key(|l| (l.0, l.1));
+        (nodes, links)
+    }
+
+    #[test]
+    fn test_sim_import_topology() {
+        common::setup();
+        let text = fs::read_to_string("tests/data/topology/ibnetdiscover.txt").unwrap();
+        let offline = ibmad::discovery::Fabric::from_ibnetdiscover(&text).unwrap();
+        let mut expected = fabric_summary(&offline);
+        assert_eq!((expected.0.len(), expected.1.len()), (4, 6));
+        // node02 trained at FDR10, which has no LinkSpeed; the simulated link comes up
+        // at the default speed instead.
+        for link in expected.1.iter_mut().filter(|l| l.5.is_none()) {
+            link.5 = Some(LinkSpeed::Sdr);
+        }
+
+        let (mut fabric, tx) = connect_to_sim(move |sim| {
+            sim.import_ibnetdiscover(&text).unwrap();
+        });
+        fabric.timeout = 5;
+        fabric.seq_discover().expect("Discovery should succeed");
+        assert_eq!(fabric_summary(&fabric), expected);
+
+        // The agent sits where the file was generated from: node01 port 1.
+        let local = fabric
+            .get_port_info(SmpTarget::DirectRoute([0; 64]), 1)
+            .unwrap();
+        assert_eq!(local.lid(), 1);
+        let node02 = discovered_node(&fabric, "node02 mlx4_0");
+        let port = node02.ports[0].read().unwrap().clone();
+        assert_eq!(port.link_width_active, Some(LinkWidth::X4));
+
+        // A JSON dump of the live discovery rebuilds the same fabric.
+        let json = fabric.to_json().unwrap();
+        let _ = tx.send(true);
+        let (mut fabric, tx) = connect_to_sim(move |sim| {
+            sim.import_json(&json).unwrap();
+        });
+        fabric.timeout = 5;
+        fabric.seq_discover().expect("Discovery should succeed");
+        assert_eq!(fabric_summary(&fabric), expected);
+        let _ = tx.send(true);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sim_server_multi_client() {
+        use ibmad::sim::server::Server;
+        use ibmad::sim::topology::{Numbering, Topology};
+        use ibmad::sim::{DEFAULT_CLIENT, Fabric};
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Fabric>();
+        assert_send_sync::<Server>();
+
+        common::setup();
+        let mut sim = Fabric::new(fs::File::open("/dev/null").unwrap());
+        let leaf_spine = Topology::LeafSpine {
+            spines: 2,
+            leaves: 2,
+            hosts_per_leaf: 2,
+            uplinks_per_leaf: 2,
+        };
+        leaf_spine.build(&mut sim, Numbering::default()).unwrap();
+
+        // host-00001 is the default client; another host and a spine join it.
+        let first_port = |sim: &Fabric, desc: &str| {
+            let node = sim
+                .nodes
+                .iter()
+                .find(|n| n.read().unwrap().description == desc)
+                .unwrap()
+                .clone();
+            node.read().unwrap().ports[0].clone()
+        };
+        let host = first_port(&sim, "host-00004");
+        let host_client = sim.attach_client(&host);
+        let spine = first_port(&sim, "spine-0");
+        let spine_client = sim.attach_client(&spine);
+        let server = Server::new(sim);
+
+        // Two agents share the default client.
+        let clients = [
+            (DEFAULT_CLIENT, "host-00001"),
+            (DEFAULT_CLIENT, "host-00001"),
+            (host_client, "host-00004"),
+            (spine_client, "spine-0"),
+        ];
+        let mut sweeps = Vec::new();
+        for (client, start) in clients {
+            let file = server.connect(client).unwrap();
+            sweeps.push(tokio::task::spawn_blocking(move || {
+                let mut fabric = discovery_client(IbMadPort { file });
+                fabric.timeout = 5;
+                fabric.seq_discover().expect("Discovery should succeed");
+                let first = fabric.nodes[0].read().unwrap().description.clone();
+                assert_eq!(first.as_deref(), Some(start));
+                fabric
+            }));
+        }
+        for sweep in sweeps {
+            let fabric = sweep.await.unwrap();
+            // 2 spines, 2 leaves, 4 uplinks and 4 hosts.
+            assert_eq!(discovered_shape(&fabric), (4, 4, 8));
+        }
+
+        // The fabric can be changed while it is served.
+        let leaf = server
+            .fabric()
+            .lock()
+            .unwrap()
+            .nodes
+            .iter()
+            .find(|n| n.read().unwrap().description == "leaf-1")
+            .unwrap()
+            .clone();
+        leaf.write().unwrap().description = "renamed".to_string();
+        let file = server.connect(spine_client).unwrap();
+        let fabric = tokio::task::spawn_blocking(move || {
+            let mut fabric = discovery_client(IbMadPort { file });
+            fabric.timeout = 5;
+            fabric.seq_discover().unwrap();
+            fabric
+        })
+        .await
+        .unwrap();
+        assert_eq!(discovered_node(&fabric, "renamed").lid, 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sim_fabric_events() {
+        use ibmad::discovery::diff::PortId;
+        use ibmad::sim::events::{EventTrigger, FabricEvent};
+        use ibmad::sim::server::Server;
+        use ibmad::sim::topology::{Numbering, Topology};
+        use ibmad::sim::{DEFAULT_CLIENT, Fabric};
+
+        async fn sweep(server: &Server) -> io::Result<ibmad::discovery::Fabric> {
+            let file = server.connect(DEFAULT_CLIENT).unwrap();
+            tokio::task::spawn_blocking(move || {
+                let mut fabric = discovery_client(IbMadPort { file });
+                fabric.timeout = 5;
+                fabric.seq_discover().map(|_| fabric)
+            })
+            .await
+            .unwrap()
+        }
+
+        common::setup();
+        let mut sim = Fabric::new(fs::File::open("/dev/null").unwrap());
+        let leaf_spine = Topology::LeafSpine {
+            spines: 2,
+            leaves: 2,
+            hosts_per_leaf: 2,
+            uplinks_per_leaf: 2,
+        };
+        leaf_spine.build(&mut sim, Numbering::default()).unwrap();
+        let server = Server::new(sim);
+
+        // Switches are numbered first: spines 1-2, leaves 3-4, then hosts 5-8.
+        let guid = |i: u64| Numbering::default().guid_base + i;
+        let (spine0, spine1, leaf1, host2, host4) = (guid(1), guid(2), guid(4), guid(6), guid(8));
+
+        let before = sweep(&server).await.unwrap();
+        assert_eq!(discovered_shape(&before), (4, 4, 8));
+
+        // Everything changes right after the first sweep, before the second starts.
+        {
+            let mut sim = server.fabric().lock().unwrap();
+            let now = EventTrigger::AfterMads(sim.events.mads_processed());
+            sim.events
+                .add(
+                    now,
+                    FabricEvent::LinkDown {
+                        node_guid: host4,
+                        port: 1,
+                    },
+                )
+                .add(
+                    now,
+                    FabricEvent::SetLid {
+                        node_guid: host2,
+                        port: 1,
+                        lid: 42,
+                    },
+                )
+                .add(
+                    now,
+                    FabricEvent::SwapCables {
+                        a: (leaf1, 3),
+                        b: (leaf1, 4),
+                    },
+                )
+                .add(
+                    now,
+                    FabricEvent::SetNodeDescription {
+                        node_guid: spine0,
+                        description: "spine-0-rma".to_string(),
+                    },
+                );
+            assert_eq!(sim.events.len(), 4);
+        }
+        let after = sweep(&server).await.unwrap();
+        assert!(server.fabric().lock().unwrap().events.is_empty());
+
+        let diff = before.diff(&after).unwrap();
+        log::debug!("{}", diff);
+        assert_eq!(diff.nodes_removed.len(), 1);
+        assert_eq!(
+            diff.nodes_removed[0].description.as_deref(),
+            Some("host-00004")
+        );
+        assert_eq!(diff.links_removed.len(), 1);
+        assert_eq!(diff.lid_changes.len(), 1);
+        assert_eq!((diff.lid_changes[0].old, diff.lid_changes[0].new), (6, 42));
+        // Both uplinks of leaf-1 now go to the other spine.
+        let leaf1_id = discovered_node(&after, "leaf-1").node_guid;
+        for port in [3, 4] {
+            let port = PortId {
+                node_guid: leaf1_id,
+                port,
+            };
+            assert!(diff.links_moved.iter().any(|m| m.port == port), "{}", diff);
+        }
+        assert_eq!(discovered_node(&after, "spine-0-rma").lid, 1);
+
+        // A spine losing power in the middle of a sweep doesn't break discovery.
+        {
+            let mut sim = server.fabric().lock().unwrap();
+            let halfway = sim.events.mads_processed() + before.mads_sent / 2;
+            sim.events
+                .add(
+                    EventTrigger::AfterMads(halfway),
+                    FabricEvent::PowerOff { node_guid: spine1 },
+                )
+                .add(
+                    EventTrigger::AfterMads(halfway),
+                    FabricEvent::LinkUp {
+                        node_guid: host4,
+                        port: 1,
+                    },
+                );
+        }
+        sweep(&server)
+            .await
+            .expect("Discovery should survive churn");
+
+        // Time triggers fire with the next MAD.
+        server.fabric().lock().unwrap().events.add(
+            EventTrigger::At(time::Duration::ZERO),
+            FabricEvent::SetNodeDescription {
+                node_guid: host4,
+                description: "host-00004-new".to_string(),
+            },
+        );
+        let last = sweep(&server).await.unwrap();
+        // spine-0, both leaves and their one uplink each, and all hosts again.
+        assert_eq!(discovered_shape(&last), (3, 4, 6));
+        assert_eq!(discovered_node(&last, "host-00004-new").lid, 8);
+
+        // spine-1 stays dark through retraining and recabling until it is powered on.
+        {
+            let mut sim = server.fabric().lock().unwrap();
+            let swap = FabricEvent::SwapCables {
+                a: (leaf1, 3),
+                b: (leaf1, 4),
+            };
+            sim.apply_event(&swap).unwrap();
+            for (node_guid, port) in [(spine1, 1), (leaf1, 4)] {
+                let err = sim
+                    .apply_event(&FabricEvent::LinkUp { node_guid, port })
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            }
+        }
+        assert_eq!(discovered_shape(&sweep(&server).await.unwrap()), (3, 4, 6));
+        server
+            .fabric()
+            .lock()
+            .unwrap()
+            .apply_event(&FabricEvent::PowerOn { node_guid: spine1 })
+            .unwrap();
+        assert_eq!(discovered_shape(&sweep(&server).await.unwrap()), (4, 4, 8));
+    }
 }
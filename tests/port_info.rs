@@ -245,4 +245,138 @@ mod port_info_tests {
             }
         }
     }
+
+    #[test]
+    fn port_info_link_decoding_success() {
+        use ibmad::enums::{LinkSpeed, LinkWidth};
+        use ibmad::mad::CapabilityMask2;
+
+        let _ = env_logger::try_init();
+
+        let bytes = get_pi_mad().unwrap();
+        let dr_smp = ibmad::mad::dr_smp_mad::from_bytes(&bytes[24..256]).unwrap();
+        let mut pi = port_info::from_bytes(&dr_smp.attr_layout).unwrap();
+
+        assert_eq!(pi.active_link_width(), Some(LinkWidth::X4));
+        assert_eq!(pi.active_link_speed(), Some(LinkSpeed::Ndr));
+        assert_eq!(
+            pi.supported_link_widths(),
+            vec![LinkWidth::X1, LinkWidth::X2, LinkWidth::X4]
+        );
+        assert_eq!(pi.link_speed_ext_enabled(), 0xf);
+        assert_eq!(
+            pi.enabled_link_speeds(),
+            vec![
+                LinkSpeed::Sdr,
+                LinkSpeed::Fdr,
+                LinkSpeed::Edr,
+                LinkSpeed::Hdr,
+                LinkSpeed::Ndr
+            ]
+        );
+        assert_eq!(pi.data_rate_gbps(), Some(400.0));
+
+        let training = pi.link_training();
+        assert_eq!(training.max_width, Some(LinkWidth::X4));
+        assert_eq!(training.max_speed, Some(LinkSpeed::Ndr));
+        assert!(!training.is_degraded());
+
+        // Same port, trained at 1X HDR.
+        pi.set_link_width_active(LinkWidth::X1 as u8);
+        pi.set_link_speed_ext_active(4);
+        let training = pi.link_training();
+        assert!(training.is_width_degraded());
+        assert!(training.is_speed_degraded());
+        assert_eq!(training.active_data_rate_gbps(), Some(50.0));
+        assert_eq!(training.max_data_rate_gbps(), Some(400.0));
+
+        // Disabling wider widths means 1X is no longer a degradation.
+        pi.set_link_width_enabled(LinkWidth::X1 as u8);
+        assert!(!pi.link_training().is_width_degraded());
+
+        // Without an extended speed the legacy LinkSpeedActive is used.
+        pi.set_link_speed_ext_active(0);
+        pi.set_link_speed_active(4);
+        assert_eq!(pi.active_link_speed(), Some(LinkSpeed::Qdr));
+        assert_eq!(pi.data_rate_gbps(), Some(8.0));
+
+        // The port has no IsExtendedSpeeds2Supported, so LinkSpeedExt2 is ignored.
+        assert!(
+            !pi.capabilities2()
+                .contains(CapabilityMask2::IS_EXTENDED_SPEEDS2_SUPPORTED)
+        );
+        pi.set_link_speed_ext2_active(1);
+        assert_eq!(pi.active_link_speed(), Some(LinkSpeed::Qdr));
+
+        // XDR comes from LinkSpeedExt2 and sets the capability that enables it.
+        pi.set_link_width_active(LinkWidth::X4 as u8);
+        pi.set_supported_link_speeds(&[LinkSpeed::Ndr, LinkSpeed::Xdr]);
+        pi.set_enabled_link_speeds(&[LinkSpeed::Ndr, LinkSpeed::Xdr]);
+        pi.set_active_link_speed(Some(LinkSpeed::Xdr));
+        assert!(
+            pi.capabilities2()
+                .contains(CapabilityMask2::IS_EXTENDED_SPEEDS2_SUPPORTED)
+        );
+        assert_eq!(
+            (
+                pi.link_speed_ext2_active(),
+                pi.link_speed_ext2_supported(),
+                pi.link_speed_ext2_enabled()
+            ),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            pi.link_speed_ext_enabled(),
+            8,
+            "LinkSpeedExt keeps NDR only"
+        );
+        assert_eq!(pi.active_link_speed(), Some(LinkSpeed::Xdr));
+        assert_eq!(
+            pi.supported_link_speeds(),
+            vec![LinkSpeed::Ndr, LinkSpeed::Xdr]
+        );
+        assert_eq!(pi.data_rate_gbps(), Some(800.0));
+        assert!(!pi.link_training().is_speed_degraded());
+
+        let (legacy, ext, ext2) = LinkSpeed::to_masks(&LinkSpeed::ALL);
+        assert_eq!(LinkSpeed::from_masks(legacy, ext, ext2), LinkSpeed::ALL);
+    }
+
+    #[test]
+    fn port_info_capability_mask_success() {
+        use ibmad::mad::{CapabilityMask, CapabilityMask2};
+
+        let _ = env_logger::try_init();
+
+        let bytes = get_pi_mad().unwrap();
+        let dr_smp = ibmad::mad::dr_smp_mad::from_bytes(&bytes[24..256]).unwrap();
+        let mut pi = port_info::from_bytes(&dr_smp.attr_layout).unwrap();
+
+        let caps = pi.capabilities();
+        assert_eq!(caps.bits(), 0xa751_e848);
+        assert!(!caps.is_sm());
+        assert!(caps.is_trap_supported());
+        assert!(caps.is_extended_speeds_supported());
+        assert!(caps.is_capability_mask2_supported());
+        assert!(caps.contains(CapabilityMask::IS_CLIENT_REREGISTRATION_SUPPORTED));
+        assert!(caps.names().contains(&"IsCableInfoSupported"));
+
+        let caps2 = pi.capabilities2();
+        assert!(caps2.is_port_info_extended_supported());
+        assert!(caps2.contains(
+            CapabilityMask2::IS_LINK_WIDTH_2X_SUPPORTED
+                | CapabilityMask2::IS_LINK_SPEED_NDR_SUPPORTED
+        ));
+        assert!(!caps2.is_virtualization_supported());
+
+        // CapabilityMask2 and the extended speeds are ignored once their bits are cleared.
+        let mut caps = pi.capabilities();
+        caps.remove(CapabilityMask::IS_CAPABILITY_MASK2_SUPPORTED);
+        caps.remove(CapabilityMask::IS_EXTENDED_SPEEDS_SUPPORTED);
+        caps.insert(CapabilityMask::IS_SM);
+        pi.set_capability_mask(caps.bits());
+        assert!(pi.capabilities().is_sm());
+        assert_eq!(pi.capabilities2(), CapabilityMask2::default());
+        assert_eq!(pi.active_link_speed(), None);
+    }
 }
@@ -1,23 +1,34 @@
 #[cfg(test)]
 mod sim_tests {
-    use std::cell::RefCell;
     use std::io::{Read, Write};
     use std::os::fd::{FromRawFd, IntoRawFd};
     use std::os::unix::net::UnixStream;
-    use std::rc::Rc;
     use std::sync::mpsc::channel;
+    use std::sync::{Arc, RwLock};
     use std::{fs, thread};
 
     use ibmad::sim::Port;
 
     fn sample_umad_attr(attr_id: u16, path: [u8; 64]) -> ibmad::mad::ib_user_mad {
+        // Index 0 of the initial path is unused, the hops follow it.
+        let hop_cnt = path[1..].iter().take_while(|&&p| p != 0).count() as u8;
+        sample_dr_umad(attr_id, path, hop_cnt, 0xffff, 0xffff)
+    }
+
+    fn sample_dr_umad(
+        attr_id: u16,
+        path: [u8; 64],
+        hop_cnt: u8,
+        drslid: u16,
+        drdlid: u16,
+    ) -> ibmad::mad::ib_user_mad {
         use ibmad::mad::{dr_smp_mad, ib_mad};
 
         // build DR SMP MAD content
         let dr = dr_smp_mad {
             m_key: 0,
-            drslid: 0xffff,
-            drdlid: 0xffff,
+            drslid: drslid.to_be(),
+            drdlid: drdlid.to_be(),
             reserved: [0; 28],
             attr_layout: [0; 64],
             initial_path: path,
@@ -32,9 +43,9 @@ mod sim_tests {
             method: 0x1,
             status: 0,
             hop_ptr: 0,
-            hop_cnt: 0,
+            hop_cnt,
             tid: 0x1337 as u64,
-            attr_id: attr_id as u16,
+            attr_id: attr_id.to_be(),
             additional_status: 0,
             attr_mod: 0,
             data: [0; 232],
@@ -100,10 +111,10 @@ mod sim_tests {
                 );
                 let spine_rc = fabric.add_switch(spine);
                 {
-                    let mut spine_ref = spine_rc.borrow_mut();
+                    let mut spine_ref = spine_rc.write().unwrap();
                     for i in 0..=65 {
                         let port = Port::new_port(i, lid, spine_rc.clone());
-                        spine_ref.ports.push(Rc::new(RefCell::new(port)));
+                        spine_ref.ports.push(Arc::new(RwLock::new(port)));
                     }
                 }
                 spines.push(spine_rc);
@@ -120,11 +131,11 @@ mod sim_tests {
                     0x7ffc_0000_0000_2000 + leaf_idx as u64,
                 );
                 let leaf_rc = fabric.add_switch(leaf);
-                let mut leaf_ref = leaf_rc.borrow_mut();
+                let mut leaf_ref = leaf_rc.write().unwrap();
 
                 for i in 0..=65 {
                     let port = Port::new_port(i as u8, lid, leaf_rc.clone());
-                    leaf_ref.ports.push(Rc::new(RefCell::new(port)));
+                    leaf_ref.ports.push(Arc::new(RwLock::new(port)));
                 }
 
                 lid += 1;
@@ -135,7 +146,7 @@ mod sim_tests {
                         let base = i * 32;
 
                         let spine_port_rc = {
-                            let spine_ref = spine_rc.borrow();
+                            let spine_ref = spine_rc.read().unwrap();
 
                             // Iteration 1: Port 1-32, Iterations 2: Ports 33-64
                             spine_ref.ports[leaf_idx + 1 + base].clone()
@@ -143,8 +154,7 @@ mod sim_tests {
 
                         // Iteration 1: Port 33-48, Iterations 2: Ports 49-64
                         let leaf_port_rc = leaf_ref.ports[33 + spine_idx + (base / 2)].clone();
-                        spine_port_rc.borrow_mut().remote_port = Some(Rc::downgrade(&leaf_port_rc));
-                        leaf_port_rc.borrow_mut().remote_port = Some(Rc::downgrade(&spine_port_rc));
+                        ibmad::sim::connect_ports(&spine_port_rc, &leaf_port_rc);
                     }
                 }
 
@@ -157,27 +167,52 @@ mod sim_tests {
                     );
                     let hca_rc = fabric.add_hca(hca);
 
-                    let hca_port = Rc::new(RefCell::new(ibmad::sim::Port::new_port(
+                    let hca_port = Arc::new(RwLock::new(ibmad::sim::Port::new_port(
                         1,
                         hca_count + 1,
                         hca_rc.clone(),
                     )));
-                    hca_rc.borrow_mut().ports.push(hca_port.clone());
+                    hca_rc.write().unwrap().ports.push(hca_port.clone());
 
                     // connect HCA to leaf
                     let leaf_hca_port_rc = leaf_ref.ports[h + 1].clone();
-                    leaf_hca_port_rc.borrow_mut().remote_port = Some(Rc::downgrade(&hca_port));
-                    hca_port.borrow_mut().remote_port = Some(Rc::downgrade(&leaf_hca_port_rc));
+                    ibmad::sim::connect_ports(&leaf_hca_port_rc, &hca_port);
 
                     // first HCA becomes the first hop in dr_paths
                     if hca_count == 1 {
-                        fabric.dr_paths.insert([0; 64], Rc::downgrade(&hca_port));
+                        fabric.dr_paths.insert([0; 64], Arc::downgrade(&hca_port));
                     }
                 }
             }
         }
     }
 
+    /// switch-1 (GUID 0x1001, LID 1) with host-N (GUID 0x2000 + N, LID 2 + N) on port
+    /// N + 1. The fabric is reached through host-0.
+    fn build_star_fabric(fabric: &mut ibmad::sim::Fabric, hosts: u8) {
+        let mut sw = ibmad::sim::Node::new_switch("switch-1", 0x1001);
+        sw.node_info.nports = hosts;
+        sw.lid = 1;
+        let sw_rc = fabric.add_switch(sw);
+        for i in 0..=hosts {
+            let port = Arc::new(RwLock::new(Port::new_port(i, 1, sw_rc.clone())));
+            sw_rc.write().unwrap().ports.push(port);
+        }
+
+        for i in 0..hosts {
+            let mut hca = ibmad::sim::Node::new_hca(&format!("host-{}", i), 0x2000 + i as u64);
+            hca.lid = 2 + i as u16;
+            let hca_rc = fabric.add_hca(hca);
+            let hca_port = Arc::new(RwLock::new(Port::new_port(1, 2 + i as u16, hca_rc.clone())));
+            hca_rc.write().unwrap().ports.push(hca_port.clone());
+            let sw_port = sw_rc.read().unwrap().ports[i as usize + 1].clone();
+            ibmad::sim::connect_ports(&sw_port, &hca_port);
+            if i == 0 {
+                fabric.dr_paths.insert([0; 64], Arc::downgrade(&hca_port));
+            }
+        }
+    }
+
     #[test]
     fn create_new_fabric_success() {
         let (client, server) = UnixStream::pair().unwrap();
@@ -266,4 +301,629 @@ mod sim_tests {
         }
         let _ = tx.send(true);
     }
+
+    #[test]
+    fn test_sim_fault_loss_is_seeded() {
+        use ibmad::sim::DEFAULT_CLIENT;
+        use ibmad::sim::fault::{FaultInjector, FaultKind, FaultMatch};
+
+        let _ = env_logger::try_init();
+
+        // Responses are taken straight from the fabric, so a dropped request is one
+        // without a response rather than a client timeout.
+        let run = |seed: u64| -> Vec<bool> {
+            let (_client, server) = UnixStream::pair().unwrap();
+            let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
+            let mut fabric = ibmad::sim::Fabric::new(server_file);
+            ibmad::sim::build_standard_fabric(&mut fabric);
+            fabric.faults = FaultInjector::new(seed);
+            fabric.faults.add(FaultMatch::any(), FaultKind::Loss(0.5));
+
+            let umad = sample_umad(0x0011, [0; 64]).to_bytes();
+            (0..64)
+                .map(|_| {
+                    !fabric
+                        .process_umad(DEFAULT_CLIENT, &umad)
+                        .unwrap()
+                        .is_empty()
+                })
+                .collect()
+        };
+
+        let first = run(7);
+        assert_eq!(first, run(7), "Same seed, same losses");
+        assert_ne!(first, run(8), "Another seed, other losses");
+        let dropped = first.iter().filter(|answered| !**answered).count();
+        assert!((16..=48).contains(&dropped), "{} of 64 dropped", dropped);
+    }
+
+    #[test]
+    fn test_sim_fault_truncate_is_short() {
+        use ibmad::sim::DEFAULT_CLIENT;
+        use ibmad::sim::fault::{FaultKind, FaultMatch};
+
+        let _ = env_logger::try_init();
+
+        let (_client, server) = UnixStream::pair().unwrap();
+        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
+        let mut fabric = ibmad::sim::Fabric::new(server_file);
+        ibmad::sim::build_standard_fabric(&mut fabric);
+        fabric
+            .faults
+            .add(FaultMatch::any().attr(0x0011), FaultKind::Truncate(24));
+
+        let umad = sample_umad(0x0011, [0; 64]).to_bytes();
+        let responses = fabric.process_umad(DEFAULT_CLIENT, &umad).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].bytes.len(),
+            64 + 24,
+            "UMAD header and MAD header only"
+        );
+
+        let umad = sample_umad(0x0010, [0; 64]).to_bytes();
+        let responses = fabric.process_umad(DEFAULT_CLIENT, &umad).unwrap();
+        assert_eq!(responses[0].bytes.len(), umad.len());
+    }
+
+    #[test]
+    fn test_sim_unsupported_attr_status() {
+        use ibmad::mad::dr_smp::DR_SMP_DIRECTION;
+        use ibmad::mad::ib_mad;
+        use ibmad::sim::DEFAULT_CLIENT;
+
+        let _ = env_logger::try_init();
+
+        let (_client, server) = UnixStream::pair().unwrap();
+        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
+        let mut fabric = ibmad::sim::Fabric::new(server_file);
+        ibmad::sim::build_standard_fabric(&mut fabric);
+
+        // LedInfo is not simulated. Like a real SMA, the simulator answers with
+        // status 0x0C rather than dropping the MAD, so the client fails without a timeout.
+        let umad = sample_umad(0x0031, [0; 64]).to_bytes();
+        let responses = fabric.process_umad(DEFAULT_CLIENT, &umad).unwrap();
+        assert_eq!(responses.len(), 1);
+        let mad = ib_mad::from_bytes(&responses[0].bytes[64..]).unwrap();
+        assert_eq!(u16::from_be(mad.status), DR_SMP_DIRECTION | 0x000c);
+    }
+
+    #[test]
+    fn test_dr_smp_semantics() {
+        use ibmad::mad::dr_smp::DR_SMP_DIRECTION;
+        use ibmad::mad::{dr_smp_mad, ib_mad, node_info};
+        use ibmad::sim::DEFAULT_CLIENT;
+
+        let _ = env_logger::try_init();
+
+        let (_client, server) = UnixStream::pair().unwrap();
+        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
+        let mut fabric = ibmad::sim::Fabric::new(server_file);
+        ibmad::sim::build_standard_fabric(&mut fabric);
+        let leaf0 = fabric.switches[16].upgrade().unwrap();
+        assert_eq!(leaf0.read().unwrap().description, "leaf-0");
+
+        let path = |hops: &[u8]| {
+            let mut path = [0u8; 64];
+            path[1..=hops.len()].copy_from_slice(hops);
+            path
+        };
+        let mut send = |umad: ibmad::mad::ib_user_mad| {
+            let mut responses = fabric
+                .process_umad(DEFAULT_CLIENT, &umad.to_bytes())
+                .unwrap();
+            assert!(responses.len() <= 1);
+            responses
+                .pop()
+                .map(|r| ib_mad::from_bytes(&r.bytes[64..]).unwrap())
+        };
+        let guid = |mad: &ib_mad| {
+            let dr = dr_smp_mad::from_bytes(&mad.data).unwrap();
+            let ni = node_info::from_bytes(&dr.attr_layout).unwrap();
+            ni.node_guid
+        };
+
+        // host0001 -> leaf-0 port 33 -> spine-0 port 3 -> leaf-1 port 33.
+        let mad = send(sample_umad(0x0011, path(&[1, 33, 3]))).expect("path is valid");
+        assert_eq!(guid(&mad), 0x7ffc_0000_0000_2001);
+        assert_eq!(u16::from_be(mad.status), DR_SMP_DIRECTION);
+        assert_eq!((mad.hop_ptr, mad.hop_cnt), (0, 3));
+        let dr = dr_smp_mad::from_bytes(&mad.data).unwrap();
+        assert_eq!(dr.return_path[..5], [0, 1, 1, 33, 0]);
+
+        // Real switches discard these rather than answer them.
+        assert!(send(sample_dr_umad(0x0011, path(&[1]), 64, 0xffff, 0xffff)).is_none());
+        assert!(
+            send(sample_umad(0x0011, path(&[1, 66]))).is_none(),
+            "no such port"
+        );
+        assert!(
+            send(sample_umad(0x0011, path(&[1, 65]))).is_none(),
+            "port not cabled"
+        );
+        assert!(
+            send(sample_umad(0x0011, path(&[2]))).is_none(),
+            "CAs have one port"
+        );
+        assert!(
+            send(sample_umad(0x0011, path(&[1, 1, 1]))).is_none(),
+            "CAs can't forward"
+        );
+
+        leaf0.read().unwrap().ports[34]
+            .write()
+            .unwrap()
+            .port_info
+            .set_port_physical_state(3);
+        assert!(
+            send(sample_umad(0x0011, path(&[1, 34]))).is_none(),
+            "link down"
+        );
+        assert!(send(sample_umad(0x0011, path(&[1, 33]))).is_some());
+
+        // A LID routed tail from leaf-0 to DrDLID 2005 (spine-5).
+        let mad = send(sample_dr_umad(0x0011, path(&[1]), 1, 0xffff, 2005)).unwrap();
+        assert_eq!(guid(&mad), 0x7ffc_0000_0000_1005);
+        assert_eq!(mad.hop_ptr, 0);
+
+        // A LID routed head to leaf-1 (3001), with the response LID routed to DrSLID.
+        let mut umad = sample_dr_umad(0x0011, path(&[33]), 1, 4001, 0xffff);
+        umad.addr.lid = 3001u16.to_be();
+        let mad = send(umad).unwrap();
+        assert_eq!(guid(&mad), 0x7ffc_0000_0000_1000);
+        // ... which can't come back if DrSLID isn't the requester.
+        let mut umad = sample_dr_umad(0x0011, path(&[33]), 1, 4002, 0xffff);
+        umad.addr.lid = 3001u16.to_be();
+        assert!(send(umad).is_none());
+
+        // Attribute errors come back as a status along the same path.
+        let mut umad = sample_umad(0x0015, path(&[1]));
+        umad.data[20..24].copy_from_slice(&99u32.to_be_bytes()); // AttributeModifier
+        let mad = send(umad).unwrap();
+        assert_eq!(u16::from_be(mad.status), DR_SMP_DIRECTION | 0x1c);
+        let mad = send(sample_umad(0x0012, [0; 64])).unwrap();
+        assert_eq!(u16::from_be(mad.status), DR_SMP_DIRECTION | 0x0c);
+    }
+
+    #[test]
+    fn test_sa_get_table() {
+        use ibmad::enums::SaAttrID;
+        use ibmad::mad::sa::{
+            LR_COMP_FROM_LID, NR_COMP_LID, PIR_COMP_LID, PIR_COMP_PORT_NUM, PR_COMP_DLID,
+        };
+        use ibmad::mad::{
+            IbMadPort, link_record, node_record, path_record, port_info_record, query_sa_table,
+        };
+
+        let _ = env_logger::try_init();
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
+        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
+
+        let (tx, rx) = channel::<bool>();
+        thread::spawn(|| {
+            let mut fabric = ibmad::sim::Fabric::new(server_file);
+            ibmad::sim::build_standard_fabric(&mut fabric);
+            // host0001 (LID 4001) is the agent's node and runs the master SM.
+            let mut smi = ibmad::mad::sm_info { data: [0; 64] };
+            smi.set_sm_state(3);
+            fabric.hcas[0].upgrade().unwrap().write().unwrap().sm_info = Some(smi);
+            // Every link runs 4X HDR, which only LinkSpeedExtActive can express.
+            for node in &fabric.nodes {
+                for port in &node.read().unwrap().ports {
+                    let pi = &mut port.write().unwrap().port_info;
+                    pi.set_link_width_active(0x02);
+                    pi.set_active_link_speed(Some(ibmad::enums::LinkSpeed::Hdr));
+                }
+            }
+            let _ = fabric.run(rx);
+        });
+
+        let mut port = IbMadPort { file: client_file };
+        let mut query = |attr: SaAttrID, mask: u64, template: &[u8]| {
+            query_sa_table(&mut port, 0, 1000, 4001, attr, mask, template)
+                .unwrap_or_else(|e| panic!("{:?} query failed: {}", attr, e))
+        };
+
+        // 1072 NodeRecords take several hundred RMPP segments.
+        let nodes = query(SaAttrID::NodeRecord, 0, &[]);
+        assert_eq!(nodes.len(), 16 + 32 + 1024);
+        let rec = node_record::from_bytes(&nodes[0]).unwrap();
+        assert_eq!(
+            (rec.lid(), rec.node_description().as_str()),
+            (2000, "spine-0")
+        );
+
+        let mut want = node_record { data: [0; 108] };
+        want.set_lid(4002);
+        let nodes = query(SaAttrID::NodeRecord, NR_COMP_LID, &want.data);
+        assert_eq!(nodes.len(), 1);
+        let rec = node_record::from_bytes(&nodes[0]).unwrap();
+        assert_eq!(rec.node_description(), "host0002");
+        assert_eq!({ rec.node_info().node_guid }, 0x7ffc_0000_0000_3002);
+
+        let mut want = port_info_record { data: [0; 68] };
+        want.set_endport_lid(3000);
+        want.set_port_num(1);
+        let ports = query(
+            SaAttrID::PortInfoRecord,
+            PIR_COMP_LID | PIR_COMP_PORT_NUM,
+            &want.data,
+        );
+        assert_eq!(ports.len(), 1);
+        let pi = port_info_record::from_bytes(&ports[0]).unwrap().port_info();
+        assert_eq!((pi.local_portnum(), pi.port_state()), (1, 4));
+
+        let mut want = link_record { data: [0; 8] };
+        want.set_from_lid(4001);
+        let links = query(SaAttrID::LinkRecord, LR_COMP_FROM_LID, &want.data);
+        assert_eq!(links.len(), 1);
+        let link = link_record::from_bytes(&links[0]).unwrap();
+        assert_eq!(
+            (link.from_port(), link.to_lid(), link.to_port()),
+            (1, 3000, 1)
+        );
+
+        let mut want = path_record { data: [0; 64] };
+        want.set_dlid(5024);
+        let paths = query(SaAttrID::PathRecord, PR_COMP_DLID, &want.data);
+        assert_eq!(paths.len(), 1);
+        let path = path_record::from_bytes(&paths[0]).unwrap();
+        assert_eq!((path.slid(), path.dlid()), (4001, 5024));
+        assert_eq!(
+            u64::from_be_bytes(path.dgid()[8..].try_into().unwrap()),
+            0x7ffc_0000_0000_3400
+        );
+        assert_eq!(
+            (path.mtu(), path.rate(), path.reversible()),
+            (5, 17, 1),
+            "200 Gb/s"
+        );
+
+        // Unknown LIDs match nothing, which is an empty table rather than an error.
+        want.set_dlid(9999);
+        assert!(query(SaAttrID::PathRecord, PR_COMP_DLID, &want.data).is_empty());
+
+        let _ = tx.send(true);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sa_transfer_cleanup() {
+        use ibmad::mad::sa::{SA_CLASS_VERSION, SA_METHOD_GET_TABLE};
+        use ibmad::mad::{IB_MGMT_CLASS_SUBN_ADM, ib_mad, sa_mad};
+        use ibmad::sim::{DEFAULT_CLIENT, server::Server};
+
+        /// A NodeRecord GetTable to the SM at LID 4001, which is never ACKed.
+        fn get_table(tid: u64) -> ibmad::mad::ib_user_mad {
+            let mut umad = sample_umad(0x0011, [0; 64]);
+            let mut mad = ib_mad::from_bytes(&umad.data).unwrap();
+            mad.mgmt_class = IB_MGMT_CLASS_SUBN_ADM;
+            mad.class_version = SA_CLASS_VERSION;
+            mad.method = SA_METHOD_GET_TABLE;
+            mad.tid = tid.to_be();
+            let sa_bytes = sa_mad::new().to_bytes();
+            mad.data = [0; 232];
+            mad.data[..sa_bytes.len()].copy_from_slice(&sa_bytes);
+            umad.data[..256].copy_from_slice(&mad.to_bytes());
+            umad.addr.qpn = 1u32.to_be();
+            umad.addr.lid = 4001u16.to_be();
+            umad
+        }
+
+        let _ = env_logger::try_init();
+
+        let mut fabric = ibmad::sim::Fabric::new(fs::File::open("/dev/null").unwrap());
+        ibmad::sim::build_standard_fabric(&mut fabric);
+        let mut smi = ibmad::mad::sm_info { data: [0; 64] };
+        smi.set_sm_state(3);
+        fabric.hcas[0].upgrade().unwrap().write().unwrap().sm_info = Some(smi);
+
+        // A requester that stops ACKing is given up on after the RMPP timeout.
+        let responses = fabric
+            .process_umad(DEFAULT_CLIENT, &get_table(1).to_bytes())
+            .unwrap();
+        assert_eq!(responses.len(), 1, "Only the first segment is sent unasked");
+        assert_eq!(fabric.pending_sa_transfers(), 1);
+        let node_info = sample_umad(0x0011, [0; 64]).to_bytes();
+        fabric.process_umad(DEFAULT_CLIENT, &node_info).unwrap();
+        assert_eq!(fabric.pending_sa_transfers(), 1);
+        fabric.rmpp_timeout = std::time::Duration::ZERO;
+        fabric.process_umad(DEFAULT_CLIENT, &node_info).unwrap();
+        assert_eq!(fabric.pending_sa_transfers(), 0);
+
+        // A requester that goes away is given up on right away.
+        fabric.rmpp_timeout = std::time::Duration::from_secs(60);
+        let server = Server::new(fabric);
+        let mut client_file = server.connect(DEFAULT_CLIENT).unwrap();
+        let client_file = tokio::task::spawn_blocking(move || {
+            client_file.write_all(&get_table(2).to_bytes()).unwrap();
+            let mut buf = [0u8; 320];
+            client_file.read_exact(&mut buf).unwrap();
+            client_file
+        })
+        .await
+        .unwrap();
+        assert_eq!(server.fabric().lock().unwrap().pending_sa_transfers(), 1);
+
+        drop(client_file);
+        for _ in 0..100 {
+            if server.fabric().lock().unwrap().pending_sa_transfers() == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.fabric().lock().unwrap().pending_sa_transfers(), 0);
+    }
+
+    #[test]
+    fn test_perf_counters() {
+        use ibmad::mad::{
+            IbMadPort, clear_port_counters, clear_port_counters_extended, query_port_counters,
+            query_port_counters_extended,
+        };
+        use ibmad::sim::perf::{Counter, TrafficModel};
+
+        let _ = env_logger::try_init();
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let client_file = unsafe { fs::File::from_raw_fd(client.into_raw_fd()) };
+        let server_file = unsafe { fs::File::from_raw_fd(server.into_raw_fd()) };
+
+        const LEAF3: u64 = 0x7ffc_0000_0000_2003;
+        let (tx, rx) = channel::<bool>();
+        thread::spawn(move || {
+            let mut fabric = ibmad::sim::Fabric::new(server_file);
+            ibmad::sim::build_standard_fabric(&mut fabric);
+            fabric.traffic = TrafficModel {
+                pkts_per_sec: 1000.0,
+                bytes_per_pkt: 2048,
+                multicast_ratio: 0.1,
+                errors: vec![(Counter::XmitWait, 0.25)],
+                realtime: false,
+            };
+            fabric.hcas[0].upgrade().unwrap().read().unwrap().ports[0]
+                .write()
+                .unwrap()
+                .error_rates = vec![(Counter::RcvErrors, 2.0)];
+            // Ten steps of a second give the same counts as one of ten seconds.
+            for _ in 0..10 {
+                fabric.advance_counters(std::time::Duration::from_secs(1));
+            }
+            fabric
+                .inject_counter(LEAF3, 7, Counter::SymbolErrors, 500)
+                .unwrap();
+            fabric
+                .inject_counter(LEAF3, 7, Counter::LinkDowned, 300)
+                .unwrap();
+            fabric
+                .inject_counter(LEAF3, 9, Counter::XmitData, 1 << 40)
+                .unwrap();
+            assert!(
+                fabric
+                    .inject_counter(LEAF3, 99, Counter::SymbolErrors, 1)
+                    .is_err()
+            );
+            let _ = fabric.run(rx);
+        });
+
+        let mut port = IbMadPort { file: client_file };
+
+        let pc = query_port_counters(&mut port, 0, 1000, 1, 3003, 7, 0).unwrap();
+        assert_eq!(pc.port_select(), 7);
+        assert_eq!(pc.symbol_error_counter(), 500);
+        assert_eq!(pc.link_downed_counter(), 255, "8-bit counter saturates");
+        assert_eq!((pc.port_xmit_pkts(), pc.port_rcv_pkts()), (10_000, 10_000));
+        assert_eq!(pc.port_xmit_data(), 10_000 * 512);
+        assert_eq!(pc.port_xmit_wait(), 2);
+
+        let pc = query_port_counters(&mut port, 0, 1000, 1, 3003, 9, 0).unwrap();
+        assert_eq!(pc.port_xmit_data(), u32::MAX, "32-bit counter saturates");
+        let ext = query_port_counters_extended(&mut port, 0, 1000, 1, 3003, 9, 0).unwrap();
+        assert_eq!(ext.port_xmit_data(), (1 << 40) + 10_000 * 512);
+
+        let ext = query_port_counters_extended(&mut port, 0, 1000, 1, 3003, 7, 0).unwrap();
+        assert_eq!(ext.link_downed_counter(), 300);
+        assert_eq!(ext.port_multicast_xmit_pkts(), 1_000);
+        assert_eq!(ext.port_unicast_rcv_pkts(), 9_000);
+
+        let pc = query_port_counters(&mut port, 0, 1000, 1, 4001, 1, 0).unwrap();
+        assert_eq!(pc.port_rcv_errors(), 20);
+
+        // Unlinked ports carry no traffic; AllPortSelect sums the 64 linked ones.
+        let pc = query_port_counters(&mut port, 0, 1000, 1, 3003, 65, 0).unwrap();
+        assert_eq!(pc.port_xmit_pkts(), 0);
+        let ext = query_port_counters_extended(&mut port, 0, 1000, 1, 3003, 0xff, 0).unwrap();
+        assert_eq!(ext.port_xmit_pkts(), 64 * 10_000);
+
+        // Sets reset only the selected counters.
+        let pc = clear_port_counters(&mut port, 0, 1000, 1, 3003, 7, 0x0001, 0).unwrap();
+        assert_eq!(pc.symbol_error_counter(), 0);
+        assert_eq!(pc.link_downed_counter(), 255);
+        assert_eq!(pc.port_xmit_pkts(), 10_000);
+
+        let ext =
+            clear_port_counters_extended(&mut port, 0, 1000, 1, 3003, 7, 0x0001, 1 << 2).unwrap();
+        assert_eq!((ext.port_xmit_data(), ext.link_downed_counter()), (0, 0));
+        assert_eq!(ext.port_xmit_pkts(), 10_000);
+
+        let pc = clear_port_counters(&mut port, 0, 1000, 1, 3003, 0xff, 0xffff, 0x01).unwrap();
+        assert_eq!((pc.port_xmit_pkts(), pc.port_xmit_wait()), (0, 0));
+
+        let err = query_port_counters(&mut port, 0, 1000, 1, 3003, 99, 0).unwrap_err();
+        assert!(err.to_string().contains("0x1c"), "{}", err);
+
+        let _ = tx.send(true);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sim_server_keeps_response_order() {
+        use ibmad::sim::fault::{FaultKind, FaultMatch};
+        use ibmad::sim::{DEFAULT_CLIENT, server::Server};
+
+        let _ = env_logger::try_init();
+
+        let mut fabric = ibmad::sim::Fabric::new(fs::File::open("/dev/null").unwrap());
+        build_star_fabric(&mut fabric, 1);
+
+        // Only the NodeInfo answer is held back.
+        fabric.faults.add(
+            FaultMatch::node(0x2000).attr(0x0011),
+            FaultKind::Delay(std::time::Duration::from_millis(100)),
+        );
+        let server = Server::new(fabric);
+        let mut client_file = server.connect(DEFAULT_CLIENT).unwrap();
+
+        let tids = tokio::task::spawn_blocking(move || {
+            for (tid, attr_id) in [(1u64, 0x0011), (2u64, 0x0010)] {
+                let mut umad = sample_umad(attr_id, [0; 64]);
+                umad.data[8..16].copy_from_slice(&tid.to_ne_bytes());
+                client_file.write_all(&umad.to_bytes()).unwrap();
+            }
+
+            let mut tids = Vec::new();
+            for _ in 0..2 {
+                let mut buf = [0u8; 320];
+                client_file.read_exact(&mut buf).unwrap();
+                tids.push(u64::from_ne_bytes(buf[72..80].try_into().unwrap()));
+            }
+            tids
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(tids, vec![1, 2], "The delayed response is not overtaken");
+    }
+
+    #[test]
+    fn test_sim_fabric_event_traps() {
+        use ibmad::mad::trap::{
+            IB_METHOD_TRAP, NOTICE_ATTR_ID, NOTICE_TYPE_INFO, NOTICE_TYPE_URGENT, PRODUCER_TYPE_CA,
+            PRODUCER_TYPE_SWITCH, TRAP144_NODE_DESCRIPTION_CHANGE,
+        };
+        use ibmad::mad::{IB_MGMT_CLASS_LID_ROUTED_SMP, ib_mad, ib_user_mad, notice, smp_mad};
+        use ibmad::sim::events::FabricEvent;
+        use ibmad::sim::{ClientId, DEFAULT_CLIENT, Fabric};
+
+        /// The Notices sent to `client` ahead of the answer to its next NodeInfo Get.
+        fn traps(fabric: &mut Fabric, client: ClientId) -> Vec<notice> {
+            let node_info = sample_umad(0x0011, [0; 64]).to_bytes();
+            let responses = fabric.process_umad(client, &node_info).unwrap();
+            let (answer, traps) = responses.split_last().unwrap();
+            assert!(
+                ib_user_mad::from_bytes(&answer.bytes)
+                    .unwrap()
+                    .is_response()
+            );
+
+            traps
+                .iter()
+                .map(|trap| {
+                    let umad = ib_user_mad::from_bytes(&trap.bytes).unwrap();
+                    assert!(!umad.is_response());
+                    let mad = ib_mad::from_bytes(&umad.data).unwrap();
+                    assert_eq!(
+                        (mad.mgmt_class, mad.method, u16::from_be(mad.attr_id)),
+                        (IB_MGMT_CLASS_LID_ROUTED_SMP, IB_METHOD_TRAP, NOTICE_ATTR_ID)
+                    );
+                    let smp = smp_mad::from_bytes(&mad.data).unwrap();
+                    notice::from_bytes(&smp.attr_layout).unwrap()
+                })
+                .collect()
+        }
+
+        let _ = env_logger::try_init();
+
+        let mut fabric = Fabric::new(fs::File::open("/dev/null").unwrap());
+        build_star_fabric(&mut fabric, 3);
+        let switch = fabric.switches[0].upgrade().unwrap();
+        let switch_port0 = switch.read().unwrap().ports[0].clone();
+        let switch_client = fabric.attach_client(&switch_port0);
+        assert!(traps(&mut fabric, DEFAULT_CLIENT).is_empty());
+
+        // The switch reports the link to host-2 going down.
+        fabric
+            .apply_event(&FabricEvent::LinkDown {
+                node_guid: 0x2002,
+                port: 1,
+            })
+            .unwrap();
+        let sent = traps(&mut fabric, DEFAULT_CLIENT);
+        assert_eq!(sent.len(), 1);
+        let trap = &sent[0];
+        assert_eq!(
+            (trap.is_generic(), trap.notice_type(), trap.producer_type()),
+            (1, NOTICE_TYPE_URGENT, PRODUCER_TYPE_SWITCH)
+        );
+        assert_eq!(
+            (trap.trap_number(), trap.issuer_lid(), trap.trap128_lid()),
+            (128, 1, 1)
+        );
+        let port_state_change = switch
+            .read()
+            .unwrap()
+            .switch_info
+            .unwrap()
+            .port_state_change();
+        assert_eq!(port_state_change, 1);
+
+        // host-1 reports its new name and LID.
+        fabric
+            .apply_event(&FabricEvent::SetNodeDescription {
+                node_guid: 0x2001,
+                description: "renamed".to_string(),
+            })
+            .unwrap();
+        fabric
+            .apply_event(&FabricEvent::SetLid {
+                node_guid: 0x2001,
+                port: 1,
+                lid: 42,
+            })
+            .unwrap();
+        let sent = traps(&mut fabric, DEFAULT_CLIENT);
+        let summary: Vec<_> = sent
+            .iter()
+            .map(|t| {
+                (
+                    t.trap_number(),
+                    t.notice_type(),
+                    t.producer_type(),
+                    t.issuer_lid(),
+                    t.trap144_lid(),
+                    t.trap144_change_flags(),
+                    t.trap144_other_local_changes(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    144,
+                    NOTICE_TYPE_INFO,
+                    PRODUCER_TYPE_CA,
+                    3,
+                    3,
+                    TRAP144_NODE_DESCRIPTION_CHANGE,
+                    0
+                ),
+                (144, NOTICE_TYPE_INFO, PRODUCER_TYPE_CA, 42, 42, 0, 1),
+            ]
+        );
+
+        // Every client is sent every trap, and only once.
+        let numbers: Vec<u16> = traps(&mut fabric, switch_client)
+            .iter()
+            .map(|t| t.trap_number())
+            .collect();
+        assert_eq!(numbers, vec![128, 144, 144]);
+        assert!(traps(&mut fabric, switch_client).is_empty());
+
+        // A switch without power raises nothing, and CAs don't raise trap 128.
+        fabric
+            .apply_event(&FabricEvent::PowerOff { node_guid: 0x1001 })
+            .unwrap();
+        assert!(traps(&mut fabric, DEFAULT_CLIENT).is_empty());
+    }
 }
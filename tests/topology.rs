@@ -0,0 +1,510 @@
+#[cfg(test)]
+mod common;
+
+#[cfg(test)]
+mod topology_tests {
+    use std::{fs, io};
+
+    use ibmad::discovery::Fabric;
+    use ibmad::discovery::cabling::{CableMap, NodeRef};
+    use ibmad::discovery::cache::{IBND_CACHE_MAGIC, IBND_CACHE_VERSION};
+    use ibmad::discovery::diff::{LinkMove, PortId};
+    use ibmad::discovery::json::FABRIC_JSON_VERSION;
+    use ibmad::enums::{IbNodeType, IbPortLinkLayerState, LinkSpeed, LinkWidth};
+    use ibmad::mad::{node_info, port_info};
+
+    use super::common;
+
+    fn load_fixture() -> Fabric {
+        let text = fs::read_to_string("tests/data/topology/ibnetdiscover.txt").unwrap();
+        Fabric::from_ibnetdiscover(&text).expect("Fixture should parse")
+    }
+
+    fn node_by_desc(fabric: &Fabric, desc: &str) -> ibmad::discovery::Node {
+        fabric
+            .nodes
+            .iter()
+            .map(|n| n.read().unwrap().clone())
+            .find(|n| n.description.as_deref() == Some(desc))
+            .unwrap_or_else(|| panic!("node '{}' not found", desc))
+    }
+
+    #[test]
+    fn test_ibnetdiscover_import() {
+        common::setup();
+        let fabric = load_fixture();
+
+        assert!(fabric.port.is_none());
+        assert_eq!(fabric.nodes.len(), 4);
+        assert_eq!(fabric.switches.len(), 2);
+        assert_eq!(fabric.hcas.len(), 2);
+
+        let leaf = node_by_desc(&fabric, "SwitchX -  Mellanox Technologies");
+        assert_eq!(leaf.node_type, IbNodeType::Switch);
+        assert_eq!(leaf.node_guid.to_be(), 0x0002_c903_00a7_b3f0);
+        assert_eq!(leaf.vendor_id, 0x2c9);
+        assert_eq!(leaf.device_id, 0xc738);
+        assert_eq!(leaf.nports, 36);
+        assert_eq!(leaf.lid, 3);
+        assert_eq!(leaf.dr_path[1], 1, "Leaf is one hop from node01 port 1");
+
+        let mut numbers: Vec<u8> = leaf
+            .ports
+            .iter()
+            .map(|p| p.read().unwrap().number)
+            .collect();
+        numbers.sort();
+        assert_eq!(numbers, vec![0, 1, 2, 35]);
+
+        let port2 = leaf
+            .ports
+            .iter()
+            .find(|p| p.read().unwrap().number == 2)
+            .unwrap()
+            .read()
+            .unwrap()
+            .clone();
+        assert_eq!(port2.lid, 3);
+        assert_eq!(port2.link_state, IbPortLinkLayerState::Active);
+        assert_eq!(port2.link_width_active, Some(LinkWidth::X4));
+        assert_eq!(port2.link_speed_active, None, "FDR10 is not decoded");
+
+        let remote = port2.remote().expect("Port 2 should be linked");
+        let remote = remote.read().unwrap();
+        assert_eq!(remote.number, 1);
+        assert_eq!(remote.lid, 2);
+        assert_eq!(remote.guids, vec![0x0002_c903_000e_0b81u64.to_be()]);
+        let remote_node = remote.parent.upgrade().unwrap();
+        assert_eq!(
+            remote_node.read().unwrap().description.as_deref(),
+            Some("node02 mlx4_0")
+        );
+
+        let node01 = node_by_desc(&fabric, "node01 mlx4_0");
+        assert_eq!(node01.node_type, IbNodeType::CA);
+        assert_eq!(node01.system_guid.to_be(), 0x0002_c903_000e_0b75);
+        assert_eq!(node01.lid, 1);
+        assert_eq!(node01.local_port, 1);
+        assert_eq!(node01.dr_path, [0; 64], "Import was initiated from node01");
+        let port = node01.ports[0].read().unwrap();
+        assert_eq!(port.link_speed_active, Some(LinkSpeed::Qdr));
+
+        let spine = node_by_desc(&fabric, "spine SwitchX");
+        assert_eq!(spine.lid, 4);
+        assert_eq!(&spine.dr_path[..3], &[0, 1, 35]);
+        assert!(spine.enhanced_port0);
+        assert!(!leaf.enhanced_port0);
+        assert_eq!(
+            fabric.nodes[0].read().unwrap().description.as_deref(),
+            Some("node01 mlx4_0"),
+            "Nodes are ordered from the initiating node"
+        );
+    }
+
+    #[test]
+    fn test_ibnetdiscover_round_trip() {
+        common::setup();
+        let first = load_fixture().to_ibnetdiscover().unwrap();
+        let second = Fabric::from_ibnetdiscover(&first)
+            .unwrap()
+            .to_ibnetdiscover()
+            .unwrap();
+        assert_eq!(first, second);
+        assert!(first.contains("# Initiated from node 0002c903000e0b72 port 0002c903000e0b73"));
+        assert!(
+            first.contains("[35]\t\"S-0002c90300a7b400\"[35]\t\t# \"spine SwitchX\" lid 4 4xQDR\n")
+        );
+        assert!(first.contains("# \"spine SwitchX\" enhanced port 0 lid 4 lmc 0\n"));
+        assert!(first.contains("# \"SwitchX -  Mellanox Technologies\" base port 0 lid 3 lmc 0\n"));
+    }
+
+    #[test]
+    fn test_ibnetdiscover_import_errors() {
+        common::setup();
+        let err = Fabric::from_ibnetdiscover("[1]\t\"S-0000000000000001\"[1]\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = Fabric::from_ibnetdiscover("Widget\t2 \"W-1\"\n").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    #[test]
+    fn test_offline_fabric_cannot_query() {
+        common::setup();
+        let mut fabric = load_fixture();
+        let err = fabric.query_port_info([0; 64], 0, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+
+    struct CacheWriter(Vec<u8>);
+
+    impl CacheWriter {
+        fn u8(&mut self, v: u8) {
+            self.0.push(v);
+        }
+        fn u16(&mut self, v: u16) {
+            self.0.extend(v.to_le_bytes());
+        }
+        fn u32(&mut self, v: u32) {
+            self.0.extend(v.to_le_bytes());
+        }
+        fn u64(&mut self, v: u64) {
+            self.0.extend(v.to_le_bytes());
+        }
+
+        fn node(
+            &mut self,
+            guid: u64,
+            node_type: u8,
+            nports: u8,
+            lid: u16,
+            desc: &str,
+            ports: &[u8],
+        ) {
+            let ni = node_info {
+                base_version: 1,
+                class_version: 1,
+                node_type,
+                nports,
+                system_guid: guid.to_be(),
+                node_guid: guid.to_be(),
+                port_guid: guid.to_be(),
+                partition_cap: 64u16.to_be(),
+                device_id: 0x1021u16.to_be(),
+                revision: 0,
+                local_port: ports[0],
+                vendor_id: [0x00, 0x02, 0xc9],
+                reserved: [0; 24],
+            };
+            self.u16(lid);
+            self.u8(0);
+            self.u8((node_type == 2) as u8); // Switches have an enhanced port 0.
+            self.0.extend([0; 64]);
+            self.u64(guid);
+            self.u8(node_type);
+            self.u8(nports);
+            self.0.extend(ni.to_bytes());
+            let mut nd = [0u8; 64];
+            nd[..desc.len()].copy_from_slice(desc.as_bytes());
+            self.0.extend(nd);
+            self.u8(ports.len() as u8);
+            for p in ports {
+                self.u64(guid);
+                self.u8(*p);
+            }
+        }
+
+        fn port(&mut self, node: u64, number: u8, lid: u16, remote: Option<(u64, u8)>) {
+            let mut pi = port_info { data: [0; 64] };
+            pi.set_lid(lid);
+            pi.set_port_state(4);
+            pi.set_port_physical_state(5);
+            pi.set_link_width_supported(0x3);
+            pi.set_link_width_enabled(0x3);
+            pi.set_link_width_active(0x2);
+            pi.set_link_speed_supported(0x7);
+            pi.set_link_speed_enabled(0x7);
+            pi.set_link_speed_active(0x4);
+            pi.set_mtu_cap(5);
+            pi.set_neighbor_mtu(5);
+
+            self.u64(node);
+            self.u8(number);
+            self.u8(0);
+            self.u16(lid);
+            self.u8(0);
+            self.0.extend(pi.to_bytes());
+            self.u64(node);
+            let (guid, port) = remote.unwrap_or((0, 0));
+            self.u8(remote.is_some() as u8);
+            self.u64(guid);
+            self.u8(port);
+        }
+    }
+
+    #[test]
+    fn test_ibnetdiscover_cache_import() {
+        common::setup();
+        let (sw, host) = (0x0002_c903_00a7_b3f0u64, 0x0002_c903_000e_0b72u64);
+
+        let mut w = CacheWriter(Vec::new());
+        w.u32(IBND_CACHE_MAGIC);
+        w.u32(IBND_CACHE_VERSION);
+        w.u32(2);
+        w.u32(3);
+        w.u64(host);
+        w.u32(1);
+        w.node(sw, 2, 36, 3, "leaf-1", &[0, 7]);
+        w.node(host, 1, 1, 0, "host-1 mlx5_0", &[1]);
+        w.port(sw, 0, 3, None);
+        w.port(sw, 7, 3, Some((host, 1)));
+        w.port(host, 1, 12, Some((sw, 7)));
+
+        let fabric = Fabric::from_ibnetdiscover_cache(&w.0).expect("Cache should parse");
+        assert_eq!(fabric.switches.len(), 1);
+        assert_eq!(fabric.hcas.len(), 1);
+
+        let leaf = node_by_desc(&fabric, "leaf-1");
+        assert_eq!(leaf.lid, 3);
+        assert_eq!(leaf.node_guid.to_be(), sw);
+        assert_eq!(leaf.partition_cap, 64);
+        assert_eq!(leaf.dr_path[1], 1);
+        assert!(leaf.enhanced_port0);
+
+        let port7 = leaf
+            .ports
+            .iter()
+            .find(|p| p.read().unwrap().number == 7)
+            .unwrap()
+            .read()
+            .unwrap()
+            .clone();
+        assert!(
+            port7.guids.is_empty(),
+            "Only switch port 0 carries the GUID"
+        );
+        assert_eq!(
+            port7.link_width_supported,
+            vec![LinkWidth::X1, LinkWidth::X4]
+        );
+        assert_eq!(port7.link_speed_active, Some(LinkSpeed::Qdr));
+        assert_eq!(port7.data_rate_gbps(), Some(32.0));
+
+        let remote = port7.remote().expect("Port 7 should be linked");
+        let remote = remote.read().unwrap();
+        assert_eq!(remote.lid, 12);
+        assert_eq!(remote.guids, vec![host.to_be()]);
+        assert_eq!(remote.remote().unwrap().read().unwrap().number, 7);
+
+        let mut bad = w.0.clone();
+        bad[0] ^= 0xff;
+        let err = Fabric::from_ibnetdiscover_cache(&bad).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Fabric::from_ibnetdiscover_cache(&w.0[..100]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_topology_diff() {
+        common::setup();
+        let text = fs::read_to_string("tests/data/topology/ibnetdiscover.txt").unwrap();
+        let old = Fabric::from_ibnetdiscover(&text).unwrap();
+        assert!(old.diff(&old).unwrap().is_empty());
+
+        // node02 is gone, node03 is cabled to leaf port 3, node01 moved from leaf
+        // port 1 to 5 and got a new LID, and the leaf-spine link retrained to EDR.
+        let node02 = text
+            .find("vendid=0x2c9\ndevid=0x1003\nsysimgguid=0x2c903000e0b83")
+            .unwrap();
+        let text = text[..node02].to_string()
+            + "vendid=0x2c9\ndevid=0x1017\nsysimgguid=0x2c903000e0c00\ncaguid=0x2c903000e0c00\n\
+               Ca\t1 \"H-0002c903000e0c00\"\t\t# \"node03 mlx5_0\"\n\
+               [1](2c903000e0c01) \t\"S-0002c90300a7b3f0\"[3]\t\t# lid 9 lmc 0 \"leaf\" lid 3 4xQDR\n";
+        let text = text
+            .replace(
+                "[2]\t\"H-0002c903000e0b80\"[1](2c903000e0b81) \t\t# \"node02 mlx4_0\" lid 2 4xFDR10\n",
+                "[3]\t\"H-0002c903000e0c00\"[1](2c903000e0c01) \t\t# \"node03 mlx5_0\" lid 9 4xQDR\n",
+            )
+            .replace("[1]\t\"H-0002c903000e0b72\"[1]", "[5]\t\"H-0002c903000e0b72\"[1]")
+            .replace(
+                "\"S-0002c90300a7b3f0\"[1]\t\t# lid 1 lmc 0",
+                "\"S-0002c90300a7b3f0\"[5]\t\t# lid 7 lmc 0",
+            )
+            .replace("lid 4 4xQDR", "lid 4 4xEDR")
+            .replace("\"[35]\t\t# \"SwitchX -  Mellanox Technologies\" lid 3 4xQDR", "\"[35]\t\t# \"SwitchX -  Mellanox Technologies\" lid 3 4xEDR");
+        let new = Fabric::from_ibnetdiscover(&text).unwrap();
+
+        let diff = old.diff(&new).unwrap();
+        log::debug!("{}", diff);
+
+        let leaf = 0x0002_c903_00a7_b3f0u64.to_be();
+        let spine = 0x0002_c903_00a7_b400u64.to_be();
+        let node01 = 0x0002_c903_000e_0b72u64.to_be();
+        let node02 = 0x0002_c903_000e_0b80u64.to_be();
+        let node03 = 0x0002_c903_000e_0c00u64.to_be();
+        let id = |node_guid, port| PortId { node_guid, port };
+
+        assert_eq!(diff.nodes_added.len(), 1);
+        assert_eq!(diff.nodes_added[0].node_guid, node03);
+        assert_eq!(diff.nodes_removed.len(), 1);
+        assert_eq!(
+            diff.nodes_removed[0].description.as_deref(),
+            Some("node02 mlx4_0")
+        );
+
+        assert_eq!(
+            diff.links_moved,
+            vec![LinkMove {
+                port: id(node01, 1),
+                old_remote: id(leaf, 1),
+                new_remote: id(leaf, 5),
+            }]
+        );
+        let link = |a: PortId, b: PortId| (a.min(b), a.max(b));
+        assert_eq!(diff.links_added, vec![link(id(leaf, 3), id(node03, 1))]);
+        assert_eq!(diff.links_removed, vec![link(id(leaf, 2), id(node02, 1))]);
+
+        let rate_ports: Vec<PortId> = diff.link_rate_changes.iter().map(|c| c.port).collect();
+        assert_eq!(rate_ports.len(), 2);
+        assert!(rate_ports.contains(&id(leaf, 35)) && rate_ports.contains(&id(spine, 35)));
+        for c in &diff.link_rate_changes {
+            assert_eq!(c.old, (Some(LinkWidth::X4), Some(LinkSpeed::Qdr)));
+            assert_eq!(c.new, (Some(LinkWidth::X4), Some(LinkSpeed::Edr)));
+        }
+
+        assert_eq!(diff.lid_changes.len(), 1);
+        assert_eq!(diff.lid_changes[0].port, id(node01, 1));
+        assert_eq!((diff.lid_changes[0].old, diff.lid_changes[0].new), (1, 7));
+        assert!(diff.link_state_changes.is_empty());
+
+        let report = diff.to_string();
+        assert!(report.contains(
+            "link moved: 0x0002c903000e0b72[1] was 0x0002c90300a7b3f0[1] now 0x0002c90300a7b3f0[5]"
+        ));
+        assert!(report.contains("link rate: 0x0002c90300a7b3f0[35] 4xQDR -> 4xEDR"));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        common::setup();
+        let fabric = load_fixture();
+
+        let mut out = Vec::new();
+        fabric.write_json(&mut out).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["version"], FABRIC_JSON_VERSION);
+        assert_eq!(value["nodes"][0]["guid"], "0x0002c903000e0b72");
+        assert_eq!(value["nodes"][0]["node_type"], "CA");
+        assert_eq!(value["links"].as_array().unwrap().len(), 3);
+
+        let loaded = Fabric::read_json(out.as_slice()).unwrap();
+        assert!(loaded.port.is_none());
+        assert_eq!(loaded.switches.len(), 2);
+        assert_eq!(loaded.hcas.len(), 2);
+        assert!(fabric.diff(&loaded).unwrap().is_empty());
+        assert_eq!(fabric.to_json().unwrap(), loaded.to_json().unwrap());
+
+        let spine = node_by_desc(&loaded, "spine SwitchX");
+        assert_eq!(&spine.dr_path[..3], &[0, 1, 35]);
+        assert_eq!(spine.device_id, 0xc738);
+        assert!(spine.enhanced_port0);
+        assert_eq!(
+            fabric.to_ibnetdiscover().unwrap(),
+            loaded.to_ibnetdiscover().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_json_errors() {
+        common::setup();
+        let mut json = load_fixture().to_json().unwrap();
+
+        json.version = FABRIC_JSON_VERSION + 1;
+        let text = serde_json::to_string(&json).unwrap();
+        let err = Fabric::read_json(text.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("version"));
+        assert!(Fabric::from_json(&json).is_err());
+
+        json.version = FABRIC_JSON_VERSION;
+        json.links[0].b.port = 99;
+        let err = Fabric::from_json(&json).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("unknown port"));
+    }
+
+    #[test]
+    fn test_graph_export() {
+        common::setup();
+        let fabric = load_fixture();
+
+        let dot = fabric.to_dot().unwrap();
+        assert!(dot.starts_with("graph fabric {"));
+        assert!(dot.trim_end().ends_with('}'));
+        assert!(dot.contains(
+            "\t\"0x0002c90300a7b3f0\" [label=\"SwitchX -  Mellanox Technologies\\n0x0002c90300a7b3f0\\nlid 3\", shape=box"
+        ));
+        assert!(dot.contains("\"0x0002c903000e0b72\" [label=\"node01 mlx4_0"));
+        assert!(dot.contains("shape=ellipse"));
+        assert!(dot.contains("{ rank=same; \"0x0002c903000e0b72\"; } // hop 0"));
+        assert!(dot.contains(
+            "\"0x0002c903000e0b72\" -- \"0x0002c90300a7b3f0\" [taillabel=\"1\", headlabel=\"1\", label=\"4xQDR\"];"
+        ));
+        assert_eq!(dot.matches(" -- ").count(), 3, "Each link is drawn once");
+
+        let graphml = fabric.to_graphml().unwrap();
+        assert!(graphml.contains("<graph id=\"fabric\" edgedefault=\"undirected\">"));
+        assert_eq!(graphml.matches("<node id=").count(), 4);
+        assert_eq!(graphml.matches("<edge ").count(), 3);
+        assert!(graphml.contains("<node id=\"0x0002c90300a7b400\">"));
+        assert!(graphml.contains("<data key=\"d0\">Switch</data>"));
+        assert!(graphml.contains("<data key=\"d1\">spine SwitchX</data>"));
+    }
+
+    #[test]
+    fn test_cabling_validation() {
+        common::setup();
+        let fabric = load_fixture();
+
+        let text = fs::read_to_string("tests/data/topology/ibnetdiscover.txt").unwrap();
+        let expected = CableMap::from_ibnetdiscover(&text).unwrap();
+        assert_eq!(expected.cables.len(), 3);
+        let report = fabric.validate_cabling(&expected).unwrap();
+        assert!(report.is_ok(), "{}", report);
+
+        let csv = "\
+node_a, port_a, node_b, port_b, rate
+# node01 uplink
+node01 mlx4_0, 1, 0x0002c90300a7b3f0, 1, 4xQDR
+0x0002c90300a7b3f0, 2, node02 mlx4_0, 1, 4xHDR
+S-0002c90300a7b3f0, 35, spine SwitchX, 36, 4x
+node03 mlx4_0, 1, 0x0002c90300a7b3f0, 3
+";
+        let map = CableMap::from_csv(csv).unwrap();
+        assert_eq!(map.cables.len(), 4);
+        assert_eq!(map.cables[2].a.node, NodeRef::Guid(0x0002_c903_00a7_b3f0));
+        assert_eq!(map.cables[2].width, Some(LinkWidth::X4));
+        assert_eq!(map.cables[2].speed, None);
+
+        let report = fabric.validate_cabling(&map).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.missing_nodes,
+            vec![NodeRef::Name("node03 mlx4_0".into())]
+        );
+        assert_eq!(report.missing_links, vec![map.cables[3].clone()]);
+        assert!(report.unexpected_nodes.is_empty());
+
+        assert_eq!(report.miswired.len(), 1);
+        assert_eq!(report.miswired[0].end, map.cables[2].a);
+        assert_eq!(
+            report.miswired[0].actual,
+            PortId {
+                node_guid: 0x0002_c903_00a7_b400u64.to_be(),
+                port: 35
+            }
+        );
+
+        assert_eq!(report.wrong_rates.len(), 1);
+        assert_eq!(report.wrong_rates[0].cable, map.cables[1]);
+        assert_eq!(report.wrong_rates[0].actual, (Some(LinkWidth::X4), None));
+
+        let text = report.to_string();
+        assert!(text.contains("missing node: 'node03 mlx4_0'"));
+        assert!(text.contains(
+            "miswired: 0x0002c90300a7b3f0[35] should go to 'spine SwitchX'[36] but goes to 0x0002c90300a7b400[35]"
+        ));
+        assert!(text.contains(
+            "wrong rate: 0x0002c90300a7b3f0[2] <-> 'node02 mlx4_0'[1] expected 4xHDR got 4x"
+        ));
+
+        let partial = CableMap::from_csv("node01 mlx4_0, 1, 0x0002c90300a7b3f0, 1").unwrap();
+        let report = fabric.validate_cabling(&partial).unwrap();
+        assert_eq!(report.unexpected_nodes.len(), 2);
+
+        let err = CableMap::from_csv("a, 1, b").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+        let err = CableMap::from_csv("a, 1, b, 2, 4xWARP").unwrap_err();
+        assert!(err.to_string().contains("invalid rate"));
+    }
+}